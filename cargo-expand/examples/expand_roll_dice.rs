@@ -11,7 +11,7 @@ fn main() {
         Ok(code) => code,
         Err(err) => {
             let _ = writeln!(io::stderr(), "{}", err);
-            1
+            err.exit_code()
         }
     });
 }
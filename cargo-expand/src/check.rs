@@ -0,0 +1,359 @@
+use crate::diff::print_item_diff;
+use crate::error::{Error, Result};
+use crate::json::{item_ident, item_module};
+use crate::opts::Coloring;
+use crate::{expand_syntax_tree, format_file, make_tempdir, Expand};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use syn::{Expr, File, Item, Stmt, Type};
+use termcolor::{ColorChoice, StandardStream, WriteColor};
+
+const EXTENSION: &str = ".expanded.rs";
+
+// Items that cannot be told apart by name share one snapshot.
+const UNNAMED: &str = "_";
+
+#[derive(Default)]
+struct Summary {
+    matched: usize,
+    changed: usize,
+    new: usize,
+    obsolete: usize,
+}
+
+// Every top-level item of the expansion is kept in
+// `<dir>/<kind>.<name>.expanded.rs` along with the impls of the same name, such
+// as those generated by derives. Items selected by the ITEM argument go in the
+// subdirectory of `<dir>` named after the module that ITEM is in.
+pub fn cargo_expand_check(args: &Expand, color: &Coloring, dir: &Path) -> Result<i32> {
+    let outdir = make_tempdir();
+    let outfile_path = outdir.path().join("expanded");
+
+    let syntax_tree = match expand_syntax_tree(args, color, &outfile_path)? {
+        Ok(syntax_tree) => syntax_tree,
+        Err(code) => return Ok(code),
+    };
+    if let Some(filter) = &args.item {
+        if syntax_tree.items.is_empty() {
+            return Err(Error::NoSuchItem(filter.to_string()));
+        }
+    }
+
+    let mut dir = dir.to_owned();
+    dir.extend(item_module(args));
+
+    let color_choice = match color {
+        Coloring::Auto if io::stdout().is_terminal() => ColorChoice::Auto,
+        Coloring::Auto | Coloring::Never => ColorChoice::Never,
+        Coloring::Always => ColorChoice::Always,
+    };
+    let mut stream = StandardStream::stdout(color_choice);
+
+    // Only a full expansion can tell that a snapshot no longer has an item.
+    let full = args.item.is_none() && args.derive.is_none() && args.attr.is_none();
+    let summary = check_snapshots(&mut stream, syntax_tree.items, &dir, args.bless, full)?;
+
+    let mismatched = summary.changed + summary.new + summary.obsolete;
+    let _ = writeln!(
+        io::stderr(),
+        "{}: {} matched, {} changed, {} new, {} obsolete",
+        if args.bless { "blessed" } else { "check" },
+        summary.matched,
+        summary.changed,
+        summary.new,
+        summary.obsolete,
+    );
+    Ok(if mismatched == 0 || args.bless { 0 } else { 1 })
+}
+
+// Compares the items with the snapshots in `dir`, printing the differences to
+// `stream`, or with `bless` writing the snapshots instead.
+fn check_snapshots(
+    stream: &mut impl WriteColor,
+    items: Vec<Item>,
+    dir: &Path,
+    bless: bool,
+    full: bool,
+) -> Result<Summary> {
+    let mut by_name: BTreeMap<String, Vec<Item>> = BTreeMap::new();
+    for item in items {
+        let name = snapshot_name(&item).unwrap_or_else(|| UNNAMED.to_owned());
+        by_name.entry(name).or_default().push(item);
+    }
+    let snapshots = file_names(by_name);
+
+    let mut summary = Summary::default();
+    for (name, items) in &snapshots {
+        let path = dir.join(format!("{}{}", name, EXTENSION));
+        let source = format_file(&File {
+            shebang: None,
+            attrs: Vec::new(),
+            items: items.clone(),
+        });
+        let old = match fs::read_to_string(&path) {
+            Ok(old) => Some(old),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => return Err(Error::Io(err)),
+        };
+        if old.as_ref() == Some(&source) {
+            summary.matched += 1;
+            continue;
+        }
+
+        if old.is_some() {
+            summary.changed += 1;
+        } else {
+            summary.new += 1;
+        }
+        if bless {
+            fs::create_dir_all(dir)?;
+            fs::write(&path, source)?;
+        } else {
+            let old_label = match old {
+                Some(_) => path.display().to_string(),
+                None => "/dev/null".to_owned(),
+            };
+            let new_label = format!("expanded: {}", name);
+            let old = old.unwrap_or_default();
+            print_item_diff(stream, &old_label, &new_label, &old, &source);
+        }
+    }
+
+    if full {
+        for path in existing_snapshots(dir)? {
+            let name = path.file_name().unwrap().to_string_lossy();
+            let name = &name[..name.len() - EXTENSION.len()];
+            if snapshots.contains_key(name) {
+                continue;
+            }
+            summary.obsolete += 1;
+            if bless {
+                fs::remove_file(&path)?;
+            } else {
+                let _ = writeln!(io::stderr(), "obsolete snapshot: {}", path.display());
+            }
+        }
+    }
+
+    Ok(summary)
+}
+
+// Names that differ only in case, like a struct `Foo` and a fn `foo`, would
+// share a file on a case-insensitive filesystem, so the file name starts with
+// the kind of item, and those that still collide, all but the first in order,
+// get a suffix, which cannot be part of an identifier.
+fn file_names(by_name: BTreeMap<String, Vec<Item>>) -> BTreeMap<String, Vec<Item>> {
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    let mut snapshots = BTreeMap::new();
+    for (name, items) in by_name {
+        let mut file_name = if name == UNNAMED {
+            name
+        } else {
+            let kind = items.iter().find_map(item_kind).unwrap_or("impl");
+            format!("{}.{}", kind, name)
+        };
+        let count = seen.entry(file_name.to_lowercase()).or_default();
+        *count += 1;
+        if *count > 1 {
+            file_name = format!("{}-{}", file_name, count);
+        }
+        snapshots.insert(file_name, items);
+    }
+    snapshots
+}
+
+// None for impls, which are named after their self type.
+fn item_kind(item: &Item) -> Option<&'static str> {
+    Some(match item {
+        Item::Const(item) if item.ident == "_" => return None,
+        Item::Const(_) => "const",
+        Item::Enum(_) => "enum",
+        Item::ExternCrate(_) => "extern_crate",
+        Item::Fn(_) => "fn",
+        Item::Macro(_) => "macro",
+        Item::Mod(_) => "mod",
+        Item::Static(_) => "static",
+        Item::Struct(_) => "struct",
+        Item::Trait(_) | Item::TraitAlias(_) => "trait",
+        Item::Type(_) => "type",
+        Item::Union(_) => "union",
+        _ => return None,
+    })
+}
+
+// Impls are kept with their self type, including the ones that derives wrap
+// in `const _: () = { ... };`.
+fn snapshot_name(item: &Item) -> Option<String> {
+    if let Some(ident) = item_ident(item) {
+        return Some(ident.trim_start_matches("r#").to_owned());
+    }
+    let Item::Const(item) = item else {
+        return None;
+    };
+    let Expr::Block(block) = &*item.expr else {
+        return None;
+    };
+    block.block.stmts.iter().find_map(|stmt| match stmt {
+        Stmt::Item(Item::Impl(item)) => match &*item.self_ty {
+            Type::Path(ty) => Some(ty.path.segments.last()?.ident.to_string()),
+            _ => None,
+        },
+        _ => None,
+    })
+}
+
+fn existing_snapshots(dir: &Path) -> Result<Vec<PathBuf>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(Error::Io(err)),
+    };
+    let mut paths = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        let is_snapshot = path
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().ends_with(EXTENSION));
+        if is_snapshot && path.is_file() {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use termcolor::NoColor;
+
+    fn items(source: &str) -> Vec<Item> {
+        syn::parse_file(source).unwrap().items
+    }
+
+    fn check(dir: &Path, source: &str, bless: bool, full: bool) -> (Summary, String) {
+        let mut stream = NoColor::new(Vec::new());
+        let summary = check_snapshots(&mut stream, items(source), dir, bless, full)
+            .unwrap_or_else(|err| panic!("{}", err));
+        (summary, String::from_utf8(stream.into_inner()).unwrap())
+    }
+
+    fn counts(summary: &Summary) -> [usize; 4] {
+        [
+            summary.matched,
+            summary.changed,
+            summary.new,
+            summary.obsolete,
+        ]
+    }
+
+    fn file_names_of(source: &str) -> Vec<String> {
+        let mut by_name: BTreeMap<String, Vec<Item>> = BTreeMap::new();
+        for item in items(source) {
+            let name = snapshot_name(&item).unwrap_or_else(|| UNNAMED.to_owned());
+            by_name.entry(name).or_default().push(item);
+        }
+        file_names(by_name).into_keys().collect()
+    }
+
+    #[test]
+    fn test_snapshot_name() {
+        let names: Vec<Option<String>> = items(
+            "
+            struct S;
+            fn r#match() {}
+            impl S {}
+            const _: () = {
+                impl ::core::clone::Clone for T {}
+            };
+            use std::fmt;
+            ",
+        )
+        .iter()
+        .map(snapshot_name)
+        .collect();
+        assert_eq!(
+            names,
+            [
+                Some("S".to_owned()),
+                Some("match".to_owned()),
+                Some("S".to_owned()),
+                Some("T".to_owned()),
+                None,
+            ],
+        );
+    }
+
+    #[test]
+    fn test_file_names() {
+        assert_eq!(
+            file_names_of("struct Foo; impl Foo {} fn foo() {} const FOO: () = (); use std::fmt;"),
+            ["_", "const.FOO", "fn.foo", "struct.Foo"],
+        );
+        assert_eq!(
+            file_names_of("struct FOO; struct Foo; impl Bar {}"),
+            ["impl.Bar", "struct.FOO", "struct.Foo-2"],
+        );
+    }
+
+    #[test]
+    fn test_existing_snapshots() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(existing_snapshots(&dir.path().join("missing"))
+            .unwrap_or_else(|err| panic!("{}", err))
+            .is_empty());
+
+        fs::write(dir.path().join("fn.b.expanded.rs"), "").unwrap();
+        fs::write(dir.path().join("fn.a.expanded.rs"), "").unwrap();
+        fs::write(dir.path().join("notes.rs"), "").unwrap();
+        fs::create_dir(dir.path().join("mod.m.expanded.rs")).unwrap();
+        let paths = existing_snapshots(dir.path()).unwrap_or_else(|err| panic!("{}", err));
+        assert_eq!(
+            paths,
+            [
+                dir.path().join("fn.a.expanded.rs"),
+                dir.path().join("fn.b.expanded.rs"),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_check_and_bless() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = &dir.path().join("snapshots");
+        let source = "struct S; impl S {} fn f() {}";
+
+        let (summary, diff) = check(dir, source, false, true);
+        assert_eq!(counts(&summary), [0, 0, 2, 0]);
+        assert!(diff.contains("--- /dev/null\n+++ expanded: struct.S\n"));
+        assert!(!dir.exists());
+
+        let (summary, _) = check(dir, source, true, true);
+        assert_eq!(counts(&summary), [0, 0, 2, 0]);
+        let snapshot = fs::read_to_string(dir.join("struct.S.expanded.rs")).unwrap();
+        assert_eq!(snapshot, "struct S;\nimpl S {}\n");
+
+        let (summary, diff) = check(dir, source, false, true);
+        assert_eq!(counts(&summary), [2, 0, 0, 0]);
+        assert!(diff.is_empty());
+
+        let source = "struct S; impl S {} fn f() { 1; }";
+        let (summary, diff) = check(dir, source, false, true);
+        assert_eq!(counts(&summary), [1, 1, 0, 0]);
+        assert!(diff.contains("+    1;\n"));
+
+        fs::write(dir.join("fn.g.expanded.rs"), "fn g() {}\n").unwrap();
+        let (summary, _) = check(dir, source, false, false);
+        assert_eq!(counts(&summary), [1, 1, 0, 0]);
+        let (summary, _) = check(dir, source, false, true);
+        assert_eq!(counts(&summary), [1, 1, 0, 1]);
+
+        let (summary, _) = check(dir, source, true, true);
+        assert_eq!(counts(&summary), [1, 1, 0, 1]);
+        assert!(!dir.join("fn.g.expanded.rs").exists());
+        let (summary, _) = check(dir, source, false, true);
+        assert_eq!(counts(&summary), [2, 0, 0, 0]);
+    }
+}
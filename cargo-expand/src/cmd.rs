@@ -15,6 +15,10 @@ impl Line {
         }
     }
 
+    pub fn bin(&self) -> &OsStr {
+        &self.bin
+    }
+
     pub fn arg<S: AsRef<OsStr>>(&mut self, arg: S) {
         self.args.push(arg.as_ref().to_owned());
     }
@@ -27,10 +31,6 @@ impl Line {
         self.args
             .extend(args.into_iter().map(|arg| arg.as_ref().to_owned()));
     }
-
-    pub fn insert<S: AsRef<OsStr>>(&mut self, index: usize, arg: S) {
-        self.args.insert(index, arg.as_ref().to_owned());
-    }
 }
 
 impl Display for Line {
@@ -1,16 +1,13 @@
+use crate::manifest;
 use serde::Deserialize;
 use std::env;
 use std::fs;
 use std::io::{self, Write};
-use std::path::PathBuf;
-
-#[derive(Deserialize)]
-struct Sections {
-    #[serde(default)]
-    expand: Config,
-}
+use std::path::{Path, PathBuf};
+use toml::Table;
 
 #[derive(Deserialize, Default)]
+#[serde(rename_all = "kebab-case")]
 pub struct Config {
     pub theme: Option<String>,
     pub color: Option<String>,
@@ -21,23 +18,114 @@ pub struct Config {
     /// aesthetic formatting when it works.
     #[serde(default)]
     pub rustfmt: bool,
+    /// Defaults for the sanitize passes. Each is enabled if the corresponding
+    /// command line flag asks for it, or if the config does and the flag's
+    /// --no- form is not passed.
+    #[serde(default)]
+    pub keep_macro_rules: bool,
+    #[serde(default)]
+    pub strip_derived: bool,
+    #[serde(default)]
+    pub hide_prelude: bool,
+    #[serde(default)]
+    pub collapse_format_args: bool,
+    #[serde(default)]
+    pub strip_rustc_attrs: bool,
+    /// Default feature selection. --features replaces the configured list,
+    /// and --no-all-features and --default-features turn off the flags.
+    pub features: Option<Vec<String>>,
+    #[serde(default)]
+    pub all_features: bool,
+    #[serde(default)]
+    pub no_default_features: bool,
+    /// Default ITEM to expand, if none is passed. --no-item expands the
+    /// whole crate instead.
+    pub item: Option<String>,
+}
+
+// Configuration is looked up in the following places, each overriding the
+// keys set by the ones before it:
+//
+//   1. The [expand] section of $CARGO_HOME/config.toml
+//   2. The [expand] section of .cargo/config.toml in the manifest directory
+//      and each of its ancestors, the closest directory taking precedence
+//   3. [package.metadata.expand] in the package's Cargo.toml
+//
+// Command line flags take precedence over all of these, key by key. For keys
+// that enable something, the flag's --no- form (or --default-features for
+// no-default-features, and --no-item for item) turns it off again.
+pub fn deserialize(manifest_path: Option<&Path>) -> Config {
+    let cargo_home = env::var_os("CARGO_HOME").map(PathBuf::from);
+    deserialize_with_home(manifest_path, cargo_home.as_deref())
 }
 
-pub fn deserialize() -> Config {
-    try_deserialize().unwrap_or_default()
+fn deserialize_with_home(manifest_path: Option<&Path>, cargo_home: Option<&Path>) -> Config {
+    let mut merged = Table::new();
+
+    let manifest_path = manifest::find_cargo_manifest(manifest_path).ok();
+    for config_path in config_paths(manifest_path.as_deref(), cargo_home) {
+        if let Some(table) = try_read_expand_section(&config_path) {
+            merge(&mut merged, config_path.display(), table);
+        }
+    }
+
+    if let Some(manifest_path) = &manifest_path {
+        if let Ok(cargo_manifest) = manifest::parse(Some(manifest_path)) {
+            let metadata = cargo_manifest
+                .package
+                .and_then(|package| package.metadata)
+                .and_then(|metadata| metadata.expand);
+            if let Some(table) = metadata {
+                merge(&mut merged, manifest_path.display(), table);
+            }
+        }
+    }
+
+    toml::Value::Table(merged).try_into().unwrap_or_default()
 }
 
-fn try_deserialize() -> Option<Config> {
-    let cargo_home = env::var_os("CARGO_HOME").map(PathBuf::from)?;
+// Least specific first.
+fn config_paths(manifest_path: Option<&Path>, cargo_home: Option<&Path>) -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    if let Some(cargo_home) = cargo_home {
+        dirs.push(cargo_home.to_owned());
+    }
+    let project_dir = match manifest_path.and_then(Path::parent) {
+        Some(dir) => Some(dir.to_owned()),
+        None => env::current_dir().ok(),
+    };
+    if let Some(project_dir) = project_dir {
+        let project_dir = fs::canonicalize(&project_dir).unwrap_or(project_dir);
+        let mut ancestors: Vec<PathBuf> = project_dir
+            .ancestors()
+            .map(|dir| dir.join(".cargo"))
+            .collect();
+        ancestors.reverse();
+        dirs.extend(ancestors);
+    }
+
     let config_names = ["config", "config.toml"];
-    let config_path = config_names
-        .iter()
-        .map(|name| cargo_home.join(name))
-        .find(|path| path.exists())?;
+    let mut paths: Vec<PathBuf> = Vec::new();
+    for dir in dirs {
+        let Some(path) = config_names
+            .iter()
+            .map(|name| dir.join(name))
+            .find(|path| path.exists())
+        else {
+            continue;
+        };
+        // $CARGO_HOME is usually also one of the ancestors' .cargo.
+        let path = fs::canonicalize(&path).unwrap_or(path);
+        paths.retain(|seen| *seen != path);
+        paths.push(path);
+    }
+    paths
+}
 
-    let content = fs::read_to_string(&config_path).ok()?;
+fn try_read_expand_section(config_path: &Path) -> Option<Table> {
+    let content = fs::read_to_string(config_path).ok()?;
 
-    let full_config: Sections = match toml::from_str(&content) {
+    let mut full_config: Table = match toml::from_str(&content) {
         Ok(config) => config,
         Err(err) => {
             let _ = writeln!(io::stderr(), "Warning: {}: {}", config_path.display(), err);
@@ -45,5 +133,83 @@ fn try_deserialize() -> Option<Config> {
         }
     };
 
-    Some(full_config.expand)
+    match full_config.remove("expand")? {
+        toml::Value::Table(table) => Some(table),
+        _ => {
+            let _ = writeln!(
+                io::stderr(),
+                "Warning: {}: `expand` is not a table",
+                config_path.display(),
+            );
+            None
+        }
+    }
+}
+
+// Overrides the keys of `merged` with the ones from `table`, unless `table`
+// has invalid values, so that a broken file does not discard the rest.
+fn merge(merged: &mut Table, source: impl std::fmt::Display, table: Table) {
+    if let Err(err) = toml::Value::Table(table.clone()).try_into::<Config>() {
+        let _ = writeln!(io::stderr(), "Warning: {}: {}", source, err);
+        return;
+    }
+    merged.extend(table);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(path: &Path, content: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    #[test]
+    fn test_merge_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = fs::canonicalize(dir.path()).unwrap();
+        let home = root.join("home");
+        write(
+            &home.join("config.toml"),
+            "[expand]\ntheme = 'home'\npager = true\nrustfmt = true\nitem = 'home'\n",
+        );
+        write(
+            &root.join(".cargo/config.toml"),
+            "[expand]\ntheme = 'root'\nstrip-derived = true\nitem = 'root'\n",
+        );
+        // The older name, `config`, is read too.
+        write(
+            &root.join("ws/.cargo/config"),
+            "[expand]\ntheme = 'ws'\nfeatures = ['a']\n",
+        );
+        // Invalid values discard only the file they are in.
+        write(
+            &root.join("ws/pkg/.cargo/config.toml"),
+            "[expand]\ntheme = 'pkg'\npager = 'yes'\n",
+        );
+        let manifest_path = root.join("ws/pkg/Cargo.toml");
+        write(
+            &manifest_path,
+            "[package]\nname = 'pkg'\n\n[package.metadata.expand]\nitem = 'manifest'\n",
+        );
+
+        let config = deserialize_with_home(Some(&manifest_path), Some(&home));
+        assert_eq!(config.theme.as_deref(), Some("ws"));
+        assert!(config.pager);
+        assert!(config.rustfmt);
+        assert!(config.strip_derived);
+        assert_eq!(config.features, Some(vec!["a".to_owned()]));
+        assert_eq!(config.item.as_deref(), Some("manifest"));
+
+        // $CARGO_HOME has the lowest precedence even when it is also one of
+        // the ancestors.
+        let config = deserialize_with_home(Some(&manifest_path), Some(&root.join(".cargo")));
+        assert_eq!(config.theme.as_deref(), Some("ws"));
+        assert!(!config.pager);
+
+        let config = deserialize_with_home(Some(&home.join("Cargo.toml")), Some(&home));
+        assert_eq!(config.theme.as_deref(), Some("root"));
+        assert_eq!(config.item.as_deref(), Some("root"));
+    }
 }
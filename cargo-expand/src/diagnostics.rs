@@ -0,0 +1,213 @@
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::io::Write;
+
+// One line of `cargo --message-format=json` output. Only compiler messages
+// are of interest; artifacts and build-finished messages are skipped.
+#[derive(Deserialize)]
+struct Message {
+    reason: String,
+    target: Option<Target>,
+    message: Option<Diagnostic>,
+}
+
+#[derive(Deserialize)]
+struct Target {
+    name: String,
+    kind: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct Diagnostic {
+    message: String,
+    level: String,
+    rendered: Option<String>,
+}
+
+// Compiler diagnostics, written out as they arrive and counted by the target
+// they were reported for, in the order the targets first reported something.
+#[derive(Default)]
+pub struct Diagnostics {
+    groups: Vec<Group>,
+    seen: HashSet<String>,
+    nightly_required: bool,
+}
+
+struct Group {
+    target: String,
+    kind: String,
+    errors: usize,
+    warnings: usize,
+    duplicates: usize,
+}
+
+impl Diagnostics {
+    // Takes one line of cargo's stdout, and writes the diagnostic in it, if
+    // any, to `out`.
+    pub fn push_json(&mut self, line: &str, out: &mut impl Write) {
+        let Ok(message) = serde_json::from_str::<Message>(line) else {
+            return;
+        };
+        if message.reason != "compiler-message" {
+            return;
+        }
+        let (Some(target), Some(diagnostic)) = (message.target, message.message) else {
+            return;
+        };
+        self.note_text(&diagnostic.message);
+        if crate::ignore_cargo_err(&diagnostic.message)
+            || diagnostic.message.starts_with("aborting due to")
+        {
+            return;
+        }
+        let Some(rendered) = diagnostic.rendered else {
+            return;
+        };
+
+        let kind = target.kind.first().cloned().unwrap_or_default();
+        let index = match self
+            .groups
+            .iter()
+            .position(|group| group.target == target.name && group.kind == kind)
+        {
+            Some(index) => index,
+            None => {
+                self.groups.push(Group {
+                    target: target.name,
+                    kind,
+                    errors: 0,
+                    warnings: 0,
+                    duplicates: 0,
+                });
+                self.groups.len() - 1
+            }
+        };
+        let group = &mut self.groups[index];
+
+        // The same diagnostic is reported again for every crate type and for
+        // cached builds being replayed.
+        if !self.seen.insert(rendered.clone()) {
+            group.duplicates += 1;
+            return;
+        }
+        if diagnostic.level.starts_with("error") {
+            group.errors += 1;
+        } else {
+            group.warnings += 1;
+        }
+        let _ = write!(out, "{}", rendered);
+        if !rendered.ends_with('\n') {
+            let _ = writeln!(out);
+        }
+    }
+
+    // Takes text that cargo printed outside of JSON messages, such as its own
+    // errors on stderr.
+    pub fn note_text(&mut self, text: &str) {
+        if text.contains("only accepted on the nightly") {
+            self.nightly_required = true;
+        }
+    }
+
+    pub fn errors(&self) -> usize {
+        self.groups.iter().map(|group| group.errors).sum()
+    }
+
+    pub fn nightly_required(&self) -> bool {
+        self.nightly_required
+    }
+
+    // A count of what each target reported, once the build is done.
+    pub fn print_summary(&self, out: &mut impl Write) {
+        for group in &self.groups {
+            let mut counts = Vec::new();
+            if group.errors > 0 {
+                counts.push(crate::plural(group.errors, "error"));
+            }
+            if group.warnings > 0 {
+                counts.push(crate::plural(group.warnings, "warning"));
+            }
+            if counts.is_empty() {
+                continue;
+            }
+            let mut summary = format!("`{}` ({}): {}", group.target, group.kind, counts.join(", "));
+            if group.duplicates > 0 {
+                summary += &format!(" ({})", crate::plural(group.duplicates, "duplicate"));
+            }
+            let _ = writeln!(out, "{}\n", summary);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn message(target: &str, kind: &str, level: &str, text: &str) -> String {
+        json!({
+            "reason": "compiler-message",
+            "target": { "name": target, "kind": [kind] },
+            "message": {
+                "message": text,
+                "level": level,
+                "rendered": format!("{}: {}", level, text),
+            },
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn test_render() {
+        let mut diagnostics = Diagnostics::default();
+        let mut out = Vec::new();
+        let lines = [
+            message("app", "bin", "warning", "unused variable: `x`"),
+            r#"{"reason":"compiler-artifact","target":{"name":"dep","kind":["lib"]}}"#.to_owned(),
+            message("app", "lib", "error", "cannot find value `y`"),
+            message("app", "bin", "warning", "unused variable: `x`"),
+            message("app", "bin", "error", "mismatched types"),
+            message("app", "bin", "error", "aborting due to 1 previous error"),
+            "not json".to_owned(),
+        ];
+        let mut written = Vec::new();
+        for line in &lines {
+            diagnostics.push_json(line, &mut out);
+            written.push(String::from_utf8(std::mem::take(&mut out)).unwrap());
+        }
+        // Each diagnostic is written as soon as it arrives.
+        assert_eq!(
+            written,
+            [
+                "warning: unused variable: `x`\n",
+                "",
+                "error: cannot find value `y`\n",
+                "",
+                "error: mismatched types\n",
+                "",
+                "",
+            ],
+        );
+        assert_eq!(diagnostics.errors(), 2);
+        assert!(!diagnostics.nightly_required());
+
+        diagnostics.print_summary(&mut out);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "`app` (bin): 1 error, 1 warning (1 duplicate)\n\n\
+             `app` (lib): 1 error\n\n",
+        );
+    }
+
+    #[test]
+    fn test_nightly_required() {
+        let mut diagnostics = Diagnostics::default();
+        diagnostics.note_text("error: the option `Z` is only accepted on the nightly compiler\n");
+        assert!(diagnostics.nightly_required());
+        assert_eq!(diagnostics.errors(), 0);
+
+        let mut out = Vec::new();
+        diagnostics.print_summary(&mut out);
+        assert!(out.is_empty());
+    }
+}
@@ -0,0 +1,422 @@
+use crate::error::Result;
+use crate::opts::Coloring;
+use crate::{expand_syntax_tree, format_item, make_tempdir, manifest, Expand};
+use quote::ToTokens;
+use similar::{ChangeTag, TextDiff};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use syn::{ImplItem, Item};
+use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
+
+// A top-level item of the expanded code, formatted on its own.
+struct NamedItem {
+    name: String,
+    source: String,
+}
+
+pub fn cargo_expand_diff(mut args: Expand, color: &Coloring) -> Result<i32> {
+    if args.base_features.is_none() && args.base_rev.is_none() {
+        let _ = writeln!(
+            io::stderr(),
+            "ERROR: --diff requires a baseline, use --base-features or --base-rev.",
+        );
+        return Ok(1);
+    }
+
+    let outdir = make_tempdir();
+
+    let new = match expand_items(&args, color, &outdir.path().join("new"))? {
+        Ok(items) => items,
+        Err(code) => return Ok(code),
+    };
+
+    let mut worktree = None;
+    if let Some(rev) = &args.base_rev {
+        let path = outdir.path().join("worktree");
+        match Worktree::add(args.manifest_path.as_deref(), rev, &path)? {
+            Ok(added) => {
+                args.manifest_path = Some(added.manifest_path.clone());
+                worktree = Some(added);
+            }
+            Err(code) => return Ok(code),
+        }
+    }
+    if let Some(base_features) = args.base_features.take() {
+        args.features = Some(base_features);
+    }
+
+    let base = expand_items(&args, color, &outdir.path().join("base"));
+    drop(worktree);
+    let base = match base? {
+        Ok(items) => items,
+        Err(code) => return Ok(code),
+    };
+
+    print_diff(&base, &new, color);
+    Ok(0)
+}
+
+// Expands the crate and splits the result into individually formatted
+// top-level items.
+fn expand_items(
+    args: &Expand,
+    color: &Coloring,
+    outfile_path: &Path,
+) -> Result<std::result::Result<Vec<NamedItem>, i32>> {
+    let syntax_tree = match expand_syntax_tree(args, color, outfile_path)? {
+        Ok(syntax_tree) => syntax_tree,
+        Err(code) => return Ok(Err(code)),
+    };
+
+    let names = item_names(&syntax_tree.items);
+    let items = names
+        .into_iter()
+        .zip(syntax_tree.items)
+        .map(|(name, item)| NamedItem {
+            name,
+            source: format_item(item),
+        })
+        .collect();
+    Ok(Ok(items))
+}
+
+// Names that pair up items regardless of their order. Items sharing a name,
+// like several `impl S` blocks, are told apart by their signature, and only
+// items identical in that too are numbered in order.
+fn item_names(items: &[Item]) -> Vec<String> {
+    let mut count = BTreeMap::new();
+    for item in items {
+        *count.entry(item_name(item)).or_insert(0) += 1;
+    }
+    let names: Vec<String> = items
+        .iter()
+        .map(|item| {
+            let name = item_name(item);
+            if count[&name] > 1 {
+                format!("{} {{{}}}", name, item_signature(item))
+            } else {
+                name
+            }
+        })
+        .collect();
+
+    let mut count = BTreeMap::new();
+    for name in &names {
+        *count.entry(name.clone()).or_insert(0) += 1;
+    }
+    let mut seen = BTreeMap::new();
+    names
+        .into_iter()
+        .map(|name| {
+            if count[&name] == 1 {
+                return name;
+            }
+            let nth = seen.entry(name.clone()).or_insert(0);
+            *nth += 1;
+            format!("{} #{}", name, nth)
+        })
+        .collect()
+}
+
+// A short description of an item, used to pair up the same item on both sides
+// of the diff.
+fn item_name(item: &Item) -> String {
+    match item {
+        Item::Const(item) => format!("const {}", item.ident),
+        Item::Enum(item) => format!("enum {}", item.ident),
+        Item::ExternCrate(item) => format!("extern crate {}", item.ident),
+        Item::Fn(item) => format!("fn {}", item.sig.ident),
+        Item::ForeignMod(_) => "extern block".to_owned(),
+        Item::Impl(item) => match &item.trait_ {
+            Some((negative, path, _for)) => format!(
+                "impl {}{} for {}",
+                if negative.is_some() { "!" } else { "" },
+                tokens(path),
+                tokens(&item.self_ty),
+            ),
+            None => format!("impl {}", tokens(&item.self_ty)),
+        },
+        Item::Macro(item) => match &item.ident {
+            Some(ident) => format!("macro {}", ident),
+            None => format!("{}!", tokens(&item.mac.path)),
+        },
+        Item::Mod(item) => format!("mod {}", item.ident),
+        Item::Static(item) => format!("static {}", item.ident),
+        Item::Struct(item) => format!("struct {}", item.ident),
+        Item::Trait(item) => format!("trait {}", item.ident),
+        Item::TraitAlias(item) => format!("trait {}", item.ident),
+        Item::Type(item) => format!("type {}", item.ident),
+        Item::Union(item) => format!("union {}", item.ident),
+        Item::Use(item) => format!("use {}", tokens(&item.tree)),
+        _ => tokens(item),
+    }
+}
+
+// What distinguishes items of the same name: the type of a const, the
+// signature of a fn, the generics and members of an impl.
+fn item_signature(item: &Item) -> String {
+    match item {
+        Item::Const(item) => tokens(&item.ty),
+        Item::Static(item) => tokens(&item.ty),
+        Item::Fn(item) => tokens(&item.sig),
+        Item::Impl(item) => {
+            let generics = tokens(&item.generics) + &tokens(&item.generics.where_clause);
+            let members: Vec<String> = item.items.iter().map(impl_item_name).collect();
+            let members = members.join(", ");
+            if generics.is_empty() {
+                members
+            } else {
+                format!("{} {}", generics, members)
+            }
+        }
+        Item::ForeignMod(item) => {
+            let members: Vec<String> = item.items.iter().map(tokens).collect();
+            format!("{} {}", tokens(&item.abi), members.join(" "))
+        }
+        _ => tokens(item),
+    }
+}
+
+fn impl_item_name(item: &ImplItem) -> String {
+    match item {
+        ImplItem::Const(item) => format!("const {}", item.ident),
+        ImplItem::Fn(item) => format!("fn {}", item.sig.ident),
+        ImplItem::Type(item) => format!("type {}", item.ident),
+        _ => tokens(item),
+    }
+}
+
+fn tokens<T: ToTokens>(node: &T) -> String {
+    let mut string = node.to_token_stream().to_string();
+    for (spaced, tight) in [
+        (" :: ", "::"),
+        (" < ", "<"),
+        (" <", "<"),
+        (" >", ">"),
+        (" ,", ","),
+    ] {
+        string = string.replace(spaced, tight);
+    }
+    string
+}
+
+fn print_diff(base: &[NamedItem], new: &[NamedItem], color: &Coloring) {
+    let color_choice = match color {
+        Coloring::Auto if io::stdout().is_terminal() => ColorChoice::Auto,
+        Coloring::Auto | Coloring::Never => ColorChoice::Never,
+        Coloring::Always => ColorChoice::Always,
+    };
+    let mut stream = StandardStream::stdout(color_choice);
+
+    let base_by_name: BTreeMap<&str, &str> = base
+        .iter()
+        .map(|item| (item.name.as_str(), item.source.as_str()))
+        .collect();
+    let new_by_name: BTreeMap<&str, &str> = new
+        .iter()
+        .map(|item| (item.name.as_str(), item.source.as_str()))
+        .collect();
+
+    let (mut changed, mut added, mut removed) = (0, 0, 0);
+    for item in new {
+        let old = match base_by_name.get(item.name.as_str()) {
+            Some(old) if *old == item.source => continue,
+            Some(old) => {
+                changed += 1;
+                old
+            }
+            None => {
+                added += 1;
+                ""
+            }
+        };
+        let (base_label, new_label) = (
+            format!("base: {}", item.name),
+            format!("new: {}", item.name),
+        );
+        print_item_diff(&mut stream, &base_label, &new_label, old, &item.source);
+    }
+    for item in base {
+        if !new_by_name.contains_key(item.name.as_str()) {
+            removed += 1;
+            let (base_label, new_label) = (
+                format!("base: {}", item.name),
+                format!("new: {}", item.name),
+            );
+            print_item_diff(&mut stream, &base_label, &new_label, &item.source, "");
+        }
+    }
+
+    let _ = writeln!(io::stderr());
+    if changed + added + removed == 0 {
+        let _ = writeln!(io::stderr(), "No differences in expanded code");
+    } else {
+        let _ = writeln!(
+            io::stderr(),
+            "{} changed, {} added, {} removed",
+            crate::plural(changed, "item"),
+            crate::plural(added, "item"),
+            crate::plural(removed, "item"),
+        );
+    }
+}
+
+pub fn print_item_diff(
+    stream: &mut impl WriteColor,
+    old_label: &str,
+    new_label: &str,
+    old: &str,
+    new: &str,
+) {
+    let diff = TextDiff::from_lines(old, new);
+
+    let _ = stream.set_color(ColorSpec::new().set_bold(true));
+    let _ = writeln!(stream, "--- {}", old_label);
+    let _ = writeln!(stream, "+++ {}", new_label);
+    let _ = stream.reset();
+
+    for hunk in diff.unified_diff().context_radius(3).iter_hunks() {
+        let _ = stream.set_color(ColorSpec::new().set_fg(Some(Color::Cyan)));
+        let _ = writeln!(stream, "{}", hunk.header());
+        let _ = stream.reset();
+        for change in hunk.iter_changes() {
+            let (sign, color) = match change.tag() {
+                ChangeTag::Delete => ("-", Some(Color::Red)),
+                ChangeTag::Insert => ("+", Some(Color::Green)),
+                ChangeTag::Equal => (" ", None),
+            };
+            let _ = stream.set_color(ColorSpec::new().set_fg(color));
+            let _ = write!(stream, "{}{}", sign, change.value());
+            if change.missing_newline() {
+                let _ = writeln!(stream);
+            }
+            let _ = stream.reset();
+        }
+    }
+}
+
+// A git worktree containing a checkout of the baseline revision. The worktree
+// is removed again when this is dropped.
+struct Worktree {
+    repo: PathBuf,
+    path: PathBuf,
+    manifest_path: PathBuf,
+}
+
+impl Worktree {
+    fn add(
+        manifest_path: Option<&Path>,
+        rev: &str,
+        path: &Path,
+    ) -> Result<std::result::Result<Self, i32>> {
+        let manifest_path = fs::canonicalize(manifest::find_cargo_manifest(manifest_path)?)?;
+        let manifest_dir = manifest_path.parent().unwrap();
+
+        let output = Command::new("git")
+            .arg("-C")
+            .arg(manifest_dir)
+            .arg("rev-parse")
+            .arg("--show-toplevel")
+            .stderr(Stdio::inherit())
+            .output()?;
+        if !output.status.success() {
+            let _ = writeln!(
+                io::stderr(),
+                "ERROR: --base-rev requires {} to be inside a git repository.",
+                manifest_dir.display(),
+            );
+            return Ok(Err(1));
+        }
+        let toplevel = String::from_utf8_lossy(&output.stdout);
+        let repo = fs::canonicalize(toplevel.trim())?;
+        let relative_manifest_path = manifest_path.strip_prefix(&repo).unwrap().to_owned();
+
+        let status = Command::new("git")
+            .arg("-C")
+            .arg(&repo)
+            .arg("worktree")
+            .arg("add")
+            .arg("--quiet")
+            .arg("--detach")
+            .arg(path)
+            .arg(rev)
+            .status()?;
+        if !status.success() {
+            let _ = writeln!(io::stderr(), "ERROR: failed to check out revision {}", rev);
+            return Ok(Err(1));
+        }
+
+        Ok(Ok(Worktree {
+            repo,
+            path: path.to_owned(),
+            manifest_path: path.join(relative_manifest_path),
+        }))
+    }
+}
+
+impl Drop for Worktree {
+    fn drop(&mut self) {
+        let _ = Command::new("git")
+            .arg("-C")
+            .arg(&self.repo)
+            .arg("worktree")
+            .arg("remove")
+            .arg("--force")
+            .arg(&self.path)
+            .stderr(Stdio::null())
+            .status();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(source: &str) -> Vec<String> {
+        item_names(&syn::parse_file(source).unwrap().items)
+    }
+
+    #[test]
+    fn test_unique_names() {
+        assert_eq!(names("struct S; fn f() {}"), ["struct S", "fn f"]);
+    }
+
+    #[test]
+    fn test_duplicates_independent_of_order() {
+        let forward = names(
+            "impl S { fn a() {} }
+             impl S { fn b() {} }
+             const _: u8 = 0;
+             const _: () = ();",
+        );
+        assert_eq!(
+            forward,
+            [
+                "impl S {fn a}",
+                "impl S {fn b}",
+                "const _ {u8}",
+                "const _ {()}",
+            ],
+        );
+
+        let mut backward = names(
+            "const _: () = ();
+             const _: u8 = 0;
+             impl S { fn b() {} }
+             impl S { fn a() {} }",
+        );
+        backward.reverse();
+        assert_eq!(backward, forward);
+    }
+
+    #[test]
+    fn test_identical_duplicates() {
+        assert_eq!(
+            names("const _: () = (); const _: () = {};"),
+            ["const _ {()} #1", "const _ {()} #2"],
+        );
+    }
+}
@@ -1,33 +1,179 @@
+use crate::opts::Expand;
+use proc_macro2::Span;
 use syn::visit_mut::{self, VisitMut};
 use syn::{
-    Attribute, Block, Expr, ExprArray, ExprAssign, ExprAsync, ExprAwait, ExprBinary, ExprBlock,
-    ExprBreak, ExprCall, ExprCast, ExprClosure, ExprConst, ExprContinue, ExprField, ExprForLoop,
-    ExprGroup, ExprIf, ExprIndex, ExprInfer, ExprLet, ExprLit, ExprLoop, ExprMacro, ExprMatch,
-    ExprMethodCall, ExprParen, ExprPath, ExprRange, ExprReference, ExprRepeat, ExprReturn,
-    ExprStruct, ExprTry, ExprTryBlock, ExprTuple, ExprUnary, ExprUnsafe, ExprWhile, ExprYield,
-    File, Item, ItemMod, Stmt,
+    parse_quote, Arm, Attribute, Block, Expr, ExprArray, ExprAssign, ExprAsync, ExprAwait,
+    ExprBinary, ExprBlock, ExprBreak, ExprCall, ExprCast, ExprClosure, ExprConst, ExprContinue,
+    ExprField, ExprForLoop, ExprGroup, ExprIf, ExprIndex, ExprInfer, ExprLet, ExprLit, ExprLoop,
+    ExprMacro, ExprMatch, ExprMethodCall, ExprParen, ExprPath, ExprRange, ExprReference,
+    ExprRepeat, ExprReturn, ExprStruct, ExprTry, ExprTryBlock, ExprTuple, ExprUnary, ExprUnsafe,
+    ExprWhile, ExprYield, Field, File, ForeignItem, ImplItem, Item, ItemConst, ItemEnum,
+    ItemExternCrate, ItemFn, ItemForeignMod, ItemImpl, ItemMacro, ItemMod, ItemStatic, ItemStruct,
+    ItemTrait, ItemTraitAlias, ItemType, ItemUnion, ItemUse, Lit, Local, Member, Meta, Pat, Stmt,
+    StmtMacro, TraitItem, Variant,
 };
 
-pub fn sanitize(syntax_tree: &mut File) {
-    remove_macro_rules_from_vec_item(&mut syntax_tree.items);
-    Sanitize.visit_file_mut(syntax_tree);
+// Optional passes on top of the cleanup that is always done.
+#[derive(Default)]
+pub struct Passes {
+    pub keep_macro_rules: bool,
+    pub strip_derived: bool,
+    pub hide_prelude: bool,
+    pub collapse_format_args: bool,
+    pub strip_rustc_attrs: bool,
 }
 
-// - Remove all macro_rules
+impl Passes {
+    pub fn new(args: &Expand) -> Self {
+        Passes {
+            keep_macro_rules: args.keep_macro_rules,
+            strip_derived: args.strip_derived,
+            hide_prelude: args.hide_prelude,
+            collapse_format_args: args.collapse_format_args,
+            strip_rustc_attrs: args.strip_rustc_attrs,
+        }
+    }
+}
+
+pub fn sanitize(syntax_tree: &mut File, passes: &Passes) {
+    if passes.hide_prelude {
+        hide_prelude(syntax_tree);
+    }
+    let mut sanitize = Sanitize { passes };
+    sanitize.retain_items(&mut syntax_tree.items);
+    sanitize.visit_file_mut(syntax_tree);
+}
+
+// - Remove all macro_rules, unless keep_macro_rules
 // - Remove doc attributes on statements (dtolnay/cargo-expand#71)
-struct Sanitize;
+// - Remove impls generated by derives, if strip_derived
+// - Replace expanded format_args internals by format_args!, if
+//   collapse_format_args
+// - Remove #[rustc_*] attributes, if strip_rustc_attrs
+struct Sanitize<'a> {
+    passes: &'a Passes,
+}
+
+impl Sanitize<'_> {
+    fn retain_items(&self, items: &mut Vec<Item>) {
+        items.retain(|item| self.retain_item(item));
+    }
+
+    fn retain_item(&self, item: &Item) -> bool {
+        match item {
+            Item::Macro(item) => {
+                self.passes.keep_macro_rules && item.mac.path.is_ident("macro_rules")
+            }
+            _ => !(self.passes.strip_derived && is_derived(item)),
+        }
+    }
+
+    fn remove_rustc_attributes(&self, attrs: &mut Vec<Attribute>) {
+        if self.passes.strip_rustc_attrs {
+            attrs.retain(|attr| {
+                !attr
+                    .path()
+                    .segments
+                    .first()
+                    .is_some_and(|segment| segment.ident.to_string().starts_with("rustc_"))
+            });
+        }
+    }
+}
+
+impl VisitMut for Sanitize<'_> {
+    fn visit_file_mut(&mut self, i: &mut File) {
+        self.remove_rustc_attributes(&mut i.attrs);
+        visit_mut::visit_file_mut(self, i);
+    }
+
+    fn visit_item_mut(&mut self, i: &mut Item) {
+        if let Some(attrs) = item_attrs_mut(i) {
+            self.remove_rustc_attributes(attrs);
+        }
+        visit_mut::visit_item_mut(self, i);
+    }
 
-impl VisitMut for Sanitize {
     fn visit_item_mod_mut(&mut self, i: &mut ItemMod) {
         if let Some((_, items)) = &mut i.content {
-            remove_macro_rules_from_vec_item(items);
+            self.retain_items(items);
         }
         visit_mut::visit_item_mod_mut(self, i);
     }
 
+    fn visit_impl_item_mut(&mut self, i: &mut ImplItem) {
+        match i {
+            ImplItem::Const(item) => self.remove_rustc_attributes(&mut item.attrs),
+            ImplItem::Fn(item) => self.remove_rustc_attributes(&mut item.attrs),
+            ImplItem::Type(item) => self.remove_rustc_attributes(&mut item.attrs),
+            ImplItem::Macro(item) => self.remove_rustc_attributes(&mut item.attrs),
+            _ => {}
+        }
+        visit_mut::visit_impl_item_mut(self, i);
+    }
+
+    fn visit_trait_item_mut(&mut self, i: &mut TraitItem) {
+        match i {
+            TraitItem::Const(item) => self.remove_rustc_attributes(&mut item.attrs),
+            TraitItem::Fn(item) => self.remove_rustc_attributes(&mut item.attrs),
+            TraitItem::Type(item) => self.remove_rustc_attributes(&mut item.attrs),
+            TraitItem::Macro(item) => self.remove_rustc_attributes(&mut item.attrs),
+            _ => {}
+        }
+        visit_mut::visit_trait_item_mut(self, i);
+    }
+
+    fn visit_foreign_item_mut(&mut self, i: &mut ForeignItem) {
+        match i {
+            ForeignItem::Fn(item) => self.remove_rustc_attributes(&mut item.attrs),
+            ForeignItem::Static(item) => self.remove_rustc_attributes(&mut item.attrs),
+            ForeignItem::Type(item) => self.remove_rustc_attributes(&mut item.attrs),
+            ForeignItem::Macro(item) => self.remove_rustc_attributes(&mut item.attrs),
+            _ => {}
+        }
+        visit_mut::visit_foreign_item_mut(self, i);
+    }
+
+    fn visit_field_mut(&mut self, i: &mut Field) {
+        self.remove_rustc_attributes(&mut i.attrs);
+        visit_mut::visit_field_mut(self, i);
+    }
+
+    fn visit_variant_mut(&mut self, i: &mut Variant) {
+        self.remove_rustc_attributes(&mut i.attrs);
+        visit_mut::visit_variant_mut(self, i);
+    }
+
+    fn visit_arm_mut(&mut self, i: &mut Arm) {
+        self.remove_rustc_attributes(&mut i.attrs);
+        visit_mut::visit_arm_mut(self, i);
+    }
+
+    fn visit_local_mut(&mut self, i: &mut Local) {
+        self.remove_rustc_attributes(&mut i.attrs);
+        visit_mut::visit_local_mut(self, i);
+    }
+
+    fn visit_stmt_macro_mut(&mut self, i: &mut StmtMacro) {
+        self.remove_rustc_attributes(&mut i.attrs);
+        visit_mut::visit_stmt_macro_mut(self, i);
+    }
+
+    fn visit_expr_mut(&mut self, i: &mut Expr) {
+        if let Some(attrs) = attrs_mut(i) {
+            self.remove_rustc_attributes(attrs);
+        }
+        visit_mut::visit_expr_mut(self, i);
+        if self.passes.collapse_format_args {
+            if let Some(collapsed) = collapse_format_args(i) {
+                *i = collapsed;
+            }
+        }
+    }
+
     fn visit_block_mut(&mut self, i: &mut Block) {
         i.stmts.retain(|stmt| match stmt {
-            Stmt::Item(Item::Macro(_)) => false,
+            Stmt::Item(item) => self.retain_item(item),
             _ => true,
         });
         visit_mut::visit_block_mut(self, i);
@@ -48,11 +194,228 @@ impl VisitMut for Sanitize {
     }
 }
 
-fn remove_macro_rules_from_vec_item(items: &mut Vec<Item>) {
-    items.retain(|item| match item {
-        Item::Macro(_) => false,
+// The compiler injects `#![feature(prelude_import)]`, `extern crate std;` and
+// `#[prelude_import] use std::prelude::rust_20xx::*;` into every crate root.
+fn hide_prelude(syntax_tree: &mut File) {
+    let has_prelude_import = syntax_tree.items.iter().any(|item| match item {
+        Item::Use(item) => has_attr(&item.attrs, "prelude_import"),
+        _ => false,
+    });
+    if !has_prelude_import {
+        return;
+    }
+
+    syntax_tree.items.retain(|item| match item {
+        Item::Use(item) => !has_attr(&item.attrs, "prelude_import"),
+        Item::ExternCrate(item) => {
+            item.rename.is_some() || (item.ident != "std" && item.ident != "core")
+        }
         _ => true,
     });
+    syntax_tree.attrs.retain(|attr| match &attr.meta {
+        Meta::List(list) => {
+            !(list.path.is_ident("feature") && list.tokens.to_string() == "prelude_import")
+        }
+        _ => true,
+    });
+}
+
+// Derives mark their impls with #[automatically_derived]. Some derives, such
+// as serde's, wrap the impl in a `const _: () = { ... };` block.
+fn is_derived(item: &Item) -> bool {
+    match item {
+        Item::Impl(item) => has_attr(&item.attrs, "automatically_derived"),
+        Item::Const(item) if item.ident == "_" => match &*item.expr {
+            Expr::Block(expr) => expr.block.stmts.iter().any(|stmt| match stmt {
+                Stmt::Item(item @ Item::Impl(_)) => is_derived(item),
+                _ => false,
+            }),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+fn has_attr(attrs: &[Attribute], name: &str) -> bool {
+    attrs.iter().any(|attr| attr.path().is_ident(name))
+}
+
+// Older compilers print format_args! expanded into a call to one of:
+//
+//     ::core::fmt::Arguments::new_const(&["pieces"])
+//     ::core::fmt::Arguments::new_v1(&["pieces"], &[Argument::new_display(&x)])
+//     ::core::fmt::Arguments::new_v1(&["pieces"], &match (&x,) {
+//         args => [Argument::new_display(args.0)],
+//     })
+//
+// Calls with explicit formatting specs (new_v1_formatted) are left alone.
+fn collapse_format_args(expr: &Expr) -> Option<Expr> {
+    let Expr::Call(call) = expr else {
+        return None;
+    };
+    let Expr::Path(func) = &*call.func else {
+        return None;
+    };
+    let segments = &func.path.segments;
+    if segments.len() < 2 || segments[segments.len() - 2].ident != "Arguments" {
+        return None;
+    }
+    let constructor = &segments[segments.len() - 1].ident;
+
+    let mut args = call.args.iter();
+    let pieces = format_pieces(args.next()?)?;
+    let (placeholders, values) = if constructor == "new_const" {
+        (Vec::new(), Vec::new())
+    } else if constructor == "new_v1" {
+        format_arguments(args.next()?)?
+    } else {
+        return None;
+    };
+    if args.next().is_some()
+        || pieces.len() < placeholders.len()
+        || pieces.len() > placeholders.len() + 1
+    {
+        return None;
+    }
+
+    let mut format_string = String::new();
+    for (i, piece) in pieces.iter().enumerate() {
+        format_string += &piece.replace('{', "{{").replace('}', "}}");
+        if let Some((index, spec)) = placeholders.get(i) {
+            format_string += &format!("{{{}{}}}", index, spec);
+        }
+    }
+    if let Some((index, spec)) = placeholders.get(pieces.len()) {
+        format_string += &format!("{{{}{}}}", index, spec);
+    }
+
+    let format_string = syn::LitStr::new(&format_string, Span::call_site());
+    Some(parse_quote!(format_args!(#format_string #(, #values)*)))
+}
+
+fn format_pieces(expr: &Expr) -> Option<Vec<String>> {
+    let Expr::Array(array) = unreference(expr) else {
+        return None;
+    };
+    array
+        .elems
+        .iter()
+        .map(|elem| match elem {
+            Expr::Lit(ExprLit {
+                lit: Lit::Str(lit), ..
+            }) => Some(lit.value()),
+            _ => None,
+        })
+        .collect()
+}
+
+// Returns the placeholders as (argument index, format spec), and the
+// argument values.
+#[allow(clippy::type_complexity)]
+fn format_arguments(expr: &Expr) -> Option<(Vec<(usize, &'static str)>, Vec<Expr>)> {
+    match unreference(expr) {
+        Expr::Array(array) => {
+            let mut placeholders = Vec::new();
+            let mut values = Vec::new();
+            for (index, elem) in array.elems.iter().enumerate() {
+                let (spec, value) = format_argument(elem)?;
+                placeholders.push((index, spec));
+                values.push(unreference(value).clone());
+            }
+            Some((placeholders, values))
+        }
+        Expr::Match(expr) => {
+            let Expr::Tuple(tuple) = &*expr.expr else {
+                return None;
+            };
+            let [arm] = expr.arms.as_slice() else {
+                return None;
+            };
+            let (Pat::Ident(_), Expr::Array(array)) = (&arm.pat, &*arm.body) else {
+                return None;
+            };
+            let mut placeholders = Vec::new();
+            for elem in &array.elems {
+                let (spec, value) = format_argument(elem)?;
+                let Expr::Field(field) = value else {
+                    return None;
+                };
+                let Member::Unnamed(index) = &field.member else {
+                    return None;
+                };
+                let index = index.index as usize;
+                if index >= tuple.elems.len() {
+                    return None;
+                }
+                placeholders.push((index, spec));
+            }
+            let values = tuple
+                .elems
+                .iter()
+                .map(|elem| unreference(elem).clone())
+                .collect();
+            Some((placeholders, values))
+        }
+        _ => None,
+    }
+}
+
+// Argument::new_display(&x) and friends.
+fn format_argument(expr: &Expr) -> Option<(&'static str, &Expr)> {
+    let Expr::Call(call) = expr else {
+        return None;
+    };
+    let Expr::Path(func) = &*call.func else {
+        return None;
+    };
+    let spec = match func.path.segments.last()?.ident.to_string().as_str() {
+        "new_display" => "",
+        "new_debug" => ":?",
+        "new_octal" => ":o",
+        "new_lower_hex" => ":x",
+        "new_upper_hex" => ":X",
+        "new_pointer" => ":p",
+        "new_binary" => ":b",
+        "new_lower_exp" => ":e",
+        "new_upper_exp" => ":E",
+        _ => return None,
+    };
+    let [value] = call.args.iter().collect::<Vec<_>>()[..] else {
+        return None;
+    };
+    Some((spec, value))
+}
+
+fn unreference(expr: &Expr) -> &Expr {
+    match expr {
+        Expr::Reference(expr) if expr.mutability.is_none() => &expr.expr,
+        _ => expr,
+    }
+}
+
+fn item_attrs_mut(item: &mut Item) -> Option<&mut Vec<Attribute>> {
+    match item {
+        Item::Const(ItemConst { attrs, .. })
+        | Item::Enum(ItemEnum { attrs, .. })
+        | Item::ExternCrate(ItemExternCrate { attrs, .. })
+        | Item::Fn(ItemFn { attrs, .. })
+        | Item::ForeignMod(ItemForeignMod { attrs, .. })
+        | Item::Impl(ItemImpl { attrs, .. })
+        | Item::Macro(ItemMacro { attrs, .. })
+        | Item::Mod(ItemMod { attrs, .. })
+        | Item::Static(ItemStatic { attrs, .. })
+        | Item::Struct(ItemStruct { attrs, .. })
+        | Item::Trait(ItemTrait { attrs, .. })
+        | Item::TraitAlias(ItemTraitAlias { attrs, .. })
+        | Item::Type(ItemType { attrs, .. })
+        | Item::Union(ItemUnion { attrs, .. })
+        | Item::Use(ItemUse { attrs, .. }) => Some(attrs),
+
+        Item::Verbatim(_) => None,
+
+        #[cfg_attr(all(test, exhaustive), deny(non_exhaustive_omitted_patterns))]
+        _ => None,
+    }
 }
 
 fn remove_doc_attributes(attrs: &mut Vec<Attribute>) {
@@ -106,3 +469,174 @@ fn attrs_mut(e: &mut Expr) -> Option<&mut Vec<Attribute>> {
         _ => None,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quote::ToTokens;
+
+    fn sanitized(passes: &Passes, input: &str) -> String {
+        let mut syntax_tree = syn::parse_file(input).unwrap();
+        sanitize(&mut syntax_tree, passes);
+        syntax_tree.into_token_stream().to_string()
+    }
+
+    fn tokens(code: &str) -> String {
+        syn::parse_file(code)
+            .unwrap()
+            .into_token_stream()
+            .to_string()
+    }
+
+    #[test]
+    fn test_collapse_format_args() {
+        let passes = Passes {
+            collapse_format_args: true,
+            ..Passes::default()
+        };
+        let input = r#"
+            fn f() {
+                ::std::io::_print(::core::fmt::Arguments::new_const(&["{}\n"]));
+                ::std::io::_print(::core::fmt::Arguments::new_v1(
+                    &["a ", " b\n"],
+                    &[::core::fmt::rt::Argument::new_display(&x)],
+                ));
+                ::std::io::_print(::core::fmt::Arguments::new_v1(
+                    &["", " ", ""],
+                    &match (&x, &y) {
+                        args => [
+                            ::core::fmt::ArgumentV1::new_debug(args.1),
+                            ::core::fmt::ArgumentV1::new_lower_hex(args.0),
+                        ],
+                    },
+                ));
+            }
+        "#;
+        let expected = r#"
+            fn f() {
+                ::std::io::_print(format_args!("{{}}\n"));
+                ::std::io::_print(format_args!("a {0} b\n", x));
+                ::std::io::_print(format_args!("{1:?} {0:x}", x, y));
+            }
+        "#;
+        assert_eq!(sanitized(&passes, input), tokens(expected));
+        assert_eq!(sanitized(&Passes::default(), input), tokens(input));
+
+        // Explicit formatting specs are left alone.
+        let input = r#"
+            fn f() {
+                ::core::fmt::Arguments::new_v1_formatted(&[""], &[], &[], unsafe { ::core::fmt::UnsafeArg::new() });
+            }
+        "#;
+        assert_eq!(sanitized(&passes, input), tokens(input));
+    }
+
+    #[test]
+    fn test_strip_derived() {
+        let passes = Passes {
+            strip_derived: true,
+            ..Passes::default()
+        };
+        let input = r#"
+            struct S;
+            #[automatically_derived]
+            impl ::core::clone::Clone for S {
+                fn clone(&self) -> S { S }
+            }
+            const _: () = {
+                extern crate serde as _serde;
+                #[automatically_derived]
+                impl _serde::Serialize for S {}
+            };
+            const _: () = {};
+            impl S {}
+            mod m {
+                #[automatically_derived]
+                impl Copy for super::S {}
+            }
+        "#;
+        let expected = r#"
+            struct S;
+            const _: () = {};
+            impl S {}
+            mod m {}
+        "#;
+        assert_eq!(sanitized(&passes, input), tokens(expected));
+        assert_eq!(sanitized(&Passes::default(), input), tokens(input));
+    }
+
+    #[test]
+    fn test_hide_prelude() {
+        let passes = Passes {
+            hide_prelude: true,
+            ..Passes::default()
+        };
+        let input = r#"
+            #![feature(prelude_import)]
+            #![allow(dead_code)]
+            #[prelude_import]
+            use std::prelude::rust_2021::*;
+            #[macro_use]
+            extern crate std;
+            extern crate alloc;
+            extern crate core as my_core;
+            fn f() {}
+        "#;
+        let expected = r#"
+            #![allow(dead_code)]
+            extern crate alloc;
+            extern crate core as my_core;
+            fn f() {}
+        "#;
+        assert_eq!(sanitized(&passes, input), tokens(expected));
+
+        // Without the prelude import, `extern crate std` was written by hand.
+        let input = "extern crate std; fn f() {}";
+        assert_eq!(sanitized(&passes, input), tokens(input));
+    }
+
+    #[test]
+    fn test_strip_rustc_attrs() {
+        let passes = Passes {
+            strip_rustc_attrs: true,
+            ..Passes::default()
+        };
+        let input = r#"
+            #![rustc_coherence_is_core]
+            #[rustc_test_marker = "t"]
+            #[inline]
+            fn t() {}
+            struct S {
+                #[rustc_layout_scalar_valid_range_start(1)]
+                a: u8,
+            }
+            impl S {
+                #[rustc_const_stable(feature = "x", since = "1.0.0")]
+                const fn f() {}
+            }
+        "#;
+        let expected = r#"
+            #[inline]
+            fn t() {}
+            struct S {
+                a: u8,
+            }
+            impl S {
+                const fn f() {}
+            }
+        "#;
+        assert_eq!(sanitized(&passes, input), tokens(expected));
+        assert_eq!(sanitized(&Passes::default(), input), tokens(input));
+    }
+
+    #[test]
+    fn test_macro_rules() {
+        let input = "macro_rules! m { () => {} } fn f() { macro_rules! n { () => {} } }";
+        assert_eq!(sanitized(&Passes::default(), input), tokens("fn f() {}"));
+        let passes = Passes {
+            keep_macro_rules: true,
+            ..Passes::default()
+        };
+        assert_eq!(sanitized(&passes, input), tokens(input));
+    }
+}
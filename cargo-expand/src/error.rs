@@ -5,10 +5,53 @@ pub enum Error {
     Io(io::Error),
     TomlSer(toml::ser::Error),
     TomlDe(toml::de::Error),
+    Json(serde_json::Error),
+    Notify(notify::Error),
+    Bat(bat::error::Error),
+    Syntect(syntect::Error),
+    /// Cargo failed, after printing the compiler's errors if there were any.
+    Build {
+        errors: usize,
+    },
+    MissingNightly,
+    /// Neither the active toolchain nor rustup provide a rustc that can
+    /// expand macros. The release is None if rustc could not be run.
+    UnsupportedToolchain {
+        release: Option<String>,
+    },
+    MissingRustfmt,
+    EmptyOutput,
+    NoSuchItem(String),
+    /// The source of the crate, which --derive and --attr are matched
+    /// against, could not be parsed.
+    UnparsedSource,
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+impl Error {
+    /// The process exit code for this error: 101 if the crate failed to build,
+    /// like cargo, 3 if a nightly toolchain is needed, 4 if rustfmt is needed,
+    /// 5 if rustc produced no output, and 1 for anything else.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Build { .. } => 101,
+            Error::MissingNightly | Error::UnsupportedToolchain { .. } => 3,
+            Error::MissingRustfmt => 4,
+            Error::EmptyOutput => 5,
+            Error::NoSuchItem(_)
+            | Error::UnparsedSource
+            | Error::Io(_)
+            | Error::TomlSer(_)
+            | Error::TomlDe(_)
+            | Error::Json(_)
+            | Error::Notify(_)
+            | Error::Bat(_)
+            | Error::Syntect(_) => 1,
+        }
+    }
+}
+
 impl From<io::Error> for Error {
     fn from(error: io::Error) -> Self {
         Error::Io(error)
@@ -27,6 +70,30 @@ impl From<toml::de::Error> for Error {
     }
 }
 
+impl From<serde_json::Error> for Error {
+    fn from(error: serde_json::Error) -> Self {
+        Error::Json(error)
+    }
+}
+
+impl From<notify::Error> for Error {
+    fn from(error: notify::Error) -> Self {
+        Error::Notify(error)
+    }
+}
+
+impl From<bat::error::Error> for Error {
+    fn from(error: bat::error::Error) -> Self {
+        Error::Bat(error)
+    }
+}
+
+impl From<syntect::Error> for Error {
+    fn from(error: syntect::Error) -> Self {
+        Error::Syntect(error)
+    }
+}
+
 impl Display for Error {
     fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
         use self::Error::*;
@@ -35,6 +102,82 @@ impl Display for Error {
             Io(e) => e.fmt(formatter),
             TomlSer(e) => e.fmt(formatter),
             TomlDe(e) => e.fmt(formatter),
+            Json(e) => e.fmt(formatter),
+            Notify(e) => e.fmt(formatter),
+            Bat(e) => e.fmt(formatter),
+            Syntect(e) => e.fmt(formatter),
+            Build { errors: 0 } => formatter.write_str("could not build the crate"),
+            Build { errors: 1 } => {
+                formatter.write_str("could not expand the crate due to the previous error")
+            }
+            Build { errors } => write!(
+                formatter,
+                "could not expand the crate due to {} previous errors",
+                errors,
+            ),
+            MissingNightly => formatter.write_str(
+                "macro expansion requires a nightly toolchain. Install one by \
+                running `rustup toolchain install nightly`",
+            ),
+            UnsupportedToolchain { release: None } => formatter.write_str(
+                "could not run rustc. Install a nightly toolchain by running \
+                `rustup toolchain install nightly`",
+            ),
+            UnsupportedToolchain {
+                release: Some(release),
+            } => write!(
+                formatter,
+                "rustc {} cannot expand macros, as it is not a nightly and does \
+                not honor RUSTC_BOOTSTRAP=1, and rustup has no nightly \
+                toolchain. Install one by running `rustup toolchain install \
+                nightly`",
+                release,
+            ),
+            MissingRustfmt => formatter.write_str(
+                "cargo-expand configuration sets rustfmt=true, but rustfmt is \
+                not found. Install rustfmt by running `rustup component add \
+                rustfmt --toolchain nightly`",
+            ),
+            EmptyOutput => formatter.write_str("rustc produced no expanded output"),
+            NoSuchItem(item) => write!(formatter, "no such item: {}", item),
+            UnparsedSource => formatter.write_str(
+                "could not parse the source of the crate to find what --derive \
+                or --attr applies to",
+            ),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exit_code() {
+        assert_eq!(Error::Build { errors: 2 }.exit_code(), 101);
+        assert_eq!(Error::MissingNightly.exit_code(), 3);
+        assert_eq!(Error::UnsupportedToolchain { release: None }.exit_code(), 3);
+        assert_eq!(Error::MissingRustfmt.exit_code(), 4);
+        assert_eq!(Error::EmptyOutput.exit_code(), 5);
+        assert_eq!(Error::NoSuchItem("m".to_owned()).exit_code(), 1);
+        assert_eq!(Error::UnparsedSource.exit_code(), 1);
+        let io = io::Error::new(io::ErrorKind::NotFound, "not found");
+        assert_eq!(Error::Io(io).exit_code(), 1);
+    }
+
+    #[test]
+    fn test_build_message() {
+        assert_eq!(
+            Error::Build { errors: 0 }.to_string(),
+            "could not build the crate",
+        );
+        assert_eq!(
+            Error::Build { errors: 1 }.to_string(),
+            "could not expand the crate due to the previous error",
+        );
+        assert_eq!(
+            Error::Build { errors: 3 }.to_string(),
+            "could not expand the crate due to 3 previous errors",
+        );
+    }
+}
@@ -0,0 +1,411 @@
+use crate::error::{Error, Result};
+use crate::json::{item_ident, item_module};
+use crate::source::{self, SourceItem};
+use crate::Expand;
+use std::collections::{HashMap, HashSet};
+use syn::punctuated::Punctuated;
+use syn::{Attribute, Expr, File, Item, Path, Stmt, Token, Type};
+
+// What the source says was produced by the macros selected by --derive and
+// --attr, by module path.
+#[derive(Default)]
+struct Origins {
+    // Types with the derive, which the derived impls are for, along with the
+    // other derives on them.
+    derived: HashMap<(Vec<String>, String), Vec<Path>>,
+    // Traits implemented by hand, by the module path and name of the type.
+    handwritten: HashSet<(Vec<String>, String, String)>,
+    // Items with the attribute, which the attribute macro replaced by items of
+    // the same name. Impl blocks are told apart by their trait.
+    attributed: HashSet<(Vec<String>, String, Option<String>)>,
+}
+
+// Keeps only the items generated by the --derive and --attr macros, along with
+// the modules containing them.
+pub fn apply(syntax_tree: &mut File, args: &Expand) -> Result<()> {
+    if args.derive.is_none() && args.attr.is_none() {
+        return Ok(());
+    }
+
+    let Some(source) = source::parse(args) else {
+        return Err(Error::UnparsedSource);
+    };
+    let mut origins = Origins::default();
+    for source_item in &source.items {
+        origins.add(source_item, args);
+    }
+
+    let mut module = item_module(args);
+    syntax_tree.shebang = None;
+    syntax_tree.attrs.clear();
+    let items = std::mem::take(&mut syntax_tree.items);
+    syntax_tree.items = filter_items(items, &mut module, &origins, args);
+
+    if syntax_tree.items.is_empty() {
+        let mut filters = Vec::new();
+        if let Some(derive) = &args.derive {
+            filters.push(format!("#[derive({})]", derive));
+        }
+        if let Some(attr) = &args.attr {
+            filters.push(format!("#[{}]", attr));
+        }
+        return Err(Error::NoSuchItem(filters.join(" or ")));
+    }
+    Ok(())
+}
+
+fn filter_items(
+    items: Vec<Item>,
+    module: &mut Vec<String>,
+    origins: &Origins,
+    args: &Expand,
+) -> Vec<Item> {
+    let mut kept = Vec::new();
+    for item in items {
+        match item {
+            Item::Mod(mut item) if item.content.is_some() => {
+                let (brace, items) = item.content.take().unwrap();
+                module.push(item.ident.to_string());
+                let items = filter_items(items, module, origins, args);
+                module.pop();
+                if !items.is_empty() {
+                    item.content = Some((brace, items));
+                    kept.push(Item::Mod(item));
+                }
+            }
+            item => {
+                if origins.produced(&item, module, args) {
+                    kept.push(item);
+                }
+            }
+        }
+    }
+    kept
+}
+
+impl Origins {
+    fn add(&mut self, source_item: &SourceItem, args: &Expand) {
+        let item = &source_item.item;
+        let Some(ident) = item_ident(item) else {
+            return;
+        };
+        let module = &source_item.module;
+        if let Some(derive) = &args.derive {
+            let is_adt = matches!(item, Item::Struct(_) | Item::Enum(_) | Item::Union(_));
+            let (wanted, others): (Vec<Path>, Vec<Path>) = derives(item_attrs(item))
+                .into_iter()
+                .partition(|path| path_matches(path, derive));
+            if is_adt && !wanted.is_empty() {
+                self.derived.insert((module.clone(), ident.clone()), others);
+            }
+            if let Some(trait_name) = impl_trait_name(item) {
+                let key = (module.clone(), ident.clone(), trait_name);
+                self.handwritten.insert(key);
+            }
+        }
+        if let Some(attr) = &args.attr {
+            if has_attr(item_attrs(item), attr) {
+                let trait_name = impl_trait_name(item);
+                self.attributed.insert((module.clone(), ident, trait_name));
+            }
+        }
+    }
+
+    fn produced(&self, item: &Item, module: &[String], args: &Expand) -> bool {
+        if let Some(derive) = &args.derive {
+            if self.derived_impl(item, module, derive) {
+                return true;
+            }
+        }
+        if args.attr.is_some() {
+            if let Some(ident) = item_ident(item) {
+                let key = (module.to_vec(), ident, impl_trait_name(item));
+                if self.attributed.contains(&key) {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    // `impl Trait for Type`, where Type has #[derive(Trait)] in the source.
+    // Derives other than the standard ones may implement other traits too,
+    // like clap's derive(Parser) does CommandFactory, so for those an impl of
+    // any trait is taken to be derived, unless the source has it or it is named
+    // after another derive of the type. Some derives, such as serde's, wrap the
+    // impl in `const _: () = { ... };`.
+    fn derived_impl(&self, item: &Item, module: &[String], derive: &str) -> bool {
+        match item {
+            Item::Impl(item) => {
+                let Some((_, path, _)) = &item.trait_ else {
+                    return false;
+                };
+                let Type::Path(ty) = &*item.self_ty else {
+                    return false;
+                };
+                let Some(self_ty) = ty.path.segments.last() else {
+                    return false;
+                };
+                let key = (module.to_vec(), self_ty.ident.to_string());
+                let Some(others) = self.derived.get(&key) else {
+                    return false;
+                };
+                if derive_matches(path, derive) {
+                    return true;
+                }
+                if is_std_derive(derive) {
+                    return false;
+                }
+                let Some(trait_name) = path.segments.last() else {
+                    return false;
+                };
+                let (module, self_ty) = key;
+                let key = (module, self_ty, trait_name.ident.to_string());
+                !self.handwritten.contains(&key)
+                    && !others
+                        .iter()
+                        .any(|other| derive_matches(path, &path_string(other)))
+            }
+            Item::Const(item) if item.ident == "_" => {
+                let Expr::Block(expr) = &*item.expr else {
+                    return false;
+                };
+                expr.block.stmts.iter().any(|stmt| match stmt {
+                    Stmt::Item(item) => self.derived_impl(item, module, derive),
+                    _ => false,
+                })
+            }
+            _ => false,
+        }
+    }
+}
+
+// Whether the trait implemented by an expanded impl is the one generated by
+// the derive. derive(PartialEq) and derive(Eq) also implement these traits
+// prefixed by Structural.
+fn derive_matches(trait_path: &Path, derive: &str) -> bool {
+    let Some(name) = trait_path.segments.last() else {
+        return false;
+    };
+    let name = name.ident.to_string();
+    let derive = derive.rsplit("::").next().unwrap_or(derive).trim();
+    name == derive || name.strip_prefix("Structural") == Some(derive)
+}
+
+// The derives of the standard library, which implement just the trait of the
+// same name.
+fn is_std_derive(derive: &str) -> bool {
+    let derive = derive.rsplit("::").next().unwrap_or(derive).trim();
+    matches!(
+        derive,
+        "Clone" | "Copy" | "Debug" | "Default" | "Eq" | "Hash" | "Ord" | "PartialEq" | "PartialOrd",
+    )
+}
+
+// The derives listed by the #[derive(...)] attributes.
+fn derives(attrs: &[Attribute]) -> Vec<Path> {
+    attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("derive"))
+        .filter_map(|attr| {
+            attr.parse_args_with(Punctuated::<Path, Token![,]>::parse_terminated)
+                .ok()
+        })
+        .flatten()
+        .collect()
+}
+
+fn path_string(path: &Path) -> String {
+    let segments: Vec<String> = path
+        .segments
+        .iter()
+        .map(|segment| segment.ident.to_string())
+        .collect();
+    segments.join("::")
+}
+
+fn has_attr(attrs: &[Attribute], attr: &str) -> bool {
+    attrs.iter().any(|a| path_matches(a.path(), attr))
+}
+
+// `tokio::main` is matched by "tokio::main" and "main".
+fn path_matches(path: &Path, wanted: &str) -> bool {
+    let wanted: Vec<&str> = wanted
+        .trim_start_matches("::")
+        .split("::")
+        .map(str::trim)
+        .collect();
+    let segments: Vec<String> = path
+        .segments
+        .iter()
+        .map(|segment| segment.ident.to_string())
+        .collect();
+    segments.len() >= wanted.len() && segments[segments.len() - wanted.len()..] == wanted[..]
+}
+
+fn impl_trait_name(item: &Item) -> Option<String> {
+    let Item::Impl(item) = item else {
+        return None;
+    };
+    let (_, path, _) = item.trait_.as_ref()?;
+    Some(path.segments.last()?.ident.to_string())
+}
+
+fn item_attrs(item: &Item) -> &[Attribute] {
+    match item {
+        Item::Const(item) => &item.attrs,
+        Item::Enum(item) => &item.attrs,
+        Item::ExternCrate(item) => &item.attrs,
+        Item::Fn(item) => &item.attrs,
+        Item::ForeignMod(item) => &item.attrs,
+        Item::Impl(item) => &item.attrs,
+        Item::Macro(item) => &item.attrs,
+        Item::Mod(item) => &item.attrs,
+        Item::Static(item) => &item.attrs,
+        Item::Struct(item) => &item.attrs,
+        Item::Trait(item) => &item.attrs,
+        Item::TraitAlias(item) => &item.attrs,
+        Item::Type(item) => &item.attrs,
+        Item::Union(item) => &item.attrs,
+        Item::Use(item) => &item.attrs,
+        _ => &[],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(path: &str) -> Path {
+        syn::parse_str(path).unwrap()
+    }
+
+    fn attrs(source: &str) -> Vec<Attribute> {
+        match syn::parse_str::<Item>(source).unwrap() {
+            Item::Struct(item) => item.attrs,
+            _ => panic!("expected a struct"),
+        }
+    }
+
+    #[test]
+    fn test_derive_matches() {
+        assert!(derive_matches(&path("::core::clone::Clone"), "Clone"));
+        assert!(derive_matches(
+            &path("_serde::Serialize"),
+            "serde::Serialize"
+        ));
+        assert!(derive_matches(&path("Serialize"), " Serialize "));
+        assert!(!derive_matches(&path("::core::clone::Clone"), "Copy"));
+
+        // derive(PartialEq) and derive(Eq) also implement the Structural
+        // traits, but a Structural derive is not PartialEq.
+        let structural = path("::core::marker::StructuralPartialEq");
+        assert!(derive_matches(&structural, "PartialEq"));
+        assert!(derive_matches(&path("::core::marker::StructuralEq"), "Eq"));
+        assert!(!derive_matches(
+            &path("::core::cmp::PartialEq"),
+            "StructuralPartialEq"
+        ));
+        assert!(!derive_matches(&structural, "Eq"));
+    }
+
+    #[test]
+    fn test_derives() {
+        let attrs = attrs("#[derive(Debug, serde::Serialize)] #[derive(Clone)] struct S;");
+        let has_derive = |attrs: &[Attribute], derive: &str| {
+            derives(attrs).iter().any(|path| path_matches(path, derive))
+        };
+        assert!(has_derive(&attrs, "Debug"));
+        assert!(has_derive(&attrs, "Clone"));
+        assert!(has_derive(&attrs, "Serialize"));
+        assert!(has_derive(&attrs, "serde::Serialize"));
+        assert!(has_derive(&attrs, "::serde::Serialize"));
+        assert!(!has_derive(&attrs, "other::Serialize"));
+        assert!(!has_derive(&attrs, "Deserialize"));
+        let names: Vec<String> = derives(&attrs).iter().map(path_string).collect();
+        assert_eq!(names, ["Debug", "serde::Serialize", "Clone"]);
+
+        let attrs = self::attrs("#[serde(rename = \"Debug\")] struct S;");
+        assert!(!has_derive(&attrs, "Debug"));
+    }
+
+    #[test]
+    fn test_path_matches() {
+        let tokio_main = path("tokio::main");
+        assert!(path_matches(&tokio_main, "main"));
+        assert!(path_matches(&tokio_main, "tokio::main"));
+        assert!(path_matches(&tokio_main, "::tokio::main"));
+        assert!(path_matches(&tokio_main, "tokio :: main"));
+        assert!(!path_matches(&tokio_main, "okio::main"));
+        assert!(!path_matches(&tokio_main, "other::tokio::main"));
+        assert!(!path_matches(&path("main"), "tokio::main"));
+    }
+
+    #[test]
+    fn test_derived_impl() {
+        let module = vec!["m".to_owned()];
+        let source = "
+            #[derive(Debug, serde::Serialize, serde::Deserialize, clap::Parser)]
+            struct S;
+            impl std::fmt::Display for S {}
+            struct T;
+        ";
+        let derived = |item: &str, derive: &str| {
+            let args = Expand {
+                derive: Some(derive.to_owned()),
+                ..Expand::default()
+            };
+            let mut origins = Origins::default();
+            for item in syn::parse_file(source).unwrap().items {
+                let source_item = SourceItem {
+                    file: std::path::PathBuf::new(),
+                    module: module.clone(),
+                    item,
+                };
+                origins.add(&source_item, &args);
+            }
+            let item: Item = syn::parse_str(item).unwrap();
+            origins.derived_impl(&item, &module, derive)
+        };
+
+        assert!(derived("impl ::core::fmt::Debug for S {}", "Debug"));
+        assert!(derived(
+            "impl<T> ::core::fmt::Debug for self::S<T> {}",
+            "Debug"
+        ));
+        assert!(!derived("impl ::core::fmt::Debug for T {}", "Debug"));
+        assert!(!derived("impl S {}", "Debug"));
+
+        // serde wraps its impls in an unnamed const.
+        let serde = "
+            const _: () = {
+                extern crate serde as _serde;
+                impl _serde::Serialize for S {}
+            };
+        ";
+        assert!(derived(serde, "Serialize"));
+        assert!(derived(serde, "serde::Serialize"));
+        assert!(!derived(serde, "Deserialize"));
+        assert!(!derived(
+            "const C: () = { impl _serde::Serialize for S {} };",
+            "Serialize",
+        ));
+
+        // derive(Parser) also implements traits of other names, which are
+        // neither written by hand nor implemented by the standard derives.
+        let command_factory = "impl clap::CommandFactory for S {}";
+        assert!(derived(command_factory, "Parser"));
+        assert!(derived(command_factory, "clap::Parser"));
+        assert!(!derived(command_factory, "Debug"));
+        assert!(!derived("impl ::core::fmt::Debug for S {}", "Parser"));
+        assert!(!derived("impl std::fmt::Display for S {}", "Parser"));
+
+        let module: &[String] = &[];
+        let item: Item = syn::parse_str("impl ::core::fmt::Debug for S {}").unwrap();
+        let mut origins = Origins::default();
+        origins
+            .derived
+            .insert((vec!["m".to_owned()], "S".to_owned()), Vec::new());
+        assert!(!origins.derived_impl(&item, module, "Debug"));
+    }
+}
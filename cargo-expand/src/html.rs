@@ -0,0 +1,270 @@
+use crate::error::Result;
+use crate::json::{item_ident, item_module};
+use crate::source::{self, SourceItem};
+use crate::{format_file, Expand, Expansion};
+use bat::assets::HighlightingAssets;
+use std::collections::hash_map::{Entry, HashMap};
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use syn::spanned::Spanned;
+use syn::{File, Item};
+use syntect::html::highlighted_html_for_string;
+
+const STYLE: &str = "
+body { margin: 0; font-family: sans-serif; }
+details { border-bottom: 1px solid #8884; }
+summary { padding: 4px 8px; cursor: pointer; font-family: monospace; white-space: pre; }
+summary a { float: right; font-family: sans-serif; font-size: smaller; }
+pre { margin: 0; padding: 4px 8px 8px 24px; overflow-x: auto; }
+";
+
+// Where an item of the original source is defined.
+struct Location {
+    path: PathBuf,
+    line: usize,
+    is_impl: bool,
+}
+
+// Renders every top-level item of the expansion as a collapsible block,
+// labeled by its first line and linked to the item of the same name in the
+// source before expansion.
+pub fn write_html(expansion: &Expansion, args: &Expand, path: &Path) -> Result<i32> {
+    let assets = HighlightingAssets::from_binary();
+    let highlighter = match expansion.theme.as_deref() {
+        Some("none") => None,
+        theme => {
+            let theme =
+                assets.get_theme(theme.unwrap_or_else(|| HighlightingAssets::default_theme()));
+            Some((assets.get_syntax_set()?, theme))
+        }
+    };
+    let highlight = |code: &str| -> Result<String> {
+        Ok(match highlighter {
+            Some((syntax_set, theme)) => {
+                let syntax = syntax_set
+                    .find_syntax_by_extension("rs")
+                    .unwrap_or_else(|| syntax_set.find_syntax_plain_text());
+                highlighted_html_for_string(code, syntax_set, syntax, theme)?
+            }
+            None => format!("<pre>{}</pre>\n", escape(code)),
+        })
+    };
+
+    let (root, locations) = match source::parse(args) {
+        Some(source) => (source.package_dir, locations(source.items)),
+        None => (PathBuf::new(), HashMap::new()),
+    };
+    let prefix: String = item_module(args)
+        .iter()
+        .map(|segment| format!("{}::", segment))
+        .collect();
+
+    let mut body = String::new();
+    match &expansion.syntax_tree {
+        Some(syntax_tree) => {
+            if !syntax_tree.attrs.is_empty() {
+                let attrs = format_file(&File {
+                    shebang: None,
+                    attrs: syntax_tree.attrs.clone(),
+                    items: Vec::new(),
+                });
+                body += &highlight(&attrs)?;
+            }
+            for (i, item) in syntax_tree.items.iter().enumerate() {
+                let source = format_file(&File {
+                    shebang: None,
+                    attrs: Vec::new(),
+                    items: vec![item.clone()],
+                });
+                let _ = write!(
+                    body,
+                    "<details open id=\"item-{}\">\n<summary>{}",
+                    i,
+                    escape(summary(&source)),
+                );
+                let location = item_ident(item)
+                    .and_then(|ident| locations.get(&format!("{}{}", prefix, ident)));
+                if let Some(location) = location {
+                    let display = location.path.strip_prefix(&root).unwrap_or(&location.path);
+                    let _ = write!(
+                        body,
+                        "<a href=\"{}\">{}:{}</a>",
+                        escape(&file_url(&location.path, location.line)),
+                        escape(&display.display().to_string()),
+                        location.line,
+                    );
+                }
+                body += "</summary>\n";
+                body += &highlight(&source)?;
+                body += "</details>\n";
+            }
+        }
+        None => body += &highlight(&expansion.formatted)?,
+    }
+
+    let title = match &args.item {
+        Some(item) => format!("cargo expand {}", item),
+        None => "cargo expand".to_owned(),
+    };
+    let html = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n<style>{}</style>\n</head>\n<body>\n{}</body>\n</html>\n",
+        escape(&title),
+        STYLE,
+        body,
+    );
+    fs::write(path, html)?;
+
+    let _ = writeln!(io::stderr(), "Wrote {}", path.display());
+    Ok(0)
+}
+
+// Where each item of the source is, by path. Impls are found by the name of
+// their self type, so they only count if the type itself is not found.
+fn locations(items: Vec<SourceItem>) -> HashMap<String, Location> {
+    let mut locations: HashMap<String, Location> = HashMap::new();
+    for source_item in items {
+        let Some(ident) = item_ident(&source_item.item) else {
+            continue;
+        };
+        let mut name = source_item.module;
+        name.push(ident);
+        let location = Location {
+            line: source_item.item.span().start().line,
+            is_impl: matches!(source_item.item, Item::Impl(_)),
+            path: source_item.file,
+        };
+        match locations.entry(name.join("::")) {
+            Entry::Occupied(mut entry) => {
+                if entry.get().is_impl && !location.is_impl {
+                    entry.insert(location);
+                }
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(location);
+            }
+        }
+    }
+    locations
+}
+
+// The first line of the item that is not an attribute or doc comment, such as
+// `impl ::core::fmt::Debug for Foo`.
+fn summary(source: &str) -> &str {
+    let line = source
+        .lines()
+        .find(|line| !line.starts_with("#[") && !line.starts_with("///"))
+        .unwrap_or_default();
+    line.trim_end_matches(['{', '}', ';']).trim_end()
+}
+
+// A file:// URL for the line of a source file. Browsers ignore the #L
+// fragment of a local file, so the link opens the file at its top; the line
+// is there for editors and viewers that understand it, and in the link text.
+fn file_url(path: &Path, line: usize) -> String {
+    let path = path.to_string_lossy().replace('\\', "/");
+    let mut url = String::from("file://");
+    if !path.starts_with('/') {
+        // A Windows path like C:/dir.
+        url.push('/');
+    }
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' | b':' => {
+                url.push(byte as char);
+            }
+            byte => {
+                let _ = write!(url, "%{:02X}", byte);
+            }
+        }
+    }
+    let _ = write!(url, "#L{}", line);
+    url
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for ch in text.chars() {
+        match ch {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            ch => escaped.push(ch),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summary() {
+        let source = "/// Docs.\n#[derive(Debug)]\npub struct S {\n    a: u8,\n}\n";
+        assert_eq!(summary(source), "pub struct S");
+        assert_eq!(summary("impl Trait for S {}\n"), "impl Trait for S");
+        assert_eq!(summary("pub struct Unit;\n"), "pub struct Unit");
+        assert_eq!(summary("#[attr]\n"), "");
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(
+            escape("impl<T> From<&T> for \"S\""),
+            "impl&lt;T&gt; From&lt;&amp;T&gt; for &quot;S&quot;",
+        );
+        assert_eq!(escape("plain"), "plain");
+    }
+
+    #[test]
+    fn test_file_url() {
+        assert_eq!(
+            file_url(Path::new("/home/me/my crate/src/lib.rs"), 7),
+            "file:///home/me/my%20crate/src/lib.rs#L7",
+        );
+        assert_eq!(
+            file_url(Path::new("/src/a#b?c%d\u{e9}.rs"), 1),
+            "file:///src/a%23b%3Fc%25d%C3%A9.rs#L1",
+        );
+    }
+
+    fn source_items(file: &str, module: &[&str], source: &str) -> Vec<SourceItem> {
+        syn::parse_file(source)
+            .unwrap()
+            .items
+            .into_iter()
+            .map(|item| SourceItem {
+                file: PathBuf::from(file),
+                module: module.iter().map(|segment| segment.to_string()).collect(),
+                item,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_locations() {
+        let mut items = source_items("src/lib.rs", &[], "impl S {}\n\nstruct S;\n\nfn f() {}\n");
+        items.extend(source_items("src/m.rs", &["m"], "struct S;\nimpl S {}\n"));
+        items.extend(source_items("src/n.rs", &["n"], "impl T {}\nimpl T {}\n"));
+        let locations = locations(items);
+
+        let location = |name: &str| {
+            let location = &locations[name];
+            (
+                location.path.to_str().unwrap(),
+                location.line,
+                location.is_impl,
+            )
+        };
+        // The type is preferred over an impl of it, whichever comes first.
+        assert_eq!(location("S"), ("src/lib.rs", 3, false));
+        assert_eq!(location("m::S"), ("src/m.rs", 1, false));
+        // Without the type, the first impl.
+        assert_eq!(location("n::T"), ("src/n.rs", 1, true));
+        assert_eq!(location("f"), ("src/lib.rs", 5, false));
+        assert_eq!(locations.len(), 4);
+    }
+}
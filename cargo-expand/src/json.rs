@@ -0,0 +1,240 @@
+use crate::edit::{self, Passes};
+use crate::error::Result;
+use crate::opts::Coloring;
+use crate::{expand_syntax_tree_with_passes, format_item, make_tempdir, Expand};
+use serde::Serialize;
+use std::io::{self, Write};
+use syn::{Attribute, Expr, File, Item, Path, Stmt, Type};
+
+#[derive(Serialize)]
+struct Output {
+    items: Vec<OutputItem>,
+}
+
+#[derive(Serialize)]
+struct OutputItem {
+    /// Path of the item in the form accepted as the ITEM argument, or null for
+    /// items that cannot be selected by name, such as `use` or `const _`.
+    path: Option<String>,
+    kind: &'static str,
+    source: String,
+    /// The macro that produced this item, if it can be told from the expanded
+    /// code.
+    #[serde(rename = "macro")]
+    provenance: Option<Provenance>,
+}
+
+#[derive(Serialize)]
+struct Provenance {
+    kind: ProvenanceKind,
+    name: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+enum ProvenanceKind {
+    Derive,
+    Attribute,
+}
+
+pub fn cargo_expand_json(args: &Expand, color: &Coloring) -> Result<i32> {
+    let outdir = make_tempdir();
+    let outfile_path = outdir.path().join("expanded");
+
+    // Provenance is told partly from #[rustc_*] attributes, so those are only
+    // stripped once it is known.
+    let passes = Passes {
+        strip_rustc_attrs: false,
+        ..Passes::new(args)
+    };
+    let syntax_tree = match expand_syntax_tree_with_passes(args, &passes, color, &outfile_path)? {
+        Ok(syntax_tree) => syntax_tree,
+        Err(code) => return Ok(code),
+    };
+
+    let prefix: String = item_module(args)
+        .iter()
+        .map(|segment| format!("{}::", segment))
+        .collect();
+    let items = output_items(syntax_tree, &prefix, args.strip_rustc_attrs);
+
+    let mut stdout = io::stdout();
+    serde_json::to_writer(&mut stdout, &Output { items })?;
+    let _ = writeln!(stdout);
+    Ok(0)
+}
+
+fn output_items(mut syntax_tree: File, prefix: &str, strip_rustc_attrs: bool) -> Vec<OutputItem> {
+    let provenances: Vec<Option<Provenance>> = syntax_tree.items.iter().map(provenance).collect();
+    if strip_rustc_attrs {
+        let passes = Passes {
+            // The other passes have run already. Keep what they kept.
+            keep_macro_rules: true,
+            strip_derived: false,
+            hide_prelude: false,
+            collapse_format_args: false,
+            strip_rustc_attrs: true,
+        };
+        edit::sanitize(&mut syntax_tree, &passes);
+    }
+
+    syntax_tree
+        .items
+        .into_iter()
+        .zip(provenances)
+        .map(|(item, provenance)| OutputItem {
+            path: item_ident(&item).map(|ident| format!("{}{}", prefix, ident)),
+            kind: item_kind(&item),
+            provenance,
+            source: format_item(item),
+        })
+        .collect()
+}
+
+// Items selected by the ITEM argument live in the module it names.
+pub fn item_module(args: &Expand) -> Vec<String> {
+    let Some(filter) = &args.item else {
+        return Vec::new();
+    };
+    let filter = filter.to_string();
+    let filter = filter.strip_prefix("::").unwrap_or(&filter);
+    match filter.rsplit_once("::") {
+        Some((module, _name)) => module.split("::").map(str::to_owned).collect(),
+        None => Vec::new(),
+    }
+}
+
+pub fn item_ident(item: &Item) -> Option<String> {
+    let ident = match item {
+        Item::Const(item) => &item.ident,
+        Item::Enum(item) => &item.ident,
+        Item::ExternCrate(item) => &item.ident,
+        Item::Fn(item) => &item.sig.ident,
+        Item::Macro(item) => item.ident.as_ref()?,
+        Item::Mod(item) => &item.ident,
+        Item::Static(item) => &item.ident,
+        Item::Struct(item) => &item.ident,
+        Item::Trait(item) => &item.ident,
+        Item::TraitAlias(item) => &item.ident,
+        Item::Type(item) => &item.ident,
+        Item::Union(item) => &item.ident,
+        // Impl blocks are selected by the name of their self type.
+        Item::Impl(item) => match &*item.self_ty {
+            Type::Path(ty) => &ty.path.segments.last()?.ident,
+            _ => return None,
+        },
+        _ => return None,
+    };
+    if ident == "_" {
+        None
+    } else {
+        Some(ident.to_string())
+    }
+}
+
+pub fn item_kind(item: &Item) -> &'static str {
+    match item {
+        Item::Const(_) => "const",
+        Item::Enum(_) => "enum",
+        Item::ExternCrate(_) => "extern_crate",
+        Item::Fn(_) => "fn",
+        Item::ForeignMod(_) => "foreign_mod",
+        Item::Impl(_) => "impl",
+        Item::Macro(_) => "macro",
+        Item::Mod(_) => "mod",
+        Item::Static(_) => "static",
+        Item::Struct(_) => "struct",
+        Item::Trait(_) => "trait",
+        Item::TraitAlias(_) => "trait_alias",
+        Item::Type(_) => "type",
+        Item::Union(_) => "union",
+        Item::Use(_) => "use",
+        _ => "verbatim",
+    }
+}
+
+// Derives mark their impls with #[automatically_derived]. Some derives, such
+// as serde's, wrap the impl in a `const _: () = { ... };` block. The #[test]
+// and #[bench] attributes leave behind a const carrying #[rustc_test_marker].
+fn provenance(item: &Item) -> Option<Provenance> {
+    match item {
+        Item::Impl(item) => {
+            if !has_attr(&item.attrs, "automatically_derived") {
+                return None;
+            }
+            let (_, path, _) = item.trait_.as_ref()?;
+            Some(Provenance {
+                kind: ProvenanceKind::Derive,
+                name: derive_name(path)?,
+            })
+        }
+        Item::Const(item) => {
+            if has_attr(&item.attrs, "rustc_test_marker") {
+                return Some(Provenance {
+                    kind: ProvenanceKind::Attribute,
+                    name: "test".to_owned(),
+                });
+            }
+            if item.ident != "_" {
+                return None;
+            }
+            let Expr::Block(expr) = &*item.expr else {
+                return None;
+            };
+            expr.block.stmts.iter().find_map(|stmt| match stmt {
+                Stmt::Item(item @ Item::Impl(_)) => provenance(item),
+                _ => None,
+            })
+        }
+        _ => None,
+    }
+}
+
+fn derive_name(path: &Path) -> Option<String> {
+    let name = path.segments.last()?.ident.to_string();
+    // derive(PartialEq) and derive(Eq) also implement these marker traits.
+    Some(match name.strip_prefix("Structural") {
+        Some(derive) if !derive.is_empty() => derive.to_owned(),
+        _ => name,
+    })
+}
+
+fn has_attr(attrs: &[Attribute], name: &str) -> bool {
+    attrs.iter().any(|attr| attr.path().is_ident(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use syn::parse_quote;
+
+    fn test_file() -> File {
+        parse_quote! {
+            #[rustc_test_marker = "it_works"]
+            const it_works: test::TestDescAndFn = test::TestDescAndFn {};
+
+            #[automatically_derived]
+            impl ::core::clone::Clone for S {}
+        }
+    }
+
+    #[test]
+    fn test_provenance() {
+        let items = output_items(test_file(), "", false);
+        let test = items[0].provenance.as_ref().unwrap();
+        assert!(matches!(test.kind, ProvenanceKind::Attribute));
+        assert_eq!(test.name, "test");
+        assert!(items[0].source.contains("rustc_test_marker"));
+        let derive = items[1].provenance.as_ref().unwrap();
+        assert!(matches!(derive.kind, ProvenanceKind::Derive));
+        assert_eq!(derive.name, "Clone");
+    }
+
+    #[test]
+    fn test_provenance_with_strip_rustc_attrs() {
+        let items = output_items(test_file(), "", true);
+        assert!(!items[0].source.contains("rustc_test_marker"));
+        assert_eq!(items[0].provenance.as_ref().unwrap().name, "test");
+        assert_eq!(items[1].provenance.as_ref().unwrap().name, "Clone");
+    }
+}
@@ -14,23 +14,42 @@
 )]
 #![cfg_attr(all(test, exhaustive), feature(non_exhaustive_omitted_patterns_lint))]
 
+mod check;
 mod cmd;
 mod config;
+mod diagnostics;
+mod diff;
 mod edit;
 mod error;
+mod filter;
 mod fmt;
+mod html;
+mod json;
 mod manifest;
+mod mbe;
+mod metadata;
+mod offline;
 mod opts;
+mod output;
+mod source;
+mod toolchain;
 mod unparse;
 mod version;
+mod watch;
+mod workspace;
 
 use crate::cmd::Line;
 use crate::config::Config;
+use crate::diagnostics::Diagnostics;
+use crate::edit::Passes;
+pub use crate::error::Error;
 use crate::error::Result;
 use crate::opts::Coloring::*;
-pub use crate::opts::{Coloring, Expand};
-use crate::unparse::unparse_maximal;
+pub use crate::opts::{Coloring, Expand, Location, MessageFormat};
+use crate::toolchain::Route;
+use crate::unparse::{panic_message, unparse_maximal, unparse_with_fallbacks};
 use crate::version::Version;
+use crate::workspace::Job;
 use bat::{PagingMode, PrettyPrinter};
 use clap::ValueEnum;
 use quote::quote;
@@ -42,16 +61,26 @@ use std::panic::{self, PanicInfo, UnwindSafe};
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
 use std::ptr;
+use std::sync::{Mutex, PoisonError};
+use std::thread;
 use std::thread::Result as ThreadResult;
-use termcolor::{Color::Green, ColorChoice, ColorSpec, StandardStream, WriteColor};
+use syn::{File, Item};
+use termcolor::{
+    Color::{Green, Yellow},
+    ColorChoice, ColorSpec, StandardStream, WriteColor,
+};
 
 cargo_subcommand_metadata::description!("Show result of macro expansion");
 
+// Work around rustfmt not being able to parse paths containing $crate. This
+// placeholder should be the same width as $crate to preserve alignments.
+const DOLLAR_CRATE_PLACEHOLDER: &str = "Ξcrate";
+
 fn cargo_binary() -> OsString {
     env::var_os("CARGO").unwrap_or_else(|| "cargo".to_owned().into())
 }
 
-pub fn cargo_expand(args: Expand) -> Result<i32> {
+pub fn cargo_expand(mut args: Expand) -> Result<i32> {
     if args.version {
         let version = Version {
             verbose: args.verbose,
@@ -60,7 +89,8 @@ pub fn cargo_expand(args: Expand) -> Result<i32> {
         return Ok(0);
     }
 
-    let config = config::deserialize();
+    let config = config::deserialize(args.manifest_path.as_deref());
+    apply_config(&mut args, &config);
 
     if args.themes {
         for theme in PrettyPrinter::new().themes() {
@@ -80,124 +110,334 @@ pub fn cargo_expand(args: Expand) -> Result<i32> {
         }
     }
 
-    let mut rustfmt = None;
-    if config.rustfmt {
-        rustfmt = which_rustfmt();
-        if rustfmt.is_none() {
-            let _ = io::stderr().write_all(
-                b"ERROR: cargo-expand configuration sets rustfmt=true, but \
-                rustfmt is not found. Install rustfmt by running `rustup \
-                component add rustfmt --toolchain nightly`.\n",
-            );
-            return Ok(1);
-        }
+    let color = get_color(&args, &config);
+
+    if args.diff {
+        return diff::cargo_expand_diff(args, &color);
     }
 
-    let mut builder = tempfile::Builder::new();
-    builder.prefix("cargo-expand");
-    let outdir = builder.tempdir().expect("failed to create tmp file");
-    let outfile_path = outdir.path().join("expanded");
+    if let Some(dir) = &args.check {
+        return check::cargo_expand_check(&args, &color, dir);
+    }
+
+    let rustfmt = find_rustfmt(&config)?;
+
+    if args.workspace || args.all_targets {
+        return workspace::cargo_expand_workspace(&args, &config, &color, rustfmt.as_deref());
+    }
+
+    if args.watch {
+        return watch::cargo_expand_watch(&args, config, &color, rustfmt.as_deref());
+    }
+
+    expand_once(&args, &config, &color, rustfmt.as_deref())
+}
+
+/// The result of expanding a crate, as returned by [`expand`].
+pub struct Expansion {
+    /// The code as printed by rustc.
+    pub raw: String,
+    /// The code after removing what `cargo expand` does not show and applying
+    /// the ITEM filter, or None if the output of rustc could not be parsed.
+    /// Occurrences of `$crate` appear as the identifier `Ξcrate`.
+    pub syntax_tree: Option<File>,
+    /// The code as `cargo expand` prints it.
+    pub formatted: String,
+    theme: Option<String>,
+    pager: bool,
+    color: Coloring,
+}
+
+/// Expands the crate selected by `args` and returns the result instead of
+/// printing it. Options belonging to other modes, such as `diff` or
+/// `workspace`, are ignored.
+pub fn expand(mut args: Expand) -> Result<Expansion> {
+    let config = config::deserialize(args.manifest_path.as_deref());
+    apply_config(&mut args, &config);
     let color = get_color(&args, &config);
+    let rustfmt = find_rustfmt(&config)?;
+    expand_with(&args, &config, &color, rustfmt.as_deref())
+}
 
-    // Run cargo
-    let mut cmd = Command::new(cargo_binary());
-    apply_args(&mut cmd, &args, &color, &outfile_path);
-    cmd.env("RUSTC_BOOTSTRAP", "1");
-    let code = filter_err(&mut cmd)?;
+impl Expansion {
+    /// Prints the formatted code the way `cargo expand` does, highlighted
+    /// according to the theme and color settings it was expanded with.
+    pub fn print(&self) {
+        print_expanded(
+            &self.formatted,
+            self.theme.as_deref(),
+            self.pager,
+            &self.color,
+        );
+    }
+}
 
-    if !outfile_path.exists() {
-        return Ok(1);
+fn find_rustfmt(config: &Config) -> Result<Option<PathBuf>> {
+    if !config.rustfmt {
+        return Ok(None);
+    }
+    match which_rustfmt() {
+        Some(rustfmt) => Ok(Some(rustfmt)),
+        None => Err(Error::MissingRustfmt),
     }
+}
 
-    let mut content = fs::read_to_string(&outfile_path)?;
-    if content.is_empty() {
-        let _ = writeln!(io::stderr(), "ERROR: rustc produced no expanded output");
-        return Ok(if code == 0 { 1 } else { code });
+// Fills in the defaults from the configuration for whatever the command line
+// leaves unspecified. See config::deserialize for the order of precedence.
+fn apply_config(args: &mut Expand, config: &Config) {
+    // A pass enabled by the configuration is turned off by its --no- flag.
+    args.keep_macro_rules |= config.keep_macro_rules && !args.no_keep_macro_rules;
+    args.strip_derived |= config.strip_derived && !args.no_strip_derived;
+    args.hide_prelude |= config.hide_prelude && !args.no_hide_prelude;
+    args.collapse_format_args |= config.collapse_format_args && !args.no_collapse_format_args;
+    args.strip_rustc_attrs |= config.strip_rustc_attrs && !args.no_strip_rustc_attrs;
+
+    // Each feature setting on the command line overrides only the same
+    // setting in the configuration.
+    if args.features.is_none() {
+        args.features = config.features.as_ref().map(|features| features.join(","));
+    }
+    args.all_features |= config.all_features && !args.no_all_features;
+    args.no_default_features |= config.no_default_features && !args.default_features;
+
+    // A configured item only applies to modes that expand a single target,
+    // and --no-item asks for the whole crate regardless.
+    if args.item.is_none() && !args.no_item && !args.ugly && !args.workspace && !args.all_targets {
+        if let Some(item) = &config.item {
+            match opts::parse_selector(item) {
+                Ok(selector) => args.item = Some(selector),
+                Err(err) => {
+                    let _ = writeln!(io::stderr(), "Warning: invalid item `{}`: {}", item, err);
+                }
+            }
+        }
     }
+}
+
+// Runs the expansion and prints the result in the requested message format.
+fn expand_once(
+    args: &Expand,
+    config: &Config,
+    color: &Coloring,
+    rustfmt: Option<&Path>,
+) -> Result<i32> {
+    if args.macro_rules || args.at.is_some() {
+        return offline::cargo_expand_macro_rules(args, config, color);
+    }
+
+    if let MessageFormat::Json = args.message_format {
+        return json::cargo_expand_json(args, color);
+    }
+
+    let expansion = expand_with(args, config, color, rustfmt)?;
+
+    if let Some(output) = &args.output {
+        return output::write_expanded(&expansion.formatted, args, output);
+    }
+
+    if let Some(html) = &args.html {
+        return html::write_html(&expansion, args, html);
+    }
+
+    expansion.print();
+    Ok(0)
+}
+
+fn expand_with(
+    args: &Expand,
+    config: &Config,
+    color: &Coloring,
+    rustfmt: Option<&Path>,
+) -> Result<Expansion> {
+    let outdir = make_tempdir();
+    let outfile_path = outdir.path().join("expanded");
+
+    // Run cargo
+    let route = toolchain::route(args.manifest_path.as_deref(), args.verbose, color)?;
+    let raw = run_cargo(args, route, None, color, &outfile_path)?;
 
     // Format the expanded code
-    if !args.ugly {
-        let questionably_formatted = content;
-
-        // Work around rustfmt not being able to parse paths containing $crate.
-        // This placeholder should be the same width as $crate to preserve
-        // alignments.
-        const DOLLAR_CRATE_PLACEHOLDER: &str = "Ξcrate";
-        let wip = questionably_formatted.replace("$crate", DOLLAR_CRATE_PLACEHOLDER);
-
-        // Support cargo-expand built with panic=abort, as otherwise proc-macro2
-        // ends up using a catch_unwind.
-        proc_macro2::fallback::force();
-
-        enum Stage {
-            Formatted(String),
-            Unformatted(String),
-            QuestionablyFormatted,
+    let (syntax_tree, formatted) = if args.ugly {
+        let wip = raw.replace("$crate", DOLLAR_CRATE_PLACEHOLDER);
+        let syntax_tree = syn::parse_file(&wip).ok().map(|mut syntax_tree| {
+            edit::sanitize(&mut syntax_tree, &Passes::new(args));
+            syntax_tree
+        });
+        (syntax_tree, raw.clone())
+    } else {
+        format_expanded(raw.clone(), args, config, color, rustfmt, &outdir)?
+    };
+
+    Ok(Expansion {
+        raw,
+        syntax_tree,
+        formatted,
+        theme: theme(args, config).map(str::to_owned),
+        pager: config.pager,
+        color: *color,
+    })
+}
+
+// Removes misplaced comments and pretty prints the code produced by rustc,
+// falling back to rustfmt and then to the unformatted code. Also returns the
+// syntax tree that was formatted, if the code could be parsed.
+fn format_expanded(
+    content: String,
+    args: &Expand,
+    config: &Config,
+    color: &Coloring,
+    rustfmt: Option<&Path>,
+    outdir: &tempfile::TempDir,
+) -> Result<(Option<File>, String)> {
+    let mut fallbacks = Vec::new();
+    let result = format_stages(content, args, config, rustfmt, outdir, &mut fallbacks);
+    if args.verbose {
+        for fallback in &fallbacks {
+            print_fallback(fallback, color);
         }
+    }
+    result
+}
+
+// Does the work of format_expanded, describing in `fallbacks` each stage that
+// did not go as intended.
+fn format_stages(
+    content: String,
+    args: &Expand,
+    config: &Config,
+    rustfmt: Option<&Path>,
+    outdir: &tempfile::TempDir,
+    fallbacks: &mut Vec<String>,
+) -> Result<(Option<File>, String)> {
+    let outfile_path = outdir.path().join("expanded");
+    let questionably_formatted = content;
+
+    let wip = questionably_formatted.replace("$crate", DOLLAR_CRATE_PLACEHOLDER);
+
+    // Support cargo-expand built with panic=abort, as otherwise proc-macro2
+    // ends up using a catch_unwind.
+    proc_macro2::fallback::force();
+
+    enum Stage {
+        Formatted(String),
+        Unformatted(String),
+        QuestionablyFormatted,
+    }
 
-        let mut stage = Stage::QuestionablyFormatted;
+    let mut stage = Stage::QuestionablyFormatted;
+    let mut parsed = None;
 
-        // Discard comments, which are misplaced by the compiler
-        if let Ok(mut syntax_tree) = syn::parse_file(&wip) {
-            edit::sanitize(&mut syntax_tree);
-            if let Some(filter) = args.item {
+    // Discard comments, which are misplaced by the compiler
+    match syn::parse_file(&wip) {
+        Ok(mut syntax_tree) => {
+            edit::sanitize(&mut syntax_tree, &Passes::new(args));
+            if let Some(filter) = &args.item {
                 syntax_tree.shebang = None;
                 syntax_tree.attrs.clear();
                 syntax_tree.items = filter.apply_to(&syntax_tree);
                 if syntax_tree.items.is_empty() {
-                    let _ = writeln!(io::stderr(), "WARNING: no such item: {}", filter);
-                    return Ok(1);
+                    return Err(Error::NoSuchItem(filter.to_string()));
                 }
             }
+            filter::apply(&mut syntax_tree, args)?;
             if !config.rustfmt {
-                if let Ok(formatted) = ignore_panic(|| unparse_maximal(&syntax_tree)) {
-                    stage = Stage::Formatted(formatted);
+                match ignore_panic(|| unparse_with_fallbacks(&syntax_tree, args.raw_fallback)) {
+                    Ok(unparsed) => {
+                        let shown = if args.raw_fallback {
+                            "its tokens"
+                        } else {
+                            "`...`"
+                        };
+                        for fallback in unparsed.fallbacks {
+                            let name = match fallback.name {
+                                Some(name) => format!(" `{}`", name),
+                                None => String::new(),
+                            };
+                            fallbacks.push(format!(
+                                "prettyplease could not print {}{}, showing {} instead: {}",
+                                fallback.kind, name, shown, fallback.reason,
+                            ));
+                        }
+                        stage = Stage::Formatted(unparsed.formatted);
+                    }
+                    Err(payload) => fallbacks.push(format!(
+                        "prettyplease failed, trying rustfmt: {}",
+                        panic_message(&*payload),
+                    )),
                 }
             }
             if let Stage::QuestionablyFormatted = stage {
                 let unformatted = quote!(#syntax_tree).to_string();
                 stage = Stage::Unformatted(unformatted);
             }
+            parsed = Some(syntax_tree);
         }
+        Err(err) => fallbacks.push(format!(
+            "failed to parse expanded code, trying rustfmt: {}",
+            err,
+        )),
+    }
 
-        let to_rustfmt = match &stage {
-            Stage::Formatted(_) => None,
-            Stage::Unformatted(unformatted) => Some(unformatted),
-            Stage::QuestionablyFormatted => Some(&wip),
-        };
+    let to_rustfmt = match &stage {
+        Stage::Formatted(_) => None,
+        Stage::Unformatted(unformatted) => Some(unformatted),
+        Stage::QuestionablyFormatted => Some(&wip),
+    };
 
-        if let Some(unformatted) = to_rustfmt {
-            if let Some(rustfmt) = rustfmt.or_else(which_rustfmt) {
-                fs::write(&outfile_path, unformatted)?;
-
-                fmt::write_rustfmt_config(&outdir)?;
-
-                for edition in &["2021", "2018", "2015"] {
-                    let output = Command::new(&rustfmt)
-                        .arg("--edition")
-                        .arg(edition)
-                        .arg(&outfile_path)
-                        .stderr(Stdio::null())
-                        .output();
-                    if let Ok(output) = output {
-                        if output.status.success() {
-                            stage = Stage::Formatted(fs::read_to_string(&outfile_path)?);
-                            break;
-                        }
+    if let Some(unformatted) = to_rustfmt {
+        if let Some(rustfmt) = rustfmt.map(Path::to_owned).or_else(which_rustfmt) {
+            fs::write(&outfile_path, unformatted)?;
+
+            fmt::write_rustfmt_config(outdir)?;
+
+            let mut rustfmt_err = String::new();
+            for edition in &["2021", "2018", "2015"] {
+                let output = Command::new(&rustfmt)
+                    .arg("--edition")
+                    .arg(edition)
+                    .arg(&outfile_path)
+                    .output();
+                match output {
+                    Ok(output) if output.status.success() => {
+                        stage = Stage::Formatted(fs::read_to_string(&outfile_path)?);
+                        break;
                     }
+                    Ok(output) => {
+                        let stderr = String::from_utf8_lossy(&output.stderr);
+                        rustfmt_err = match stderr.lines().next() {
+                            Some(line) => line.to_owned(),
+                            None => format!("exited with {}", output.status),
+                        };
+                    }
+                    Err(err) => rustfmt_err = err.to_string(),
                 }
             }
+            if !matches!(stage, Stage::Formatted(_)) {
+                fallbacks.push(format!(
+                    "rustfmt failed, showing the code as printed by rustc: {}",
+                    rustfmt_err,
+                ));
+            }
+        } else {
+            fallbacks.push("rustfmt not found, showing the code as printed by rustc".to_owned());
         }
-
-        content = match stage {
-            Stage::Formatted(formatted) => formatted.replace(DOLLAR_CRATE_PLACEHOLDER, "$crate"),
-            Stage::Unformatted(_) | Stage::QuestionablyFormatted => questionably_formatted,
-        };
     }
 
-    // Run pretty printer
-    let theme = args.theme.or(config.theme);
-    let none_theme = theme.as_deref() == Some("none");
+    let formatted = match stage {
+        Stage::Formatted(formatted) => formatted.replace(DOLLAR_CRATE_PLACEHOLDER, "$crate"),
+        Stage::Unformatted(_) | Stage::QuestionablyFormatted => questionably_formatted,
+    };
+
+    Ok((parsed, formatted))
+}
+
+// Prints expanded code to stdout, syntax highlighted when appropriate.
+fn theme<'a>(args: &'a Expand, config: &'a Config) -> Option<&'a str> {
+    args.theme.as_deref().or(config.theme.as_deref())
+}
+
+fn print_expanded(content: &str, theme: Option<&str>, pager: bool, color: &Coloring) {
+    let none_theme = theme == Some("none");
     let do_color = match color {
         Always => true,
         Never => false,
@@ -217,7 +457,7 @@ pub fn cargo_expand(args: Expand) -> Result<i32> {
         if let Some(theme) = theme {
             pretty_printer.theme(theme);
         }
-        if config.pager {
+        if pager {
             pretty_printer.paging_mode(PagingMode::QuitIfOneScreen);
         }
 
@@ -226,8 +466,113 @@ pub fn cargo_expand(args: Expand) -> Result<i32> {
     } else {
         let _ = write!(io::stdout(), "{}", content);
     }
+}
 
-    Ok(0)
+fn make_tempdir() -> tempfile::TempDir {
+    let mut builder = tempfile::Builder::new();
+    builder.prefix("cargo-expand");
+    builder.tempdir().expect("failed to create tmp file")
+}
+
+// Returns the expanded source written by rustc, or the exit code to fail with
+// if there is none.
+fn run_cargo(
+    args: &Expand,
+    route: Route,
+    job: Option<&Job>,
+    color: &Coloring,
+    outfile_path: &Path,
+) -> Result<String> {
+    let mut cmd = route.command();
+    apply_args(&mut cmd, args, job, color, outfile_path, route);
+    let (code, diagnostics) = filter_err(&mut cmd)?;
+    diagnostics.print_summary(&mut io::stderr().lock());
+
+    if diagnostics.nightly_required() {
+        return Err(Error::MissingNightly);
+    }
+
+    if !outfile_path.exists() {
+        return Err(Error::Build {
+            errors: diagnostics.errors(),
+        });
+    }
+
+    let content = fs::read_to_string(outfile_path)?;
+    if content.is_empty() {
+        return Err(if code == 0 {
+            Error::EmptyOutput
+        } else {
+            Error::Build {
+                errors: diagnostics.errors(),
+            }
+        });
+    }
+
+    Ok(content)
+}
+
+// Expands the crate and parses the result, applying the same normalization
+// and item filter as the regular output. Used by the modes that work on
+// individual items rather than printing the whole file.
+fn expand_syntax_tree(
+    args: &Expand,
+    color: &Coloring,
+    outfile_path: &Path,
+) -> Result<std::result::Result<File, i32>> {
+    expand_syntax_tree_with_passes(args, &Passes::new(args), color, outfile_path)
+}
+
+fn expand_syntax_tree_with_passes(
+    args: &Expand,
+    passes: &Passes,
+    color: &Coloring,
+    outfile_path: &Path,
+) -> Result<std::result::Result<File, i32>> {
+    let route = toolchain::route(args.manifest_path.as_deref(), args.verbose, color)?;
+    let content = run_cargo(args, route, None, color, outfile_path)?;
+
+    let wip = content.replace("$crate", DOLLAR_CRATE_PLACEHOLDER);
+
+    // Support cargo-expand built with panic=abort, as otherwise proc-macro2
+    // ends up using a catch_unwind.
+    proc_macro2::fallback::force();
+
+    let mut syntax_tree = match syn::parse_file(&wip) {
+        Ok(syntax_tree) => syntax_tree,
+        Err(err) => {
+            let _ = writeln!(
+                io::stderr(),
+                "ERROR: failed to parse expanded code: {}",
+                err
+            );
+            return Ok(Err(1));
+        }
+    };
+    edit::sanitize(&mut syntax_tree, passes);
+    if let Some(filter) = &args.item {
+        syntax_tree.shebang = None;
+        syntax_tree.attrs.clear();
+        syntax_tree.items = filter.apply_to(&syntax_tree);
+    }
+    filter::apply(&mut syntax_tree, args)?;
+
+    Ok(Ok(syntax_tree))
+}
+
+// Formats a single top-level item on its own.
+fn format_item(item: Item) -> String {
+    format_file(&File {
+        shebang: None,
+        attrs: Vec::new(),
+        items: vec![item],
+    })
+}
+
+fn format_file(file: &File) -> String {
+    let formatted =
+        ignore_panic(|| unparse_maximal(file)).unwrap_or_else(|_| quote!(#file).to_string() + "\n");
+    formatted.replace(DOLLAR_CRATE_PLACEHOLDER, "$crate")
 }
 
 fn which_rustfmt() -> Option<PathBuf> {
@@ -243,7 +588,14 @@ fn which_rustfmt() -> Option<PathBuf> {
     }
 }
 
-fn apply_args(cmd: &mut Command, args: &Expand, color: &Coloring, outfile: &Path) {
+fn apply_args(
+    cmd: &mut Command,
+    args: &Expand,
+    job: Option<&Job>,
+    color: &Coloring,
+    outfile: &Path,
+    route: Route,
+) {
     let mut line = Line::new("cargo");
 
     line.arg("rustc");
@@ -252,15 +604,26 @@ fn apply_args(cmd: &mut Command, args: &Expand, color: &Coloring, outfile: &Path
         line.arg("--verbose");
     }
 
+    let color_arg = match color {
+        Coloring::Auto => {
+            if cfg!(not(windows)) && io::stderr().is_terminal() {
+                Coloring::Always
+            } else {
+                Coloring::Never
+            }
+        }
+        color => *color,
+    };
     line.arg("--color");
-    match color {
-        Coloring::Auto => line.arg(if cfg!(not(windows)) && io::stderr().is_terminal() {
-            "always"
-        } else {
-            "never"
-        }),
-        color => line.arg(color.to_possible_value().unwrap().get_name()),
-    }
+    line.arg(color_arg.to_possible_value().unwrap().get_name());
+
+    // Diagnostics are collected from the JSON messages so that they can be
+    // deduplicated, with the rendered text keeping the requested coloring.
+    line.arg("--message-format");
+    line.arg(match color_arg {
+        Coloring::Always => "json-diagnostic-rendered-ansi",
+        Coloring::Auto | Coloring::Never => "json",
+    });
 
     for kv in &args.config {
         line.arg("--config");
@@ -272,50 +635,9 @@ fn apply_args(cmd: &mut Command, args: &Expand, color: &Coloring, outfile: &Path
         line.arg(unstable_flag);
     }
 
-    if let Some(package) = &args.package {
-        line.arg("--package");
-        line.args(package);
-    }
-
-    let mut has_explicit_build_target = false;
-    if args.lib {
-        line.arg("--lib");
-        has_explicit_build_target = true;
-    }
-
-    if let Some(bin) = &args.bin {
-        line.arg("--bin");
-        line.args(bin);
-        has_explicit_build_target = true;
-    }
-
-    if let Some(example) = &args.example {
-        line.arg("--example");
-        line.args(example);
-        has_explicit_build_target = true;
-    }
-
-    if let Some(test) = &args.test {
-        line.arg("--test");
-        line.args(test);
-        has_explicit_build_target = true;
-    }
-
-    if let Some(bench) = &args.bench {
-        line.arg("--bench");
-        line.args(bench);
-        has_explicit_build_target = true;
-    }
-
-    if !has_explicit_build_target {
-        if let Ok(cargo_manifest) = manifest::parse(args.manifest_path.as_deref()) {
-            if let Some(root_package) = cargo_manifest.package {
-                if let Some(default_run) = &root_package.default_run {
-                    line.arg("--bin");
-                    line.arg(default_run);
-                }
-            }
-        }
+    match job {
+        Some(job) => job.select(&mut line),
+        None => select_package_and_target(&mut line, args),
     }
 
     if let Some(features) = &args.features {
@@ -385,14 +707,74 @@ fn apply_args(cmd: &mut Command, args: &Expand, color: &Coloring, outfile: &Path
     line.arg("-Zunpretty=expanded");
 
     if args.verbose {
-        let mut display = line.clone();
-        display.insert(0, "+nightly");
-        print_command(display, color);
+        print_command(route.display(line.clone()), color);
     }
 
     cmd.args(line);
 }
 
+fn select_package_and_target(line: &mut Line, args: &Expand) {
+    if let Some(package) = &args.package {
+        line.arg("--package");
+        line.args(package);
+    }
+
+    let mut has_explicit_build_target = false;
+    if args.lib {
+        line.arg("--lib");
+        has_explicit_build_target = true;
+    }
+
+    if let Some(bin) = &args.bin {
+        line.arg("--bin");
+        line.args(bin);
+        has_explicit_build_target = true;
+    }
+
+    if let Some(example) = &args.example {
+        line.arg("--example");
+        line.args(example);
+        has_explicit_build_target = true;
+    }
+
+    if let Some(test) = &args.test {
+        line.arg("--test");
+        line.args(test);
+        has_explicit_build_target = true;
+    }
+
+    if let Some(bench) = &args.bench {
+        line.arg("--bench");
+        line.args(bench);
+        has_explicit_build_target = true;
+    }
+
+    if !has_explicit_build_target {
+        if let Ok(cargo_manifest) = manifest::parse(args.manifest_path.as_deref()) {
+            if let Some(root_package) = cargo_manifest.package {
+                if let Some(default_run) = &root_package.default_run {
+                    line.arg("--bin");
+                    line.arg(default_run);
+                }
+            }
+        }
+    }
+}
+
+fn print_fallback(fallback: &str, color: &Coloring) {
+    let color_choice = match color {
+        Coloring::Auto => ColorChoice::Auto,
+        Coloring::Always => ColorChoice::Always,
+        Coloring::Never => ColorChoice::Never,
+    };
+
+    let mut stream = StandardStream::stderr(color_choice);
+    let _ = stream.set_color(ColorSpec::new().set_bold(true).set_fg(Some(Yellow)));
+    let _ = write!(stream, "{:>12}", "Fallback");
+    let _ = stream.reset();
+    let _ = writeln!(stream, " {}", fallback);
+}
+
 fn print_command(line: Line, color: &Coloring) {
     let color_choice = match color {
         Coloring::Auto => ColorChoice::Auto,
@@ -407,21 +789,47 @@ fn print_command(line: Line, color: &Coloring) {
     let _ = writeln!(stream, " `{}`", line);
 }
 
-fn filter_err(cmd: &mut Command) -> io::Result<i32> {
-    let mut child = cmd.stderr(Stdio::piped()).spawn()?;
+// Forwards cargo's stderr, minus known noise, while collecting the compiler
+// diagnostics that cargo prints to stdout as JSON.
+fn filter_err(cmd: &mut Command) -> io::Result<(i32, Diagnostics)> {
+    let mut child = cmd.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+    let mut stdout = io::BufReader::new(child.stdout.take().unwrap());
     let mut stderr = io::BufReader::new(child.stderr.take().unwrap());
-    let mut line = String::new();
-    while let Ok(n) = stderr.read_line(&mut line) {
-        if n == 0 {
-            break;
-        }
-        if !ignore_cargo_err(&line) {
-            let _ = write!(io::stderr(), "{}", line);
+    let diagnostics = Mutex::new(Diagnostics::default());
+
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            let mut line = String::new();
+            while let Ok(n) = stdout.read_line(&mut line) {
+                if n == 0 {
+                    break;
+                }
+                let mut diagnostics = diagnostics.lock().unwrap();
+                diagnostics.push_json(&line, &mut io::stderr().lock());
+                line.clear();
+            }
+        });
+
+        let mut line = String::new();
+        while let Ok(n) = stderr.read_line(&mut line) {
+            if n == 0 {
+                break;
+            }
+            diagnostics.lock().unwrap().note_text(&line);
+            if !ignore_cargo_err(&line) {
+                let _ = write!(io::stderr(), "{}", line);
+            }
+            line.clear();
         }
-        line.clear();
-    }
+    });
+
     let code = child.wait()?.code().unwrap_or(1);
-    Ok(code)
+    Ok((code, diagnostics.into_inner().unwrap()))
+}
+
+// "1 error", "2 errors".
+fn plural(n: usize, noun: &str) -> String {
+    format!("{} {}{}", n, noun, if n == 1 { "" } else { "s" })
 }
 
 fn ignore_cargo_err(line: &str) -> bool {
@@ -457,6 +865,10 @@ where
 {
     type PanicHook = dyn Fn(&PanicInfo) + Sync + Send + 'static;
 
+    // The hook is global, so concurrent callers (see workspace.rs) take turns.
+    static HOOK_LOCK: Mutex<()> = Mutex::new(());
+    let _guard = HOOK_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+
     let null_hook: Box<PanicHook> = Box::new(|_panic_info| { /* ignore */ });
     let sanity_check = ptr::addr_of!(*null_hook);
     let original_hook = panic::take_hook();
@@ -498,3 +910,180 @@ fn get_color(args: &Expand, config: &Config) -> Coloring {
 
     Coloring::Auto // default
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn format(content: &str, config: &Config, rustfmt: &Path) -> (String, Vec<String>) {
+        let outdir = make_tempdir();
+        let mut fallbacks = Vec::new();
+        let (_syntax_tree, formatted) = format_stages(
+            content.to_owned(),
+            &Expand::default(),
+            config,
+            Some(rustfmt),
+            &outdir,
+            &mut fallbacks,
+        )
+        .unwrap_or_else(|err| panic!("{}", err));
+        (formatted, fallbacks)
+    }
+
+    #[cfg(unix)]
+    fn fake_rustfmt(dir: &Path, script: &str) -> PathBuf {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = dir.join("rustfmt");
+        fs::write(&path, format!("#!/bin/sh\n{}\n", script)).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    fn parse_args(args: &[&str]) -> Expand {
+        let argv = ["cargo", "expand"].iter().chain(args);
+        let opts::Subcommand::Expand(expand) =
+            opts::Subcommand::try_parse_from(argv).unwrap_or_else(|err| panic!("{}", err));
+        expand
+    }
+
+    #[cfg(unix)]
+    const RUSTFMT_OK: &str = r#"printf 'formatted by rustfmt\n' > "$3""#;
+
+    #[cfg(unix)]
+    const RUSTFMT_ERR: &str = "echo 'error: expected item' >&2; exit 1";
+
+    #[test]
+    fn test_prettyplease() {
+        let (formatted, fallbacks) =
+            format("fn f(){}", &Config::default(), Path::new("/nonexistent"));
+        assert_eq!(formatted, "fn f() {}\n");
+        assert!(fallbacks.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unformatted_to_rustfmt() {
+        let dir = make_tempdir();
+        let config = Config {
+            rustfmt: true,
+            ..Config::default()
+        };
+        let rustfmt = fake_rustfmt(dir.path(), RUSTFMT_OK);
+        let (formatted, fallbacks) = format("fn f(){}", &config, &rustfmt);
+        assert_eq!(formatted, "formatted by rustfmt\n");
+        assert!(fallbacks.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unformatted_to_questionably_formatted() {
+        let dir = make_tempdir();
+        let config = Config {
+            rustfmt: true,
+            ..Config::default()
+        };
+        let rustfmt = fake_rustfmt(dir.path(), RUSTFMT_ERR);
+        let (formatted, fallbacks) = format("fn f(){}", &config, &rustfmt);
+        assert_eq!(formatted, "fn f(){}");
+        assert_eq!(
+            fallbacks,
+            ["rustfmt failed, showing the code as printed by rustc: error: expected item"],
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unparseable_to_rustfmt() {
+        let dir = make_tempdir();
+        let rustfmt = fake_rustfmt(dir.path(), RUSTFMT_OK);
+        let (formatted, fallbacks) = format("fn f(", &Config::default(), &rustfmt);
+        assert_eq!(formatted, "formatted by rustfmt\n");
+        assert_eq!(fallbacks.len(), 1);
+        assert!(fallbacks[0].starts_with("failed to parse expanded code, trying rustfmt: "));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unparseable_to_questionably_formatted() {
+        let dir = make_tempdir();
+        let rustfmt = fake_rustfmt(dir.path(), RUSTFMT_ERR);
+        let (formatted, fallbacks) = format("fn f(", &Config::default(), &rustfmt);
+        assert_eq!(formatted, "fn f(");
+        assert_eq!(fallbacks.len(), 2);
+        assert!(fallbacks[1].starts_with("rustfmt failed"));
+    }
+
+    #[test]
+    fn test_config_passes() {
+        let config = Config {
+            strip_derived: true,
+            hide_prelude: true,
+            ..Config::default()
+        };
+        let passes = |args: &[&str]| {
+            let mut args = parse_args(args);
+            apply_config(&mut args, &config);
+            (args.strip_derived, args.hide_prelude, args.keep_macro_rules)
+        };
+        assert_eq!(passes(&[]), (true, true, false));
+        assert_eq!(passes(&["--keep-macro-rules"]), (true, true, true));
+        assert_eq!(passes(&["--no-strip-derived"]), (false, true, false));
+        // The last of a flag and its --no- form wins.
+        assert_eq!(
+            passes(&["--strip-derived", "--no-strip-derived"]),
+            (false, true, false),
+        );
+        assert_eq!(
+            passes(&["--no-strip-derived", "--strip-derived"]),
+            (true, true, false),
+        );
+    }
+
+    #[test]
+    fn test_config_features() {
+        let config = Config {
+            features: Some(vec!["a".to_owned(), "b".to_owned()]),
+            all_features: true,
+            no_default_features: true,
+            ..Config::default()
+        };
+        let features = |args: &[&str]| {
+            let mut args = parse_args(args);
+            apply_config(&mut args, &config);
+            (args.features, args.all_features, args.no_default_features)
+        };
+        assert_eq!(features(&[]), (Some("a,b".to_owned()), true, true));
+        assert_eq!(
+            features(&["--features", "c"]),
+            (Some("c".to_owned()), true, true),
+        );
+        assert_eq!(
+            features(&["--no-all-features"]),
+            (Some("a,b".to_owned()), false, true),
+        );
+        assert_eq!(
+            features(&["--default-features"]),
+            (Some("a,b".to_owned()), true, false),
+        );
+    }
+
+    #[test]
+    fn test_config_item() {
+        let config = Config {
+            item: Some("m::f".to_owned()),
+            ..Config::default()
+        };
+        let item = |args: &[&str]| {
+            let mut args = parse_args(args);
+            apply_config(&mut args, &config);
+            args.item.map(|item| item.to_string())
+        };
+        assert_eq!(item(&[]).as_deref(), Some("m::f"));
+        assert_eq!(item(&["g"]).as_deref(), Some("g"));
+        assert_eq!(item(&["--no-item"]), None);
+        assert_eq!(item(&["--workspace"]), None);
+        assert!(opts::Subcommand::try_parse_from(["cargo", "expand", "--no-item", "g"]).is_err());
+    }
+}
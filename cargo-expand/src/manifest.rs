@@ -1,5 +1,7 @@
 use crate::error::Result;
+use serde::de::IgnoredAny;
 use serde::Deserialize;
+use std::collections::BTreeMap;
 use std::env;
 use std::fs;
 use std::io::{self, ErrorKind};
@@ -8,12 +10,29 @@ use std::path::{Path, PathBuf};
 #[derive(Deserialize, Debug)]
 pub struct CargoManifest {
     pub package: Option<CargoPackage>,
+    #[serde(default)]
+    pub dependencies: BTreeMap<String, CargoDependency>,
+    #[serde(default, rename = "dev-dependencies")]
+    pub dev_dependencies: BTreeMap<String, CargoDependency>,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct CargoPackage {
     #[serde(rename = "default-run")]
     pub default_run: Option<String>,
+    pub metadata: Option<CargoMetadata>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CargoMetadata {
+    pub expand: Option<toml::Table>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum CargoDependency {
+    Detailed { path: Option<PathBuf> },
+    Version(IgnoredAny),
 }
 
 pub fn parse(manifest_path: Option<&Path>) -> Result<CargoManifest> {
@@ -23,7 +42,7 @@ pub fn parse(manifest_path: Option<&Path>) -> Result<CargoManifest> {
     Ok(cargo_manifest)
 }
 
-fn find_cargo_manifest(manifest_path: Option<&Path>) -> io::Result<PathBuf> {
+pub fn find_cargo_manifest(manifest_path: Option<&Path>) -> io::Result<PathBuf> {
     if let Some(manifest_path) = manifest_path {
         return Ok(manifest_path.to_owned());
     }
@@ -0,0 +1,1074 @@
+// Declarative macro expansion ("macro by example"), done in-process on token
+// streams so that no compiler is needed. This follows the macro_rules rules of
+// rustc closely enough for debugging, with two deliberate simplifications:
+// hygiene is not modeled, and the matcher backtracks into repetitions instead
+// of reporting an ambiguity error.
+
+use proc_macro2::{Delimiter, Group, Ident, Punct, Spacing, Span, TokenStream, TokenTree};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt::{self, Display};
+use std::rc::Rc;
+use syn::parse::{ParseStream, Parser};
+use syn::{Block, Expr, Item, Meta, Pat, Path, Stmt, Type, Visibility};
+
+pub struct MacroRules {
+    rules: Vec<Rule>,
+}
+
+struct Rule {
+    matcher: Vec<Matcher>,
+    transcriber: Vec<Transcriber>,
+}
+
+enum Matcher {
+    Token(TokenTree),
+    Group(Delimiter, Vec<Matcher>),
+    Fragment(String, Fragment),
+    Repeat(Repetition<Matcher>),
+}
+
+enum Transcriber {
+    Token(TokenTree),
+    Group(Delimiter, Span, Vec<Transcriber>),
+    Var(Punct, Ident),
+    Crate(Span),
+    Repeat(Repetition<Transcriber>),
+}
+
+struct Repetition<T> {
+    inner: Vec<T>,
+    separator: Option<TokenTree>,
+    op: RepeatOp,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum RepeatOp {
+    ZeroOrMore,
+    OneOrMore,
+    ZeroOrOne,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Fragment {
+    Block,
+    Expr,
+    Ident,
+    Item,
+    Lifetime,
+    Literal,
+    Meta,
+    Pat,
+    PatParam,
+    Path,
+    Stmt,
+    Tt,
+    Ty,
+    Vis,
+}
+
+#[derive(Clone)]
+enum Binding {
+    Leaf(Fragment, TokenStream),
+    Seq(Vec<Binding>),
+}
+
+type Bindings = HashMap<String, Binding>;
+
+pub struct Error(String);
+
+impl Display for Error {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str(&self.0)
+    }
+}
+
+fn error<T>(message: impl Display) -> Result<T, Error> {
+    Err(Error(message.to_string()))
+}
+
+impl MacroRules {
+    // Parses the body of `macro_rules! name { ... }`.
+    pub fn parse(body: TokenStream) -> Result<Self, Error> {
+        let tokens: Vec<TokenTree> = body.into_iter().collect();
+        let mut rules = Vec::new();
+        let mut i = 0;
+        while i < tokens.len() {
+            let matcher = match &tokens[i] {
+                TokenTree::Group(group) => parse_matcher(group.stream())?,
+                token => return error(format_args!("expected macro matcher, found `{}`", token)),
+            };
+            match (tokens.get(i + 1), tokens.get(i + 2)) {
+                (Some(TokenTree::Punct(eq)), Some(TokenTree::Punct(gt)))
+                    if eq.as_char() == '=' && gt.as_char() == '>' => {}
+                _ => return error("expected `=>` after macro matcher"),
+            }
+            let transcriber = match tokens.get(i + 3) {
+                Some(TokenTree::Group(group)) => parse_transcriber(group.stream())?,
+                _ => return error("expected macro transcriber after `=>`"),
+            };
+            rules.push(Rule {
+                matcher,
+                transcriber,
+            });
+            i += 4;
+            match tokens.get(i) {
+                Some(TokenTree::Punct(semi)) if semi.as_char() == ';' => i += 1,
+                None => {}
+                Some(token) => return error(format_args!("expected `;`, found `{}`", token)),
+            }
+        }
+        if rules.is_empty() {
+            return error("macro_rules! has no rules");
+        }
+        Ok(MacroRules { rules })
+    }
+
+    // Expands one invocation, given the tokens between its delimiters, using
+    // the first rule that matches.
+    pub fn expand(&self, input: TokenStream) -> Result<TokenStream, Error> {
+        let input: Vec<TokenTree> = input.into_iter().collect();
+        for rule in &self.rules {
+            if let Some(bound) = match_seq(&rule.matcher, &input, None) {
+                let bindings = collect_bindings(&bound);
+                let mut output = TokenStream::new();
+                transcribe(&rule.transcriber, &bindings, &mut Vec::new(), &mut output)?;
+                return Ok(output);
+            }
+        }
+        error("no rules expected this input")
+    }
+}
+
+fn parse_matcher(stream: TokenStream) -> Result<Vec<Matcher>, Error> {
+    let mut tokens = stream.into_iter().peekable();
+    let mut matchers = Vec::new();
+    while let Some(token) = tokens.next() {
+        let dollar = match token {
+            TokenTree::Punct(punct) if punct.as_char() == '$' => punct,
+            TokenTree::Group(group) => {
+                let inner = parse_matcher(group.stream())?;
+                matchers.push(Matcher::Group(group.delimiter(), inner));
+                continue;
+            }
+            token => {
+                matchers.push(Matcher::Token(token));
+                continue;
+            }
+        };
+        match tokens.next() {
+            Some(TokenTree::Ident(name)) => {
+                match tokens.next() {
+                    Some(TokenTree::Punct(colon)) if colon.as_char() == ':' => {}
+                    _ => return error(format_args!("missing fragment specifier for `${}`", name)),
+                }
+                let fragment = match tokens.next() {
+                    Some(TokenTree::Ident(kind)) => Fragment::from_name(&kind.to_string())?,
+                    _ => return error(format_args!("missing fragment specifier for `${}`", name)),
+                };
+                matchers.push(Matcher::Fragment(unraw(&name), fragment));
+            }
+            Some(TokenTree::Group(group)) if group.delimiter() == Delimiter::Parenthesis => {
+                let inner = parse_matcher(group.stream())?;
+                let (separator, op) = parse_repeat_op(&mut tokens)?;
+                matchers.push(Matcher::Repeat(Repetition {
+                    inner,
+                    separator,
+                    op,
+                }));
+            }
+            Some(token) => {
+                matchers.push(Matcher::Token(TokenTree::Punct(dollar)));
+                matchers.push(Matcher::Token(token));
+            }
+            None => matchers.push(Matcher::Token(TokenTree::Punct(dollar))),
+        }
+    }
+    Ok(matchers)
+}
+
+fn parse_transcriber(stream: TokenStream) -> Result<Vec<Transcriber>, Error> {
+    let mut tokens = stream.into_iter().peekable();
+    let mut transcribers = Vec::new();
+    while let Some(token) = tokens.next() {
+        let dollar = match token {
+            TokenTree::Punct(punct) if punct.as_char() == '$' => punct,
+            TokenTree::Group(group) => {
+                let inner = parse_transcriber(group.stream())?;
+                transcribers.push(Transcriber::Group(group.delimiter(), group.span(), inner));
+                continue;
+            }
+            token => {
+                transcribers.push(Transcriber::Token(token));
+                continue;
+            }
+        };
+        match tokens.peek() {
+            Some(TokenTree::Ident(name)) if name == "crate" => {
+                transcribers.push(Transcriber::Crate(name.span()));
+                tokens.next();
+            }
+            Some(TokenTree::Ident(name)) => {
+                transcribers.push(Transcriber::Var(dollar, name.clone()));
+                tokens.next();
+            }
+            Some(TokenTree::Group(group)) if group.delimiter() == Delimiter::Parenthesis => {
+                let inner = parse_transcriber(group.stream())?;
+                tokens.next();
+                let (separator, op) = parse_repeat_op(&mut tokens)?;
+                transcribers.push(Transcriber::Repeat(Repetition {
+                    inner,
+                    separator,
+                    op,
+                }));
+            }
+            _ => transcribers.push(Transcriber::Token(TokenTree::Punct(dollar))),
+        }
+    }
+    Ok(transcribers)
+}
+
+fn parse_repeat_op(
+    tokens: &mut impl Iterator<Item = TokenTree>,
+) -> Result<(Option<TokenTree>, RepeatOp), Error> {
+    let op = |token: &TokenTree| match token {
+        TokenTree::Punct(punct) => match punct.as_char() {
+            '*' => Some(RepeatOp::ZeroOrMore),
+            '+' => Some(RepeatOp::OneOrMore),
+            '?' => Some(RepeatOp::ZeroOrOne),
+            _ => None,
+        },
+        _ => None,
+    };
+    let first = match tokens.next() {
+        Some(token) => token,
+        None => return error("expected one of `*`, `+`, or `?`"),
+    };
+    if let Some(op) = op(&first) {
+        return Ok((None, op));
+    }
+    match tokens.next().as_ref().and_then(op) {
+        Some(RepeatOp::ZeroOrOne) => {
+            error("the `?` macro repetition operator does not take a separator")
+        }
+        Some(op) => Ok((Some(first), op)),
+        None => error("expected one of `*`, `+`, or `?`"),
+    }
+}
+
+impl Fragment {
+    fn from_name(name: &str) -> Result<Self, Error> {
+        Ok(match name {
+            "block" => Fragment::Block,
+            "expr" | "expr_2021" => Fragment::Expr,
+            "ident" => Fragment::Ident,
+            "item" => Fragment::Item,
+            "lifetime" => Fragment::Lifetime,
+            "literal" => Fragment::Literal,
+            "meta" => Fragment::Meta,
+            "pat" => Fragment::Pat,
+            "pat_param" => Fragment::PatParam,
+            "path" => Fragment::Path,
+            "stmt" => Fragment::Stmt,
+            "tt" => Fragment::Tt,
+            "ty" => Fragment::Ty,
+            "vis" => Fragment::Vis,
+            _ => return error(format_args!("invalid fragment specifier `{}`", name)),
+        })
+    }
+
+    // Number of tokens at the start of `input` that make up this fragment.
+    fn match_len(self, input: &[TokenTree]) -> Option<usize> {
+        match self {
+            Fragment::Tt => return input.first().map(|_| 1),
+            Fragment::Ident => {
+                return match input.first()? {
+                    TokenTree::Ident(ident) if ident != "_" => Some(1),
+                    TokenTree::Group(group) if group.delimiter() == Delimiter::None => {
+                        let mut inner = group.stream().into_iter();
+                        match (inner.next(), inner.next()) {
+                            (Some(TokenTree::Ident(_)), None) => Some(1),
+                            _ => None,
+                        }
+                    }
+                    _ => None,
+                };
+            }
+            Fragment::Lifetime => {
+                return match (input.first()?, input.get(1)?) {
+                    (TokenTree::Punct(quote), TokenTree::Ident(_))
+                        if quote.as_char() == '\'' && quote.spacing() == Spacing::Joint =>
+                    {
+                        Some(2)
+                    }
+                    _ => None,
+                };
+            }
+            Fragment::Literal => {
+                return match input.first()? {
+                    TokenTree::Literal(_) => Some(1),
+                    TokenTree::Ident(ident) if ident == "true" || ident == "false" => Some(1),
+                    TokenTree::Punct(minus) if minus.as_char() == '-' => match input.get(1)? {
+                        TokenTree::Literal(_) => Some(2),
+                        _ => None,
+                    },
+                    _ => None,
+                };
+            }
+            Fragment::Block => {
+                return match input.first()? {
+                    TokenTree::Group(group) if group.delimiter() == Delimiter::Brace => Some(1),
+                    _ => None,
+                };
+            }
+            Fragment::Stmt => {
+                // A statement fragment does not include its semicolon, but syn
+                // parses `let` only with one. So one is added, and taken off
+                // again along with whatever semicolon ends the statement.
+                let mut stream: TokenStream = input.iter().cloned().collect();
+                stream.extend([TokenTree::Punct(Punct::new(';', Spacing::Alone))]);
+                let parser = |input: ParseStream| {
+                    let stmt = input.parse::<Stmt>()?;
+                    let rest: TokenStream = input.parse()?;
+                    Ok((stmt, rest.into_iter().count()))
+                };
+                let (stmt, rest) = parser.parse2(stream).ok()?;
+                let semi = match stmt {
+                    Stmt::Local(_) => true,
+                    Stmt::Expr(_, semi) => semi.is_some(),
+                    Stmt::Macro(stmt) => stmt.semi_token.is_some(),
+                    Stmt::Item(_) => false,
+                };
+                let len = (input.len() + 1 - rest - usize::from(semi)).min(input.len());
+                return if len == 0 { None } else { Some(len) };
+            }
+            _ => {}
+        }
+
+        let stream: TokenStream = input.iter().cloned().collect();
+        let parser = |input: ParseStream| {
+            match self {
+                Fragment::Expr => drop(input.parse::<Expr>()?),
+                Fragment::Item => drop(input.parse::<Item>()?),
+                Fragment::Meta => drop(input.parse::<Meta>()?),
+                Fragment::Pat => drop(Pat::parse_multi_with_leading_vert(input)?),
+                Fragment::PatParam => drop(Pat::parse_single(input)?),
+                Fragment::Path => drop(input.parse::<Path>()?),
+                Fragment::Ty => drop(input.parse::<Type>()?),
+                Fragment::Vis => drop(input.parse::<Visibility>()?),
+                Fragment::Block
+                | Fragment::Ident
+                | Fragment::Lifetime
+                | Fragment::Literal
+                | Fragment::Stmt
+                | Fragment::Tt => unreachable!(),
+            }
+            let rest: TokenStream = input.parse()?;
+            Ok(rest.into_iter().count())
+        };
+        let rest = parser.parse2(stream).ok()?;
+        let len = input.len() - rest;
+        if len == 0 && self != Fragment::Vis {
+            return None;
+        }
+        Some(len)
+    }
+
+    // Fragments other than these are substituted as a single opaque token
+    // tree, the way rustc does, so that `$e * 2` keeps `$e` grouped.
+    fn is_transparent(self) -> bool {
+        match self {
+            Fragment::Ident
+            | Fragment::Lifetime
+            | Fragment::Literal
+            | Fragment::Tt
+            | Fragment::Vis => true,
+            _ => false,
+        }
+    }
+}
+
+// Matches `matchers` against all of `input`, adding to `bound`.
+//
+// This is a backtracking search, run as a loop over an explicit list of goals
+// rather than by recursion, so that the number of tokens a repetition matches
+// is not limited by the stack. Each choice between another iteration of a
+// repetition and going on after it saves the state to come back to. The goals
+// and bindings are lists shared between the states, so saving one is cheap.
+fn match_seq(matchers: &[Matcher], input: &[TokenTree], bound: Bound) -> Option<Bound> {
+    let mut matching = Matching {
+        input,
+        choices: Vec::new(),
+        failed: HashSet::new(),
+        repetitions: 0,
+    };
+    let mut state = State {
+        pos: 0,
+        goals: Goals::push(Goal::Seq(matchers), &None),
+        bound,
+    };
+    loop {
+        let next = match state.goals.take() {
+            None if state.pos == input.len() => return Some(state.bound),
+            None => None,
+            Some(node) => {
+                state.goals = node.next.clone();
+                matching.step(&node.goal, state)
+            }
+        };
+        state = match next {
+            Some(next) => next,
+            None => matching.backtrack()?,
+        };
+    }
+}
+
+struct Matching<'a, 'm> {
+    input: &'a [TokenTree],
+    // States to go back to, the latest last.
+    choices: Vec<Choice<'m>>,
+    // Whether matching succeeds never depends on the bindings so far, only on
+    // the position, so every position after the first iteration of a
+    // repetition from which nothing matched is recorded here, by repetition,
+    // and not tried again. Without this, nested repetitions like
+    // `$($($t:tt)*)*` would try every way of splitting the input between them,
+    // exponentially many, before failing.
+    failed: HashSet<(usize, usize)>,
+    // Numbers the repetitions as they are entered, for `failed`.
+    repetitions: usize,
+}
+
+enum Choice<'m> {
+    Resume(State<'m>),
+    // Everything after this was tried from the end of an iteration of the
+    // repetition at the position, and failed.
+    Failed(usize, usize),
+}
+
+struct State<'m> {
+    pos: usize,
+    goals: Option<Rc<Goals<'m>>>,
+    bound: Bound,
+}
+
+// What is left to match, the next goal first.
+struct Goals<'m> {
+    goal: Goal<'m>,
+    next: Option<Rc<Goals<'m>>>,
+}
+
+enum Goal<'m> {
+    Seq(&'m [Matcher]),
+    // The end of an iteration of a repetition, which started at `start`.
+    EndIteration {
+        repetition: &'m Repetition<Matcher>,
+        id: usize,
+        start: usize,
+        outer: Bound,
+        iterations: Iterations,
+    },
+}
+
+// Bindings as they are matched, the latest first.
+type Bound = Option<Rc<BoundNode>>;
+
+struct BoundNode {
+    binding: BoundItem,
+    prev: Bound,
+}
+
+enum BoundItem {
+    Leaf(String, Fragment, TokenStream),
+    // The iterations of a repetition whose fragments are `names`.
+    Seq(Rc<BTreeSet<String>>, Iterations),
+}
+
+// The bindings of each iteration of a repetition, the latest first.
+type Iterations = Option<Rc<IterationNode>>;
+
+struct IterationNode {
+    bound: Bound,
+    prev: Iterations,
+}
+
+impl<'m> Goals<'m> {
+    fn push(goal: Goal<'m>, next: &Option<Rc<Goals<'m>>>) -> Option<Rc<Goals<'m>>> {
+        Some(Rc::new(Goals {
+            goal,
+            next: next.clone(),
+        }))
+    }
+}
+
+impl<'a, 'm> Matching<'a, 'm> {
+    // Works on one goal, returning the state after it, or None if it does not
+    // match.
+    fn step(&mut self, goal: &Goal<'m>, mut state: State<'m>) -> Option<State<'m>> {
+        let (first, remaining) = match goal {
+            Goal::Seq(matchers) => match matchers.split_first() {
+                Some(split) => split,
+                None => return Some(state),
+            },
+            Goal::EndIteration {
+                repetition,
+                id,
+                start,
+                outer,
+                iterations,
+            } => {
+                // An iteration that consumes nothing would repeat forever.
+                if state.pos == *start {
+                    return None;
+                }
+                let iterations = Some(Rc::new(IterationNode {
+                    bound: state.bound.take(),
+                    prev: iterations.clone(),
+                }));
+                return self.repeat(repetition, *id, outer.clone(), iterations, state);
+            }
+        };
+        if !remaining.is_empty() {
+            state.goals = Goals::push(Goal::Seq(remaining), &state.goals);
+        }
+        let input = self.input;
+        match first {
+            Matcher::Token(expected) => {
+                if !token_eq(expected, input.get(state.pos)?) {
+                    return None;
+                }
+                state.pos += 1;
+            }
+            Matcher::Group(delimiter, inner) => {
+                let group = match input.get(state.pos)? {
+                    TokenTree::Group(group) if group.delimiter() == *delimiter => group,
+                    _ => return None,
+                };
+                // The first way the group matches is kept, without
+                // backtracking into it.
+                let group_input: Vec<TokenTree> = group.stream().into_iter().collect();
+                state.bound = match_seq(inner, &group_input, state.bound.take())?;
+                state.pos += 1;
+            }
+            Matcher::Fragment(name, fragment) => {
+                let len = fragment.match_len(&input[state.pos..])?;
+                let tokens = input[state.pos..state.pos + len].iter().cloned().collect();
+                let binding = BoundItem::Leaf(name.clone(), *fragment, tokens);
+                state.bound = bind(binding, state.bound.take());
+                state.pos += len;
+            }
+            Matcher::Repeat(repetition) => {
+                let id = self.repetitions;
+                self.repetitions += 1;
+                let outer = state.bound.take();
+                return self.repeat(repetition, id, outer, None, state);
+            }
+        }
+        Some(state)
+    }
+
+    // Goes on after `iterations` of `repetition`, preferring another iteration
+    // and saving the state after the repetition to come back to.
+    fn repeat(
+        &mut self,
+        repetition: &'m Repetition<Matcher>,
+        id: usize,
+        outer: Bound,
+        iterations: Iterations,
+        state: State<'m>,
+    ) -> Option<State<'m>> {
+        let first = iterations.is_none();
+        if !first {
+            if self.failed.contains(&(id, state.pos)) {
+                return None;
+            }
+            self.choices.push(Choice::Failed(id, state.pos));
+        }
+
+        let after = (repetition.op != RepeatOp::OneOrMore || !first).then(|| {
+            let mut names = BTreeSet::new();
+            matcher_names(&repetition.inner, &mut names);
+            let binding = BoundItem::Seq(Rc::new(names), iterations.clone());
+            State {
+                pos: state.pos,
+                goals: state.goals.clone(),
+                bound: bind(binding, outer.clone()),
+            }
+        });
+
+        let more_allowed = repetition.op != RepeatOp::ZeroOrOne || first;
+        let start = match (&repetition.separator, first) {
+            (Some(separator), false) => self
+                .input
+                .get(state.pos)
+                .filter(|token| token_eq(separator, token))
+                .map(|_| state.pos + 1),
+            _ => Some(state.pos),
+        };
+        let another = match start {
+            Some(start) if more_allowed => {
+                let end = Goal::EndIteration {
+                    repetition,
+                    id,
+                    start: state.pos,
+                    outer,
+                    iterations,
+                };
+                let goals = Goals::push(end, &state.goals);
+                Some(State {
+                    pos: start,
+                    goals: Goals::push(Goal::Seq(&repetition.inner), &goals),
+                    bound: None,
+                })
+            }
+            _ => None,
+        };
+
+        match (another, after) {
+            (Some(another), Some(after)) => {
+                self.choices.push(Choice::Resume(after));
+                Some(another)
+            }
+            (another, after) => another.or(after),
+        }
+    }
+
+    // The latest state saved, or None if there is none left.
+    fn backtrack(&mut self) -> Option<State<'m>> {
+        loop {
+            match self.choices.pop()? {
+                Choice::Resume(state) => return Some(state),
+                Choice::Failed(id, pos) => {
+                    self.failed.insert((id, pos));
+                }
+            }
+        }
+    }
+}
+
+fn bind(binding: BoundItem, prev: Bound) -> Bound {
+    Some(Rc::new(BoundNode { binding, prev }))
+}
+
+// The bindings of a rule that matched.
+fn collect_bindings(mut bound: &Bound) -> Bindings {
+    let mut bindings = Bindings::new();
+    while let Some(node) = bound {
+        match &node.binding {
+            BoundItem::Leaf(name, fragment, tokens) => {
+                bindings.insert(name.clone(), Binding::Leaf(*fragment, tokens.clone()));
+            }
+            BoundItem::Seq(names, iterations) => {
+                let mut seqs: Vec<Bindings> = Vec::new();
+                let mut iteration = iterations;
+                while let Some(node) = iteration {
+                    seqs.push(collect_bindings(&node.bound));
+                    iteration = &node.prev;
+                }
+                seqs.reverse();
+                for name in names.iter() {
+                    let seq = seqs.iter().map(|seq| seq[name].clone()).collect();
+                    bindings.insert(name.clone(), Binding::Seq(seq));
+                }
+            }
+        }
+        bound = &node.prev;
+    }
+    bindings
+}
+
+// A repetition can have as many iterations as the input has tokens, too many
+// to drop the list of them recursively.
+impl Drop for IterationNode {
+    fn drop(&mut self) {
+        let mut prev = self.prev.take();
+        while let Some(node) = prev {
+            prev = match Rc::try_unwrap(node) {
+                Ok(mut node) => node.prev.take(),
+                Err(_) => break,
+            };
+        }
+    }
+}
+
+fn matcher_names(matchers: &[Matcher], names: &mut BTreeSet<String>) {
+    for matcher in matchers {
+        match matcher {
+            Matcher::Token(_) => {}
+            Matcher::Group(_, inner) => matcher_names(inner, names),
+            Matcher::Fragment(name, _) => {
+                names.insert(name.clone());
+            }
+            Matcher::Repeat(repetition) => matcher_names(&repetition.inner, names),
+        }
+    }
+}
+
+fn token_eq(expected: &TokenTree, actual: &TokenTree) -> bool {
+    match (expected, actual) {
+        (TokenTree::Ident(expected), TokenTree::Ident(actual)) => expected == actual,
+        (TokenTree::Punct(expected), TokenTree::Punct(actual)) => {
+            expected.as_char() == actual.as_char()
+        }
+        (TokenTree::Literal(expected), TokenTree::Literal(actual)) => {
+            expected.to_string() == actual.to_string()
+        }
+        _ => false,
+    }
+}
+
+fn transcribe(
+    transcribers: &[Transcriber],
+    bindings: &Bindings,
+    indices: &mut Vec<usize>,
+    output: &mut TokenStream,
+) -> Result<(), Error> {
+    for transcriber in transcribers {
+        match transcriber {
+            Transcriber::Token(token) => output.extend([token.clone()]),
+            Transcriber::Group(delimiter, span, inner) => {
+                let mut stream = TokenStream::new();
+                transcribe(inner, bindings, indices, &mut stream)?;
+                let mut group = Group::new(*delimiter, stream);
+                group.set_span(*span);
+                output.extend([TokenTree::Group(group)]);
+            }
+            Transcriber::Var(dollar, name) => {
+                match lookup(bindings, &unraw(name), indices) {
+                    Some(Binding::Leaf(fragment, tokens)) => {
+                        if fragment.is_transparent() {
+                            output.extend(tokens.clone());
+                        } else {
+                            let group = Group::new(Delimiter::None, tokens.clone());
+                            output.extend([TokenTree::Group(group)]);
+                        }
+                    }
+                    Some(Binding::Seq(_)) => {
+                        return error(format_args!(
+                            "variable `{}` is still repeating at this depth",
+                            name,
+                        ));
+                    }
+                    // Not a metavariable of this macro, for example in the
+                    // body of a nested macro_rules. Leave it as it is.
+                    None => output.extend([
+                        TokenTree::Punct(dollar.clone()),
+                        TokenTree::Ident(name.clone()),
+                    ]),
+                }
+            }
+            // Within the same crate, `$crate` refers to `crate`.
+            Transcriber::Crate(span) => {
+                output.extend([TokenTree::Ident(Ident::new("crate", *span))])
+            }
+            Transcriber::Repeat(repetition) => {
+                let mut names = BTreeSet::new();
+                transcriber_names(&repetition.inner, &mut names);
+                let mut count = None;
+                for name in &names {
+                    if let Some(Binding::Seq(seq)) = lookup(bindings, name, indices) {
+                        match count {
+                            Some(count) if count != seq.len() => {
+                                return error(format_args!(
+                                    "meta-variable `{}` repeats {} times, but another repeats {} times",
+                                    name,
+                                    seq.len(),
+                                    count,
+                                ));
+                            }
+                            _ => count = Some(seq.len()),
+                        }
+                    }
+                }
+                let count = match count {
+                    Some(count) => count,
+                    None => {
+                        return error(
+                            "attempted to repeat an expression containing no syntax variables \
+                             matched as repeating at this depth",
+                        );
+                    }
+                };
+                for i in 0..count {
+                    if i > 0 {
+                        output.extend(repetition.separator.clone());
+                    }
+                    indices.push(i);
+                    let result = transcribe(&repetition.inner, bindings, indices, output);
+                    indices.pop();
+                    result?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Looks up a metavariable, descending into the current iteration of every
+// repetition that it is part of.
+fn lookup<'a>(bindings: &'a Bindings, name: &str, indices: &[usize]) -> Option<&'a Binding> {
+    let mut binding = bindings.get(name)?;
+    for &i in indices {
+        match binding {
+            Binding::Seq(seq) => binding = seq.get(i)?,
+            Binding::Leaf(..) => break,
+        }
+    }
+    Some(binding)
+}
+
+fn transcriber_names(transcribers: &[Transcriber], names: &mut BTreeSet<String>) {
+    for transcriber in transcribers {
+        match transcriber {
+            Transcriber::Token(_) | Transcriber::Crate(_) => {}
+            Transcriber::Group(_, _, inner) => transcriber_names(inner, names),
+            Transcriber::Var(_, name) => {
+                names.insert(unraw(name));
+            }
+            Transcriber::Repeat(repetition) => transcriber_names(&repetition.inner, names),
+        }
+    }
+}
+
+fn unraw(ident: &Ident) -> String {
+    let name = ident.to_string();
+    match name.strip_prefix("r#") {
+        Some(name) => name.to_owned(),
+        None => name,
+    }
+}
+
+// Statements are parsed the way they appear in a block, so that `let` and
+// items are accepted alongside expressions.
+pub fn parse_stmts(tokens: TokenStream) -> syn::Result<Vec<Stmt>> {
+    Block::parse_within.parse2(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    fn expand(rules: &str, input: &str) -> Result<String, String> {
+        let rules = MacroRules::parse(rules.parse().unwrap()).map_err(|err| err.to_string())?;
+        let output = rules
+            .expand(input.parse().unwrap())
+            .map_err(|err| err.to_string())?;
+        // Reparse so that the spacing of punctuation does not matter.
+        Ok(output
+            .to_string()
+            .parse::<TokenStream>()
+            .unwrap()
+            .to_string())
+    }
+
+    #[test]
+    fn test_fragment_specifiers() {
+        let cases = [
+            ("$x:block", "{ a; b }", "{ a ; b }"),
+            ("$x:expr", "a + b * 2", "a + b * 2"),
+            ("$x:ident", "foo", "foo"),
+            ("$x:item", "struct S;", "struct S ;"),
+            ("$x:lifetime", "'a", "'a"),
+            ("$x:literal", "-1", "- 1"),
+            ("$x:literal", "true", "true"),
+            ("$x:meta", "derive(Debug)", "derive (Debug)"),
+            ("$x:pat", "Some(_) | None", "Some (_) | None"),
+            ("$x:pat_param", "Some(_)", "Some (_)"),
+            ("$x:path", "std::mem::swap", "std :: mem :: swap"),
+            ("$x:stmt", "let x = 1", "let x = 1"),
+            ("$x:stmt", "struct S;", "struct S ;"),
+            ("$x:tt", "[a, b]", "[a , b]"),
+            ("$x:ty", "Vec<u8>", "Vec < u8 >"),
+            ("$x:vis", "pub(crate)", "pub (crate)"),
+        ];
+        for (matcher, input, expected) in cases {
+            let rules = format!("({}) => {{ $x }}", matcher);
+            assert_eq!(
+                expand(&rules, input).as_deref(),
+                Ok(expected),
+                "{}",
+                matcher
+            );
+        }
+    }
+
+    #[test]
+    fn test_fragment_mismatch() {
+        let cases = [
+            ("$x:ident", "_"),
+            ("$x:ident", "1"),
+            ("$x:lifetime", "a"),
+            ("$x:literal", "a"),
+            ("$x:block", "(a)"),
+            ("$x:expr", ""),
+            ("$x:ty", "1"),
+        ];
+        for (matcher, input) in cases {
+            let rules = format!("({}) => {{ $x }}", matcher);
+            assert_eq!(
+                expand(&rules, input),
+                Err("no rules expected this input".to_owned()),
+                "{} against {:?}",
+                matcher,
+                input,
+            );
+        }
+    }
+
+    #[test]
+    fn test_stmt_excludes_semicolon() {
+        let rules = "($($s:stmt);*) => { $($s;)* }";
+        assert_eq!(
+            expand(rules, "let x = 1; f(x)").as_deref(),
+            Ok("let x = 1 ; f (x) ;"),
+        );
+    }
+
+    #[test]
+    fn test_empty_vis() {
+        let rules = "($v:vis fn $name:ident) => { $v struct $name; }";
+        assert_eq!(expand(rules, "fn f").as_deref(), Ok("struct f ;"));
+        assert_eq!(expand(rules, "pub fn f").as_deref(), Ok("pub struct f ;"));
+    }
+
+    #[test]
+    fn test_invalid_fragment_specifier() {
+        let err = expand("($x:foo) => {}", "").unwrap_err();
+        assert_eq!(err, "invalid fragment specifier `foo`");
+        let err = expand("($x) => {}", "").unwrap_err();
+        assert_eq!(err, "missing fragment specifier for `$x`");
+    }
+
+    #[test]
+    fn test_opaque_fragments_keep_grouping() {
+        let rules = "($e:expr) => { $e * 2 }";
+        let rules = MacroRules::parse(rules.parse().unwrap()).ok().unwrap();
+        let output = rules.expand("1 + 1".parse().unwrap()).ok().unwrap();
+        let expr: Expr = syn::parse2(output).unwrap();
+        match expr {
+            Expr::Binary(binary) => assert!(matches!(*binary.left, Expr::Group(_))),
+            _ => panic!("expected a binary expression"),
+        }
+    }
+
+    #[test]
+    fn test_repetitions() {
+        let rules = "($($x:ident),*) => { [$($x)*] }";
+        assert_eq!(expand(rules, "").as_deref(), Ok("[]"));
+        assert_eq!(expand(rules, "a").as_deref(), Ok("[a]"));
+        assert_eq!(expand(rules, "a, b, c").as_deref(), Ok("[a b c]"));
+        assert!(expand(rules, "a, b,").is_err());
+        assert!(expand(rules, "a b").is_err());
+
+        let rules = "($($x:ident);+) => { $($x),+ }";
+        assert!(expand(rules, "").is_err());
+        assert_eq!(expand(rules, "a").as_deref(), Ok("a"));
+        assert_eq!(expand(rules, "a; b").as_deref(), Ok("a , b"));
+
+        let rules = "($($x:ident)? end) => { [$($x)?] }";
+        assert_eq!(expand(rules, "end").as_deref(), Ok("[]"));
+        assert_eq!(expand(rules, "a end").as_deref(), Ok("[a]"));
+        assert!(expand(rules, "a b end").is_err());
+
+        let err = expand("($($x:ident),?) => {}", "").unwrap_err();
+        assert_eq!(
+            err,
+            "the `?` macro repetition operator does not take a separator",
+        );
+    }
+
+    #[test]
+    fn test_trailing_separator() {
+        let rules = "($($x:expr),* $(,)?) => { $($x);* }";
+        assert_eq!(expand(rules, "1, 2").as_deref(), Ok("1 ; 2"));
+        assert_eq!(expand(rules, "1, 2,").as_deref(), Ok("1 ; 2"));
+    }
+
+    #[test]
+    fn test_backtracking_into_repetition() {
+        let rules = "($($x:tt)* ; $last:tt) => { [$($x)*] $last }";
+        assert_eq!(expand(rules, "a b ; c").as_deref(), Ok("[a b] c"));
+    }
+
+    #[test]
+    fn test_nested_repetitions() {
+        let rules = "($($name:ident: [$($v:literal),*]);*) => { $($(($name, $v))*)* }";
+        assert_eq!(
+            expand(rules, "a: [1, 2]; b: []; c: [3]").as_deref(),
+            Ok("(a , 1) (a , 2) (c , 3)"),
+        );
+
+        let rules = "($($($x:ident)+),+) => { $([$($x)+])+ }";
+        assert_eq!(
+            expand(rules, "a b, c, d e f").as_deref(),
+            Ok("[a b] [c] [d e f]"),
+        );
+    }
+
+    #[test]
+    fn test_repetition_errors() {
+        let err = expand("($($x:ident)*) => { $x }", "a b").unwrap_err();
+        assert_eq!(err, "variable `x` is still repeating at this depth");
+
+        let rules = "($($x:ident)* ; $($y:ident)*) => { $(($x $y))* }";
+        let err = expand(rules, "a b ; c").unwrap_err();
+        assert_eq!(
+            err,
+            "meta-variable `y` repeats 1 times, but another repeats 2 times",
+        );
+
+        let err = expand("() => { $(a)* }", "").unwrap_err();
+        assert!(err.starts_with("attempted to repeat an expression containing no syntax variables"));
+    }
+
+    #[test]
+    fn test_first_matching_rule() {
+        let rules = "
+            (one) => { 1 };
+            ($x:ident) => { 2 };
+            ($($x:tt)*) => { 3 }
+        ";
+        assert_eq!(expand(rules, "one").as_deref(), Ok("1"));
+        assert_eq!(expand(rules, "two").as_deref(), Ok("2"));
+        assert_eq!(expand(rules, "three four").as_deref(), Ok("3"));
+    }
+
+    #[test]
+    fn test_crate_and_foreign_metavariables() {
+        let rules = "($x:ident) => { $crate::f($x); macro_rules! m { ($y:tt) => { $y } } }";
+        assert_eq!(
+            expand(rules, "a").as_deref(),
+            Ok("crate :: f (a) ; macro_rules ! m { ($ y : tt) => { $ y } }"),
+        );
+    }
+
+    #[test]
+    fn test_pathological_nesting_terminates() {
+        // Every way of splitting the input among the nested repetitions is a
+        // match for the repetitions, and none of them lets `!` match.
+        let rules = "($($($x:tt)*)* !) => {}";
+        let input = vec!["a"; 64].join(" ");
+        let start = Instant::now();
+        assert!(expand(rules, &input).is_err());
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        let rules = "($($($($x:ident)+)+)+ ; $($y:ident)+ !) => { $($y)+ }";
+        let input = vec!["a"; 48].join(" ") + " ; b c";
+        let start = Instant::now();
+        assert!(expand(rules, &input).is_err());
+        assert!(expand(rules, &(input + " !")).is_ok());
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_long_repetition() {
+        // Each iteration used to take a stack frame or more.
+        let rules = "($($t:tt)*) => { $($t)* }";
+        let input = vec!["a"; 6000].join(" ");
+        assert_eq!(expand(rules, &input).unwrap(), input);
+
+        let rules = "($($t:tt),* !) => { 0 }";
+        let input = vec!["a"; 6000].join(", ");
+        assert!(expand(rules, &input).is_err());
+        assert_eq!(expand(rules, &(input + " !")).unwrap(), "0");
+    }
+}
@@ -0,0 +1,130 @@
+use crate::cmd::Line;
+use crate::error::Result;
+use crate::{cargo_binary, Expand};
+use serde::Deserialize;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+// The subset of `cargo metadata --format-version 1` used by cargo-expand.
+#[derive(Deserialize)]
+pub struct Metadata {
+    pub packages: Vec<Package>,
+    pub workspace_members: Vec<String>,
+    pub target_directory: PathBuf,
+    pub resolve: Option<Resolve>,
+}
+
+#[derive(Deserialize)]
+pub struct Package {
+    pub id: String,
+    pub name: String,
+    pub version: String,
+    pub source: Option<String>,
+    pub edition: String,
+    pub manifest_path: PathBuf,
+    pub targets: Vec<Target>,
+}
+
+#[derive(Deserialize)]
+pub struct Target {
+    pub name: String,
+    pub kind: Vec<String>,
+    pub src_path: PathBuf,
+}
+
+#[derive(Deserialize)]
+pub struct Resolve {
+    pub nodes: Vec<Node>,
+}
+
+#[derive(Deserialize)]
+pub struct Node {
+    pub id: String,
+    pub deps: Vec<NodeDep>,
+    pub features: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct NodeDep {
+    /// Name by which the dependency is referred to in code.
+    pub name: String,
+    pub pkg: String,
+    pub dep_kinds: Vec<DepKind>,
+}
+
+#[derive(Deserialize)]
+pub struct DepKind {
+    /// "dev" or "build", or null for normal dependencies.
+    pub kind: Option<String>,
+}
+
+impl Metadata {
+    pub fn package(&self, id: &str) -> Option<&Package> {
+        self.packages.iter().find(|package| package.id == id)
+    }
+
+    pub fn node(&self, id: &str) -> Option<&Node> {
+        let resolve = self.resolve.as_ref()?;
+        resolve.nodes.iter().find(|node| node.id == id)
+    }
+}
+
+impl Package {
+    pub fn is_proc_macro(&self) -> bool {
+        self.targets
+            .iter()
+            .any(|target| target.kind.iter().any(|kind| kind == "proc-macro"))
+    }
+}
+
+// Returns None if cargo failed, in which case it has already printed why.
+// Without `no_deps`, the dependency graph is resolved for the features
+// selected on the command line.
+pub fn cargo_metadata(args: &Expand, no_deps: bool) -> Result<Option<Metadata>> {
+    let mut line = Line::new(cargo_binary());
+    line.arg("metadata");
+    line.arg("--format-version=1");
+    if no_deps {
+        line.arg("--no-deps");
+    } else {
+        if let Some(features) = &args.features {
+            line.arg("--features");
+            line.arg(features);
+        }
+        if args.all_features {
+            line.arg("--all-features");
+        }
+        if args.no_default_features {
+            line.arg("--no-default-features");
+        }
+        if let Some(target) = &args.target {
+            line.arg("--filter-platform");
+            line.arg(target);
+        }
+    }
+    if let Some(manifest_path) = &args.manifest_path {
+        line.arg("--manifest-path");
+        line.arg(manifest_path);
+    }
+    for kv in &args.config {
+        line.arg("--config");
+        line.arg(kv);
+    }
+    if args.frozen {
+        line.arg("--frozen");
+    }
+    if args.locked {
+        line.arg("--locked");
+    }
+    if args.offline {
+        line.arg("--offline");
+    }
+
+    let mut cmd = Command::new(cargo_binary());
+    cmd.args(line).stderr(Stdio::inherit());
+    let output = cmd.output()?;
+    if !output.status.success() {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_slice(&output.stdout)?))
+}
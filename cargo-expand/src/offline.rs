@@ -0,0 +1,831 @@
+use crate::config::Config;
+use crate::error::Result;
+use crate::mbe::{self, MacroRules};
+use crate::opts::{Coloring, Location};
+use crate::unparse::unparse_maximal;
+use crate::{ignore_panic, manifest, metadata, print_expanded, theme, workspace, Expand};
+use proc_macro2::TokenStream;
+use quote::ToTokens;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use syn::parse::Parser;
+use syn::punctuated::Punctuated;
+use syn::visit::{self, Visit};
+use syn::visit_mut::{self, VisitMut};
+use syn::{
+    Block, Expr, ExprMacro, File, ImplItem, Item, ItemImpl, ItemMod, Macro, Pat, Stmt, Token, Type,
+};
+
+// Same as rustc's default #![recursion_limit].
+const STEP_LIMIT: usize = 128;
+
+type Definitions = HashMap<String, MacroRules>;
+
+// A macro invocation to expand, and the syntactic position it appears in,
+// which determines how its expansion is parsed.
+struct Site {
+    mac: Macro,
+    context: Context,
+    line: usize,
+}
+
+#[derive(Copy, Clone)]
+enum Context {
+    Items,
+    ImplItems,
+    Stmts,
+    Expr,
+    Type,
+    Pat,
+}
+
+// The result of expanding an invocation, parsed according to its context.
+enum Expansion {
+    Items(Vec<Item>),
+    ImplItems(Vec<ImplItem>),
+    Stmts(Vec<Stmt>),
+    Expr(Expr),
+    Type(Type),
+    Pat(Pat),
+}
+
+pub fn cargo_expand_macro_rules(args: &Expand, config: &Config, color: &Coloring) -> Result<i32> {
+    proc_macro2::fallback::force();
+
+    let manifest_path = manifest::find_cargo_manifest(args.manifest_path.as_deref())?;
+    let manifest_dir = manifest_path.parent().unwrap();
+    let crate_root = match crate_root(args)? {
+        Ok(Some(crate_root)) => crate_root,
+        Ok(None) => {
+            let _ = writeln!(
+                io::stderr(),
+                "ERROR: no target to expand in {}",
+                manifest_dir.display(),
+            );
+            return Ok(1);
+        }
+        Err(code) => return Ok(code),
+    };
+    let crate_file = load_module(&crate_root)?;
+
+    let mut definitions = Definitions::new();
+    collect_definitions(&crate_file, &mut definitions);
+
+    let (sites, description) = if let Some(location) = &args.at {
+        let sites = match sites_at(manifest_dir, location, &mut definitions)? {
+            Some(sites) => sites,
+            None => return Ok(1),
+        };
+        (
+            sites,
+            format!("{}:{}", location.file.display(), location.line),
+        )
+    } else if let Some(filter) = &args.item {
+        let items = filter.apply_to(&crate_file);
+        if items.is_empty() {
+            let _ = writeln!(io::stderr(), "WARNING: no such item: {}", filter);
+            return Ok(1);
+        }
+        (find_sites(&items, &definitions), filter.to_string())
+    } else {
+        let _ = writeln!(
+            io::stderr(),
+            "ERROR: --macro-rules requires an invocation site, use --at FILE:LINE or pass an ITEM.",
+        );
+        return Ok(1);
+    };
+
+    if sites.is_empty() {
+        let _ = writeln!(
+            io::stderr(),
+            "ERROR: no invocation of a macro_rules! macro from this crate in {}",
+            description,
+        );
+        return Ok(1);
+    }
+
+    let mut content = String::new();
+    let mut code = 0;
+    for site in sites {
+        let name = macro_name(&site.mac).unwrap();
+        if !content.is_empty() {
+            content.push('\n');
+        }
+        let _ = writeln!(content, "// {}! at line {}", name, site.line);
+        if let Err(message) = expand_steps(&site, &definitions, &mut content) {
+            let _ = writeln!(io::stderr(), "ERROR: {}", message);
+            code = 1;
+            break;
+        }
+    }
+
+    print_expanded(&content, theme(args, config), config.pager, color);
+    Ok(code)
+}
+
+// The invocation at `--at FILE:LINE`, if there is one. FILE is relative to the
+// current directory or else to the manifest's directory. Returns None if the
+// file does not parse.
+fn sites_at(
+    manifest_dir: &Path,
+    location: &Location,
+    definitions: &mut Definitions,
+) -> Result<Option<Vec<Site>>> {
+    let path = if location.file.exists() {
+        location.file.clone()
+    } else {
+        manifest_dir.join(&location.file)
+    };
+    let file = match parse(&path)? {
+        Some(file) => file,
+        None => return Ok(None),
+    };
+    // Definitions in the invocation's own file take precedence.
+    collect_definitions(&file, definitions);
+    let mut sites = find_sites(&file.items, definitions);
+    sites.retain(|site| site.line == location.line);
+    sites.truncate(1);
+    Ok(Some(sites))
+}
+
+// Expands the invocation, then whatever invocations of local macros its
+// expansion contains, one at a time, appending each intermediate result.
+fn expand_steps(
+    site: &Site,
+    definitions: &Definitions,
+    content: &mut String,
+) -> std::result::Result<(), String> {
+    let name = macro_name(&site.mac).unwrap();
+    let tokens = expand_macro(&site.mac, definitions)?;
+    let mut expansion = match Expansion::parse(site.context, tokens.clone()) {
+        Ok(expansion) => expansion,
+        Err(err) => {
+            return Err(format!(
+                "expansion of {}! is not valid in this position: {}\n{}",
+                name, err, tokens,
+            ));
+        }
+    };
+    let _ = write!(content, "\n// step 1: {}!\n{}", name, expansion.format());
+
+    for step in 2..=STEP_LIMIT + 1 {
+        if step > STEP_LIMIT {
+            return Err(format!(
+                "recursion limit reached while expanding {}!, stopped after {} steps",
+                name, STEP_LIMIT,
+            ));
+        }
+        let mut expand_first = ExpandFirst {
+            definitions,
+            expanded: None,
+        };
+        expansion.visit_mut(&mut expand_first);
+        match expand_first.expanded {
+            None => break,
+            Some(Ok(name)) => {
+                let _ = write!(
+                    content,
+                    "\n// step {}: {}!\n{}",
+                    step,
+                    name,
+                    expansion.format()
+                );
+            }
+            Some(Err(message)) => return Err(message),
+        }
+    }
+    Ok(())
+}
+
+fn expand_macro(
+    mac: &Macro,
+    definitions: &Definitions,
+) -> std::result::Result<TokenStream, String> {
+    let name = macro_name(mac).unwrap();
+    definitions[&name]
+        .expand(mac.tokens.clone())
+        .map_err(|err| format!("failed to expand {}!({}): {}", name, mac.tokens, err))
+}
+
+// The name of the macro_rules! macro invoked by `mac`, for paths that can
+// refer to one defined in this crate.
+fn macro_name(mac: &Macro) -> Option<String> {
+    let segments = &mac.path.segments;
+    let last = segments.last()?;
+    let local = match segments.len() {
+        1 => true,
+        2 => segments[0].ident == "crate" || segments[0].ident == "self",
+        _ => false,
+    };
+    if local && last.ident != "macro_rules" {
+        Some(last.ident.to_string())
+    } else {
+        None
+    }
+}
+
+fn is_local(mac: &Macro, definitions: &Definitions) -> bool {
+    macro_name(mac).is_some_and(|name| definitions.contains_key(&name))
+}
+
+// The root file of the selected target, as cargo metadata has it, so that a
+// `path` set in the manifest is followed. Err if cargo or the package
+// selection failed, which has already been reported.
+fn crate_root(args: &Expand) -> Result<std::result::Result<Option<PathBuf>, i32>> {
+    let metadata = match metadata::cargo_metadata(args, true)? {
+        Some(metadata) => metadata,
+        None => return Ok(Err(1)),
+    };
+    let packages = match workspace::select_packages(args, &metadata)? {
+        Ok(packages) => packages,
+        Err(code) => return Ok(Err(code)),
+    };
+    let [package] = packages[..] else {
+        return Ok(Ok(None));
+    };
+    let units = workspace::select_targets(args, package);
+    Ok(Ok(units.first().map(|unit| unit.src_path().to_owned())))
+}
+
+fn parse(path: &Path) -> Result<Option<File>> {
+    let content = fs::read_to_string(path)?;
+    match syn::parse_file(&content) {
+        Ok(file) => Ok(Some(file)),
+        Err(err) => {
+            let _ = writeln!(
+                io::stderr(),
+                "ERROR: failed to parse {}: {}",
+                path.display(),
+                err,
+            );
+            Ok(None)
+        }
+    }
+}
+
+// Parses a crate root or module file, inlining the files of its out-of-line
+// modules so that the whole module tree can be searched and selected from.
+fn load_module(path: &Path) -> Result<File> {
+    let mut file = parse(path)?.unwrap_or_else(|| File {
+        shebang: None,
+        attrs: Vec::new(),
+        items: Vec::new(),
+    });
+    let is_mod_rs = match path.file_name().and_then(|name| name.to_str()) {
+        Some("lib.rs" | "main.rs" | "mod.rs") => true,
+        _ => false,
+    };
+    let dir = if is_mod_rs {
+        path.parent().unwrap().to_owned()
+    } else {
+        path.with_extension("")
+    };
+    inline_modules(&mut file.items, &dir)?;
+    Ok(file)
+}
+
+fn inline_modules(items: &mut [Item], dir: &Path) -> Result<()> {
+    for item in items {
+        if let Item::Mod(ItemMod {
+            ident,
+            content,
+            semi,
+            ..
+        }) = item
+        {
+            match content {
+                Some((_brace, items)) => inline_modules(items, &dir.join(ident.to_string()))?,
+                None => {
+                    let name = ident.to_string();
+                    let candidates = [
+                        dir.join(format!("{}.rs", name)),
+                        dir.join(&name).join("mod.rs"),
+                    ];
+                    if let Some(path) = candidates.iter().find(|path| path.exists()) {
+                        let file = load_module(path)?;
+                        *content = Some((Default::default(), file.items));
+                        *semi = None;
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn collect_definitions(file: &File, definitions: &mut Definitions) {
+    struct CollectDefinitions<'a>(&'a mut Definitions);
+
+    impl<'ast, 'a> Visit<'ast> for CollectDefinitions<'a> {
+        fn visit_item_macro(&mut self, i: &'ast syn::ItemMacro) {
+            if i.mac.path.is_ident("macro_rules") {
+                if let Some(ident) = &i.ident {
+                    match MacroRules::parse(i.mac.tokens.clone()) {
+                        Ok(rules) => {
+                            self.0.insert(ident.to_string(), rules);
+                        }
+                        Err(err) => {
+                            let _ = writeln!(
+                                io::stderr(),
+                                "WARNING: ignoring macro_rules! {}: {}",
+                                ident,
+                                err,
+                            );
+                        }
+                    }
+                }
+            }
+            visit::visit_item_macro(self, i);
+        }
+    }
+
+    CollectDefinitions(definitions).visit_file(file);
+}
+
+// Finds the outermost invocations of local macros, in source order.
+fn find_sites(items: &[Item], definitions: &Definitions) -> Vec<Site> {
+    struct FindSites<'a> {
+        definitions: &'a Definitions,
+        sites: Vec<Site>,
+    }
+
+    impl<'a> FindSites<'a> {
+        // Returns whether `mac` is a site, in which case its tokens must not
+        // be searched any further.
+        fn site(&mut self, mac: &Macro, context: Context) -> bool {
+            if !is_local(mac, self.definitions) {
+                return false;
+            }
+            self.sites.push(Site {
+                mac: mac.clone(),
+                context,
+                line: mac.bang_token.span.start().line,
+            });
+            true
+        }
+    }
+
+    impl<'ast, 'a> Visit<'ast> for FindSites<'a> {
+        fn visit_item(&mut self, i: &'ast Item) {
+            if let Item::Macro(item) = i {
+                if item.ident.is_none() && self.site(&item.mac, Context::Items) {
+                    return;
+                }
+            }
+            visit::visit_item(self, i);
+        }
+
+        fn visit_impl_item(&mut self, i: &'ast ImplItem) {
+            if let ImplItem::Macro(item) = i {
+                if self.site(&item.mac, Context::ImplItems) {
+                    return;
+                }
+            }
+            visit::visit_impl_item(self, i);
+        }
+
+        fn visit_stmt(&mut self, i: &'ast Stmt) {
+            if let Stmt::Macro(stmt) = i {
+                if self.site(&stmt.mac, Context::Stmts) {
+                    return;
+                }
+            }
+            visit::visit_stmt(self, i);
+        }
+
+        fn visit_expr(&mut self, i: &'ast Expr) {
+            if let Expr::Macro(expr) = i {
+                if self.site(&expr.mac, Context::Expr) {
+                    return;
+                }
+            }
+            visit::visit_expr(self, i);
+        }
+
+        fn visit_type(&mut self, i: &'ast Type) {
+            if let Type::Macro(ty) = i {
+                if self.site(&ty.mac, Context::Type) {
+                    return;
+                }
+            }
+            visit::visit_type(self, i);
+        }
+
+        fn visit_pat(&mut self, i: &'ast Pat) {
+            if let Pat::Macro(pat) = i {
+                if self.site(&pat.mac, Context::Pat) {
+                    return;
+                }
+            }
+            visit::visit_pat(self, i);
+        }
+
+        // Arguments of other macros, like println! or vec!, commonly contain
+        // invocations of local macros.
+        fn visit_macro(&mut self, i: &'ast Macro) {
+            if let Ok(args) = i.parse_body_with(Punctuated::<Expr, Token![,]>::parse_terminated) {
+                for arg in &args {
+                    self.visit_expr(arg);
+                }
+            }
+        }
+    }
+
+    let mut find_sites = FindSites {
+        definitions,
+        sites: Vec::new(),
+    };
+    for item in items {
+        find_sites.visit_item(item);
+    }
+    find_sites.sites
+}
+
+// Expands the first invocation of a local macro in leftmost-outermost order,
+// which is the order rustc expands them in.
+struct ExpandFirst<'a> {
+    definitions: &'a Definitions,
+    expanded: Option<std::result::Result<String, String>>,
+}
+
+impl<'a> ExpandFirst<'a> {
+    fn expand(&mut self, mac: &Macro, context: Context) -> Option<Expansion> {
+        if self.expanded.is_some() || !is_local(mac, self.definitions) {
+            return None;
+        }
+        let name = macro_name(mac).unwrap();
+        let tokens = match expand_macro(mac, self.definitions) {
+            Ok(tokens) => tokens,
+            Err(message) => {
+                self.expanded = Some(Err(message));
+                return None;
+            }
+        };
+        self.expanded = Some(Ok(name));
+        // Keep going even if the expansion does not parse in this position,
+        // so that the step can still be shown.
+        Some(
+            Expansion::parse(context, tokens.clone()).unwrap_or_else(|_| match context {
+                Context::Items => Expansion::Items(vec![Item::Verbatim(tokens)]),
+                Context::ImplItems => Expansion::ImplItems(vec![ImplItem::Verbatim(tokens)]),
+                Context::Stmts | Context::Expr => Expansion::Expr(Expr::Verbatim(tokens)),
+                Context::Type => Expansion::Type(Type::Verbatim(tokens)),
+                Context::Pat => Expansion::Pat(Pat::Verbatim(tokens)),
+            }),
+        )
+    }
+
+    fn expand_items(&mut self, items: &mut Vec<Item>) {
+        for i in 0..items.len() {
+            if self.expanded.is_some() {
+                return;
+            }
+            if let Item::Macro(item) = &items[i] {
+                if item.ident.is_none() {
+                    if let Some(Expansion::Items(expansion)) =
+                        self.expand(&item.mac, Context::Items)
+                    {
+                        items.splice(i..=i, expansion);
+                        return;
+                    }
+                }
+            }
+            self.visit_item_mut(&mut items[i]);
+        }
+    }
+
+    fn expand_impl_items(&mut self, items: &mut Vec<ImplItem>) {
+        for i in 0..items.len() {
+            if self.expanded.is_some() {
+                return;
+            }
+            if let ImplItem::Macro(item) = &items[i] {
+                if let Some(Expansion::ImplItems(expansion)) =
+                    self.expand(&item.mac, Context::ImplItems)
+                {
+                    items.splice(i..=i, expansion);
+                    return;
+                }
+            }
+            self.visit_impl_item_mut(&mut items[i]);
+        }
+    }
+
+    fn expand_stmts(&mut self, stmts: &mut Vec<Stmt>) {
+        for i in 0..stmts.len() {
+            if self.expanded.is_some() {
+                return;
+            }
+            if let Stmt::Macro(stmt) = &stmts[i] {
+                let semi = stmt.semi_token;
+                match self.expand(&stmt.mac, Context::Stmts) {
+                    Some(Expansion::Stmts(mut expansion)) => {
+                        if let Some(Stmt::Expr(_, last_semi @ None)) = expansion.last_mut() {
+                            *last_semi = semi;
+                        }
+                        stmts.splice(i..=i, expansion);
+                        return;
+                    }
+                    Some(Expansion::Expr(expr)) => {
+                        stmts[i] = Stmt::Expr(expr, semi);
+                        return;
+                    }
+                    _ => {}
+                }
+            }
+            self.visit_stmt_mut(&mut stmts[i]);
+        }
+    }
+}
+
+impl<'a> VisitMut for ExpandFirst<'a> {
+    fn visit_item_mod_mut(&mut self, i: &mut ItemMod) {
+        if let Some((_brace, items)) = &mut i.content {
+            self.expand_items(items);
+        }
+    }
+
+    fn visit_item_impl_mut(&mut self, i: &mut ItemImpl) {
+        self.expand_impl_items(&mut i.items);
+    }
+
+    fn visit_block_mut(&mut self, i: &mut Block) {
+        self.expand_stmts(&mut i.stmts);
+    }
+
+    fn visit_expr_mut(&mut self, i: &mut Expr) {
+        if let Expr::Macro(ExprMacro { mac, .. }) = i {
+            if let Some(Expansion::Expr(expr)) = self.expand(mac, Context::Expr) {
+                *i = parenthesize(expr);
+                return;
+            }
+        }
+        if self.expanded.is_none() {
+            visit_mut::visit_expr_mut(self, i);
+        }
+    }
+
+    fn visit_type_mut(&mut self, i: &mut Type) {
+        if let Type::Macro(ty) = i {
+            if let Some(Expansion::Type(ty)) = self.expand(&ty.mac, Context::Type) {
+                *i = ty;
+                return;
+            }
+        }
+        if self.expanded.is_none() {
+            visit_mut::visit_type_mut(self, i);
+        }
+    }
+
+    fn visit_pat_mut(&mut self, i: &mut Pat) {
+        if let Pat::Macro(pat) = i {
+            if let Some(Expansion::Pat(pat)) = self.expand(&pat.mac, Context::Pat) {
+                *i = pat;
+                return;
+            }
+        }
+        if self.expanded.is_none() {
+            visit_mut::visit_pat_mut(self, i);
+        }
+    }
+
+    fn visit_macro_mut(&mut self, i: &mut Macro) {
+        if self.expanded.is_some() {
+            return;
+        }
+        if let Ok(mut args) = i.parse_body_with(Punctuated::<Expr, Token![,]>::parse_terminated) {
+            for arg in &mut args {
+                self.visit_expr_mut(arg);
+            }
+            if let Some(Ok(_)) = self.expanded {
+                i.tokens = args.into_token_stream();
+            }
+        }
+    }
+}
+
+// Preserves the grouping of an expression that replaces a macro invocation,
+// which rustc maintains implicitly.
+fn parenthesize(expr: Expr) -> Expr {
+    match expr {
+        Expr::Assign(_)
+        | Expr::Binary(_)
+        | Expr::Cast(_)
+        | Expr::Closure(_)
+        | Expr::Let(_)
+        | Expr::Range(_)
+        | Expr::Unary(_) => Expr::Paren(syn::ExprParen {
+            attrs: Vec::new(),
+            paren_token: Default::default(),
+            expr: Box::new(expr),
+        }),
+        expr => expr,
+    }
+}
+
+impl Expansion {
+    fn parse(context: Context, tokens: TokenStream) -> syn::Result<Self> {
+        Ok(match context {
+            Context::Items => Expansion::Items(syn::parse2::<File>(tokens)?.items),
+            Context::ImplItems => {
+                let tokens = quote::quote!(impl Expansion { #tokens });
+                Expansion::ImplItems(syn::parse2::<ItemImpl>(tokens)?.items)
+            }
+            Context::Stmts => Expansion::Stmts(mbe::parse_stmts(tokens)?),
+            Context::Expr => Expansion::Expr(syn::parse2(tokens)?),
+            Context::Type => Expansion::Type(syn::parse2(tokens)?),
+            Context::Pat => Expansion::Pat(Pat::parse_multi_with_leading_vert.parse2(tokens)?),
+        })
+    }
+
+    fn visit_mut(&mut self, visitor: &mut ExpandFirst) {
+        match self {
+            Expansion::Items(items) => visitor.expand_items(items),
+            Expansion::ImplItems(items) => visitor.expand_impl_items(items),
+            Expansion::Stmts(stmts) => visitor.expand_stmts(stmts),
+            Expansion::Expr(expr) => visitor.visit_expr_mut(expr),
+            Expansion::Type(ty) => visitor.visit_type_mut(ty),
+            Expansion::Pat(pat) => visitor.visit_pat_mut(pat),
+        }
+    }
+
+    // Formats the expansion by wrapping it in whatever item makes it valid at
+    // the top level of a file, then unwrapping it from the formatted code.
+    fn format(&self) -> String {
+        let (file, unwrap): (File, fn(&str) -> String) = match self {
+            Expansion::Items(items) => (file(items.clone()), str::to_owned),
+            Expansion::ImplItems(items) => (
+                syn::parse_quote!(impl Expansion { #(#items)* }),
+                unwrap_body,
+            ),
+            Expansion::Stmts(stmts) => (
+                syn::parse_quote!(fn expansion() { #(#stmts)* }),
+                unwrap_body,
+            ),
+            Expansion::Expr(expr) => (syn::parse_quote!(fn expansion() { #expr }), unwrap_body),
+            Expansion::Type(ty) => (syn::parse_quote!(type Expansion = #ty;), |formatted| {
+                let formatted = formatted.trim_end();
+                let formatted = formatted
+                    .strip_prefix("type Expansion = ")
+                    .unwrap_or(formatted);
+                let formatted = formatted.strip_suffix(';').unwrap_or(formatted);
+                formatted.to_owned() + "\n"
+            }),
+            Expansion::Pat(pat) => (
+                syn::parse_quote!(fn expansion() { let #pat; }),
+                |formatted| {
+                    let body = unwrap_body(formatted);
+                    let body = body.trim_end();
+                    let body = body.strip_prefix("let ").unwrap_or(body);
+                    let body = body.strip_suffix(';').unwrap_or(body);
+                    body.to_owned() + "\n"
+                },
+            ),
+        };
+        match ignore_panic(|| unparse_maximal(&file)) {
+            Ok(formatted) => unwrap(&formatted).replace("$crate", "crate"),
+            Err(_) => file.to_token_stream().to_string() + "\n",
+        }
+    }
+}
+
+fn file(items: Vec<Item>) -> File {
+    File {
+        shebang: None,
+        attrs: Vec::new(),
+        items,
+    }
+}
+
+// Removes the first and last line of a formatted item, and the indentation of
+// the lines in between.
+fn unwrap_body(formatted: &str) -> String {
+    let lines: Vec<&str> = formatted.trim_end().lines().collect();
+    let mut body = String::new();
+    if lines.len() > 2 {
+        for line in &lines[1..lines.len() - 1] {
+            body += line.strip_prefix("    ").unwrap_or(line);
+            body.push('\n');
+        }
+    }
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sites(source: &str) -> (Vec<Site>, Definitions) {
+        let file = syn::parse_file(source).unwrap();
+        let mut definitions = Definitions::new();
+        collect_definitions(&file, &mut definitions);
+        (find_sites(&file.items, &definitions), definitions)
+    }
+
+    #[test]
+    fn test_recursive_expansion() {
+        let source = r#"
+            macro_rules! count {
+                () => { 0 };
+                ($x:tt $($rest:tt)*) => { 1 + count!($($rest)*) };
+            }
+
+            fn f() -> usize {
+                count!(a b)
+            }
+        "#;
+        let (sites, definitions) = sites(source);
+        assert_eq!(sites.len(), 1);
+        assert_eq!(sites[0].line, 8);
+
+        let mut content = String::new();
+        expand_steps(&sites[0], &definitions, &mut content).unwrap();
+        let expected = "
+// step 1: count!
+1 + count!(b)
+
+// step 2: count!
+1 + (1 + count!())
+
+// step 3: count!
+1 + (1 + 0)
+";
+        assert_eq!(content, expected);
+    }
+
+    #[test]
+    fn test_recursion_limit() {
+        let source = r#"
+            macro_rules! forever {
+                ($($x:tt)*) => { forever!($($x)* x) };
+            }
+
+            fn f() {
+                forever!();
+            }
+        "#;
+        let (sites, definitions) = sites(source);
+        let mut content = String::new();
+        let err = expand_steps(&sites[0], &definitions, &mut content).unwrap_err();
+        assert_eq!(
+            err,
+            "recursion limit reached while expanding forever!, stopped after 128 steps",
+        );
+        assert!(content.contains("\n// step 128: forever!\n"));
+        assert!(!content.contains("\n// step 129: "));
+    }
+
+    #[test]
+    fn test_sites_at() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("src")).unwrap();
+        let source = "\
+macro_rules! double {
+    ($e:expr) => { $e * 2 };
+}
+
+fn f() -> i32 {
+    let a = double!(1);
+    double!(a) + double!(2)
+}
+";
+        // Relative to the manifest directory, since no such file exists in
+        // the current directory.
+        let path = dir.path().join("src/offline_sites_at.rs");
+        fs::write(&path, source).unwrap();
+        let at = |file: &Path, line| {
+            let location = Location {
+                file: file.to_owned(),
+                line,
+            };
+            let mut definitions = Definitions::new();
+            sites_at(dir.path(), &location, &mut definitions)
+                .unwrap_or_else(|err| panic!("{}", err))
+                .unwrap()
+        };
+
+        let relative = Path::new("src/offline_sites_at.rs");
+        let sites = at(relative, 6);
+        assert_eq!(sites.len(), 1);
+        assert_eq!(sites[0].mac.tokens.to_string(), "1");
+
+        // The first of several invocations on the line.
+        let sites = at(&path, 7);
+        assert_eq!(sites.len(), 1);
+        assert_eq!(sites[0].mac.tokens.to_string(), "a");
+
+        assert!(at(relative, 5).is_empty());
+
+        // A file that does not parse.
+        fs::write(&path, "fn f(").unwrap();
+        let location = Location {
+            file: path,
+            line: 1,
+        };
+        let result = sites_at(dir.path(), &location, &mut Definitions::new());
+        assert!(result.unwrap_or_else(|err| panic!("{}", err)).is_none());
+    }
+}
@@ -9,6 +9,25 @@ const TARGET_SELECTION: &str = "Target Selection";
 const FEATURE_SELECTION: &str = "Feature Selection";
 const COMPILATION_OPTIONS: &str = "Compilation Options";
 const MANIFEST_OPTIONS: &str = "Manifest Options";
+const DIFF_OPTIONS: &str = "Diff Options";
+const MACRO_RULES_OPTIONS: &str = "Macro Rules Options";
+const SANITIZE_OPTIONS: &str = "Sanitize Options";
+const SNAPSHOT_OPTIONS: &str = "Snapshot Options";
+
+// Modes that work on a single expansion, which do not combine with expanding
+// many packages or targets at once.
+const SINGLE_EXPANSION: [&str; 10] = [
+    "diff",
+    "check",
+    "html",
+    "derive",
+    "attr",
+    "watch",
+    "macro_rules",
+    "at",
+    "message_format",
+    "item",
+];
 
 #[derive(Parser)]
 #[command(bin_name = "cargo", version, author, disable_help_subcommand = true)]
@@ -40,6 +59,74 @@ pub struct Expand {
     #[arg(long, value_name = "WHEN", hide_possible_values = true)]
     pub color: Option<Coloring>,
 
+    /// Output format for the expanded code (human, json)
+    #[arg(
+        long,
+        value_name = "FMT",
+        default_value = "human",
+        hide_possible_values = true,
+        conflicts_with_all = ["ugly", "diff"]
+    )]
+    pub message_format: MessageFormat,
+
+    /// Write the expansion into DIR, one file per module, instead of printing
+    /// it [default for --workspace and --all-targets: target/expand]
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["diff", "macro_rules", "at", "message_format"])]
+    pub output: Option<PathBuf>,
+
+    /// Write the expansion to FILE as an HTML page, with each item linked to
+    /// its source
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["ugly", "diff", "watch", "message_format", "output", "check", "macro_rules", "at"])]
+    pub html: Option<PathBuf>,
+
+    /// Make the --output directory a crate that compiles the expanded code
+    #[arg(long, requires = "output", conflicts_with_all = ["workspace", "all_targets"])]
+    pub standalone: bool,
+
+    /// Keep macro_rules! definitions in the output
+    #[arg(long, overrides_with = "no_keep_macro_rules", help_heading = SANITIZE_OPTIONS)]
+    pub keep_macro_rules: bool,
+
+    /// Remove macro_rules! definitions, even if configured to keep them
+    #[arg(long, overrides_with = "keep_macro_rules", help_heading = SANITIZE_OPTIONS)]
+    pub no_keep_macro_rules: bool,
+
+    /// Remove impls generated by derive macros
+    #[arg(long, overrides_with = "no_strip_derived", help_heading = SANITIZE_OPTIONS)]
+    pub strip_derived: bool,
+
+    /// Keep impls generated by derive macros, even if configured to remove them
+    #[arg(long, overrides_with = "strip_derived", help_heading = SANITIZE_OPTIONS)]
+    pub no_strip_derived: bool,
+
+    /// Remove the injected standard library prelude import
+    #[arg(long, overrides_with = "no_hide_prelude", help_heading = SANITIZE_OPTIONS)]
+    pub hide_prelude: bool,
+
+    /// Keep the injected prelude import, even if configured to remove it
+    #[arg(long, overrides_with = "hide_prelude", help_heading = SANITIZE_OPTIONS)]
+    pub no_hide_prelude: bool,
+
+    /// Show expanded format_args! internals as format_args!
+    #[arg(long, overrides_with = "no_collapse_format_args", help_heading = SANITIZE_OPTIONS)]
+    pub collapse_format_args: bool,
+
+    /// Show format_args! internals as expanded, even if configured to collapse them
+    #[arg(long, overrides_with = "collapse_format_args", help_heading = SANITIZE_OPTIONS)]
+    pub no_collapse_format_args: bool,
+
+    /// Remove #[rustc_*] attributes
+    #[arg(long, overrides_with = "no_strip_rustc_attrs", help_heading = SANITIZE_OPTIONS)]
+    pub strip_rustc_attrs: bool,
+
+    /// Keep #[rustc_*] attributes, even if configured to remove them
+    #[arg(long, overrides_with = "strip_rustc_attrs", help_heading = SANITIZE_OPTIONS)]
+    pub no_strip_rustc_attrs: bool,
+
+    /// Show code that fails to format as its tokens instead of `...`
+    #[arg(long, conflicts_with = "ugly")]
+    pub raw_fallback: bool,
+
     /// Override a configuration value
     #[arg(long, value_name = "KEY=VALUE")]
     pub config: Vec<String>,
@@ -52,10 +139,46 @@ pub struct Expand {
     #[arg(long)]
     pub version: bool,
 
+    /// Expand again whenever the package's source files change
+    #[arg(long, conflicts_with = "diff")]
+    pub watch: bool,
+
+    /// Show what changed in the expansion relative to a baseline
+    #[arg(long, conflicts_with = "ugly", help_heading = DIFF_OPTIONS)]
+    pub diff: bool,
+
+    /// Features to activate for the baseline of --diff
+    #[arg(long, value_name = "FEATURES", requires = "diff", help_heading = DIFF_OPTIONS)]
+    pub base_features: Option<String>,
+
+    /// Git revision to expand as the baseline of --diff
+    #[arg(long, value_name = "REV", requires = "diff", help_heading = DIFF_OPTIONS)]
+    pub base_rev: Option<String>,
+
+    /// Compare the expansion of each item against the snapshots in DIR
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["ugly", "diff", "watch", "macro_rules", "at", "message_format", "output"], help_heading = SNAPSHOT_OPTIONS)]
+    pub check: Option<PathBuf>,
+
+    /// Overwrite the snapshots of --check with the current expansion
+    #[arg(long, requires = "check", help_heading = SNAPSHOT_OPTIONS)]
+    pub bless: bool,
+
+    /// Expand macro_rules! invocations in-process, without compiling the crate
+    #[arg(long, conflicts_with_all = ["diff", "message_format"], help_heading = MACRO_RULES_OPTIONS)]
+    pub macro_rules: bool,
+
+    /// Expand the macro_rules! invocation at this source line
+    #[arg(long, value_name = "FILE:LINE", value_parser = parse_location, conflicts_with_all = ["diff", "message_format"], help_heading = MACRO_RULES_OPTIONS)]
+    pub at: Option<Location>,
+
     /// Package to expand
     #[arg(short, long, value_name = "SPEC", num_args = 0..=1, help_heading = PACKAGE_SELECTION)]
     pub package: Option<Option<String>>,
 
+    /// Expand all packages in the workspace
+    #[arg(long, conflicts_with_all = SINGLE_EXPANSION, conflicts_with = "package", help_heading = PACKAGE_SELECTION)]
+    pub workspace: bool,
+
     /// Expand only this package's library
     #[arg(long, help_heading = TARGET_SELECTION)]
     pub lib: bool,
@@ -72,6 +195,10 @@ pub struct Expand {
     #[arg(long, value_name = "NAME", num_args = 0..=1, help_heading = TARGET_SELECTION)]
     pub test: Option<Option<String>>,
 
+    /// Expand all targets: lib, bins, examples, tests, and benches
+    #[arg(long, conflicts_with_all = SINGLE_EXPANSION, conflicts_with_all = ["lib", "bin", "example", "test", "bench"], help_heading = TARGET_SELECTION)]
+    pub all_targets: bool,
+
     /// Include tests when expanding the lib or bin
     #[arg(long, help_heading = TARGET_SELECTION)]
     pub tests: bool,
@@ -85,13 +212,21 @@ pub struct Expand {
     pub features: Option<String>,
 
     /// Activate all available features
-    #[arg(long, help_heading = FEATURE_SELECTION)]
+    #[arg(long, overrides_with = "no_all_features", help_heading = FEATURE_SELECTION)]
     pub all_features: bool,
 
+    /// Do not activate all features, even if configured to
+    #[arg(long, overrides_with = "all_features", help_heading = FEATURE_SELECTION)]
+    pub no_all_features: bool,
+
     /// Do not activate the `default` feature
-    #[arg(long, help_heading = FEATURE_SELECTION)]
+    #[arg(long, overrides_with = "default_features", help_heading = FEATURE_SELECTION)]
     pub no_default_features: bool,
 
+    /// Activate the `default` feature, even if configured not to
+    #[arg(long, overrides_with = "no_default_features", help_heading = FEATURE_SELECTION)]
+    pub default_features: bool,
+
     /// Number of parallel jobs, defaults to # of CPUs
     #[arg(short, long, value_name = "N", help_heading = COMPILATION_OPTIONS)]
     pub jobs: Option<u64>,
@@ -131,6 +266,22 @@ pub struct Expand {
     /// Local path to module or other named item to expand, e.g. os::unix::ffi
     #[arg(value_name = "ITEM", value_parser = parse_selector)]
     pub item: Option<Selector>,
+
+    /// Expand the whole crate, even if the configuration sets an ITEM
+    #[arg(long, conflicts_with = "item")]
+    pub no_item: bool,
+
+    /// Expand only the impls generated by this derive macro, e.g. Serialize
+    ///
+    /// Besides impls of the trait of the same name, those of other traits that
+    /// the source neither implements by hand nor derives otherwise are taken to
+    /// come from the derive, unless it is one of the standard library's.
+    #[arg(long, value_name = "NAME", conflicts_with_all = ["ugly", "macro_rules", "at"])]
+    pub derive: Option<String>,
+
+    /// Expand only the items generated by this attribute macro, e.g. tokio::main
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["ugly", "macro_rules", "at"])]
+    pub attr: Option<String>,
 }
 
 #[derive(ValueEnum, Debug, Clone, Copy)]
@@ -140,7 +291,33 @@ pub enum Coloring {
     Never,
 }
 
-fn parse_selector(s: &str) -> Result<Selector, <Selector as FromStr>::Err> {
+#[derive(ValueEnum, Debug, Clone, Copy, Default)]
+pub enum MessageFormat {
+    #[default]
+    Human,
+    Json,
+}
+
+#[derive(Debug, Clone)]
+pub struct Location {
+    pub file: PathBuf,
+    pub line: usize,
+}
+
+fn parse_location(s: &str) -> Result<Location, String> {
+    let (file, line) = s
+        .rsplit_once(':')
+        .ok_or_else(|| "expected FILE:LINE".to_owned())?;
+    let line = line
+        .parse()
+        .map_err(|_| format!("invalid line number: {}", line))?;
+    Ok(Location {
+        file: PathBuf::from(file),
+        line,
+    })
+}
+
+pub fn parse_selector(s: &str) -> Result<Selector, <Selector as FromStr>::Err> {
     if s.starts_with("::") {
         s[2..].parse()
     } else {
@@ -0,0 +1,451 @@
+use crate::error::Result;
+use crate::metadata::{self, Metadata, Package};
+use crate::{format_file, workspace, Expand, DOLLAR_CRATE_PLACEHOLDER};
+use std::fs;
+use std::io::{self, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use syn::ext::IdentExt;
+use syn::{AttrStyle, Attribute, File, Item};
+use toml::{Table, Value};
+
+// Writes the expansion into `dir`, and with --standalone also a manifest that
+// makes `dir` a crate compiling the expanded code.
+pub fn write_expanded(content: &str, args: &Expand, dir: &Path) -> Result<i32> {
+    if !args.standalone {
+        let root = write_modules(content, !args.ugly, dir)?;
+        let _ = writeln!(io::stderr(), "Wrote {}", root.display());
+        return Ok(0);
+    }
+
+    let metadata = match metadata::cargo_metadata(args, false)? {
+        Some(metadata) => metadata,
+        None => return Ok(1),
+    };
+    let package = match workspace::select_packages(args, &metadata)? {
+        Ok(packages) if packages.len() == 1 => packages[0],
+        Ok(_) => {
+            let _ = writeln!(
+                io::stderr(),
+                "ERROR: --standalone requires selecting a package with --package",
+            );
+            return Ok(1);
+        }
+        Err(code) => return Ok(code),
+    };
+
+    let root = write_modules(content, !args.ugly, &dir.join("src"))?;
+    let is_lib = root.ends_with("lib.rs");
+    let manifest = standalone_manifest(args, &metadata, package, is_lib);
+    fs::write(dir.join("Cargo.toml"), manifest_to_string(manifest)?)?;
+    // Expanded code relies on unstable features such as prelude_import.
+    fs::write(
+        dir.join("rust-toolchain.toml"),
+        "[toolchain]\nchannel = \"nightly\"\n",
+    )?;
+
+    let _ = writeln!(io::stderr(), "Wrote crate {}", dir.display());
+    Ok(0)
+}
+
+// Writes the expanded code to lib.rs, or main.rs if it has a main function,
+// moving inline modules out to files the same way they would be laid out by
+// hand. Returns the path of the crate root.
+pub fn write_modules(content: &str, split: bool, dir: &Path) -> Result<PathBuf> {
+    let wip = content.replace("$crate", DOLLAR_CRATE_PLACEHOLDER);
+    let syntax_tree = syn::parse_file(&wip).ok();
+    let has_main = syntax_tree.as_ref().is_some_and(|syntax_tree| {
+        syntax_tree
+            .items
+            .iter()
+            .any(|item| matches!(item, Item::Fn(item) if item.sig.ident == "main"))
+    });
+    let root = dir.join(if has_main { "main.rs" } else { "lib.rs" });
+    fs::create_dir_all(dir)?;
+
+    let mut syntax_tree = match syntax_tree {
+        Some(syntax_tree) if split => syntax_tree,
+        _ => {
+            fs::write(&root, content)?;
+            return Ok(root);
+        }
+    };
+
+    let mut files = Vec::new();
+    split_modules(&mut syntax_tree.items, dir, &mut files);
+    if files.is_empty() {
+        // Nothing to split, so keep the formatting as it was.
+        fs::write(&root, content)?;
+        return Ok(root);
+    }
+    files.push((root.clone(), syntax_tree));
+
+    for (path, file) in files {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, format_file(&file))?;
+    }
+    Ok(root)
+}
+
+// Replaces `mod m { ... }` by `mod m;`, with the content going to `m.rs`, and
+// the modules declared by that going to `m/*.rs`.
+fn split_modules(items: &mut [Item], dir: &Path, files: &mut Vec<(PathBuf, File)>) {
+    for item in items {
+        let Item::Mod(module) = item else {
+            continue;
+        };
+        let Some((_brace, content)) = module.content.take() else {
+            continue;
+        };
+        module.semi = Some(Default::default());
+
+        let (inner, outer): (Vec<Attribute>, Vec<Attribute>) = mem::take(&mut module.attrs)
+            .into_iter()
+            .partition(|attr| matches!(attr.style, AttrStyle::Inner(_)));
+        // The file is where the module's name puts it, not where a #[path]
+        // pointed to.
+        module.attrs = outer
+            .into_iter()
+            .filter(|attr| !attr.path().is_ident("path"))
+            .collect();
+
+        let name = module.ident.unraw().to_string();
+        let mut file = File {
+            shebang: None,
+            attrs: inner,
+            items: content,
+        };
+        split_modules(&mut file.items, &dir.join(&name), files);
+        files.push((dir.join(format!("{}.rs", name)), file));
+    }
+}
+
+// A manifest depending on exactly the crates, versions and features that the
+// original package was built with, except for proc macros, which have already
+// been expanded.
+fn standalone_manifest(
+    args: &Expand,
+    metadata: &Metadata,
+    package: &Package,
+    is_lib: bool,
+) -> Vec<(&'static str, Table)> {
+    let mut manifest = Vec::new();
+
+    let mut package_table = Table::new();
+    package_table.insert(
+        "name".to_owned(),
+        format!("{}-expanded", package.name).into(),
+    );
+    package_table.insert("version".to_owned(), "0.0.0".into());
+    package_table.insert("edition".to_owned(), package.edition.clone().into());
+    package_table.insert("publish".to_owned(), false.into());
+    manifest.push(("package", package_table));
+
+    let lib_target = package
+        .targets
+        .iter()
+        .find(|target| target.kind.iter().any(|kind| kind.ends_with("lib")));
+    let lib_name = lib_target.map(|target| target.name.replace('-', "_"));
+    if is_lib {
+        if let Some(lib_name) = &lib_name {
+            let mut lib_table = Table::new();
+            lib_table.insert("name".to_owned(), lib_name.clone().into());
+            manifest.push(("lib", lib_table));
+        }
+    }
+
+    // Tests, benches and examples may also use dev-dependencies.
+    let dev = args.tests || args.test.is_some() || args.bench.is_some() || args.example.is_some();
+
+    let node = metadata.node(&package.id);
+    let mut dependencies = Table::new();
+    if let Some(node) = node {
+        for dep in &node.deps {
+            let wanted = dep.dep_kinds.iter().any(|dep_kind| match &dep_kind.kind {
+                None => true,
+                Some(kind) => dev && kind == "dev",
+            });
+            if !wanted {
+                continue;
+            }
+            let Some(dep_package) = metadata.package(&dep.pkg) else {
+                continue;
+            };
+            if dep_package.is_proc_macro() {
+                continue;
+            }
+            let features = match metadata.node(&dep.pkg) {
+                Some(dep_node) => dep_node.features.as_slice(),
+                None => &[],
+            };
+            dependencies.insert(dep.name.clone(), dependency(dep_package, features).into());
+        }
+    }
+
+    // Binaries, tests and the like refer to the package's own library.
+    if !is_lib {
+        if let Some(lib_name) = lib_name {
+            let features = match node {
+                Some(node) => node.features.as_slice(),
+                None => &[],
+            };
+            dependencies.insert(lib_name, dependency(package, features).into());
+        }
+    }
+    manifest.push(("dependencies", dependencies));
+
+    // Keep the crate out of any enclosing workspace.
+    manifest.push(("workspace", Table::new()));
+    manifest
+}
+
+fn manifest_to_string(manifest: Vec<(&str, Table)>) -> Result<String> {
+    let mut string = String::new();
+    for (name, section) in manifest {
+        // Serialized one by one, as a Table would put them in alphabetical
+        // order.
+        let mut table = Table::new();
+        table.insert(name.to_owned(), section.into());
+        if !string.is_empty() {
+            string.push('\n');
+        }
+        string += &toml::to_string(&table)?;
+    }
+    Ok(string)
+}
+
+fn dependency(package: &Package, features: &[String]) -> Table {
+    let mut table = Table::new();
+    table.insert("package".to_owned(), package.name.clone().into());
+    match &package.source {
+        None => {
+            let dir = package.manifest_path.parent().unwrap();
+            table.insert("path".to_owned(), dir.display().to_string().into());
+        }
+        Some(source) if source.starts_with("git+") => {
+            let url = &source["git+".len()..];
+            let (url, rev) = url.split_once('#').unwrap_or((url, ""));
+            let url = url.split_once('?').map_or(url, |(url, _query)| url);
+            table.insert("git".to_owned(), url.into());
+            if !rev.is_empty() {
+                table.insert("rev".to_owned(), rev.into());
+            }
+        }
+        Some(_registry) => {
+            table.insert("version".to_owned(), format!("={}", package.version).into());
+        }
+    }
+
+    // The resolved features already include the ones enabled by "default".
+    let features: Vec<Value> = features
+        .iter()
+        .filter(|feature| *feature != "default")
+        .map(|feature| feature.as_str().into())
+        .collect();
+    table.insert("default-features".to_owned(), false.into());
+    if !features.is_empty() {
+        table.insert("features".to_owned(), features.into());
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::Metadata;
+
+    fn files_of(source: &str) -> (String, Vec<(PathBuf, String)>) {
+        let mut syntax_tree = syn::parse_file(source).unwrap();
+        let mut files = Vec::new();
+        split_modules(&mut syntax_tree.items, Path::new("src"), &mut files);
+        let files = files
+            .iter()
+            .map(|(path, file)| (path.clone(), format_file(file)))
+            .collect();
+        (format_file(&syntax_tree), files)
+    }
+
+    #[test]
+    fn test_split_modules() {
+        let (root, files) = files_of(
+            r#"
+            mod a {
+                #![allow(dead_code)]
+                mod b {
+                    fn f() {}
+                }
+                struct S;
+            }
+            #[cfg(test)]
+            #[path = "other.rs"]
+            mod r#c {}
+            mod d;
+            "#,
+        );
+        assert_eq!(root, "mod a;\n#[cfg(test)]\nmod r#c;\nmod d;\n");
+        assert_eq!(
+            files,
+            [
+                (PathBuf::from("src/a/b.rs"), "fn f() {}\n".to_owned()),
+                (
+                    PathBuf::from("src/a.rs"),
+                    "#![allow(dead_code)]\nmod b;\nstruct S;\n".to_owned(),
+                ),
+                (PathBuf::from("src/c.rs"), "".to_owned()),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_write_modules() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |content: &str, split: bool, name: &str| {
+            let dir = dir.path().join(name);
+            let root = write_modules(content, split, &dir).unwrap_or_else(|err| panic!("{}", err));
+            (dir, root)
+        };
+
+        let content = "mod a { mod b {} }\nfn main() {}\n";
+        let (dir, root) = write(content, true, "split");
+        assert_eq!(root, dir.join("main.rs"));
+        assert_eq!(fs::read_to_string(&root).unwrap(), "mod a;\nfn main() {}\n");
+        assert_eq!(fs::read_to_string(dir.join("a.rs")).unwrap(), "mod b;\n");
+        assert_eq!(fs::read_to_string(dir.join("a/b.rs")).unwrap(), "");
+
+        let (dir, root) = write(content, false, "unsplit");
+        assert_eq!(fs::read_to_string(root).unwrap(), content);
+        assert!(!dir.join("a.rs").exists());
+
+        // Without modules to split, the formatting is kept as it was.
+        let content = "pub  fn f() {}\n";
+        let (dir, root) = write(content, true, "lib");
+        assert_eq!(root, dir.join("lib.rs"));
+        assert_eq!(fs::read_to_string(root).unwrap(), content);
+    }
+
+    fn test_metadata() -> Metadata {
+        let targets = |kinds: &str| {
+            format!(
+                r#"[{{"name": "t", "kind": [{}], "src_path": "/ws/src/lib.rs"}}]"#,
+                kinds,
+            )
+        };
+        let package = |id: &str, source: &str, manifest_path: &str, targets: &str| {
+            format!(
+                r#"{{"id": "{id}", "name": "{id}", "version": "1.2.3", "source": {source},
+                    "edition": "2021", "manifest_path": "{manifest_path}", "targets": {targets}}}"#,
+            )
+        };
+        let packages = [
+            package("my-crate", "null", "/ws/Cargo.toml", &targets(r#""lib""#)),
+            package(
+                "serde",
+                r#""registry+https://github.com/rust-lang/crates.io-index""#,
+                "/r/serde/Cargo.toml",
+                &targets(r#""lib""#),
+            ),
+            package(
+                "git-dep",
+                r#""git+https://example.com/git-dep?branch=main#0123abc""#,
+                "/g/Cargo.toml",
+                &targets(r#""lib""#),
+            ),
+            package(
+                "local",
+                "null",
+                "/ws/local/Cargo.toml",
+                &targets(r#""lib""#),
+            ),
+            package(
+                "derive",
+                "null",
+                "/ws/derive/Cargo.toml",
+                &targets(r#""proc-macro""#),
+            ),
+            package(
+                "dev-only",
+                "null",
+                "/ws/dev/Cargo.toml",
+                &targets(r#""lib""#),
+            ),
+        ];
+        let json = format!(
+            r#"{{
+                "packages": [{}],
+                "workspace_members": ["my-crate"],
+                "target_directory": "/ws/target",
+                "resolve": {{"nodes": [
+                    {{"id": "my-crate", "features": ["default", "extra"], "deps": [
+                        {{"name": "serde", "pkg": "serde", "dep_kinds": [{{"kind": null}}]}},
+                        {{"name": "renamed", "pkg": "git-dep", "dep_kinds": [{{"kind": null}}]}},
+                        {{"name": "local", "pkg": "local", "dep_kinds": [{{"kind": null}}]}},
+                        {{"name": "derive", "pkg": "derive", "dep_kinds": [{{"kind": null}}]}},
+                        {{"name": "dev_only", "pkg": "dev-only", "dep_kinds": [{{"kind": "dev"}}]}}
+                    ]}},
+                    {{"id": "serde", "features": ["default", "std"], "deps": []}}
+                ]}}
+            }}"#,
+            packages.join(", "),
+        );
+        serde_json::from_str(&json).unwrap()
+    }
+
+    #[test]
+    fn test_standalone_manifest() {
+        let metadata = test_metadata();
+        let package = metadata.package("my-crate").unwrap();
+        let args = Expand::default();
+        let manifest = standalone_manifest(&args, &metadata, package, true);
+        let expected = r#"[package]
+edition = "2021"
+name = "my-crate-expanded"
+publish = false
+version = "0.0.0"
+
+[lib]
+name = "t"
+
+[dependencies.local]
+default-features = false
+package = "local"
+path = "/ws/local"
+
+[dependencies.renamed]
+default-features = false
+git = "https://example.com/git-dep"
+package = "git-dep"
+rev = "0123abc"
+
+[dependencies.serde]
+default-features = false
+features = ["std"]
+package = "serde"
+version = "=1.2.3"
+
+[workspace]
+"#;
+        let string = manifest_to_string(manifest).unwrap_or_else(|err| panic!("{}", err));
+        assert_eq!(string, expected);
+
+        // A binary depends on the package's own library, with its features,
+        // and tests also on the dev-dependencies.
+        let args = Expand {
+            tests: true,
+            ..Expand::default()
+        };
+        let manifest = standalone_manifest(&args, &metadata, package, false);
+        let dependencies = &manifest
+            .iter()
+            .find(|(name, _)| *name == "dependencies")
+            .unwrap()
+            .1;
+        let names: Vec<&String> = dependencies.keys().collect();
+        assert_eq!(names, ["dev_only", "local", "renamed", "serde", "t"]);
+        let lib = &dependencies["t"];
+        assert_eq!(lib["path"].as_str(), Some("/ws"));
+        assert_eq!(lib["features"].as_array().unwrap().len(), 1);
+        assert_eq!(lib["features"][0].as_str(), Some("extra"));
+    }
+}
@@ -0,0 +1,212 @@
+use crate::{metadata, workspace, Expand};
+use std::fs;
+use std::path::{Path, PathBuf};
+use syn::ext::IdentExt;
+use syn::{Expr, Item, ItemMod, Lit, Meta};
+
+// The crate as written, before expansion.
+pub struct Source {
+    pub package_dir: PathBuf,
+    pub items: Vec<SourceItem>,
+}
+
+pub struct SourceItem {
+    /// The file the item is in.
+    pub file: PathBuf,
+    /// Path of the module the item is in, relative to the crate root.
+    pub module: Vec<String>,
+    pub item: Item,
+}
+
+// Parses the source files of the crate being expanded, starting from the root
+// of the selected target and following its module declarations. Returns None
+// if the target cannot be found or its root fails to parse. Other files that
+// fail to parse are left out.
+pub fn parse(args: &Expand) -> Option<Source> {
+    let metadata = metadata::cargo_metadata(args, true).ok()??;
+    let packages = workspace::select_packages(args, &metadata).ok()?.ok()?;
+    let [package] = packages[..] else {
+        return None;
+    };
+    let units = workspace::select_targets(args, package);
+    let root_file = units.first()?.src_path();
+
+    let content = fs::read_to_string(root_file).ok()?;
+    let syntax_tree = syn::parse_file(&content).ok()?;
+    let mut items = Vec::new();
+    collect_items(
+        syntax_tree.items,
+        root_file,
+        root_file.parent()?,
+        false,
+        &[],
+        &mut items,
+    );
+    Some(Source {
+        package_dir: package.manifest_path.parent()?.to_owned(),
+        items,
+    })
+}
+
+fn collect_file(file: &Path, dir: &Path, module: &[String], out: &mut Vec<SourceItem>) {
+    let Ok(content) = fs::read_to_string(file) else {
+        return;
+    };
+    let Ok(syntax_tree) = syn::parse_file(&content) else {
+        return;
+    };
+    collect_items(syntax_tree.items, file, dir, false, module, out);
+}
+
+// Items of a module whose submodules are found in `dir`. A #[path] is relative
+// to the directory of the file, except inside an inline module, where it is
+// relative to `dir` like the module's other submodules.
+fn collect_items(
+    items: Vec<Item>,
+    file: &Path,
+    dir: &Path,
+    inline: bool,
+    module: &[String],
+    out: &mut Vec<SourceItem>,
+) {
+    for item in items {
+        if let Item::Mod(item) = &item {
+            let name = item.ident.unraw().to_string();
+            let mut submodule = module.to_vec();
+            submodule.push(item.ident.to_string());
+            if let Some((_brace, items)) = &item.content {
+                collect_items(items.clone(), file, &dir.join(&name), true, &submodule, out);
+            } else if let Some(path_attr) = path_attr(item) {
+                let base = match file.parent() {
+                    Some(file_dir) if !inline => file_dir,
+                    _ => dir,
+                };
+                let file = base.join(path_attr);
+                let subdir = file.parent().unwrap_or(dir).to_owned();
+                collect_file(&file, &subdir, &submodule, out);
+            } else {
+                let subdir = dir.join(&name);
+                let file = dir.join(format!("{}.rs", name));
+                let file = if file.exists() {
+                    file
+                } else {
+                    subdir.join("mod.rs")
+                };
+                collect_file(&file, &subdir, &submodule, out);
+            }
+        }
+
+        out.push(SourceItem {
+            file: file.to_owned(),
+            module: module.to_vec(),
+            item,
+        });
+    }
+}
+
+// `#[path = "..."]`
+fn path_attr(item: &ItemMod) -> Option<String> {
+    item.attrs.iter().find_map(|attr| match &attr.meta {
+        Meta::NameValue(meta) if meta.path.is_ident("path") => match &meta.value {
+            Expr::Lit(expr) => match &expr.lit {
+                Lit::Str(lit) => Some(lit.value()),
+                _ => None,
+            },
+            _ => None,
+        },
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(dir: &Path, path: &str, content: &str) {
+        let path = dir.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    #[test]
+    fn test_module_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        write(
+            dir,
+            "src/lib.rs",
+            r#"
+                mod a;
+                mod b;
+                #[path = "other/c_impl.rs"]
+                mod c;
+                mod inline {
+                    mod d;
+                    #[path = "e_file.rs"]
+                    mod e;
+                }
+            "#,
+        );
+        // Both exist, which rustc rejects. The file next to the parent wins.
+        write(
+            dir,
+            "src/a.rs",
+            "struct A; mod nested; #[path = \"a_extra.rs\"] mod extra;",
+        );
+        write(dir, "src/a/mod.rs", "struct Ignored;");
+        write(dir, "src/a/nested.rs", "struct Nested;");
+        write(dir, "src/a_extra.rs", "struct Extra;");
+        write(dir, "src/b/mod.rs", "struct B; mod sub;");
+        write(dir, "src/b/sub.rs", "struct Sub;");
+        write(
+            dir,
+            "src/other/c_impl.rs",
+            "struct C; #[path = \"c2.rs\"] mod c2;",
+        );
+        write(dir, "src/other/c2.rs", "struct C2;");
+        write(dir, "src/inline/d.rs", "struct D;");
+        write(dir, "src/inline/e_file.rs", "struct E;");
+
+        let root = dir.join("src/lib.rs");
+        let mut items = Vec::new();
+        collect_file(&root, root.parent().unwrap(), &[], &mut items);
+        let structs: Vec<(String, String)> = items
+            .iter()
+            .filter_map(|source_item| match &source_item.item {
+                Item::Struct(item) => {
+                    let mut path = source_item.module.clone();
+                    path.push(item.ident.to_string());
+                    let file = source_item.file.strip_prefix(dir).unwrap();
+                    Some((path.join("::"), file.display().to_string()))
+                }
+                _ => None,
+            })
+            .collect();
+        let expected = [
+            ("a::A", "src/a.rs"),
+            ("a::nested::Nested", "src/a/nested.rs"),
+            ("a::extra::Extra", "src/a_extra.rs"),
+            ("b::B", "src/b/mod.rs"),
+            ("b::sub::Sub", "src/b/sub.rs"),
+            ("c::C", "src/other/c_impl.rs"),
+            ("c::c2::C2", "src/other/c2.rs"),
+            ("inline::d::D", "src/inline/d.rs"),
+            ("inline::e::E", "src/inline/e_file.rs"),
+        ];
+        let expected: Vec<(String, String)> = expected
+            .iter()
+            .map(|(path, file)| (path.to_string(), file.to_string()))
+            .collect();
+        assert_eq!(structs, expected);
+    }
+
+    #[test]
+    fn test_missing_module_file() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), "src/lib.rs", "mod missing; struct S;");
+        let root = dir.path().join("src/lib.rs");
+        let mut items = Vec::new();
+        collect_file(&root, root.parent().unwrap(), &[], &mut items);
+        assert_eq!(items.len(), 2);
+    }
+}
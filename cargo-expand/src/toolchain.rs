@@ -0,0 +1,197 @@
+use crate::cmd::Line;
+use crate::error::{Error, Result};
+use crate::opts::Coloring;
+use crate::{cargo_binary, manifest, print_command};
+use std::env;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+// How cargo gets run so that rustc accepts -Zunpretty=expanded.
+#[derive(Copy, Clone)]
+pub enum Route {
+    // The active toolchain is a nightly.
+    Nightly,
+    // The active toolchain is stable or beta, and RUSTC_BOOTSTRAP=1 unlocks
+    // its unstable flags.
+    Bootstrap,
+    // The active toolchain does not work, but rustup has a nightly installed.
+    Rustup,
+}
+
+// Probed again for every expansion rather than cached, since the toolchain
+// depends on the package and its rust-toolchain.toml, which may change between
+// the rebuilds of --watch. An expansion of a whole workspace probes once.
+pub fn route(manifest_path: Option<&Path>, verbose: bool, color: &Coloring) -> Result<Route> {
+    // rustc is run in the package's directory, where cargo will run it, so
+    // that a rust-toolchain.toml there selects the toolchain probed.
+    let manifest_path = manifest::find_cargo_manifest(manifest_path).ok();
+    let dir = manifest_path
+        .as_deref()
+        .and_then(Path::parent)
+        .filter(|dir| !dir.as_os_str().is_empty());
+    probe(dir, verbose, color)
+}
+
+fn probe(dir: Option<&Path>, verbose: bool, color: &Coloring) -> Result<Route> {
+    let rustc = env::var_os("RUSTC").unwrap_or_else(|| "rustc".to_owned().into());
+    let run = |line, env| run(line, env, dir, verbose, color);
+
+    let mut line = Line::new(&rustc);
+    line.arg("-vV");
+    let release = run(line, None).and_then(|stdout| {
+        stdout
+            .lines()
+            .find_map(|line| line.strip_prefix("release: "))
+            .map(str::to_owned)
+    });
+    let Some(release) = release else {
+        return Err(Error::UnsupportedToolchain { release: None });
+    };
+    if release.contains("-nightly") || release.contains("-dev") {
+        return Ok(Route::Nightly);
+    }
+
+    // Distributions may build rustc such that RUSTC_BOOTSTRAP is ignored.
+    let mut line = Line::new(&rustc);
+    line.args(["-Zunpretty=expanded", "-"]);
+    if run(line, Some(("RUSTC_BOOTSTRAP", "1"))).is_some() {
+        return Ok(Route::Bootstrap);
+    }
+
+    let mut line = Line::new("rustup");
+    line.args(["run", "nightly", "rustc", "-vV"]);
+    if run(line, None).is_some() {
+        return Ok(Route::Rustup);
+    }
+
+    Err(Error::UnsupportedToolchain {
+        release: Some(release),
+    })
+}
+
+// Returns the stdout of a successful run.
+fn run(
+    line: Line,
+    env: Option<(&str, &str)>,
+    dir: Option<&Path>,
+    verbose: bool,
+    color: &Coloring,
+) -> Option<String> {
+    let mut cmd = Command::new(line.bin());
+    cmd.args(line.clone())
+        .stdin(Stdio::null())
+        .stderr(Stdio::null());
+    if let Some((key, value)) = env {
+        cmd.env(key, value);
+    }
+    if let Some(dir) = dir {
+        cmd.current_dir(dir);
+    }
+    if verbose {
+        print_command(with_env(line, env), color);
+    }
+    let output = cmd.output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8(output.stdout).ok()
+}
+
+fn with_env(line: Line, env: Option<(&str, &str)>) -> Line {
+    let Some((key, value)) = env else {
+        return line;
+    };
+    let mut display = Line::new(format!("{}={}", key, value));
+    display.arg(line.bin());
+    display.args(line);
+    display
+}
+
+impl Route {
+    // The command that runs cargo.
+    pub fn command(self) -> Command {
+        match self {
+            Route::Nightly => Command::new(cargo_binary()),
+            Route::Bootstrap => {
+                let mut cmd = Command::new(cargo_binary());
+                cmd.env("RUSTC_BOOTSTRAP", "1");
+                cmd
+            }
+            Route::Rustup => {
+                let mut cmd = Command::new("rustup");
+                cmd.args(["run", "nightly", "cargo"]);
+                // $RUSTC is the compiler that was found not to work.
+                cmd.env_remove("RUSTC");
+                cmd
+            }
+        }
+    }
+
+    // How the cargo command line gets shown under --verbose.
+    pub fn display(self, line: Line) -> Line {
+        match self {
+            Route::Nightly => line,
+            Route::Bootstrap => with_env(line, Some(("RUSTC_BOOTSTRAP", "1"))),
+            Route::Rustup => {
+                let mut display = Line::new("rustup");
+                display.args(["run", "nightly"]);
+                display.arg(line.bin());
+                display.args(line);
+                display
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cargo_line() -> Line {
+        let mut line = Line::new("cargo");
+        line.args(["rustc", "--lib"]);
+        line
+    }
+
+    #[test]
+    fn test_with_env() {
+        assert_eq!(
+            with_env(cargo_line(), None).to_string(),
+            "cargo rustc --lib"
+        );
+        let line = with_env(cargo_line(), Some(("RUSTC_BOOTSTRAP", "1")));
+        assert_eq!(line.to_string(), "RUSTC_BOOTSTRAP=1 cargo rustc --lib");
+    }
+
+    #[test]
+    fn test_display() {
+        let display = |route: Route| route.display(cargo_line()).to_string();
+        assert_eq!(display(Route::Nightly), "cargo rustc --lib");
+        assert_eq!(
+            display(Route::Bootstrap),
+            "RUSTC_BOOTSTRAP=1 cargo rustc --lib",
+        );
+        assert_eq!(
+            display(Route::Rustup),
+            "rustup run nightly cargo rustc --lib",
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_in_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = std::fs::canonicalize(dir.path()).unwrap();
+        let stdout = run(Line::new("pwd"), None, Some(&dir), false, &Coloring::Never);
+        assert_eq!(stdout.unwrap().trim_end(), dir.to_str().unwrap());
+
+        let mut line = Line::new("sh");
+        line.args(["-c", "echo $RUSTC_BOOTSTRAP; exit 1"]);
+        let env = Some(("RUSTC_BOOTSTRAP", "1"));
+        assert_eq!(run(line, env, None, false, &Coloring::Never), None);
+        let mut line = Line::new("sh");
+        line.args(["-c", "echo $RUSTC_BOOTSTRAP"]);
+        let stdout = run(line, env, None, false, &Coloring::Never);
+        assert_eq!(stdout.as_deref(), Some("1\n"));
+    }
+}
@@ -1,24 +1,130 @@
-use proc_macro2::{Ident, Span};
-use quote::quote;
+use proc_macro2::{Ident, Span, TokenStream};
+use quote::{quote, ToTokens};
+use std::any::Any;
 use std::panic;
 use syn::fold::{self, Fold};
 use syn::punctuated::Punctuated;
 use syn::{
-    token, Abi, Block, Expr, File, ForeignItem, Generics, ImplItem, Item, ItemConst, ItemFn,
-    ItemForeignMod, ItemImpl, ItemTrait, ReturnType, Signature, Stmt, Token, TraitItem, Type,
-    TypeInfer, Visibility,
+    token, Abi, Block, Expr, ExprPath, File, ForeignItem, ForeignItemMacro, Generics, ImplItem,
+    ImplItemMacro, Item, ItemConst, ItemFn, ItemForeignMod, ItemImpl, ItemMacro, ItemTrait, Macro,
+    MacroDelimiter, ReturnType, Signature, Stmt, Token, TraitItem, TraitItemMacro, Type, TypeInfer,
+    Visibility,
 };
 
+// Stands in for the tokens of a node that prettyplease could not print, until
+// they are pasted into the formatted code.
+const VERBATIM_PLACEHOLDER: &str = "Ξverbatim";
+
+// A part of the syntax tree that prettyplease could not print.
+pub(crate) struct Fallback {
+    pub kind: &'static str,
+    pub name: Option<String>,
+    pub reason: String,
+}
+
+pub(crate) struct Unparsed {
+    pub formatted: String,
+    pub fallbacks: Vec<Fallback>,
+}
+
 pub(crate) fn unparse_maximal(syntax_tree: &File) -> String {
+    unparse_with_fallbacks(syntax_tree, false).formatted
+}
+
+// Formats as much as prettyplease is able to. The rest is replaced by `...`,
+// or with `raw` by its tokens printed on one line.
+pub(crate) fn unparse_with_fallbacks(syntax_tree: &File, raw: bool) -> Unparsed {
     if let Ok(formatted) = panic::catch_unwind(|| prettyplease::unparse(syntax_tree)) {
-        return formatted;
+        return Unparsed {
+            formatted,
+            fallbacks: Vec::new(),
+        };
+    }
+
+    let mut unparse = UnparseMaximal {
+        raw,
+        fallbacks: Vec::new(),
+        verbatim: Vec::new(),
+    };
+    let redacted = unparse.fold_file(syntax_tree.clone());
+    let mut formatted = prettyplease::unparse(&redacted);
+
+    // In reverse, so that `Ξverbatim1` does not match the start of
+    // `Ξverbatim10`.
+    for (i, tokens) in unparse.verbatim.iter().enumerate().rev() {
+        let placeholder = format!("{}{}", VERBATIM_PLACEHOLDER, i);
+        formatted = formatted
+            .replace(&format!("{}!();", placeholder), tokens)
+            .replace(&placeholder, tokens);
+    }
+
+    Unparsed {
+        formatted,
+        fallbacks: unparse.fallbacks,
+    }
+}
+
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        (*message).to_owned()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "panicked".to_owned()
     }
+}
 
-    let redacted = UnparseMaximal.fold_file(syntax_tree.clone());
-    prettyplease::unparse(&redacted)
+struct UnparseMaximal {
+    raw: bool,
+    fallbacks: Vec<Fallback>,
+    // Tokens of the nodes replaced by a placeholder, by placeholder index.
+    verbatim: Vec<String>,
 }
 
-struct UnparseMaximal;
+// How many fallbacks and placeholders there were before folding a node, so
+// that the ones inside of it can be dropped if the whole node is replaced.
+type Mark = (usize, usize);
+
+impl UnparseMaximal {
+    fn mark(&self) -> Mark {
+        (self.fallbacks.len(), self.verbatim.len())
+    }
+
+    // Records that the node is being replaced, and returns the identifier to
+    // replace it with if its tokens are to be shown.
+    fn redact(
+        &mut self,
+        mark: Mark,
+        kind: &'static str,
+        name: Option<String>,
+        payload: &(dyn Any + Send),
+        tokens: TokenStream,
+    ) -> Option<Ident> {
+        self.fallbacks.truncate(mark.0);
+        self.verbatim.truncate(mark.1);
+        self.fallbacks.push(Fallback {
+            kind,
+            name,
+            reason: panic_message(payload),
+        });
+        if !self.raw {
+            return None;
+        }
+        let placeholder = format!("{}{}", VERBATIM_PLACEHOLDER, self.verbatim.len());
+        self.verbatim.push(tokens.to_string());
+        Some(Ident::new(&placeholder, Span::call_site()))
+    }
+}
+
+// `Ξverbatim0!();`
+fn verbatim_macro(ident: Ident) -> Macro {
+    Macro {
+        path: ident.into(),
+        bang_token: Token![!](Span::call_site()),
+        delimiter: MacroDelimiter::Paren(token::Paren(Span::call_site())),
+        tokens: TokenStream::new(),
+    }
+}
 
 impl Fold for UnparseMaximal {
     fn fold_item(&mut self, item: Item) -> Item {
@@ -34,13 +140,25 @@ impl Fold for UnparseMaximal {
             return item;
         }
 
+        let original = item.clone();
+        let mark = self.mark();
         file.items.push(fold::fold_item(self, item));
-        let ok = panic::catch_unwind(|| prettyplease::unparse(&file)).is_ok();
-        if ok {
-            return file.items.pop().unwrap();
-        }
+        let payload = match panic::catch_unwind(|| prettyplease::unparse(&file)) {
+            Ok(_) => return file.items.pop().unwrap(),
+            Err(payload) => payload,
+        };
 
-        Item::Verbatim(quote!(...))
+        let name = crate::json::item_ident(&original);
+        let tokens = original.into_token_stream();
+        match self.redact(mark, "item", name, &*payload, tokens) {
+            Some(ident) => Item::Macro(ItemMacro {
+                attrs: Vec::new(),
+                ident: None,
+                mac: verbatim_macro(ident),
+                semi_token: Some(Token![;](Span::call_site())),
+            }),
+            None => Item::Verbatim(quote!(...)),
+        }
     }
 
     fn fold_stmt(&mut self, stmt: Stmt) -> Stmt {
@@ -85,14 +203,27 @@ impl Fold for UnparseMaximal {
             return stmt;
         }
 
+        let original = stmt.clone();
+        let mark = self.mark();
         item_fn.block.stmts.push(fold::fold_stmt(self, stmt));
-        let ok = panic::catch_unwind(|| prettyplease::unparse(&file)).is_ok();
-        if ok {
-            let item_fn = unwrap_item_fn(&mut file.items[0]);
-            return item_fn.block.stmts.pop().unwrap();
-        }
+        let payload = match panic::catch_unwind(|| prettyplease::unparse(&file)) {
+            Ok(_) => {
+                let item_fn = unwrap_item_fn(&mut file.items[0]);
+                return item_fn.block.stmts.pop().unwrap();
+            }
+            Err(payload) => payload,
+        };
 
-        Stmt::Item(Item::Verbatim(quote!(...)))
+        let tokens = original.into_token_stream();
+        match self.redact(mark, "statement", None, &*payload, tokens) {
+            Some(ident) => Stmt::Item(Item::Macro(ItemMacro {
+                attrs: Vec::new(),
+                ident: None,
+                mac: verbatim_macro(ident),
+                semi_token: Some(Token![;](Span::call_site())),
+            })),
+            None => Stmt::Item(Item::Verbatim(quote!(...))),
+        }
     }
 
     fn fold_expr(&mut self, expr: Expr) -> Expr {
@@ -130,15 +261,27 @@ impl Fold for UnparseMaximal {
             return expr;
         }
 
+        let original = expr.clone();
+        let mark = self.mark();
         item_const.expr = Box::new(fold::fold_expr(self, expr));
         file.items.push(Item::Const(item_const));
-        let ok = panic::catch_unwind(|| prettyplease::unparse(&file)).is_ok();
-        if ok {
-            let item_const = unwrap_item_const(file.items.pop().unwrap());
-            return *item_const.expr;
-        }
+        let payload = match panic::catch_unwind(|| prettyplease::unparse(&file)) {
+            Ok(_) => {
+                let item_const = unwrap_item_const(file.items.pop().unwrap());
+                return *item_const.expr;
+            }
+            Err(payload) => payload,
+        };
 
-        Expr::Verbatim(quote!(...))
+        let tokens = original.into_token_stream();
+        match self.redact(mark, "expression", None, &*payload, tokens) {
+            Some(ident) => Expr::Path(ExprPath {
+                attrs: Vec::new(),
+                qself: None,
+                path: ident.into(),
+            }),
+            None => Expr::Verbatim(quote!(...)),
+        }
     }
 
     fn fold_foreign_item(&mut self, foreign_item: ForeignItem) -> ForeignItem {
@@ -172,16 +315,28 @@ impl Fold for UnparseMaximal {
             return foreign_item;
         }
 
+        let original = foreign_item.clone();
+        let mark = self.mark();
         item_foreign_mod
             .items
             .push(fold::fold_foreign_item(self, foreign_item));
-        let ok = panic::catch_unwind(|| prettyplease::unparse(&file)).is_ok();
-        if ok {
-            let item_foreign_mod = unwrap_item_foreign_mod(&mut file.items[0]);
-            return item_foreign_mod.items.pop().unwrap();
-        }
+        let payload = match panic::catch_unwind(|| prettyplease::unparse(&file)) {
+            Ok(_) => {
+                let item_foreign_mod = unwrap_item_foreign_mod(&mut file.items[0]);
+                return item_foreign_mod.items.pop().unwrap();
+            }
+            Err(payload) => payload,
+        };
 
-        ForeignItem::Verbatim(quote!(...))
+        let tokens = original.into_token_stream();
+        match self.redact(mark, "foreign item", None, &*payload, tokens) {
+            Some(ident) => ForeignItem::Macro(ForeignItemMacro {
+                attrs: Vec::new(),
+                mac: verbatim_macro(ident),
+                semi_token: Some(Token![;](Span::call_site())),
+            }),
+            None => ForeignItem::Verbatim(quote!(...)),
+        }
     }
 
     fn fold_trait_item(&mut self, trait_item: TraitItem) -> TraitItem {
@@ -219,16 +374,28 @@ impl Fold for UnparseMaximal {
             return trait_item;
         }
 
+        let original = trait_item.clone();
+        let mark = self.mark();
         item_trait
             .items
             .push(fold::fold_trait_item(self, trait_item));
-        let ok = panic::catch_unwind(|| prettyplease::unparse(&file)).is_ok();
-        if ok {
-            let item_trait = unwrap_item_trait(&mut file.items[0]);
-            return item_trait.items.pop().unwrap();
-        }
+        let payload = match panic::catch_unwind(|| prettyplease::unparse(&file)) {
+            Ok(_) => {
+                let item_trait = unwrap_item_trait(&mut file.items[0]);
+                return item_trait.items.pop().unwrap();
+            }
+            Err(payload) => payload,
+        };
 
-        TraitItem::Verbatim(quote!(...))
+        let tokens = original.into_token_stream();
+        match self.redact(mark, "trait item", None, &*payload, tokens) {
+            Some(ident) => TraitItem::Macro(TraitItemMacro {
+                attrs: Vec::new(),
+                mac: verbatim_macro(ident),
+                semi_token: Some(Token![;](Span::call_site())),
+            }),
+            None => TraitItem::Verbatim(quote!(...)),
+        }
     }
 
     fn fold_impl_item(&mut self, impl_item: ImplItem) -> ImplItem {
@@ -265,13 +432,106 @@ impl Fold for UnparseMaximal {
             return impl_item;
         }
 
+        let original = impl_item.clone();
+        let mark = self.mark();
         item_impl.items.push(fold::fold_impl_item(self, impl_item));
-        let ok = panic::catch_unwind(|| prettyplease::unparse(&file)).is_ok();
-        if ok {
-            let item_impl = unwrap_item_impl(&mut file.items[0]);
-            return item_impl.items.pop().unwrap();
+        let payload = match panic::catch_unwind(|| prettyplease::unparse(&file)) {
+            Ok(_) => {
+                let item_impl = unwrap_item_impl(&mut file.items[0]);
+                return item_impl.items.pop().unwrap();
+            }
+            Err(payload) => payload,
+        };
+
+        let tokens = original.into_token_stream();
+        match self.redact(mark, "impl item", None, &*payload, tokens) {
+            Some(ident) => ImplItem::Macro(ImplItemMacro {
+                attrs: Vec::new(),
+                mac: verbatim_macro(ident),
+                semi_token: Some(Token![;](Span::call_site())),
+            }),
+            None => ImplItem::Verbatim(quote!(...)),
         }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use syn::parse_quote;
+
+    // A function whose body prettyplease cannot print.
+    fn unprintable_fn() -> File {
+        let mut item_fn: ItemFn = parse_quote! {
+            fn f() {
+                let x = 1;
+                let y = 2;
+            }
+        };
+        let Stmt::Local(local) = &mut item_fn.block.stmts[1] else {
+            unreachable!()
+        };
+        *local.init.as_mut().unwrap().expr = Expr::Verbatim(quote!(? ? ?));
+        File {
+            shebang: None,
+            attrs: Vec::new(),
+            items: vec![Item::Fn(item_fn)],
+        }
+    }
+
+    #[test]
+    fn test_printable() {
+        let syntax_tree: File = parse_quote!(
+            fn f() {}
+        );
+        let unparsed = unparse_with_fallbacks(&syntax_tree, false);
+        assert_eq!(unparsed.formatted, "fn f() {}\n");
+        assert!(unparsed.fallbacks.is_empty());
+    }
+
+    #[test]
+    fn test_redacted_expr() {
+        let unparsed = unparse_with_fallbacks(&unprintable_fn(), false);
+        assert_eq!(
+            unparsed.formatted,
+            "fn f() {\n    let x = 1;\n    let y = ...;\n}\n",
+        );
+        assert_eq!(unparsed.fallbacks.len(), 1);
+        assert_eq!(unparsed.fallbacks[0].kind, "expression");
+        assert_eq!(
+            unparsed.fallbacks[0].reason,
+            "not implemented: Expr::Verbatim `? ? ?`",
+        );
+    }
+
+    #[test]
+    fn test_raw_expr() {
+        let unparsed = unparse_with_fallbacks(&unprintable_fn(), true);
+        assert_eq!(
+            unparsed.formatted,
+            "fn f() {\n    let x = 1;\n    let y = ? ? ?;\n}\n",
+        );
+        assert_eq!(unparsed.fallbacks.len(), 1);
+    }
+
+    #[test]
+    fn test_redacted_item() {
+        let syntax_tree = File {
+            shebang: None,
+            attrs: Vec::new(),
+            items: vec![
+                parse_quote!(
+                    struct S;
+                ),
+                Item::Verbatim(quote!(? ? ?)),
+            ],
+        };
+        let unparsed = unparse_with_fallbacks(&syntax_tree, false);
+        assert_eq!(unparsed.formatted, "struct S;\n...\n");
+        assert_eq!(unparsed.fallbacks.len(), 1);
+        assert_eq!(unparsed.fallbacks[0].kind, "item");
 
-        ImplItem::Verbatim(quote!(...))
+        let unparsed = unparse_with_fallbacks(&syntax_tree, true);
+        assert_eq!(unparsed.formatted, "struct S;\n? ? ?\n");
     }
 }
@@ -0,0 +1,249 @@
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::manifest::{self, CargoDependency};
+use crate::metadata;
+use crate::opts::Coloring;
+use crate::{expand_once, Expand};
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use std::env;
+use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::time::Duration;
+
+// Editors tend to produce a burst of events for a single save. Wait this long
+// for things to settle before expanding again.
+const DEBOUNCE: Duration = Duration::from_millis(100);
+
+pub fn cargo_expand_watch(
+    args: &Expand,
+    mut config: Config,
+    color: &Coloring,
+    rustfmt: Option<&Path>,
+) -> Result<i32> {
+    // A pager would block the next expansion until it is dismissed.
+    config.pager = false;
+
+    let manifest_path = fs::canonicalize(manifest::find_cargo_manifest(
+        args.manifest_path.as_deref(),
+    )?)?;
+    let roots = watch_roots(&manifest_path);
+    let target_dirs = target_dirs(args, &roots)?;
+
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    for root in &roots {
+        watcher.watch(root, RecursiveMode::Recursive)?;
+    }
+
+    loop {
+        clear_terminal();
+        match expand_once(args, &config, color, rustfmt) {
+            Ok(_code) => {}
+            // Keep watching for the fix.
+            Err(err @ (Error::Build { .. } | Error::EmptyOutput | Error::NoSuchItem(_))) => {
+                let _ = writeln!(io::stderr(), "ERROR: {}", err);
+            }
+            Err(err) => return Err(err),
+        }
+        let _ = writeln!(
+            io::stderr(),
+            "\nWatching {} for changes...",
+            roots[0].display(),
+        );
+        if !wait_for_change(&rx, &target_dirs) {
+            return Ok(0);
+        }
+    }
+}
+
+// The package directory, followed by the directories of its path
+// dependencies, which is where proc macros under development usually live.
+fn watch_roots(manifest_path: &Path) -> Vec<PathBuf> {
+    let package_dir = manifest_path.parent().unwrap().to_owned();
+    let mut roots = vec![package_dir.clone()];
+    if let Ok(cargo_manifest) = manifest::parse(Some(manifest_path)) {
+        let dependencies = cargo_manifest
+            .dependencies
+            .values()
+            .chain(cargo_manifest.dev_dependencies.values());
+        for dependency in dependencies {
+            if let CargoDependency::Detailed { path: Some(path) } = dependency {
+                if let Ok(path) = fs::canonicalize(package_dir.join(path)) {
+                    if !roots.iter().any(|root| path.starts_with(root)) {
+                        roots.push(path);
+                    }
+                }
+            }
+        }
+    }
+    roots
+}
+
+// Where builds write to, whose changes are not to trigger another expansion:
+// the target directory that cargo reports, which takes .cargo/config into
+// account, the one given by --target-dir or CARGO_TARGET_DIR, and the default
+// one of each root.
+fn target_dirs(args: &Expand, roots: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let cwd = env::current_dir()?;
+    let mut target_dirs: Vec<PathBuf> = roots.iter().map(|root| root.join("target")).collect();
+    target_dirs.extend(args.target_dir.iter().cloned());
+    target_dirs.extend(env::var_os("CARGO_TARGET_DIR").map(PathBuf::from));
+    if let Ok(Some(metadata)) = metadata::cargo_metadata(args, true) {
+        target_dirs.push(metadata.target_directory);
+    }
+    Ok(target_dirs
+        .iter()
+        .map(|dir| resolve_dir(dir, &cwd))
+        .collect())
+}
+
+// The absolute path of `dir` relative to `cwd`, with symlinks resolved like
+// in the paths of events, even if `dir` has not been created yet.
+fn resolve_dir(dir: &Path, cwd: &Path) -> PathBuf {
+    let dir = cwd.join(dir);
+    let mut existing = dir.as_path();
+    let mut missing = Vec::new();
+    loop {
+        if let Ok(canonical) = fs::canonicalize(existing) {
+            return missing
+                .iter()
+                .rev()
+                .fold(canonical, |path, name| path.join(name));
+        }
+        match (existing.parent(), existing.file_name()) {
+            (Some(parent), Some(name)) => {
+                missing.push(name);
+                existing = parent;
+            }
+            _ => return dir,
+        }
+    }
+}
+
+// Blocks until a source file changes. Returns false if the watcher went away.
+fn wait_for_change(rx: &Receiver<notify::Result<Event>>, target_dirs: &[PathBuf]) -> bool {
+    loop {
+        match rx.recv() {
+            Ok(Ok(event)) if is_relevant(&event, target_dirs) => break,
+            Ok(_) => {}
+            Err(_) => return false,
+        }
+    }
+    loop {
+        match rx.recv_timeout(DEBOUNCE) {
+            Ok(_) => {}
+            Err(RecvTimeoutError::Timeout) => return true,
+            Err(RecvTimeoutError::Disconnected) => return false,
+        }
+    }
+}
+
+fn is_relevant(event: &Event, target_dirs: &[PathBuf]) -> bool {
+    match event.kind {
+        EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_) => {}
+        EventKind::Access(_) | EventKind::Any | EventKind::Other => return false,
+    }
+    event.paths.iter().any(|path| {
+        let is_source = path.extension().is_some_and(|ext| ext == "rs")
+            || path.file_name().is_some_and(|name| name == "Cargo.toml");
+        is_source && !target_dirs.iter().any(|dir| path.starts_with(dir))
+    })
+}
+
+fn clear_terminal() {
+    let mut stdout = io::stdout();
+    if stdout.is_terminal() {
+        // Clear the screen and scrollback, and move the cursor home.
+        let _ = write!(stdout, "\x1b[2J\x1b[3J\x1b[H");
+        let _ = stdout.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::event::{AccessKind, CreateKind, ModifyKind, RemoveKind};
+
+    fn event(kind: EventKind, path: &str) -> Event {
+        Event::new(kind).add_path(PathBuf::from(path))
+    }
+
+    #[test]
+    fn test_is_relevant() {
+        let target_dirs = [PathBuf::from("/ws/target"), PathBuf::from("/tmp/t")];
+        let modify = EventKind::Modify(ModifyKind::Any);
+        assert!(is_relevant(&event(modify, "/ws/src/lib.rs"), &target_dirs));
+        assert!(is_relevant(&event(modify, "/ws/Cargo.toml"), &target_dirs));
+        let create = EventKind::Create(CreateKind::File);
+        assert!(is_relevant(&event(create, "/ws/src/new.rs"), &target_dirs));
+        let remove = EventKind::Remove(RemoveKind::File);
+        assert!(is_relevant(&event(remove, "/ws/src/old.rs"), &target_dirs));
+
+        let access = EventKind::Access(AccessKind::Any);
+        assert!(!is_relevant(&event(access, "/ws/src/lib.rs"), &target_dirs));
+        assert!(!is_relevant(&event(modify, "/ws/README.md"), &target_dirs));
+        assert!(!is_relevant(&event(modify, "/ws/Cargo.lock"), &target_dirs));
+        let build_output = "/ws/target/debug/build/x/out/generated.rs";
+        assert!(!is_relevant(&event(modify, build_output), &target_dirs));
+        assert!(!is_relevant(&event(modify, "/tmp/t/lib.rs"), &target_dirs));
+        // Only a path within a target directory is left out.
+        assert!(is_relevant(&event(modify, "/ws/targets.rs"), &target_dirs));
+    }
+
+    #[test]
+    fn test_watch_roots() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = fs::canonicalize(dir.path()).unwrap();
+        let package_dir = dir.join("package");
+        for path in ["package/inner", "macros", "dev"] {
+            fs::create_dir_all(dir.join(path)).unwrap();
+        }
+        let manifest_path = package_dir.join("Cargo.toml");
+        fs::write(
+            &manifest_path,
+            r#"
+            [package]
+            name = "package"
+
+            [dependencies]
+            inner = { path = "inner" }
+            macros = { path = "../macros" }
+            missing = { path = "../missing" }
+            serde = "1.0"
+
+            [dev-dependencies]
+            dev = { path = "../dev" }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            watch_roots(&manifest_path),
+            [package_dir, dir.join("macros"), dir.join("dev")],
+        );
+    }
+
+    #[test]
+    fn test_resolve_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let cwd = fs::canonicalize(dir.path()).unwrap();
+        fs::create_dir(cwd.join("existing")).unwrap();
+        assert_eq!(
+            resolve_dir(Path::new("existing"), &cwd),
+            cwd.join("existing")
+        );
+        assert_eq!(
+            resolve_dir(Path::new("not/created"), &cwd),
+            cwd.join("not/created"),
+        );
+        assert_eq!(
+            resolve_dir(Path::new("existing/../other"), &cwd),
+            cwd.join("other"),
+        );
+        assert_eq!(
+            resolve_dir(Path::new("/abs/target"), &cwd),
+            Path::new("/abs/target")
+        );
+    }
+}
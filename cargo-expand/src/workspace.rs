@@ -0,0 +1,502 @@
+use crate::cmd::Line;
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::metadata::{self, Metadata, Package, Target};
+use crate::opts::Coloring;
+use crate::toolchain::{self, Route};
+use crate::{format_expanded, make_tempdir, manifest, output, run_cargo, Expand};
+use std::fmt::{self, Display};
+use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
+
+#[derive(Copy, Clone, PartialEq)]
+enum TargetKind {
+    Lib,
+    Bin,
+    Example,
+    Test,
+    Bench,
+}
+
+// One package target to expand.
+pub struct Unit {
+    package: String,
+    kind: TargetKind,
+    target: String,
+    src_path: PathBuf,
+}
+
+// A unit being expanded by one of the workers.
+pub struct Job<'a> {
+    unit: &'a Unit,
+}
+
+// Runs the builds of the units one at a time.
+struct Builder {
+    route: Route,
+    lock: Mutex<()>,
+}
+
+pub fn cargo_expand_workspace(
+    args: &Expand,
+    config: &Config,
+    color: &Coloring,
+    rustfmt: Option<&Path>,
+) -> Result<i32> {
+    let metadata = match metadata::cargo_metadata(args, true)? {
+        Some(metadata) => metadata,
+        None => return Ok(1),
+    };
+
+    let packages = match select_packages(args, &metadata)? {
+        Ok(packages) => packages,
+        Err(code) => return Ok(code),
+    };
+    let units: Vec<Unit> = packages
+        .iter()
+        .flat_map(|package| select_targets(args, package))
+        .collect();
+    if units.is_empty() {
+        let _ = writeln!(io::stderr(), "ERROR: no targets to expand");
+        return Ok(1);
+    }
+
+    let output = args
+        .output
+        .clone()
+        .unwrap_or_else(|| metadata.target_directory.join("expand"));
+
+    // Every unit builds in the one target directory, so that dependencies are
+    // built once and what has already been built is reused. Cargo would only
+    // let one build at a time use it anyway, so the builds run one after
+    // another, each with all of --jobs, while a second worker formats and
+    // writes out the unit built before.
+    let workers = units.len().min(2);
+    let builder = Builder {
+        route: toolchain::route(args.manifest_path.as_deref(), args.verbose, color)?,
+        lock: Mutex::new(()),
+    };
+
+    let next = AtomicUsize::new(0);
+    let failed = AtomicUsize::new(0);
+    let error = Mutex::new(None);
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| {
+                while let Some(unit) = units.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    let job = Job { unit };
+                    match expand_unit(args, &builder, &job, config, color, rustfmt, &output) {
+                        Ok(Some(path)) => {
+                            print_status("Expanded", Color::Green, color, unit, Some(&path));
+                        }
+                        Ok(None) => {
+                            failed.fetch_add(1, Ordering::Relaxed);
+                            print_status("Failed", Color::Red, color, unit, None);
+                        }
+                        Err(err) => {
+                            failed.fetch_add(1, Ordering::Relaxed);
+                            print_status("Failed", Color::Red, color, unit, None);
+                            error.lock().unwrap().get_or_insert(err);
+                        }
+                    }
+                }
+            });
+        }
+    });
+
+    if let Some(err) = error.into_inner().unwrap() {
+        return Err(err);
+    }
+    let failed = failed.into_inner();
+    let _ = writeln!(
+        io::stderr(),
+        "\nExpanded {} of {} targets into {}",
+        units.len() - failed,
+        units.len(),
+        output.display(),
+    );
+    Ok(if failed == 0 { 0 } else { 1 })
+}
+
+// With --workspace, every member. Otherwise the package selected by --package
+// or the current directory, or every member of a virtual workspace.
+pub fn select_packages<'a>(
+    args: &Expand,
+    metadata: &'a Metadata,
+) -> Result<std::result::Result<Vec<&'a Package>, i32>> {
+    let members: Vec<&Package> = metadata
+        .packages
+        .iter()
+        .filter(|package| metadata.workspace_members.contains(&package.id))
+        .collect();
+    if args.workspace {
+        return Ok(Ok(members));
+    }
+
+    if let Some(Some(name)) = &args.package {
+        return Ok(match members.iter().find(|package| package.name == *name) {
+            Some(package) => Ok(vec![package]),
+            None => {
+                let _ = writeln!(
+                    io::stderr(),
+                    "ERROR: package `{}` is not a member of the workspace",
+                    name,
+                );
+                Err(1)
+            }
+        });
+    }
+
+    let manifest_path = fs::canonicalize(manifest::find_cargo_manifest(
+        args.manifest_path.as_deref(),
+    )?)?;
+    for package in &members {
+        if fs::canonicalize(&package.manifest_path).ok().as_ref() == Some(&manifest_path) {
+            return Ok(Ok(vec![package]));
+        }
+    }
+    Ok(Ok(members))
+}
+
+// With --all-targets, every target except build scripts. Otherwise the
+// targets picked by --lib, --bin and so on, or else the library, or else all
+// binaries.
+pub fn select_targets(args: &Expand, package: &Package) -> Vec<Unit> {
+    let targets = package
+        .targets
+        .iter()
+        .filter_map(|target| Some((TargetKind::of(target)?, target)));
+
+    let explicit = args.lib
+        || args.bin.is_some()
+        || args.example.is_some()
+        || args.test.is_some()
+        || args.bench.is_some();
+    let selected: Vec<(TargetKind, &Target)> = if args.all_targets {
+        targets.collect()
+    } else if explicit {
+        targets
+            .filter(|(kind, target)| {
+                let requested = match kind {
+                    TargetKind::Lib => return args.lib,
+                    TargetKind::Bin => &args.bin,
+                    TargetKind::Example => &args.example,
+                    TargetKind::Test => &args.test,
+                    TargetKind::Bench => &args.bench,
+                };
+                match requested {
+                    Some(Some(name)) => *name == target.name,
+                    Some(None) => true,
+                    None => false,
+                }
+            })
+            .collect()
+    } else {
+        let all: Vec<(TargetKind, &Target)> = targets.collect();
+        let lib: Vec<(TargetKind, &Target)> = all
+            .iter()
+            .copied()
+            .filter(|(kind, _)| *kind == TargetKind::Lib)
+            .collect();
+        if lib.is_empty() {
+            all.into_iter()
+                .filter(|(kind, _)| *kind == TargetKind::Bin)
+                .collect()
+        } else {
+            lib
+        }
+    };
+
+    selected
+        .into_iter()
+        .map(|(kind, target)| Unit {
+            package: package.name.clone(),
+            kind,
+            target: target.name.clone(),
+            src_path: target.src_path.clone(),
+        })
+        .collect()
+}
+
+// Returns the path of the written crate root, or None if the build failed.
+fn expand_unit(
+    args: &Expand,
+    builder: &Builder,
+    job: &Job,
+    config: &Config,
+    color: &Coloring,
+    rustfmt: Option<&Path>,
+    output: &Path,
+) -> Result<Option<PathBuf>> {
+    let outdir = make_tempdir();
+    let outfile_path = outdir.path().join("expanded");
+
+    let mut content = match builder.build(args, job, color, &outfile_path) {
+        Ok(content) => content,
+        Err(Error::Build { .. } | Error::EmptyOutput) => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    if !args.ugly {
+        (_, content) = format_expanded(content, args, config, color, rustfmt, &outdir)?;
+    }
+
+    let unit = job.unit;
+    let dir = output
+        .join(&unit.package)
+        .join(unit.kind.to_string())
+        .join(&unit.target);
+    let root = output::write_modules(&content, !args.ugly, &dir)?;
+    Ok(Some(root))
+}
+
+fn print_status(
+    status: &str,
+    status_color: Color,
+    color: &Coloring,
+    unit: &Unit,
+    path: Option<&Path>,
+) {
+    let color_choice = match color {
+        Coloring::Auto if io::stderr().is_terminal() => ColorChoice::Auto,
+        Coloring::Auto | Coloring::Never => ColorChoice::Never,
+        Coloring::Always => ColorChoice::Always,
+    };
+
+    let stream = StandardStream::stderr(color_choice);
+    let mut stream = stream.lock();
+    let _ = stream.set_color(ColorSpec::new().set_bold(true).set_fg(Some(status_color)));
+    let _ = write!(stream, "{:>12}", status);
+    let _ = stream.reset();
+    let _ = write!(
+        stream,
+        " {} ({} `{}`)",
+        unit.package, unit.kind, unit.target
+    );
+    if let Some(path) = path {
+        let _ = write!(stream, " -> {}", path.display());
+    }
+    let _ = writeln!(stream);
+}
+
+impl TargetKind {
+    fn of(target: &Target) -> Option<Self> {
+        let has = |kind: &str| target.kind.iter().any(|k| k == kind);
+        if has("custom-build") {
+            None
+        } else if has("bin") {
+            Some(TargetKind::Bin)
+        } else if has("example") {
+            Some(TargetKind::Example)
+        } else if has("test") {
+            Some(TargetKind::Test)
+        } else if has("bench") {
+            Some(TargetKind::Bench)
+        } else {
+            Some(TargetKind::Lib)
+        }
+    }
+}
+
+impl Unit {
+    // The crate root of the target.
+    pub fn src_path(&self) -> &Path {
+        &self.src_path
+    }
+}
+
+impl Display for TargetKind {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str(match self {
+            TargetKind::Lib => "lib",
+            TargetKind::Bin => "bin",
+            TargetKind::Example => "example",
+            TargetKind::Test => "test",
+            TargetKind::Bench => "bench",
+        })
+    }
+}
+
+impl Builder {
+    fn build(
+        &self,
+        args: &Expand,
+        job: &Job,
+        color: &Coloring,
+        outfile_path: &Path,
+    ) -> Result<String> {
+        let _building = self.lock.lock().unwrap();
+        run_cargo(args, self.route, Some(job), color, outfile_path)
+    }
+}
+
+impl<'a> Job<'a> {
+    // Adds the arguments that select this job's package and target.
+    pub fn select(&self, line: &mut Line) {
+        line.arg("--package");
+        line.arg(&self.unit.package);
+        match self.unit.kind {
+            TargetKind::Lib => line.arg("--lib"),
+            kind => {
+                line.arg(format!("--{}", kind));
+                line.arg(&self.unit.target);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_metadata(dir: &Path) -> Metadata {
+        let package = |name: &str, targets: &[(&str, &str)]| {
+            let targets: Vec<String> = targets
+                .iter()
+                .map(|(name, kind)| {
+                    format!(
+                        r#"{{"name": "{}", "kind": ["{}"], "src_path": "/src/{}.rs"}}"#,
+                        name, kind, name,
+                    )
+                })
+                .collect();
+            format!(
+                r#"{{"id": "{name}", "name": "{name}", "version": "0.1.0", "source": null,
+                    "edition": "2021", "manifest_path": {manifest_path:?},
+                    "targets": [{targets}]}}"#,
+                name = name,
+                manifest_path = dir.join(name).join("Cargo.toml"),
+                targets = targets.join(", "),
+            )
+        };
+        let packages = [
+            package(
+                "app",
+                &[
+                    ("app", "lib"),
+                    ("app", "bin"),
+                    ("tool", "bin"),
+                    ("demo", "example"),
+                    ("it", "test"),
+                    ("speed", "bench"),
+                    ("build-script-build", "custom-build"),
+                ],
+            ),
+            package("cli", &[("cli", "bin"), ("other", "bin")]),
+            package("dep", &[("dep", "lib")]),
+        ];
+        let json = format!(
+            r#"{{"packages": [{}], "workspace_members": ["app", "cli"],
+                "target_directory": "/target", "resolve": null}}"#,
+            packages.join(", "),
+        );
+        serde_json::from_str(&json).unwrap()
+    }
+
+    fn names(packages: Vec<&Package>) -> Vec<&str> {
+        packages
+            .iter()
+            .map(|package| package.name.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_select_packages() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["app", "cli"] {
+            fs::create_dir(dir.path().join(name)).unwrap();
+            fs::write(dir.path().join(name).join("Cargo.toml"), "").unwrap();
+        }
+        fs::write(dir.path().join("Cargo.toml"), "").unwrap();
+        let metadata = test_metadata(dir.path());
+        let select = |args: Expand| {
+            select_packages(&args, &metadata)
+                .unwrap_or_else(|err| panic!("{}", err))
+                .map(names)
+        };
+
+        let args = Expand {
+            workspace: true,
+            ..Expand::default()
+        };
+        assert_eq!(select(args), Ok(vec!["app", "cli"]));
+
+        let args = Expand {
+            package: Some(Some("cli".to_owned())),
+            ..Expand::default()
+        };
+        assert_eq!(select(args), Ok(vec!["cli"]));
+        let args = Expand {
+            package: Some(Some("dep".to_owned())),
+            ..Expand::default()
+        };
+        assert_eq!(select(args), Err(1));
+
+        let args = Expand {
+            manifest_path: Some(dir.path().join("cli/Cargo.toml")),
+            ..Expand::default()
+        };
+        assert_eq!(select(args), Ok(vec!["cli"]));
+        // The root of a virtual workspace selects every member.
+        let args = Expand {
+            manifest_path: Some(dir.path().join("Cargo.toml")),
+            ..Expand::default()
+        };
+        assert_eq!(select(args), Ok(vec!["app", "cli"]));
+    }
+
+    #[test]
+    fn test_select_targets() {
+        let metadata = test_metadata(Path::new("/ws"));
+        let select = |args: Expand, package: usize| -> Vec<String> {
+            select_targets(&args, &metadata.packages[package])
+                .iter()
+                .map(|unit| format!("{} {}", unit.kind, unit.target))
+                .collect()
+        };
+
+        assert_eq!(select(Expand::default(), 0), ["lib app"]);
+        assert_eq!(select(Expand::default(), 1), ["bin cli", "bin other"]);
+
+        let args = Expand {
+            all_targets: true,
+            ..Expand::default()
+        };
+        assert_eq!(
+            select(args, 0),
+            [
+                "lib app",
+                "bin app",
+                "bin tool",
+                "example demo",
+                "test it",
+                "bench speed",
+            ],
+        );
+
+        let args = Expand {
+            lib: true,
+            bin: Some(Some("tool".to_owned())),
+            ..Expand::default()
+        };
+        assert_eq!(select(args, 0), ["lib app", "bin tool"]);
+        let args = Expand {
+            bin: Some(None),
+            test: Some(Some("it".to_owned())),
+            ..Expand::default()
+        };
+        assert_eq!(select(args, 0), ["bin app", "bin tool", "test it"]);
+        let args = Expand {
+            example: Some(Some("missing".to_owned())),
+            ..Expand::default()
+        };
+        assert!(select(args, 0).is_empty());
+
+        let units = select_targets(&Expand::default(), &metadata.packages[0]);
+        assert_eq!(units[0].src_path(), Path::new("/src/app.rs"));
+    }
+}
@@ -0,0 +1,96 @@
+use cargo_expand::Expand;
+use clap::Parser;
+use std::ffi::OsString;
+use std::fs;
+use std::path::Path;
+use syn::Item;
+
+const MANIFEST: &str = r#"
+[package]
+name = "fixture"
+version = "0.0.0"
+edition = "2021"
+publish = false
+
+[workspace]
+"#;
+
+const LIB: &str = r#"
+macro_rules! double {
+    ($e:expr) => {
+        $e * 2
+    };
+}
+
+#[derive(Clone)]
+pub struct S;
+
+pub fn four() -> i32 {
+    double!(2)
+}
+"#;
+
+fn fixture(dir: &Path) {
+    fs::create_dir_all(dir.join("src")).unwrap();
+    fs::write(dir.join("Cargo.toml"), MANIFEST).unwrap();
+    fs::write(dir.join("src/lib.rs"), LIB).unwrap();
+}
+
+fn expand(dir: &Path, args: &[&str]) -> cargo_expand::Expansion {
+    let mut argv: Vec<OsString> = vec!["expand".into(), "--color".into(), "never".into()];
+    argv.push("--manifest-path".into());
+    argv.push(dir.join("Cargo.toml").into());
+    argv.push("--target-dir".into());
+    argv.push(dir.join("target").into());
+    argv.extend(args.iter().map(OsString::from));
+    let args = Expand::try_parse_from(argv).unwrap_or_else(|err| panic!("{}", err));
+    cargo_expand::expand(args).unwrap_or_else(|err| panic!("{}", err))
+}
+
+#[test]
+fn test_expand() {
+    let dir = tempfile::tempdir().unwrap();
+    fixture(dir.path());
+
+    let expansion = expand(dir.path(), &[]);
+    assert!(expansion.raw.contains("macro_rules! double"));
+
+    let syntax_tree = expansion.syntax_tree.unwrap();
+    let names: Vec<String> = syntax_tree
+        .items
+        .iter()
+        .filter_map(|item| match item {
+            Item::Struct(item) => Some(format!("struct {}", item.ident)),
+            Item::Fn(item) => Some(format!("fn {}", item.sig.ident)),
+            Item::Impl(item) => Some(format!(
+                "impl {}",
+                item.trait_.as_ref()?.1.segments.last()?.ident
+            )),
+            Item::Macro(_) => Some("macro".to_owned()),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(names, ["struct S", "impl Clone", "fn four"]);
+
+    assert!(expansion
+        .formatted
+        .contains("impl ::core::clone::Clone for S {"));
+    assert!(expansion
+        .formatted
+        .contains("pub fn four() -> i32 {\n    2 * 2\n}\n"));
+    assert!(!expansion.formatted.contains("macro_rules!"));
+}
+
+#[test]
+fn test_expand_item() {
+    let dir = tempfile::tempdir().unwrap();
+    fixture(dir.path());
+
+    let expansion = expand(dir.path(), &["four"]);
+    let syntax_tree = expansion.syntax_tree.unwrap();
+    assert_eq!(syntax_tree.items.len(), 1);
+    assert_eq!(
+        expansion.formatted,
+        "pub fn four() -> i32 {\n    2 * 2\n}\n"
+    );
+}
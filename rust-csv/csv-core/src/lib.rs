@@ -7,6 +7,14 @@ enabled by default.
 If you're looking for more ergonomic CSV parsing routines, please use the
 [`csv`](https://docs.rs/csv) crate.
 
+# Crate features
+
+* `alloc` - Enables the `Record` and `RecordReader` types, which parse CSV
+  data into growable buffers using only the `alloc` crate. This is useful in
+  `no_std` environments that have a heap, since callers no longer need to
+  manage the output and field end buffers passed to `Reader::read_record`.
+* `libc` - Enables `memchr`'s use of `libc`.
+
 # Overview
 
 This crate has two primary APIs. The `Reader` API provides a CSV parser, and
@@ -99,15 +107,22 @@ foo,\"bar,baz\"
 #![deny(missing_docs)]
 #![no_std]
 
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
 pub use crate::reader::{
     ReadFieldNoCopyResult, ReadFieldResult, ReadRecordNoCopyResult,
     ReadRecordResult, Reader, ReaderBuilder,
 };
+#[cfg(feature = "alloc")]
+pub use crate::record::{Chunks, Record, RecordIter, RecordReader, Source};
 pub use crate::writer::{
     is_non_numeric, quote, WriteResult, Writer, WriterBuilder,
 };
 
 mod reader;
+#[cfg(feature = "alloc")]
+mod record;
 mod writer;
 
 /// A record terminator.
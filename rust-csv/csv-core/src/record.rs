@@ -0,0 +1,378 @@
+use core::{convert::Infallible, fmt, ops::Range};
+
+use alloc::vec::Vec;
+
+use crate::{ReadRecordResult, Reader};
+
+/// A single CSV record stored in growable buffers.
+///
+/// A record is a sequence of fields, where each field is an arbitrary
+/// sequence of bytes. Unlike the low level `Reader::read_record` API, a
+/// `Record` manages its own field data and field end positions, so callers
+/// never have to size or grow those buffers by hand.
+///
+/// A record is typically reused across calls to
+/// [`RecordReader::read_record`](struct.RecordReader.html#method.read_record)
+/// in order to amortize allocation.
+///
+/// This type is only available when the `alloc` feature is enabled.
+#[derive(Clone, Default)]
+pub struct Record {
+    /// The unescaped data of all fields in this record, stored contiguously.
+    fields: Vec<u8>,
+    /// The ending index of each field in `fields`.
+    ///
+    /// Only the first `len` entries are valid. The rest is spare capacity
+    /// handed to the parser.
+    ends: Vec<usize>,
+    /// The number of fields in this record.
+    len: usize,
+}
+
+impl Record {
+    /// Create a new empty record.
+    pub fn new() -> Record {
+        Record::default()
+    }
+
+    /// Create a new empty record with room for `buffer` bytes of field data
+    /// and `fields` fields.
+    pub fn with_capacity(buffer: usize, fields: usize) -> Record {
+        let mut record = Record::new();
+        record.fields.resize(buffer, 0);
+        record.ends.resize(fields, 0);
+        record
+    }
+
+    /// Returns the number of fields in this record.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns true if and only if this record has no fields.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the field at index `i`, or `None` if `i` is out of bounds.
+    pub fn get(&self, i: usize) -> Option<&[u8]> {
+        self.range(i).map(|range| &self.fields[range])
+    }
+
+    /// Returns an iterator over all fields in this record.
+    pub fn iter(&self) -> RecordIter<'_> {
+        RecordIter { record: self, i: 0 }
+    }
+
+    /// Returns the data of all fields in this record, concatenated.
+    pub fn as_slice(&self) -> &[u8] {
+        match self.len {
+            0 => &[],
+            n => &self.fields[..self.ends[n - 1]],
+        }
+    }
+
+    /// Remove all fields from this record, retaining its allocations.
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    /// Add a new field to the end of this record.
+    pub fn push_field(&mut self, field: &[u8]) {
+        let start = self.as_slice().len();
+        let end = start + field.len();
+        if self.fields.len() < end {
+            self.fields.resize(end, 0);
+        }
+        self.fields[start..end].copy_from_slice(field);
+        if self.ends.len() <= self.len {
+            self.ends.push(end);
+        } else {
+            self.ends[self.len] = end;
+        }
+        self.len += 1;
+    }
+
+    fn range(&self, i: usize) -> Option<Range<usize>> {
+        if i >= self.len {
+            return None;
+        }
+        let start = if i == 0 { 0 } else { self.ends[i - 1] };
+        Some(start..self.ends[i])
+    }
+
+    /// Double the capacity for field data, with a reasonable minimum.
+    fn expand_fields(&mut self) {
+        let new_len =
+            core::cmp::max(4, self.fields.len().checked_mul(2).unwrap());
+        self.fields.resize(new_len, 0);
+    }
+
+    /// Double the capacity for field end positions, with a reasonable
+    /// minimum.
+    fn expand_ends(&mut self) {
+        let new_len =
+            core::cmp::max(4, self.ends.len().checked_mul(2).unwrap());
+        self.ends.resize(new_len, 0);
+    }
+}
+
+impl PartialEq for Record {
+    fn eq(&self, other: &Record) -> bool {
+        self.iter().eq(other.iter())
+    }
+}
+
+impl Eq for Record {}
+
+impl fmt::Debug for Record {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut list = f.debug_list();
+        for field in self.iter() {
+            match core::str::from_utf8(field) {
+                Ok(s) => list.entry(&s),
+                Err(_) => list.entry(&field),
+            };
+        }
+        list.finish()
+    }
+}
+
+impl<'r> IntoIterator for &'r Record {
+    type Item = &'r [u8];
+    type IntoIter = RecordIter<'r>;
+
+    fn into_iter(self) -> RecordIter<'r> {
+        self.iter()
+    }
+}
+
+/// An iterator over the fields of a [`Record`](struct.Record.html).
+///
+/// The `'r` lifetime refers to the lifetime of the record being iterated.
+#[derive(Clone, Debug)]
+pub struct RecordIter<'r> {
+    record: &'r Record,
+    i: usize,
+}
+
+impl<'r> Iterator for RecordIter<'r> {
+    type Item = &'r [u8];
+
+    fn next(&mut self) -> Option<&'r [u8]> {
+        let field = self.record.get(self.i)?;
+        self.i += 1;
+        Some(field)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let n = self.record.len() - self.i;
+        (n, Some(n))
+    }
+}
+
+impl<'r> ExactSizeIterator for RecordIter<'r> {}
+
+/// A source of CSV data that hands out its bytes one chunk at a time.
+///
+/// This is modeled after `std::io::BufRead`: `fill` returns the bytes that
+/// are currently available, and `consume` marks a prefix of them as used.
+/// An empty chunk indicates that the source is exhausted.
+///
+/// Implementations are provided for byte slices and, via
+/// [`Chunks`](struct.Chunks.html), for any iterator of byte chunks.
+pub trait Source {
+    /// The type of error that can occur while filling a chunk.
+    type Error;
+
+    /// Returns the bytes that are available to read, filling them from the
+    /// underlying source if none are left.
+    ///
+    /// An empty slice is only returned once the source is exhausted.
+    fn fill(&mut self) -> Result<&[u8], Self::Error>;
+
+    /// Marks the first `amt` bytes of the most recent chunk returned by
+    /// `fill` as consumed.
+    fn consume(&mut self, amt: usize);
+}
+
+impl Source for &[u8] {
+    type Error = Infallible;
+
+    fn fill(&mut self) -> Result<&[u8], Infallible> {
+        Ok(*self)
+    }
+
+    fn consume(&mut self, amt: usize) {
+        *self = &self[amt..];
+    }
+}
+
+/// A [`Source`](trait.Source.html) that reads from an iterator of byte
+/// chunks, such as packets arriving from a serial link or blocks read from
+/// flash.
+///
+/// Empty chunks yielded by the iterator are skipped. The source is exhausted
+/// once the iterator is.
+#[derive(Clone, Debug)]
+pub struct Chunks<I: Iterator> {
+    chunks: I,
+    chunk: Option<I::Item>,
+    pos: usize,
+}
+
+impl<I> Chunks<I>
+where
+    I: Iterator,
+    I::Item: AsRef<[u8]>,
+{
+    /// Create a new source from the given iterator of chunks.
+    pub fn new<T: IntoIterator<IntoIter = I>>(chunks: T) -> Chunks<I> {
+        Chunks { chunks: chunks.into_iter(), chunk: None, pos: 0 }
+    }
+}
+
+impl<I> Source for Chunks<I>
+where
+    I: Iterator,
+    I::Item: AsRef<[u8]>,
+{
+    type Error = Infallible;
+
+    fn fill(&mut self) -> Result<&[u8], Infallible> {
+        loop {
+            if let Some(ref chunk) = self.chunk {
+                if self.pos < chunk.as_ref().len() {
+                    break;
+                }
+            }
+            match self.chunks.next() {
+                None => {
+                    self.chunk = None;
+                    return Ok(&[]);
+                }
+                Some(chunk) => {
+                    self.chunk = Some(chunk);
+                    self.pos = 0;
+                }
+            }
+        }
+        Ok(&self.chunk.as_ref().unwrap().as_ref()[self.pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos += amt;
+    }
+}
+
+/// A pull based CSV reader that parses records from a
+/// [`Source`](trait.Source.html) into growable
+/// [`Record`](struct.Record.html)s.
+///
+/// This wraps a [`Reader`](struct.Reader.html), so all of its configuration
+/// (delimiter, quoting, comments and so on) applies.
+///
+/// This type is only available when the `alloc` feature is enabled.
+///
+/// # Example
+///
+/// ```
+/// use csv_core::{Chunks, Reader, Record, RecordReader};
+///
+/// // The data may be split at arbitrary points, even inside quotes.
+/// let chunks = ["name,val", "ue\n\"wi", "dget, large\",3\n", "gizmo,4"];
+/// let mut rdr = RecordReader::new(Reader::new(), Chunks::new(chunks));
+///
+/// let mut record = Record::new();
+/// let mut rows = vec![];
+/// while rdr.read_record(&mut record).unwrap() {
+///     rows.push(format!("{:?}", record));
+/// }
+/// assert_eq!(rows, vec![
+///     r#"["name", "value"]"#,
+///     r#"["widget, large", "3"]"#,
+///     r#"["gizmo", "4"]"#,
+/// ]);
+/// ```
+#[derive(Debug)]
+pub struct RecordReader<S> {
+    core: Reader,
+    src: S,
+    /// Whether the parser has reported the end of the data.
+    eof: bool,
+}
+
+impl<S: Source> RecordReader<S> {
+    /// Create a new record reader that parses data from `src` using the given
+    /// CSV parser.
+    pub fn new(core: Reader, src: S) -> RecordReader<S> {
+        RecordReader { core, src, eof: false }
+    }
+
+    /// Read the next record into `record`, replacing its contents.
+    ///
+    /// This returns `false` when there are no more records to read, in which
+    /// case `record` is left empty. Errors can only come from the underlying
+    /// source, since parsing CSV never fails.
+    pub fn read_record(
+        &mut self,
+        record: &mut Record,
+    ) -> Result<bool, S::Error> {
+        record.clear();
+        if self.eof {
+            return Ok(false);
+        }
+        let (mut outlen, mut endlen) = (0, 0);
+        loop {
+            let (res, nin, nout, nend) = {
+                let input = self.src.fill()?;
+                self.core.read_record(
+                    input,
+                    &mut record.fields[outlen..],
+                    &mut record.ends[endlen..],
+                )
+            };
+            self.src.consume(nin);
+            outlen += nout;
+            endlen += nend;
+            match res {
+                ReadRecordResult::InputEmpty => continue,
+                ReadRecordResult::OutputFull => record.expand_fields(),
+                ReadRecordResult::OutputEndsFull => record.expand_ends(),
+                ReadRecordResult::Record => {
+                    record.len = endlen;
+                    return Ok(true);
+                }
+                ReadRecordResult::End => {
+                    self.eof = true;
+                    return Ok(false);
+                }
+            }
+        }
+    }
+
+    /// Returns the current line number of the parser, starting at `1`.
+    pub fn line(&self) -> u64 {
+        self.core.line()
+    }
+
+    /// Returns true if and only if all records have been read.
+    pub fn is_done(&self) -> bool {
+        self.eof
+    }
+
+    /// Returns a reference to the underlying source.
+    pub fn get_ref(&self) -> &S {
+        &self.src
+    }
+
+    /// Returns a mutable reference to the underlying source.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.src
+    }
+
+    /// Unwraps this reader, returning the underlying source.
+    pub fn into_inner(self) -> S {
+        self.src
+    }
+}
@@ -145,12 +145,14 @@ pub use crate::{
     error::{
         Error, ErrorKind, FromUtf8Error, IntoInnerError, Result, Utf8Error,
     },
+    progress::{Progress, ProgressInterval, ReaderStats},
     reader::{
         ByteRecordsIntoIter, ByteRecordsIter, DeserializeRecordsIntoIter,
         DeserializeRecordsIter, Reader, ReaderBuilder, StringRecordsIntoIter,
         StringRecordsIter,
     },
     string_record::{StringRecord, StringRecordIter},
+    union_writer::UnionWriter,
     writer::{Writer, WriterBuilder},
 };
 
@@ -159,10 +161,12 @@ pub mod cookbook;
 mod debug;
 mod deserializer;
 mod error;
+mod progress;
 mod reader;
 mod serializer;
 mod string_record;
 pub mod tutorial;
+mod union_writer;
 mod writer;
 
 /// The quoting style to use when writing CSV data.
@@ -0,0 +1,392 @@
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+use crate::byte_record::Position;
+
+/// Counters accumulated by a CSV reader while it parses data.
+///
+/// These counters are cumulative over the lifetime of a reader. In
+/// particular, seeking a reader does not reset them, so they always reflect
+/// the total amount of work done, which is what throughput metrics want.
+/// Use [`Reader::position`](struct.Reader.html#method.position) if you need
+/// to know where the reader currently is in the underlying data.
+///
+/// The header row, when present, is counted like any other record.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ReaderStats {
+    records: u64,
+    fields: u64,
+    bytes: u64,
+}
+
+impl ReaderStats {
+    /// The total number of records parsed.
+    #[inline]
+    pub fn records(&self) -> u64 {
+        self.records
+    }
+
+    /// The total number of fields parsed, summed over all records.
+    #[inline]
+    pub fn fields(&self) -> u64 {
+        self.fields
+    }
+
+    /// The total number of bytes consumed from the underlying reader.
+    #[inline]
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    #[inline(always)]
+    pub(crate) fn add_bytes(&mut self, bytes: u64) {
+        self.bytes += bytes;
+    }
+
+    #[inline(always)]
+    pub(crate) fn add_record(&mut self, fields: u64) {
+        self.records += 1;
+        self.fields += fields;
+    }
+}
+
+/// How often a progress handler registered on a reader is invoked.
+///
+/// Progress is only ever reported at record boundaries, so with
+/// `ProgressInterval::Bytes`, a handler is called at the end of the first
+/// record that crosses each multiple of the interval. An interval of `0` is
+/// treated as `1`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ProgressInterval {
+    /// Report progress every `N` records.
+    Records(u64),
+    /// Report progress every `N` bytes read from the underlying reader.
+    Bytes(u64),
+}
+
+/// A snapshot of a reader's progress, given to a progress handler.
+///
+/// See
+/// [`Reader::set_progress_handler`](struct.Reader.html#method.set_progress_handler)
+/// for how to register a handler.
+#[derive(Debug)]
+pub struct Progress<'a> {
+    position: &'a Position,
+    stats: ReaderStats,
+    /// Bytes consumed since the handler was registered, the same window as
+    /// `elapsed`.
+    bytes_read: u64,
+    records_read: u64,
+    total_bytes: Option<u64>,
+    elapsed: Duration,
+    done: bool,
+}
+
+impl<'a> Progress<'a> {
+    /// The position of the reader immediately after the last record parsed.
+    pub fn position(&self) -> &'a Position {
+        self.position
+    }
+
+    /// The counters accumulated by the reader so far.
+    pub fn stats(&self) -> &ReaderStats {
+        &self.stats
+    }
+
+    /// The total size of the underlying data in bytes, when known.
+    ///
+    /// This is set automatically when the reader was created from a file
+    /// path. Otherwise, it can be provided with
+    /// [`Reader::set_total_bytes`](struct.Reader.html#method.set_total_bytes).
+    pub fn total_bytes(&self) -> Option<u64> {
+        self.total_bytes
+    }
+
+    /// The time elapsed since the progress handler was registered.
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// Returns true when this is the final report, made once the reader
+    /// reaches the end of its data.
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// The fraction, between `0.0` and `1.0`, of the underlying data that
+    /// has been read. This returns `None` if the total size is not known.
+    pub fn fraction(&self) -> Option<f64> {
+        let total = self.total_bytes?;
+        if total == 0 {
+            return Some(1.0);
+        }
+        Some((self.position.byte() as f64 / total as f64).min(1.0))
+    }
+
+    /// The average number of bytes read per second since the progress
+    /// handler was registered.
+    ///
+    /// Unlike [`stats`](#method.stats), this does not count the bytes read
+    /// before the handler was registered, so that the rate is measured over
+    /// the same time as [`elapsed`](#method.elapsed).
+    pub fn bytes_per_second(&self) -> f64 {
+        per_second(self.bytes_read, self.elapsed)
+    }
+
+    /// The average number of records parsed per second since the progress
+    /// handler was registered.
+    pub fn records_per_second(&self) -> f64 {
+        per_second(self.records_read, self.elapsed)
+    }
+
+    /// An estimate of the time remaining until the end of the data is
+    /// reached, based on the average byte rate so far.
+    ///
+    /// The bytes remaining are counted from the current
+    /// [`position`](#method.position), so after seeking, the estimate is for
+    /// reading from the new position to the end, at the rate measured so
+    /// far.
+    ///
+    /// This returns `None` if the total size is not known or if no rate
+    /// could be measured yet.
+    pub fn eta(&self) -> Option<Duration> {
+        let total = self.total_bytes?;
+        let remaining = total.saturating_sub(self.position.byte());
+        if remaining == 0 {
+            return Some(Duration::from_secs(0));
+        }
+        let rate = self.bytes_per_second();
+        if !rate.is_normal() {
+            return None;
+        }
+        Some(Duration::from_secs_f64(remaining as f64 / rate))
+    }
+}
+
+fn per_second(count: u64, elapsed: Duration) -> f64 {
+    let secs = elapsed.as_secs_f64();
+    if secs == 0.0 {
+        0.0
+    } else {
+        count as f64 / secs
+    }
+}
+
+/// A progress handler registered on a reader, along with the bookkeeping
+/// required to decide when it should be called.
+pub(crate) struct ProgressHook {
+    interval: ProgressInterval,
+    /// The value of the interval's counter at which to report next.
+    next: u64,
+    start: Instant,
+    /// The counters when the handler was registered.
+    start_stats: ReaderStats,
+    handler: Box<dyn FnMut(&Progress<'_>) + Send + Sync>,
+}
+
+impl fmt::Debug for ProgressHook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ProgressHook")
+            .field("interval", &self.interval)
+            .field("next", &self.next)
+            .field("start", &self.start)
+            .finish()
+    }
+}
+
+impl ProgressHook {
+    pub(crate) fn new(
+        interval: ProgressInterval,
+        stats: &ReaderStats,
+        handler: Box<dyn FnMut(&Progress<'_>) + Send + Sync>,
+    ) -> ProgressHook {
+        let mut hook = ProgressHook {
+            interval,
+            next: 0,
+            start: Instant::now(),
+            start_stats: *stats,
+            handler,
+        };
+        hook.next = hook.next_after(hook.counter(stats));
+        hook
+    }
+
+    /// Call the handler if the interval has elapsed, or unconditionally if
+    /// `done` is true.
+    pub(crate) fn report(
+        &mut self,
+        position: &Position,
+        stats: &ReaderStats,
+        total_bytes: Option<u64>,
+        done: bool,
+    ) {
+        let counter = self.counter(stats);
+        if !done && counter < self.next {
+            return;
+        }
+        self.next = self.next_after(counter);
+        (self.handler)(&Progress {
+            position,
+            stats: *stats,
+            bytes_read: stats.bytes() - self.start_stats.bytes(),
+            records_read: stats.records() - self.start_stats.records(),
+            total_bytes,
+            elapsed: self.start.elapsed(),
+            done,
+        });
+    }
+
+    fn counter(&self, stats: &ReaderStats) -> u64 {
+        match self.interval {
+            ProgressInterval::Records(_) => stats.records(),
+            ProgressInterval::Bytes(_) => stats.bytes(),
+        }
+    }
+
+    /// Returns the first multiple of the interval strictly greater than
+    /// `counter`.
+    fn next_after(&self, counter: u64) -> u64 {
+        let step = match self.interval {
+            ProgressInterval::Records(n) | ProgressInterval::Bytes(n) => {
+                n.max(1)
+            }
+        };
+        (counter / step).saturating_add(1).saturating_mul(step)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Cursor,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use crate::{
+        byte_record::Position,
+        reader::{Reader, ReaderBuilder},
+    };
+
+    use super::{Progress, ProgressInterval, ReaderStats};
+
+    const DATA: &str = "a,b\n1,2\n30,40\n500,600\n";
+
+    #[test]
+    fn reader_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Reader<std::fs::File>>();
+        assert_send_sync::<Reader<&[u8]>>();
+    }
+
+    #[test]
+    fn handler_reports_counts() {
+        let mut rdr = ReaderBuilder::new()
+            .has_headers(false)
+            .from_reader(DATA.as_bytes());
+        rdr.set_total_bytes(Some(DATA.len() as u64));
+
+        let seen = Arc::new(Mutex::new(vec![]));
+        let log = Arc::clone(&seen);
+        rdr.set_progress_handler(ProgressInterval::Records(1), move |p| {
+            log.lock().unwrap().push((
+                p.stats().records(),
+                p.stats().fields(),
+                p.stats().bytes(),
+                p.position().byte(),
+                p.is_done(),
+            ));
+        });
+        for result in rdr.records() {
+            result.unwrap();
+        }
+
+        let seen = seen.lock().unwrap();
+        assert_eq!(
+            *seen,
+            vec![
+                (1, 2, 4, 4, false),
+                (2, 4, 8, 8, false),
+                (3, 6, 14, 14, false),
+                (4, 8, 22, 22, false),
+                (4, 8, 22, 22, true),
+            ]
+        );
+    }
+
+    #[test]
+    fn byte_interval() {
+        let mut rdr = ReaderBuilder::new()
+            .has_headers(false)
+            .from_reader(DATA.as_bytes());
+        let seen = Arc::new(Mutex::new(vec![]));
+        let log = Arc::clone(&seen);
+        rdr.set_progress_handler(ProgressInterval::Bytes(10), move |p| {
+            log.lock().unwrap().push(p.stats().bytes());
+        });
+        for result in rdr.records() {
+            result.unwrap();
+        }
+
+        // The first records ending past 10 and 20 bytes, and the end.
+        assert_eq!(*seen.lock().unwrap(), vec![14, 22, 22]);
+    }
+
+    #[test]
+    fn rate_after_seek() {
+        let mut rdr = ReaderBuilder::new()
+            .has_headers(false)
+            .from_reader(Cursor::new(DATA.as_bytes()));
+        rdr.set_total_bytes(Some(DATA.len() as u64));
+        for result in rdr.records() {
+            result.unwrap();
+        }
+        rdr.seek(Position::new()).unwrap();
+
+        let seen = Arc::new(Mutex::new(vec![]));
+        let log = Arc::clone(&seen);
+        rdr.set_progress_handler(ProgressInterval::Records(1), move |p| {
+            let secs = p.elapsed().as_secs_f64();
+            log.lock().unwrap().push((
+                p.stats().bytes(),
+                p.position().byte(),
+                (p.bytes_per_second() * secs).round() as u64,
+                p.fraction().unwrap(),
+            ));
+        });
+        for result in rdr.records() {
+            result.unwrap();
+        }
+
+        // The counters are cumulative, but the rate only counts the bytes
+        // read since the handler was registered, which here is the position.
+        for &(bytes, byte, rate_bytes, fraction) in seen.lock().unwrap().iter()
+        {
+            assert_eq!(bytes, DATA.len() as u64 + byte);
+            assert!(rate_bytes == byte || rate_bytes == 0);
+            assert!(fraction <= 1.0);
+        }
+    }
+
+    #[test]
+    fn eta_counts_from_position() {
+        let mut position = Position::new();
+        position.set_byte(250);
+        let progress = Progress {
+            position: &position,
+            // A full pass over the data, and then some after seeking.
+            stats: ReaderStats { records: 30, fields: 60, bytes: 1250 },
+            bytes_read: 500,
+            records_read: 10,
+            total_bytes: Some(1000),
+            elapsed: Duration::from_secs(5),
+            done: false,
+        };
+        assert_eq!(progress.fraction(), Some(0.25));
+        assert_eq!(progress.bytes_per_second(), 100.0);
+        assert_eq!(progress.records_per_second(), 2.0);
+        assert_eq!(progress.eta(), Some(Duration::from_secs_f64(7.5)));
+    }
+}
@@ -14,6 +14,7 @@ use {
 use crate::{
     byte_record::{ByteRecord, Position},
     error::{Error, ErrorKind, Result, Utf8Error},
+    progress::{Progress, ProgressHook, ProgressInterval, ReaderStats},
     string_record::StringRecord,
     {Terminator, Trim},
 };
@@ -107,7 +108,11 @@ impl ReaderBuilder {
     /// }
     /// ```
     pub fn from_path<P: AsRef<Path>>(&self, path: P) -> Result<Reader<File>> {
-        Ok(Reader::new(self, File::open(path)?))
+        let file = File::open(path)?;
+        let total_bytes = file.metadata().ok().map(|md| md.len());
+        let mut rdr = Reader::new(self, file);
+        rdr.set_total_bytes(total_bytes);
+        Ok(rdr)
     }
 
     /// Build a CSV parser from this configuration that reads data from `rdr`.
@@ -723,6 +728,8 @@ pub struct Reader<R> {
     ///
     /// There is more state embedded in the `CoreReader`.
     state: ReaderState,
+    /// An optional handler that is periodically notified of progress.
+    progress: Option<ProgressHook>,
 }
 
 #[derive(Debug)]
@@ -762,6 +769,10 @@ struct ReaderState {
     /// an IO error.
     /// This has no additional runtime cost.
     eof: ReaderEofState,
+    /// Counters for the records, fields and bytes parsed so far.
+    stats: ReaderStats,
+    /// The total size of the underlying data, when known.
+    total_bytes: Option<u64>,
 }
 
 /// Whether EOF of the underlying reader has been reached or not.
@@ -836,7 +847,10 @@ impl<R: io::Read> Reader<R> {
                 first: false,
                 seeked: false,
                 eof: ReaderEofState::NotEof,
+                stats: ReaderStats::default(),
+                total_bytes: None,
             },
+            progress: None,
         }
     }
 
@@ -1642,6 +1656,7 @@ impl<R: io::Read> Reader<R> {
                 )
             };
             self.rdr.consume(nin);
+            self.state.stats.add_bytes(nin as u64);
             let byte = self.state.cur_pos.byte();
             self.state
                 .cur_pos
@@ -1661,11 +1676,14 @@ impl<R: io::Read> Reader<R> {
                 }
                 Record => {
                     record.set_len(endlen);
-                    self.state.add_record(record)?;
+                    let result = self.state.add_record(record);
+                    self.report_progress(false);
+                    result?;
                     return Ok(true);
                 }
                 End => {
                     self.state.eof = ReaderEofState::Eof;
+                    self.report_progress(true);
                     return Ok(false);
                 }
             }
@@ -1715,6 +1733,142 @@ impl<R: io::Read> Reader<R> {
         &self.state.cur_pos
     }
 
+    /// Returns the counters for the records, fields and bytes this reader
+    /// has parsed so far.
+    ///
+    /// The counters include the header row and are not reset by seeking.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::error::Error;
+    /// use csv::Reader;
+    ///
+    /// # fn main() { example().unwrap(); }
+    /// fn example() -> Result<(), Box<dyn Error>> {
+    ///     let data = "\
+    /// city,country,popcount
+    /// Boston,United States,4628910
+    /// Concord,United States,42695
+    /// ";
+    ///     let mut rdr = Reader::from_reader(data.as_bytes());
+    ///     for result in rdr.records() {
+    ///         let _ = result?;
+    ///     }
+    ///     let stats = rdr.stats();
+    ///     assert_eq!(stats.records(), 3);
+    ///     assert_eq!(stats.fields(), 9);
+    ///     assert_eq!(stats.bytes(), data.len() as u64);
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn stats(&self) -> &ReaderStats {
+        &self.state.stats
+    }
+
+    /// Returns the total size of the underlying data in bytes, when known.
+    ///
+    /// This is set automatically for readers created from a file path.
+    pub fn total_bytes(&self) -> Option<u64> {
+        self.state.total_bytes
+    }
+
+    /// Set the total size of the underlying data in bytes.
+    ///
+    /// This is used to compute the fraction read and the estimated time
+    /// remaining that are reported to a progress handler. Readers created
+    /// from a file path set this automatically.
+    pub fn set_total_bytes(&mut self, total_bytes: Option<u64>) {
+        self.state.total_bytes = total_bytes;
+    }
+
+    /// Register a handler that is called periodically with the progress of
+    /// this reader.
+    ///
+    /// The handler is called whenever the given interval of records or bytes
+    /// has elapsed, and once more when the end of the data is reached. It is
+    /// given a [`Progress`](struct.Progress.html) with the current position,
+    /// the counters returned by [`stats`](#method.stats), the total size of
+    /// the data when known, and the rate and estimated time remaining.
+    ///
+    /// Progress is only reported at record boundaries. Registering a handler
+    /// replaces any handler registered previously.
+    ///
+    /// The handler must be `Send` and `Sync`, like the reader that owns it.
+    /// Share state with it through types like `Arc<Mutex<T>>`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::{
+    ///     error::Error,
+    ///     sync::{Arc, Mutex},
+    /// };
+    /// use csv::{ProgressInterval, Reader};
+    ///
+    /// # fn main() { example().unwrap(); }
+    /// fn example() -> Result<(), Box<dyn Error>> {
+    ///     let data = "\
+    /// city,country,popcount
+    /// Boston,United States,4628910
+    /// Concord,United States,42695
+    /// Oakland,United States,419267
+    /// ";
+    ///     let mut rdr = Reader::from_reader(data.as_bytes());
+    ///     rdr.set_total_bytes(Some(data.len() as u64));
+    ///
+    ///     let seen = Arc::new(Mutex::new(vec![]));
+    ///     let log = Arc::clone(&seen);
+    ///     rdr.set_progress_handler(ProgressInterval::Records(2), move |p| {
+    ///         log.lock().unwrap().push((
+    ///             p.stats().records(),
+    ///             p.fraction().unwrap(),
+    ///             p.is_done(),
+    ///         ));
+    ///     });
+    ///     for result in rdr.records() {
+    ///         let _ = result?;
+    ///     }
+    ///
+    ///     let seen = seen.lock().unwrap();
+    ///     assert_eq!(seen.len(), 3);
+    ///     assert_eq!((seen[0].0, seen[1].0), (2, 4));
+    ///     assert_eq!(seen[2], (4, 1.0, true));
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn set_progress_handler<F>(
+        &mut self,
+        interval: ProgressInterval,
+        handler: F,
+    ) where
+        F: FnMut(&Progress<'_>) + Send + Sync + 'static,
+    {
+        self.progress = Some(ProgressHook::new(
+            interval,
+            &self.state.stats,
+            Box::new(handler),
+        ));
+    }
+
+    /// Remove the progress handler registered on this reader, if any.
+    pub fn clear_progress_handler(&mut self) {
+        self.progress = None;
+    }
+
+    /// Notify the progress handler, if one is registered.
+    #[inline(always)]
+    fn report_progress(&mut self, done: bool) {
+        if let Some(ref mut hook) = self.progress {
+            hook.report(
+                &self.state.cur_pos,
+                &self.state.stats,
+                self.state.total_bytes,
+                done,
+            );
+        }
+    }
+
     /// Returns true if and only if this reader has been exhausted.
     ///
     /// When this returns true, no more records can be read from this reader
@@ -1879,6 +2033,7 @@ impl<R: io::Read + io::Seek> Reader<R> {
 impl ReaderState {
     #[inline(always)]
     fn add_record(&mut self, record: &ByteRecord) -> Result<()> {
+        self.stats.add_record(record.len() as u64);
         let i = self.cur_pos.record();
         self.cur_pos.set_record(i.checked_add(1).unwrap());
         if !self.flexible {
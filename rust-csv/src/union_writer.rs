@@ -0,0 +1,310 @@
+use std::{
+    collections::HashMap,
+    env,
+    fs::{self, File, OpenOptions},
+    io, mem,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use serde::Serialize;
+
+use crate::{
+    byte_record::ByteRecord,
+    error::Result,
+    reader::{Reader, ReaderBuilder},
+    serializer::serialize_pairs,
+    writer::{Writer, WriterBuilder},
+};
+
+/// A CSV writer for records whose fields may differ from one record to the
+/// next.
+///
+/// A [`Writer`](struct.Writer.html) derives its header row from the first
+/// record it serializes, and by default returns an error if a later record
+/// has a different number of fields. A `UnionWriter` instead accepts structs
+/// and maps (such as `HashMap` or `BTreeMap`) with any set of named fields.
+/// It buffers every record until [`finish`](#method.finish) is called, at
+/// which point it writes a header row containing the union of all field
+/// names, in the order they were first seen, followed by every record with
+/// empty fields for the names it does not have.
+///
+/// Since no output can be written before the last record has been seen,
+/// records are buffered in memory up to a configurable threshold, after
+/// which they are spilled to a temporary file. See
+/// [`spill_threshold`](#method.spill_threshold).
+///
+/// The writer given to a `UnionWriter` determines the CSV format of the
+/// output, such as the delimiter and quoting style. If it was configured to
+/// not write headers, then only the records are written, with their fields
+/// in the order of the header row that would have been written.
+///
+/// # Example
+///
+/// ```
+/// use std::{collections::BTreeMap, error::Error};
+/// use csv::{UnionWriter, Writer};
+///
+/// #[derive(serde::Serialize)]
+/// struct City<'a> {
+///     city: &'a str,
+///     #[serde(skip_serializing_if = "Option::is_none")]
+///     population: Option<u64>,
+/// }
+///
+/// # fn main() { example().unwrap(); }
+/// fn example() -> Result<(), Box<dyn Error>> {
+///     let mut wtr = UnionWriter::new(Writer::from_writer(vec![]));
+///     wtr.serialize(City { city: "Boston", population: None })?;
+///     wtr.serialize(City { city: "Concord", population: Some(42695) })?;
+///
+///     let mut extra = BTreeMap::new();
+///     extra.insert("city", "Oakland");
+///     extra.insert("state", "CA");
+///     wtr.serialize(extra)?;
+///
+///     let data = String::from_utf8(wtr.finish()?.into_inner()?)?;
+///     assert_eq!(data, "\
+/// city,population,state
+/// Boston,,
+/// Concord,42695,
+/// Oakland,,CA
+/// ");
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug)]
+pub struct UnionWriter<W: io::Write> {
+    wtr: Writer<W>,
+    /// The union of all field names seen so far, in the order they were
+    /// first seen.
+    headers: ByteRecord,
+    /// A map from field name to its index in `headers`.
+    index: HashMap<Vec<u8>, usize>,
+    /// Records buffered in memory, each stored as alternating name and value
+    /// fields.
+    buffered: Vec<ByteRecord>,
+    /// The approximate number of bytes held by `buffered`.
+    buffered_bytes: usize,
+    /// The number of buffered bytes above which records are spilled to disk.
+    /// When `None`, records are never spilled.
+    spill_threshold: Option<usize>,
+    /// The directory in which to create the spill file.
+    spill_dir: Option<PathBuf>,
+    /// The spill file, once records have been spilled.
+    spill: Option<Spill>,
+}
+
+impl<W: io::Write> UnionWriter<W> {
+    /// Create a new union writer that writes its output to the given CSV
+    /// writer once [`finish`](#method.finish) is called.
+    ///
+    /// The CSV writer should not have been written to yet.
+    pub fn new(wtr: Writer<W>) -> UnionWriter<W> {
+        UnionWriter {
+            wtr,
+            headers: ByteRecord::new(),
+            index: HashMap::new(),
+            buffered: vec![],
+            buffered_bytes: 0,
+            spill_threshold: Some(64 * (1 << 20)),
+            spill_dir: None,
+            spill: None,
+        }
+    }
+
+    /// Set the number of bytes of buffered records above which records are
+    /// spilled to a temporary file instead of being held in memory.
+    ///
+    /// When `None`, all records are held in memory. This defaults to 64 MiB.
+    ///
+    /// # Example
+    ///
+    /// This spills every record to disk, but produces the same output as
+    /// buffering them in memory.
+    ///
+    /// ```
+    /// use std::{collections::HashMap, error::Error};
+    /// use csv::{UnionWriter, Writer};
+    ///
+    /// # fn main() { example().unwrap(); }
+    /// fn example() -> Result<(), Box<dyn Error>> {
+    ///     let mut wtr = UnionWriter::new(Writer::from_writer(vec![]));
+    ///     wtr.spill_threshold(Some(0));
+    ///     for i in 0..3u32 {
+    ///         let mut row = HashMap::new();
+    ///         row.insert(format!("col{}", i), i);
+    ///         wtr.serialize(row)?;
+    ///     }
+    ///
+    ///     let data = String::from_utf8(wtr.finish()?.into_inner()?)?;
+    ///     assert_eq!(data, "col0,col1,col2\n0,,\n,1,\n,,2\n");
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn spill_threshold(
+        &mut self,
+        threshold: Option<usize>,
+    ) -> &mut UnionWriter<W> {
+        self.spill_threshold = threshold;
+        self
+    }
+
+    /// Set the directory in which the temporary spill file is created.
+    ///
+    /// This defaults to the directory returned by `std::env::temp_dir`. The
+    /// spill file is removed when this writer is finished or dropped.
+    pub fn spill_dir<P: AsRef<Path>>(
+        &mut self,
+        dir: P,
+    ) -> &mut UnionWriter<W> {
+        self.spill_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Serialize a single record using Serde and buffer it.
+    ///
+    /// The record must be a struct or a map whose values are scalars, so
+    /// that each of its fields has a name. Fields that are `None` are written
+    /// as empty fields, while fields that are skipped entirely (for example,
+    /// with `#[serde(skip_serializing_if = "...")]`) do not contribute a name
+    /// to the header row. If a record contains the same name more than once,
+    /// then the last value wins.
+    ///
+    /// Nothing is written to the underlying writer until `finish` is called.
+    pub fn serialize<S: Serialize>(&mut self, record: S) -> Result<()> {
+        let mut pairs = ByteRecord::new();
+        serialize_pairs(&mut pairs, record)?;
+        for name in pairs.iter().step_by(2) {
+            if !self.index.contains_key(name) {
+                self.index.insert(name.to_vec(), self.headers.len());
+                self.headers.push_field(name);
+            }
+        }
+        if let Some(ref mut spill) = self.spill {
+            return spill.write(&pairs);
+        }
+        self.buffered_bytes += pairs.as_slice().len();
+        self.buffered.push(pairs);
+        match self.spill_threshold {
+            Some(threshold) if self.buffered_bytes > threshold => {
+                self.spill_buffered()
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the union of the field names of all records serialized so
+    /// far, in the order they were first seen.
+    pub fn headers(&self) -> &ByteRecord {
+        &self.headers
+    }
+
+    /// Write the header row followed by every buffered record, and return
+    /// the underlying CSV writer.
+    ///
+    /// Note that the returned writer may still hold buffered output. Call
+    /// `flush` or `into_inner` on it to ensure everything has been written.
+    pub fn finish(mut self) -> Result<Writer<W>> {
+        if self.wtr.writes_headers() && !self.headers.is_empty() {
+            self.wtr.write_byte_record(&self.headers)?;
+        }
+        for pairs in mem::take(&mut self.buffered) {
+            write_row(&mut self.wtr, &self.index, &pairs)?;
+        }
+        if let Some(mut spill) = self.spill.take() {
+            let mut rdr = spill.reader()?;
+            let mut pairs = ByteRecord::new();
+            while rdr.read_byte_record(&mut pairs)? {
+                write_row(&mut self.wtr, &self.index, &pairs)?;
+            }
+        }
+        Ok(self.wtr)
+    }
+
+    /// Move all records buffered in memory to the spill file, creating it if
+    /// necessary.
+    fn spill_buffered(&mut self) -> Result<()> {
+        let dir = self.spill_dir.clone().unwrap_or_else(env::temp_dir);
+        let spill = self.spill.insert(Spill::create(&dir)?);
+        for pairs in self.buffered.drain(..) {
+            spill.write(&pairs)?;
+        }
+        self.buffered_bytes = 0;
+        Ok(())
+    }
+}
+
+/// Write a single record, given as alternating name and value fields, with
+/// its values arranged according to the header row.
+fn write_row<W: io::Write>(
+    wtr: &mut Writer<W>,
+    index: &HashMap<Vec<u8>, usize>,
+    pairs: &ByteRecord,
+) -> Result<()> {
+    let mut row = vec![&[][..]; index.len()];
+    let mut it = pairs.iter();
+    while let (Some(name), Some(value)) = (it.next(), it.next()) {
+        row[index[name]] = value;
+    }
+    wtr.write_record(row)
+}
+
+/// A temporary file holding records that did not fit in memory.
+///
+/// The file is removed when this is dropped.
+#[derive(Debug)]
+struct Spill {
+    path: PathBuf,
+    /// The writer for the spill file. This is only `None` while dropping, so
+    /// that the file is closed before it is removed.
+    wtr: Option<Writer<File>>,
+}
+
+impl Spill {
+    fn create(dir: &Path) -> Result<Spill> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        loop {
+            let path = dir.join(format!(
+                ".csv-union-{}-{}.csv",
+                process::id(),
+                COUNTER.fetch_add(1, Ordering::Relaxed),
+            ));
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => {
+                    let wtr = WriterBuilder::new()
+                        .has_headers(false)
+                        .flexible(true)
+                        .from_writer(file);
+                    return Ok(Spill { path, wtr: Some(wtr) });
+                }
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                    continue
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    fn write(&mut self, pairs: &ByteRecord) -> Result<()> {
+        self.wtr.as_mut().unwrap().write_byte_record(pairs)
+    }
+
+    /// Flush the spill file and open it for reading.
+    fn reader(&mut self) -> Result<Reader<File>> {
+        self.wtr.as_mut().unwrap().flush()?;
+        ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .from_path(&self.path)
+    }
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        drop(self.wtr.take());
+        let _ = fs::remove_file(&self.path);
+    }
+}
@@ -1096,6 +1096,11 @@ impl<W: io::Write> Writer<W> {
         Ok(())
     }
 
+    /// Returns true if this writer was configured to write a header row.
+    pub(crate) fn writes_headers(&self) -> bool {
+        !matches!(self.state.header, HeaderState::None)
+    }
+
     /// Return a reference to the underlying writer.
     pub fn get_ref(&self) -> &W {
         self.wtr.as_ref().unwrap()
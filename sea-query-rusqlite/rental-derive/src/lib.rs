@@ -0,0 +1,205 @@
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::quote;
+use syn::spanned::Spanned;
+use syn::{
+    parse_macro_input, Data, DeriveInput, Error, Fields, GenericArgument, LitInt, LitStr,
+    PathArguments, Result, Type,
+};
+
+// Implements `rental::FromRow`. Named fields are read from the column of the
+// same name, and tuple fields from the column at their position.
+//
+// Field attributes:
+// - `#[from_row(rename = "column")]` reads another column.
+// - `#[from_row(index = 3)]` reads the column at an index.
+// - `#[from_row(flatten)]` reads a nested `FromRow` struct from the same row,
+//   optionally with `prefix = "customer_"` prepended to its column names. A
+//   flattened `Option` is `None` when all of the nested columns are NULL, as
+//   they are for a LEFT JOIN that matched nothing.
+//
+// The prefix only applies to names: an index, including the position of a
+// tuple field, is always the column at that index in the row.
+#[proc_macro_derive(FromRow, attributes(from_row))]
+pub fn derive_from_row(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+enum Source {
+    Name(String),
+    Index(usize),
+    Flatten { prefix: String },
+}
+
+fn expand(input: DeriveInput) -> Result<TokenStream2> {
+    let Data::Struct(data) = &input.data else {
+        return Err(Error::new(
+            Span::call_site(),
+            "FromRow can only be derived for structs",
+        ));
+    };
+
+    let ident = &input.ident;
+    let target = ident.to_string();
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let mut values = Vec::new();
+    let mut columns = Vec::new();
+    for (i, field) in data.fields.iter().enumerate() {
+        let name = field.ident.as_ref().map(ToString::to_string);
+        let source = field_source(field, name.clone(), i)?;
+        let field_name = name.unwrap_or_else(|| i.to_string());
+        let ty = &field.ty;
+        columns.push(match &source {
+            Source::Name(column) => quote! {
+                columns.push(::rental::from_row::Column::Name(
+                    ::std::format!("{}{}", prefix, #column),
+                ));
+            },
+            Source::Index(index) => quote! {
+                columns.push(::rental::from_row::Column::Index(#index));
+            },
+            Source::Flatten { prefix: nested } => {
+                let ty = option_inner(ty).unwrap_or(ty);
+                quote! {
+                    columns.extend(<#ty as ::rental::FromRow>::columns(
+                        &::std::format!("{}{}", prefix, #nested),
+                    ));
+                }
+            }
+        });
+        values.push(match source {
+            Source::Name(column) => quote! {
+                ::rental::from_row::get(
+                    row,
+                    #target,
+                    #field_name,
+                    ::rental::from_row::Column::Name(::std::format!("{}{}", prefix, #column)),
+                )?
+            },
+            Source::Index(index) => quote! {
+                ::rental::from_row::get(
+                    row,
+                    #target,
+                    #field_name,
+                    ::rental::from_row::Column::Index(#index),
+                )?
+            },
+            Source::Flatten { prefix: nested } => match option_inner(ty) {
+                Some(inner) => quote! {
+                    ::rental::from_row::get_flattened::<#inner>(
+                        row,
+                        #target,
+                        #field_name,
+                        &::std::format!("{}{}", prefix, #nested),
+                    )?
+                },
+                None => quote! {
+                    <#ty as ::rental::FromRow>::from_row_prefixed(
+                        row,
+                        &::std::format!("{}{}", prefix, #nested),
+                    )?
+                },
+            },
+        });
+    }
+
+    let body = match &data.fields {
+        Fields::Named(fields) => {
+            let names = fields.named.iter().map(|field| &field.ident);
+            quote!(Self { #(#names: #values,)* })
+        }
+        Fields::Unnamed(_) => quote!(Self(#(#values,)*)),
+        Fields::Unit => quote!(Self),
+    };
+
+    Ok(quote! {
+        impl #impl_generics ::rental::FromRow for #ident #ty_generics #where_clause {
+            fn from_row_prefixed(
+                row: &::rental::from_row::Row<'_>,
+                prefix: &str,
+            ) -> ::std::result::Result<Self, ::rental::FromRowError> {
+                let _ = prefix;
+                ::std::result::Result::Ok(#body)
+            }
+
+            fn columns(prefix: &str) -> ::std::vec::Vec<::rental::from_row::Column> {
+                let _ = prefix;
+                #[allow(unused_mut)]
+                let mut columns = ::std::vec::Vec::new();
+                #(#columns)*
+                columns
+            }
+        }
+    })
+}
+
+fn field_source(field: &syn::Field, name: Option<String>, position: usize) -> Result<Source> {
+    let mut rename = None;
+    let mut index = None;
+    let mut flatten = false;
+    let mut prefix = None;
+
+    for attr in &field.attrs {
+        if !attr.path().is_ident("from_row") {
+            continue;
+        }
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("rename") {
+                let lit: LitStr = meta.value()?.parse()?;
+                rename = Some(lit.value());
+            } else if meta.path.is_ident("index") {
+                let lit: LitInt = meta.value()?.parse()?;
+                index = Some(lit.base10_parse()?);
+            } else if meta.path.is_ident("flatten") {
+                flatten = true;
+            } else if meta.path.is_ident("prefix") {
+                let lit: LitStr = meta.value()?.parse()?;
+                prefix = Some(lit.value());
+            } else {
+                return Err(meta.error("expected `rename`, `index`, `flatten` or `prefix`"));
+            }
+            Ok(())
+        })?;
+    }
+
+    let span = field.span();
+    match (rename, index, flatten) {
+        (Some(_), Some(_), _) => Err(Error::new(span, "`rename` and `index` conflict")),
+        (Some(_), _, true) | (_, Some(_), true) => Err(Error::new(
+            span,
+            "a flattened field takes its columns from the nested struct",
+        )),
+        (None, None, true) => Ok(Source::Flatten {
+            prefix: prefix.unwrap_or_default(),
+        }),
+        _ if prefix.is_some() => Err(Error::new(span, "`prefix` requires `flatten`")),
+        (Some(rename), None, false) => Ok(Source::Name(rename)),
+        (None, Some(index), false) => Ok(Source::Index(index)),
+        (None, None, false) => Ok(match name {
+            Some(name) => Source::Name(name.trim_start_matches("r#").to_owned()),
+            None => Source::Index(position),
+        }),
+    }
+}
+
+// `T` of `Option<T>`, as written, since the derive only sees the tokens.
+fn option_inner(ty: &Type) -> Option<&Type> {
+    let Type::Path(path) = ty else {
+        return None;
+    };
+    let segment = path.path.segments.last()?;
+    if path.qself.is_some() || segment.ident != "Option" {
+        return None;
+    }
+    let PathArguments::AngleBracketed(args) = &segment.arguments else {
+        return None;
+    };
+    match args.args.iter().collect::<Vec<_>>()[..] {
+        [GenericArgument::Type(inner)] => Some(inner),
+        _ => None,
+    }
+}
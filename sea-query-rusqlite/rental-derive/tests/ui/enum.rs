@@ -0,0 +1,9 @@
+use rental_derive::FromRow;
+
+#[derive(FromRow)]
+enum Kind {
+    Integer,
+    Text,
+}
+
+fn main() {}
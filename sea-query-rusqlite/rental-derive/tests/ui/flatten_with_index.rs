@@ -0,0 +1,11 @@
+use rental_derive::FromRow;
+
+#[derive(FromRow)]
+struct Overdue {
+    #[from_row(flatten, index = 1)]
+    customer: Customer,
+}
+
+struct Customer;
+
+fn main() {}
@@ -0,0 +1,9 @@
+use rental_derive::FromRow;
+
+#[derive(FromRow)]
+struct Overdue {
+    #[from_row(prefix = "customer_")]
+    customer_id: i64,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use rental_derive::FromRow;
+
+#[derive(FromRow)]
+struct Rental {
+    #[from_row(rename = "rental_id", index = 0)]
+    id: i64,
+}
+
+fn main() {}
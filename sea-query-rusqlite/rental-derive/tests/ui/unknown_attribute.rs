@@ -0,0 +1,9 @@
+use rental_derive::FromRow;
+
+#[derive(FromRow)]
+struct Rental {
+    #[from_row(column = "rental_id")]
+    id: i64,
+}
+
+fn main() {}
@@ -0,0 +1,495 @@
+use crate::build_query_stmt;
+use crate::executor::{Commit, Executor, Transact};
+use crate::model::{Customer, Film, Rental};
+use crate::repository::Entity;
+use crate::schema::{self, table_exists};
+use crate::store::is_date;
+use anyhow::{anyhow, bail, Context, Result};
+use rusqlite::types::ValueRef;
+use sea_query::{Alias, Asterisk, DynIden, Expr, Iden, IntoIden, Order, Query, SimpleExpr, Value};
+use std::collections::BTreeSet;
+use std::io;
+use std::iter;
+
+// Rows per INSERT, which keeps the bound values below SQLite's limit.
+pub const BATCH_SIZE: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Integer,
+    Real,
+    Text,
+    // `YYYY-MM-DD`
+    Date,
+}
+
+// A column whose values must be the id of a row in another table.
+pub struct ForeignKey<C> {
+    pub name: &'static str,
+    pub column: C,
+    pub table: DynIden,
+    pub references: DynIden,
+}
+
+// A table that rows can be imported into. CSV headers are the names of the
+// `Iden` columns, in any order. The id column is optional, and an empty id is
+// assigned by the database.
+pub trait Import: Entity {
+    fn kind(column: Self::Column) -> Kind;
+
+    // Whether the column may be left empty, for NULL.
+    fn nullable(column: Self::Column) -> bool;
+
+    fn foreign_keys() -> Vec<ForeignKey<Self::Column>> {
+        Vec::new()
+    }
+}
+
+// Imports into the table named `table`.
+pub fn import_table<R: io::Read>(
+    conn: &mut impl Transact,
+    table: &str,
+    reader: R,
+) -> Result<usize> {
+    if table == schema::Customer::Table.to_string() {
+        import::<Customer, R>(conn, reader)
+    } else if table == schema::Film::Table.to_string() {
+        import::<Film, R>(conn, reader)
+    } else if table == schema::Rental::Table.to_string() {
+        import::<Rental, R>(conn, reader)
+    } else {
+        bail!(
+            "cannot import into {:?}, expected customer, film or rental",
+            table
+        )
+    }
+}
+
+// Inserts every record in one transaction, so that nothing is imported if
+// any record is invalid. Returns the number of rows inserted.
+pub fn import<E: Import, R: io::Read>(conn: &mut impl Transact, reader: R) -> Result<usize> {
+    let mut reader = csv::Reader::from_reader(reader);
+    let columns = header_columns::<E>(reader.headers()?)?;
+
+    let tx = conn.transaction()?;
+    let mut batch = Vec::new();
+    let mut imported = 0;
+    for record in reader.records() {
+        let record = record?;
+        let line = record.position().map_or(0, |position| position.line());
+        let values = columns
+            .iter()
+            .zip(record.iter())
+            .map(|(&column, field)| {
+                parse::<E>(column, field.trim())
+                    .with_context(|| format!("line {}, column {}", line, column.to_string()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        batch.push((line, values));
+        if batch.len() == BATCH_SIZE {
+            imported += insert_batch::<E>(&tx, &columns, &mut batch)?;
+        }
+    }
+    imported += insert_batch::<E>(&tx, &columns, &mut batch)?;
+    tx.commit()?;
+
+    Ok(imported)
+}
+
+// Writes every row of any table, in the order they were inserted. Returns the
+// number of rows written.
+pub fn export<W: io::Write>(conn: &impl Executor, table: &str, writer: W) -> Result<usize> {
+    if !table_exists(conn, table)? {
+        bail!("no table {:?}", table);
+    }
+    let (sql, values) = build_query_stmt(
+        Query::select()
+            .column(Asterisk)
+            .from(Alias::new(table))
+            .order_by(Alias::new("rowid"), Order::Asc)
+            .to_owned(),
+    );
+    let mut writer = csv::Writer::from_writer(writer);
+    // Only prepared, not run, for the header.
+    let column_count = {
+        let stmt = conn.connection().prepare(sql.as_str())?;
+        writer.write_record(stmt.column_names())?;
+        stmt.column_count()
+    };
+
+    // Written as they are read, so the mapped rows are all `()`.
+    let exported = conn.query_map(&sql, &values, |row| {
+        let mut record = Vec::with_capacity(column_count);
+        for i in 0..column_count {
+            record.push(match row.get_ref(i)? {
+                ValueRef::Null => String::new(),
+                ValueRef::Integer(value) => value.to_string(),
+                ValueRef::Real(value) => value.to_string(),
+                ValueRef::Text(text) => String::from_utf8_lossy(text).into_owned(),
+                ValueRef::Blob(blob) => blob.iter().map(|byte| format!("{:02x}", byte)).collect(),
+            });
+        }
+        writer.write_record(&record)?;
+        Ok(())
+    })?;
+    writer.flush()?;
+
+    Ok(exported.len())
+}
+
+fn header_columns<E: Import>(headers: &csv::StringRecord) -> Result<Vec<E::Column>> {
+    let known: Vec<E::Column> = iter::once(E::ID)
+        .chain(E::COLUMNS.iter().copied())
+        .collect();
+    let names: Vec<String> = known.iter().map(|column| column.to_string()).collect();
+
+    let mut columns = Vec::new();
+    let mut seen = BTreeSet::new();
+    for header in headers {
+        let header = header.trim();
+        let Some(i) = names
+            .iter()
+            .position(|name| name.eq_ignore_ascii_case(header))
+        else {
+            bail!(
+                "unknown column {:?} for {}, expected {}",
+                header,
+                E::TABLE.to_string(),
+                names.join(", "),
+            );
+        };
+        if !seen.insert(i) {
+            bail!("duplicate column {:?}", header);
+        }
+        columns.push(known[i]);
+    }
+
+    for (i, &column) in known.iter().enumerate().skip(1) {
+        if !seen.contains(&i) && !E::nullable(column) {
+            bail!("missing column {} for {}", names[i], E::TABLE.to_string());
+        }
+    }
+    Ok(columns)
+}
+
+fn parse<E: Import>(column: E::Column, field: &str) -> Result<Value> {
+    let kind = E::kind(column);
+    if field.is_empty() {
+        if !E::nullable(column) {
+            bail!("a value is required");
+        }
+        return Ok(match kind {
+            Kind::Integer => Value::BigInt(None),
+            Kind::Real => Value::Double(None),
+            Kind::Text | Kind::Date => Value::String(None),
+        });
+    }
+    Ok(match kind {
+        Kind::Integer => field
+            .parse::<i64>()
+            .map_err(|_| anyhow!("invalid integer {:?}", field))?
+            .into(),
+        Kind::Real => field
+            .parse::<f64>()
+            .map_err(|_| anyhow!("invalid number {:?}", field))?
+            .into(),
+        Kind::Text => field.into(),
+        Kind::Date if is_date(field) => field.into(),
+        Kind::Date => bail!("invalid date {:?}, expected YYYY-MM-DD", field),
+    })
+}
+
+fn insert_batch<E: Import>(
+    conn: &impl Executor,
+    columns: &[E::Column],
+    batch: &mut Vec<(u64, Vec<Value>)>,
+) -> Result<usize> {
+    if batch.is_empty() {
+        return Ok(0);
+    }
+    check_foreign_keys::<E>(conn, columns, batch)?;
+
+    let mut insert = Query::insert();
+    insert.into_table(E::TABLE).columns(columns.iter().copied());
+    for (line, values) in batch.iter() {
+        let values = values.iter().cloned().map(SimpleExpr::from);
+        insert
+            .values(values)
+            .map_err(|err| anyhow!("line {}: {:?}", line, err))?;
+    }
+    let (sql, values) = build_query_stmt(insert);
+    let first_line = batch[0].0;
+    let inserted = conn
+        .execute(&sql, &values)
+        .with_context(|| format!("inserting the rows from line {}", first_line))?;
+    batch.clear();
+
+    Ok(inserted)
+}
+
+// Checked before SQLite would reject the insert, to name the first line that
+// breaks one.
+fn check_foreign_keys<E: Import>(
+    conn: &impl Executor,
+    columns: &[E::Column],
+    batch: &[(u64, Vec<Value>)],
+) -> Result<()> {
+    for foreign_key in E::foreign_keys() {
+        let column = foreign_key.column.to_string();
+        let Some(index) = columns.iter().position(|c| c.to_string() == column) else {
+            continue;
+        };
+        let id = |values: &[Value]| match values[index] {
+            Value::BigInt(id) => id,
+            _ => None,
+        };
+        let ids: BTreeSet<i64> = batch.iter().filter_map(|(_, values)| id(values)).collect();
+
+        let (sql, values) = build_query_stmt(
+            Query::select()
+                .column(foreign_key.references.clone())
+                .from(foreign_key.table.clone())
+                .and_where(Expr::col(foreign_key.references.clone()).is_in(ids))
+                .to_owned(),
+        );
+        let existing: BTreeSet<i64> = conn
+            .query_map(&sql, &values, |row| Ok(row.get(0)?))?
+            .into_iter()
+            .collect();
+
+        for (line, values) in batch {
+            if let Some(id) = id(values).filter(|id| !existing.contains(id)) {
+                bail!(
+                    "line {}: {} {} violates {}, there is no such {}",
+                    line,
+                    column,
+                    id,
+                    foreign_key.name,
+                    foreign_key.table.to_string(),
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+impl Import for Customer {
+    fn kind(column: schema::Customer) -> Kind {
+        match column {
+            schema::Customer::Id => Kind::Integer,
+            schema::Customer::Table
+            | schema::Customer::FirstName
+            | schema::Customer::LastName
+            | schema::Customer::Email => Kind::Text,
+        }
+    }
+
+    fn nullable(column: schema::Customer) -> bool {
+        matches!(column, schema::Customer::Id)
+    }
+}
+
+impl Import for Film {
+    fn kind(column: schema::Film) -> Kind {
+        match column {
+            schema::Film::Id => Kind::Integer,
+            schema::Film::RentalRate => Kind::Real,
+            schema::Film::Table
+            | schema::Film::Title
+            | schema::Film::Description
+            | schema::Film::ReleaseYear => Kind::Text,
+        }
+    }
+
+    fn nullable(column: schema::Film) -> bool {
+        matches!(column, schema::Film::Id)
+    }
+}
+
+impl Import for Rental {
+    fn kind(column: schema::Rental) -> Kind {
+        match column {
+            schema::Rental::Id | schema::Rental::CustomerId | schema::Rental::FilmId => {
+                Kind::Integer
+            }
+            schema::Rental::LateFee => Kind::Real,
+            schema::Rental::RentalDate | schema::Rental::DueDate | schema::Rental::ReturnDate => {
+                Kind::Date
+            }
+            schema::Rental::Table => Kind::Text,
+        }
+    }
+
+    fn nullable(column: schema::Rental) -> bool {
+        matches!(
+            column,
+            schema::Rental::Id | schema::Rental::ReturnDate | schema::Rental::LateFee
+        )
+    }
+
+    // As declared by `create_rental_table`.
+    fn foreign_keys() -> Vec<ForeignKey<schema::Rental>> {
+        vec![
+            ForeignKey {
+                name: "fk_rental_customer",
+                column: schema::Rental::CustomerId,
+                table: schema::Customer::Table.into_iden(),
+                references: schema::Customer::Id.into_iden(),
+            },
+            ForeignKey {
+                name: "fk_rental_film",
+                column: schema::Rental::FilmId,
+                table: schema::Film::Table.into_iden(),
+                references: schema::Film::Id.into_iden(),
+            },
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::Repository;
+    use crate::schema::{enable_foreign_keys, init_schema};
+    use rusqlite::Connection;
+
+    const CUSTOMERS: &str = "\
+id,first_name,last_name,email
+1,Cory,Hernandez,ab@zunu.hk
+2,Aaron,Fleming,fabas@nukem.my
+";
+
+    const FILMS: &str = "\
+title,description,release_year,rental_rate
+Philosopher's Stone,\"The philosopher's stone, a magical object.\",2001,10.05
+Chamber of Secrets,The Chamber of Secrets has been opened.,2002,8.99
+";
+
+    const RENTALS: &str = "\
+id,rental_date,customer_id,film_id,due_date,return_date,late_fee
+1,2023-10-10,1,1,2023-10-17,2023-10-15,0
+2,2023-10-11,1,2,2023-10-18,,
+3,2023-10-12,2,1,2023-10-20,,
+";
+
+    fn connection() -> Connection {
+        let mut conn = Connection::open_in_memory().unwrap();
+        enable_foreign_keys(&conn).unwrap();
+        init_schema(&mut conn).unwrap();
+        conn
+    }
+
+    fn seeded() -> Connection {
+        let mut conn = connection();
+        import::<Customer, _>(&mut conn, CUSTOMERS.as_bytes()).unwrap();
+        import::<Film, _>(&mut conn, FILMS.as_bytes()).unwrap();
+        import::<Rental, _>(&mut conn, RENTALS.as_bytes()).unwrap();
+        conn
+    }
+
+    fn export_string(conn: &Connection, table: &str) -> String {
+        let mut csv = Vec::new();
+        export(conn, table, &mut csv).unwrap();
+        String::from_utf8(csv).unwrap()
+    }
+
+    #[test]
+    fn import_all_tables() {
+        let conn = seeded();
+        let film = Repository::<Film, _>::new(&conn).get(1).unwrap().unwrap();
+        assert_eq!(
+            film.description,
+            "The philosopher's stone, a magical object."
+        );
+        assert_eq!(film.rental_rate, 10.05);
+        let rental = Repository::<Rental, _>::new(&conn).get(2).unwrap().unwrap();
+        assert_eq!(rental.return_date, None);
+        assert_eq!(rental.late_fee, None);
+    }
+
+    #[test]
+    fn export_round_trip() {
+        let conn = seeded();
+        let rentals = export_string(&conn, "rental");
+        assert_eq!(
+            rentals,
+            "\
+id,rental_date,customer_id,film_id,due_date,return_date,late_fee
+1,2023-10-10,1,1,2023-10-17,2023-10-15,0
+2,2023-10-11,1,2,2023-10-18,,
+3,2023-10-12,2,1,2023-10-20,,
+",
+        );
+
+        let mut copy = connection();
+        for table in ["customer", "film", "rental"] {
+            let csv = export_string(&conn, table);
+            import_table(&mut copy, table, csv.as_bytes()).unwrap();
+            assert_eq!(export_string(&copy, table), csv);
+        }
+    }
+
+    #[test]
+    fn batches() {
+        let mut conn = connection();
+        let mut csv = "first_name,last_name,email\n".to_owned();
+        for i in 0..BATCH_SIZE * 2 + 1 {
+            csv += &format!("C{},Doe,c{}@example.com\n", i, i);
+        }
+        let imported = import::<Customer, _>(&mut conn, csv.as_bytes()).unwrap();
+        assert_eq!(imported, BATCH_SIZE * 2 + 1);
+    }
+
+    #[test]
+    fn foreign_key_violation() {
+        let mut conn = connection();
+        import::<Customer, _>(&mut conn, CUSTOMERS.as_bytes()).unwrap();
+        let err = import::<Rental, _>(&mut conn, RENTALS.as_bytes()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 2: film_id 1 violates fk_rental_film, there is no such film",
+        );
+        let rentals = Repository::<Rental, _>::new(&conn);
+        assert_eq!(rentals.count(&Default::default()).unwrap(), 0);
+    }
+
+    #[test]
+    fn invalid_headers() {
+        let mut conn = connection();
+        let err = import::<Customer, _>(&mut conn, "first_name,surname\n".as_bytes()).unwrap_err();
+        assert!(err.to_string().starts_with("unknown column \"surname\""));
+        let err =
+            import::<Customer, _>(&mut conn, "first_name,last_name\n".as_bytes()).unwrap_err();
+        assert_eq!(err.to_string(), "missing column email for customer");
+    }
+
+    #[test]
+    fn invalid_values() {
+        let mut conn = seeded();
+        let csv = "rental_date,customer_id,film_id,due_date\n2023-10-12,1,1,2023-13-01\n";
+        let err = import::<Rental, _>(&mut conn, csv.as_bytes()).unwrap_err();
+        assert_eq!(err.to_string(), "line 2, column due_date");
+        assert_eq!(
+            err.root_cause().to_string(),
+            "invalid date \"2023-13-01\", expected YYYY-MM-DD",
+        );
+
+        for date in ["2023-02-29", "2023-02-31", "2023-04-31", "1900-02-29"] {
+            let csv = format!(
+                "rental_date,customer_id,film_id,due_date\n2023-10-12,1,1,{}\n",
+                date
+            );
+            let err = import::<Rental, _>(&mut conn, csv.as_bytes()).unwrap_err();
+            assert_eq!(
+                err.root_cause().to_string(),
+                format!("invalid date {:?}, expected YYYY-MM-DD", date),
+            );
+        }
+        let csv = "rental_date,customer_id,film_id,due_date\n2024-02-29,1,1,2024-03-07\n";
+        assert_eq!(import::<Rental, _>(&mut conn, csv.as_bytes()).unwrap(), 1);
+
+        let csv = "rental_date,customer_id,film_id,due_date\n2023-10-12,one,1,2023-10-19\n";
+        let err = import::<Rental, _>(&mut conn, csv.as_bytes()).unwrap_err();
+        assert_eq!(err.root_cause().to_string(), "invalid integer \"one\"");
+    }
+}
@@ -0,0 +1,97 @@
+use anyhow::Result;
+use rusqlite::{Connection, Row, Transaction};
+use sea_query_rusqlite::RusqliteValues;
+
+// Runs the statements built by `build_query_stmt`. Implemented by `Connection`
+// and `Transaction`, and by the connections of `logging`, which log them too.
+pub trait Executor {
+    // The connection underneath, for what is not a statement, like
+    // `last_insert_rowid`.
+    fn connection(&self) -> &Connection;
+
+    // Returns the number of rows changed.
+    fn execute(&self, sql: &str, values: &RusqliteValues) -> Result<usize>;
+
+    fn query_map<T, F>(&self, sql: &str, values: &RusqliteValues, f: F) -> Result<Vec<T>>
+    where
+        F: FnMut(&Row) -> Result<T>;
+
+    // The first row, failing if there is none.
+    fn query_row<T, F>(&self, sql: &str, values: &RusqliteValues, f: F) -> Result<T>
+    where
+        F: FnMut(&Row) -> Result<T>,
+    {
+        let rows = self.query_map(sql, values, f)?;
+        rows.into_iter()
+            .next()
+            .ok_or_else(|| rusqlite::Error::QueryReturnedNoRows.into())
+    }
+}
+
+// An executor that can start a transaction, like `Connection`.
+pub trait Transact: Executor {
+    type Transaction<'c>: Commit
+    where
+        Self: 'c;
+
+    fn transaction(&mut self) -> Result<Self::Transaction<'_>>;
+}
+
+// Rolled back when dropped without `commit`, like `Transaction`.
+pub trait Commit: Executor {
+    fn commit(self) -> Result<()>;
+}
+
+impl Executor for Connection {
+    fn connection(&self) -> &Connection {
+        self
+    }
+
+    fn execute(&self, sql: &str, values: &RusqliteValues) -> Result<usize> {
+        Ok(Connection::execute(self, sql, &*values.as_params())?)
+    }
+
+    fn query_map<T, F>(&self, sql: &str, values: &RusqliteValues, mut f: F) -> Result<Vec<T>>
+    where
+        F: FnMut(&Row) -> Result<T>,
+    {
+        let mut stmt = self.prepare(sql)?;
+        let mut rows = stmt.query(&*values.as_params())?;
+        let mut mapped = Vec::new();
+        while let Some(row) = rows.next()? {
+            mapped.push(f(row)?);
+        }
+        Ok(mapped)
+    }
+}
+
+impl Executor for Transaction<'_> {
+    fn connection(&self) -> &Connection {
+        self
+    }
+
+    fn execute(&self, sql: &str, values: &RusqliteValues) -> Result<usize> {
+        Executor::execute(self.connection(), sql, values)
+    }
+
+    fn query_map<T, F>(&self, sql: &str, values: &RusqliteValues, f: F) -> Result<Vec<T>>
+    where
+        F: FnMut(&Row) -> Result<T>,
+    {
+        Executor::query_map(self.connection(), sql, values, f)
+    }
+}
+
+impl Transact for Connection {
+    type Transaction<'c> = Transaction<'c>;
+
+    fn transaction(&mut self) -> Result<Transaction<'_>> {
+        Ok(Connection::transaction(self)?)
+    }
+}
+
+impl Commit for Transaction<'_> {
+    fn commit(self) -> Result<()> {
+        Ok(Transaction::commit(self)?)
+    }
+}
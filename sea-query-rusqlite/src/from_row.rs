@@ -0,0 +1,276 @@
+use rusqlite::types::{FromSql, Value};
+use std::error::Error;
+use std::fmt::{self, Display};
+
+pub use rusqlite::Row;
+
+// Implemented by `#[derive(FromRow)]`.
+pub trait FromRow: Sized {
+    fn from_row(row: &Row) -> Result<Self, FromRowError> {
+        Self::from_row_prefixed(row, "")
+    }
+
+    // Reads the columns named by `prefix` followed by the column name of each
+    // field, as a struct flattened into another one does.
+    fn from_row_prefixed(row: &Row, prefix: &str) -> Result<Self, FromRowError>;
+
+    // The columns `from_row_prefixed` reads.
+    fn columns(prefix: &str) -> Vec<Column>;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Column {
+    Name(String),
+    Index(usize),
+}
+
+#[derive(Debug)]
+pub enum FromRowError {
+    // The row has no such column.
+    MissingColumn {
+        target: &'static str,
+        field: &'static str,
+        column: Column,
+    },
+    // The column holds a value of another type, or NULL for a field that is
+    // not an `Option`.
+    InvalidValue {
+        target: &'static str,
+        field: &'static str,
+        column: Column,
+        source: rusqlite::Error,
+    },
+}
+
+// Reads the field `field` of the struct `target`.
+pub fn get<T: FromSql>(
+    row: &Row,
+    target: &'static str,
+    field: &'static str,
+    column: Column,
+) -> Result<T, FromRowError> {
+    let value = match &column {
+        Column::Name(name) => row.get(name.as_str()),
+        Column::Index(index) => row.get(*index),
+    };
+    value.map_err(|source| match source {
+        rusqlite::Error::InvalidColumnName(_) | rusqlite::Error::InvalidColumnIndex(_) => {
+            FromRowError::MissingColumn {
+                target,
+                field,
+                column,
+            }
+        }
+        source => FromRowError::InvalidValue {
+            target,
+            field,
+            column,
+            source,
+        },
+    })
+}
+
+// Reads the flattened field `field` of the struct `target`, which is `None` if
+// every column of `T` is NULL.
+pub fn get_flattened<T: FromRow>(
+    row: &Row,
+    target: &'static str,
+    field: &'static str,
+    prefix: &str,
+) -> Result<Option<T>, FromRowError> {
+    for column in T::columns(prefix) {
+        if get::<Value>(row, target, field, column)? != Value::Null {
+            return T::from_row_prefixed(row, prefix).map(Some);
+        }
+    }
+    Ok(None)
+}
+
+impl Display for Column {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Column::Name(name) => write!(f, "column `{}`", name),
+            Column::Index(index) => write!(f, "column {}", index),
+        }
+    }
+}
+
+impl Display for FromRowError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FromRowError::MissingColumn {
+                target,
+                field,
+                column,
+            } => write!(f, "{}.{}: the row has no {}", target, field, column),
+            FromRowError::InvalidValue {
+                target,
+                field,
+                column,
+                source,
+            } => write!(
+                f,
+                "{}.{}: invalid value in {}: {}",
+                target, field, column, source
+            ),
+        }
+    }
+}
+
+impl Error for FromRowError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            FromRowError::MissingColumn { .. } => None,
+            FromRowError::InvalidValue { source, .. } => Some(source),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FromRow;
+    use rusqlite::Connection;
+
+    #[derive(Debug, PartialEq, FromRow)]
+    struct Name {
+        first_name: String,
+        last_name: String,
+    }
+
+    #[derive(Debug, PartialEq, FromRow)]
+    struct Joined {
+        #[from_row(flatten, prefix = "customer_")]
+        customer: Name,
+        #[from_row(rename = "film_title")]
+        title: String,
+        return_date: Option<String>,
+    }
+
+    #[derive(Debug, PartialEq, FromRow)]
+    struct LeftJoined {
+        title: String,
+        #[from_row(flatten, prefix = "customer_")]
+        customer: Option<Name>,
+    }
+
+    #[derive(Debug, PartialEq, FromRow)]
+    struct Pair(i64, #[from_row(index = 2)] String);
+
+    fn query<T: FromRow>(sql: &str) -> Result<T, FromRowError> {
+        let conn = Connection::open_in_memory().unwrap();
+        let mut stmt = conn.prepare(sql).unwrap();
+        let mut rows = stmt.query([]).unwrap();
+        let row = rows.next().unwrap().unwrap();
+        T::from_row(row)
+    }
+
+    #[test]
+    fn nested_and_renamed() {
+        let joined: Joined = query(
+            "SELECT 'Cory' AS customer_first_name, 'Hernandez' AS customer_last_name,
+                    'Chamber of Secrets' AS film_title, NULL AS return_date",
+        )
+        .unwrap();
+        assert_eq!(
+            joined,
+            Joined {
+                customer: Name {
+                    first_name: "Cory".to_owned(),
+                    last_name: "Hernandez".to_owned(),
+                },
+                title: "Chamber of Secrets".to_owned(),
+                return_date: None,
+            },
+        );
+    }
+
+    #[test]
+    fn by_index() {
+        let pair: Pair = query("SELECT 1, 'skipped', 'two'").unwrap();
+        assert_eq!(pair, Pair(1, "two".to_owned()));
+    }
+
+    #[test]
+    fn missing_column() {
+        let err = query::<Name>("SELECT 'Cory' AS first_name").unwrap_err();
+        assert!(matches!(
+            &err,
+            FromRowError::MissingColumn { field: "last_name", column, .. }
+                if *column == Column::Name("last_name".to_owned())
+        ));
+        assert_eq!(
+            err.to_string(),
+            "Name.last_name: the row has no column `last_name`"
+        );
+    }
+
+    #[test]
+    fn null_in_required_field() {
+        let err = query::<Name>("SELECT 'Cory' AS first_name, NULL AS last_name").unwrap_err();
+        assert!(matches!(
+            err,
+            FromRowError::InvalidValue {
+                field: "last_name",
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn columns() {
+        assert_eq!(
+            Joined::columns("a_"),
+            [
+                Column::Name("a_customer_first_name".to_owned()),
+                Column::Name("a_customer_last_name".to_owned()),
+                Column::Name("a_film_title".to_owned()),
+                Column::Name("a_return_date".to_owned()),
+            ],
+        );
+        assert_eq!(Pair::columns(""), [Column::Index(0), Column::Index(2)]);
+    }
+
+    #[test]
+    fn flattened_option() {
+        let joined: LeftJoined = query(
+            "SELECT 'Chamber of Secrets' AS title,
+                    NULL AS customer_first_name, NULL AS customer_last_name",
+        )
+        .unwrap();
+        assert_eq!(joined.customer, None);
+
+        let joined: LeftJoined = query(
+            "SELECT 'Chamber of Secrets' AS title,
+                    'Cory' AS customer_first_name, 'Hernandez' AS customer_last_name",
+        )
+        .unwrap();
+        assert_eq!(
+            joined.customer,
+            Some(Name {
+                first_name: "Cory".to_owned(),
+                last_name: "Hernandez".to_owned(),
+            }),
+        );
+
+        // Only all NULL is `None`.
+        let err = query::<LeftJoined>(
+            "SELECT 'Chamber of Secrets' AS title,
+                    'Cory' AS customer_first_name, NULL AS customer_last_name",
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err,
+            FromRowError::InvalidValue {
+                field: "last_name",
+                ..
+            }
+        ));
+
+        let err = query::<LeftJoined>("SELECT 'Chamber of Secrets' AS title").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "LeftJoined.customer: the row has no column `customer_first_name`"
+        );
+    }
+}
@@ -0,0 +1,33 @@
+// Lets `#[derive(FromRow)]` refer to `::rental` from within this crate.
+extern crate self as rental;
+
+pub mod csv_io;
+pub mod executor;
+pub mod from_row;
+pub mod logging;
+pub mod migration;
+pub mod model;
+pub mod repository;
+pub mod schema;
+pub mod store;
+
+pub use from_row::{FromRow, FromRowError};
+pub use rental_derive::FromRow;
+
+use firedbg_lib::fire;
+use sea_query::{SchemaStatementBuilder, SqliteQueryBuilder};
+use sea_query_rusqlite::{RusqliteBinder, RusqliteValues};
+
+pub fn build_schema_stmt<T>(stmt: T) -> String
+where
+    T: SchemaStatementBuilder,
+{
+    fire::dbg!("return", stmt.build(SqliteQueryBuilder))
+}
+
+pub fn build_query_stmt<T>(stmt: T) -> (String, RusqliteValues)
+where
+    T: RusqliteBinder,
+{
+    fire::dbg!("return", stmt.build_rusqlite(SqliteQueryBuilder))
+}
@@ -0,0 +1,370 @@
+use crate::executor::{Commit, Executor, Transact};
+use anyhow::Result;
+use rusqlite::{Connection, Row, Transaction};
+use sea_query::{QueryBuilder, SqliteQueryBuilder};
+use sea_query_rusqlite::RusqliteValues;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Default)]
+pub struct LogOptions {
+    // Log every statement, not only the slow ones.
+    pub all: bool,
+    // Statements that take at least this long are logged as slow queries.
+    pub slow_threshold: Option<Duration>,
+    // Log the query plan of each statement logged.
+    pub explain: bool,
+}
+
+// A connection that logs the statements run through `Executor`, with their
+// bound values, how long they took and how many rows they returned or changed.
+// What is done through `connection` is not logged.
+pub struct LoggedConnection {
+    conn: Connection,
+    logger: Logger,
+}
+
+// A transaction of a `LoggedConnection`, logged the same way.
+pub struct LoggedTransaction<'c> {
+    tx: Transaction<'c>,
+    logger: &'c Logger,
+}
+
+struct Logger {
+    options: LogOptions,
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+impl LoggedConnection {
+    // Logs to stderr.
+    pub fn new(conn: Connection, options: LogOptions) -> Self {
+        Self::with_writer(conn, options, io::stderr())
+    }
+
+    pub fn with_writer<W: Write + Send + 'static>(
+        conn: Connection,
+        options: LogOptions,
+        writer: W,
+    ) -> Self {
+        Self {
+            conn,
+            logger: Logger {
+                options,
+                writer: Mutex::new(Box::new(writer)),
+            },
+        }
+    }
+}
+
+impl Executor for LoggedConnection {
+    fn connection(&self) -> &Connection {
+        &self.conn
+    }
+
+    fn execute(&self, sql: &str, values: &RusqliteValues) -> Result<usize> {
+        self.logger.execute(&self.conn, sql, values)
+    }
+
+    fn query_map<T, F>(&self, sql: &str, values: &RusqliteValues, f: F) -> Result<Vec<T>>
+    where
+        F: FnMut(&Row) -> Result<T>,
+    {
+        self.logger.query_map(&self.conn, sql, values, f)
+    }
+}
+
+impl Transact for LoggedConnection {
+    type Transaction<'c> = LoggedTransaction<'c>;
+
+    fn transaction(&mut self) -> Result<LoggedTransaction<'_>> {
+        Ok(LoggedTransaction {
+            tx: self.conn.transaction()?,
+            logger: &self.logger,
+        })
+    }
+}
+
+impl Executor for LoggedTransaction<'_> {
+    fn connection(&self) -> &Connection {
+        &self.tx
+    }
+
+    fn execute(&self, sql: &str, values: &RusqliteValues) -> Result<usize> {
+        self.logger.execute(&self.tx, sql, values)
+    }
+
+    fn query_map<T, F>(&self, sql: &str, values: &RusqliteValues, f: F) -> Result<Vec<T>>
+    where
+        F: FnMut(&Row) -> Result<T>,
+    {
+        self.logger.query_map(&self.tx, sql, values, f)
+    }
+}
+
+impl Commit for LoggedTransaction<'_> {
+    fn commit(self) -> Result<()> {
+        Ok(self.tx.commit()?)
+    }
+}
+
+// The steps of the plan SQLite picks for the statement, each indented by its
+// depth in the plan.
+pub fn explain(conn: &Connection, sql: &str, values: &RusqliteValues) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare(&format!("EXPLAIN QUERY PLAN {}", sql))?;
+    let mut rows = stmt.query(&*values.as_params())?;
+    let mut depths = HashMap::new();
+    let mut plan = Vec::new();
+    while let Some(row) = rows.next()? {
+        let id: i64 = row.get(0)?;
+        let parent: i64 = row.get(1)?;
+        let detail: String = row.get(3)?;
+        let depth = depths.get(&parent).map_or(0, |depth| depth + 1);
+        depths.insert(id, depth);
+        plan.push(format!("{}{}", "  ".repeat(depth), detail));
+    }
+    Ok(plan)
+}
+
+impl Logger {
+    fn execute<C: Executor>(&self, conn: &C, sql: &str, values: &RusqliteValues) -> Result<usize> {
+        self.logged(conn.connection(), sql, values, "changed", || {
+            let changed = conn.execute(sql, values)?;
+            Ok((changed, changed))
+        })
+    }
+
+    fn query_map<C, T, F>(
+        &self,
+        conn: &C,
+        sql: &str,
+        values: &RusqliteValues,
+        f: F,
+    ) -> Result<Vec<T>>
+    where
+        C: Executor,
+        F: FnMut(&Row) -> Result<T>,
+    {
+        self.logged(conn.connection(), sql, values, "returned", || {
+            let mapped = conn.query_map(sql, values, f)?;
+            let count = mapped.len();
+            Ok((mapped, count))
+        })
+    }
+
+    // Runs a statement and logs it, if it is to be logged. `run` returns its
+    // result along with the number of rows returned or changed.
+    fn logged<T>(
+        &self,
+        conn: &Connection,
+        sql: &str,
+        values: &RusqliteValues,
+        rows: &str,
+        run: impl FnOnce() -> Result<(T, usize)>,
+    ) -> Result<T> {
+        let start = Instant::now();
+        let result = run();
+        let elapsed = start.elapsed();
+
+        let slow = self
+            .options
+            .slow_threshold
+            .is_some_and(|threshold| elapsed >= threshold);
+        if self.options.all || slow {
+            let outcome = match &result {
+                Ok((_, 1)) => format!("1 row {}", rows),
+                Ok((_, count)) => format!("{} rows {}", count, rows),
+                Err(err) => format!("failed with {}", err),
+            };
+            let mut line = format!(
+                "{}{:.3?}, {}: {}",
+                if slow { "slow query, " } else { "" },
+                elapsed,
+                outcome,
+                sql,
+            );
+            if !values.0.is_empty() {
+                let values: Vec<String> = values
+                    .0
+                    .iter()
+                    .map(|value| SqliteQueryBuilder.value_to_string(&value.0))
+                    .collect();
+                line.push_str(&format!(" -- [{}]", values.join(", ")));
+            }
+            self.log(&line);
+
+            // Statements SQLite has no plan for, like `BEGIN`, log no plan.
+            if self.options.explain {
+                for step in explain(conn, sql, values).unwrap_or_default() {
+                    self.log(&format!("    {}", step));
+                }
+            }
+        }
+
+        result.map(|(value, _)| value)
+    }
+
+    // A log that cannot be written to is no reason to fail the statement.
+    fn log(&self, line: &str) {
+        if let Ok(mut writer) = self.writer.lock() {
+            let _ = writeln!(writer, "{}", line);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build_query_stmt;
+    use crate::model::Customer;
+    use crate::repository::Repository;
+    use crate::schema::{self, enable_foreign_keys, init_schema};
+    use sea_query::{Asterisk, Expr, Query};
+    use std::sync::Arc;
+
+    #[derive(Clone, Default)]
+    struct Log(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Log {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Log {
+        fn take(&self) -> Vec<String> {
+            let log = std::mem::take(&mut *self.0.lock().unwrap());
+            String::from_utf8(log)
+                .unwrap()
+                .lines()
+                .map(str::to_owned)
+                .collect()
+        }
+    }
+
+    fn connection(options: LogOptions) -> (LoggedConnection, Log) {
+        let log = Log::default();
+        let conn = Connection::open_in_memory().unwrap();
+        enable_foreign_keys(&conn).unwrap();
+        let mut conn = LoggedConnection::with_writer(conn, options, log.clone());
+        init_schema(&mut conn).unwrap();
+        log.take();
+        (conn, log)
+    }
+
+    fn customer() -> Customer {
+        Customer {
+            id: 0,
+            first_name: "Cory".to_owned(),
+            last_name: "Hernandez".to_owned(),
+            email: "ab@zunu.hk".to_owned(),
+        }
+    }
+
+    #[test]
+    fn logs_values_and_row_counts() {
+        let (conn, log) = connection(LogOptions {
+            all: true,
+            ..Default::default()
+        });
+        Repository::<Customer, _>::new(&conn)
+            .create(&customer())
+            .unwrap();
+
+        let lines = log.take();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains(", 1 row changed: INSERT INTO \"customer\""));
+        assert!(lines[0].ends_with(" -- ['Cory', 'Hernandez', 'ab@zunu.hk']"));
+        assert!(lines[1].contains(", 1 row returned: SELECT "));
+        assert!(lines[1].ends_with(" -- [1]"));
+    }
+
+    #[test]
+    fn slow_threshold() {
+        let (conn, log) = connection(LogOptions {
+            slow_threshold: Some(Duration::from_secs(60)),
+            ..Default::default()
+        });
+        Repository::<Customer, _>::new(&conn)
+            .create(&customer())
+            .unwrap();
+        assert!(log.take().is_empty());
+
+        let (conn, log) = connection(LogOptions {
+            slow_threshold: Some(Duration::ZERO),
+            ..Default::default()
+        });
+        Repository::<Customer, _>::new(&conn)
+            .create(&customer())
+            .unwrap();
+        let lines = log.take();
+        assert_eq!(lines.len(), 2);
+        assert!(lines.iter().all(|line| line.starts_with("slow query, ")));
+    }
+
+    #[test]
+    fn explain() {
+        let (conn, log) = connection(LogOptions {
+            all: true,
+            explain: true,
+            ..Default::default()
+        });
+        Repository::<Customer, _>::new(&conn).get(1).unwrap();
+
+        let lines = log.take();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[1].starts_with("    SEARCH "));
+        assert!(lines[1].contains(" USING INTEGER PRIMARY KEY"));
+
+        // There is no index on the email.
+        let (sql, values) = build_query_stmt(
+            Query::select()
+                .column(Asterisk)
+                .from(schema::Customer::Table)
+                .and_where(Expr::col(schema::Customer::Email).eq("ab@zunu.hk"))
+                .to_owned(),
+        );
+        let plan = super::explain(conn.connection(), &sql, &values).unwrap();
+        assert_eq!(plan.len(), 1);
+        assert!(plan[0].starts_with("SCAN "));
+    }
+
+    #[test]
+    fn transaction_rolls_back_unless_committed() {
+        let (mut conn, log) = connection(LogOptions {
+            all: true,
+            ..Default::default()
+        });
+        {
+            let tx = conn.transaction().unwrap();
+            Repository::<Customer, _>::new(&tx)
+                .create(&customer())
+                .unwrap();
+        }
+        let lines = log.take();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains(": INSERT INTO \"customer\""));
+
+        let customers = Repository::<Customer, _>::new(&conn);
+        assert_eq!(customers.count(&Default::default()).unwrap(), 0);
+
+        let tx = conn.transaction().unwrap();
+        Repository::<Customer, _>::new(&tx)
+            .create(&customer())
+            .unwrap();
+        tx.commit().unwrap();
+        let customers = Repository::<Customer, _>::new(&conn);
+        assert_eq!(customers.count(&Default::default()).unwrap(), 1);
+    }
+
+    #[test]
+    fn is_send() {
+        fn assert_send<T: Send>() {}
+        assert_send::<LoggedConnection>();
+    }
+}
@@ -1,294 +1,535 @@
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use firedbg_lib::fire;
-use rusqlite::{Connection, Row};
-use sea_query::{
-    ColumnDef, Expr, ForeignKey, Iden, InsertStatement, Query, SchemaStatementBuilder,
-    SelectStatement, SqliteQueryBuilder, Table, TableCreateStatement,
-};
-use sea_query_rusqlite::{RusqliteBinder, RusqliteValues};
+use rental::csv_io;
+use rental::executor::Transact;
+use rental::logging::{LogOptions, LoggedConnection};
+use rental::migration::{self, Mode, MIGRATIONS};
+use rental::model::{Customer, CustomerFilter, Rental};
+use rental::repository::{Page, Repository};
+use rental::schema;
+use rental::store::{self, OverdueRental, Receipt};
+use rusqlite::{Connection, OpenFlags};
+use serde::Serialize;
+use serde_json::json;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use structopt::StructOpt;
 
-fn main() -> Result<()> {
-    let conn = &Connection::open_in_memory()?;
-
-    init_schema(conn)?;
-
-    insert_sample_data(conn)?;
-
-    perform_select(conn)?;
+#[derive(StructOpt, Debug)]
+struct Opt {
+    /// SQLite database, which `init` creates if it does not exist
+    #[structopt(long, default_value = "rental.db", parse(from_os_str))]
+    db: PathBuf,
+    /// Print results as JSON instead of tables
+    #[structopt(long)]
+    json: bool,
+    /// Log every statement to stderr, with its values, time and row count
+    #[structopt(long)]
+    log: bool,
+    /// Log the statements that take at least this many milliseconds
+    #[structopt(long)]
+    slow_ms: Option<u64>,
+    /// Log the query plan of each statement logged, logging every statement
+    /// unless `--log` or `--slow-ms` selects which
+    #[structopt(long)]
+    explain: bool,
+    #[structopt(subcommand)]
+    command: Command,
+}
 
-    Ok(())
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// Create the schema, or bring it up to date
+    Init {
+        /// Print the SQL of the pending migrations without running it
+        #[structopt(long)]
+        dry_run: bool,
+    },
+    /// Import the sample customers, films and rentals
+    Seed {
+        /// Directory with customer.csv, film.csv and rental.csv to import
+        /// instead of the samples
+        #[structopt(long, parse(from_os_str))]
+        dir: Option<PathBuf>,
+    },
+    /// Manage customers
+    Customers(CustomersCommand),
+    /// Check out a film for a customer
+    Rent {
+        customer_id: i64,
+        film_id: i64,
+        /// Rental date, YYYY-MM-DD
+        #[structopt(long, default_value = "now")]
+        date: String,
+        /// Number of days until the film is due
+        #[structopt(long, default_value = "7")]
+        days: u32,
+    },
+    /// Return a rented film, charging late fees
+    Return {
+        rental_id: i64,
+        /// Return date, YYYY-MM-DD
+        #[structopt(long, default_value = "now")]
+        date: String,
+    },
+    /// List the rentals overdue as of a date
+    Overdue {
+        /// Report date, YYYY-MM-DD
+        #[structopt(long, default_value = "now")]
+        as_of: String,
+    },
+    /// Import rows into customer, film or rental from a CSV file
+    Import {
+        table: String,
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+    },
+    /// Write every row of a table as CSV
+    Export {
+        table: String,
+        /// Output file, instead of stdout
+        #[structopt(parse(from_os_str))]
+        file: Option<PathBuf>,
+    },
 }
 
-fn init_schema(conn: &Connection) -> Result<()> {
-    let customer_table = create_customer_table();
-    let film_table = create_film_table();
-    let rental_table = create_rental_table();
+#[derive(StructOpt, Debug)]
+enum CustomersCommand {
+    /// List customers, optionally filtered
+    List {
+        /// Part of the first or last name
+        #[structopt(long)]
+        name: Option<String>,
+        #[structopt(long)]
+        email: Option<String>,
+        /// Page number, from 1
+        #[structopt(long, default_value = "1")]
+        page: u64,
+        #[structopt(long, default_value = "20")]
+        per_page: u64,
+    },
+}
 
-    let customer_table_res = conn.execute(&build_schema_stmt(customer_table), [])?;
-    fire::dbg!(customer_table_res);
+// In the order that satisfies the foreign keys of rental.
+const SAMPLES: [(&str, &str); 3] = [
+    ("customer", include_str!("../data/customer.csv")),
+    ("film", include_str!("../data/film.csv")),
+    ("rental", include_str!("../data/rental.csv")),
+];
 
-    let film_table_res = conn.execute(&build_schema_stmt(film_table), [])?;
-    fire::dbg!(film_table_res);
+fn main() -> Result<()> {
+    let Opt {
+        db,
+        json,
+        log,
+        slow_ms,
+        explain,
+        command,
+    } = Opt::from_args();
 
-    let rental_table_res = conn.execute(&build_schema_stmt(rental_table), [])?;
-    fire::dbg!(rental_table_res);
+    fire::dbg!(&db);
+    fire::dbg!(&command);
 
-    Ok(())
+    let mut conn =
+        open_db(&db, &command).with_context(|| format!("cannot open {}", db.display()))?;
+    if let Some(options) = log_options(log, slow_ms, explain) {
+        run(
+            &mut LoggedConnection::new(conn, options),
+            &db,
+            json,
+            command,
+        )
+    } else {
+        run(&mut conn, &db, json, command)
+    }
 }
 
-fn insert_sample_data(conn: &Connection) -> Result<()> {
-    let (sql, values) = build_query_stmt(insert_customers());
-    let customer_insert_res = conn.execute(sql.as_str(), &*values.as_params())?;
-    fire::dbg!(customer_insert_res);
-
-    let (sql, values) = build_query_stmt(insert_films());
-    let film_insert_res = conn.execute(sql.as_str(), &*values.as_params())?;
-    fire::dbg!(film_insert_res);
+// None if nothing is logged. `--explain` alone logs every statement, as only
+// the statements logged get their query plan shown.
+fn log_options(log: bool, slow_ms: Option<u64>, explain: bool) -> Option<LogOptions> {
+    let all = log || (explain && slow_ms.is_none());
+    if !all && slow_ms.is_none() {
+        return None;
+    }
+    Some(LogOptions {
+        all,
+        slow_threshold: slow_ms.map(Duration::from_millis),
+        explain,
+    })
+}
 
-    let (sql, values) = build_query_stmt(insert_rentals());
-    let rental_insert_res = conn.execute(sql.as_str(), &*values.as_params())?;
-    fire::dbg!(rental_insert_res);
+// A dry run of `init` on a database that does not exist yet shows the
+// migrations on an empty database in memory, leaving no file behind.
+fn open_db(db: &Path, command: &Command) -> Result<Connection> {
+    if matches!(command, Command::Init { dry_run: true }) && !db.exists() {
+        let conn = Connection::open_in_memory()?;
+        schema::enable_foreign_keys(&conn)?;
+        return Ok(conn);
+    }
+    schema::open(db, open_flags(command))
+}
 
-    Ok(())
+// Only `init` creates the database, so that a mistyped `--db` is an error
+// rather than a new empty database.
+fn open_flags(command: &Command) -> OpenFlags {
+    let mut flags = OpenFlags::default();
+    if !matches!(command, Command::Init { dry_run: false }) {
+        flags.remove(OpenFlags::SQLITE_OPEN_CREATE);
+    }
+    flags
 }
 
-fn perform_select(conn: &Connection) -> Result<()> {
-    let (sql, values) = build_query_stmt(select_overdue_rentals());
-    let mut stmt = conn.prepare(sql.as_str())?;
-    let mut rows = stmt.query(&*values.as_params())?;
+fn run(conn: &mut impl Transact, db: &Path, json: bool, command: Command) -> Result<()> {
+    if !matches!(command, Command::Init { .. }) {
+        let applied = migration::applied_versions(conn)?;
+        if MIGRATIONS.iter().any(|m| !applied.contains(&m.version)) {
+            bail!(
+                "the schema of {} is not up to date, run `init` first",
+                db.display(),
+            );
+        }
+    }
 
-    while let Some(row) = rows.next()? {
-        println!("{:?}", OverdueRental::from(row));
+    match command {
+        Command::Init { dry_run } => {
+            let mode = if dry_run { Mode::DryRun } else { Mode::Apply };
+            let migrated = migration::up(conn, None, mode)?;
+            if json {
+                let versions: Vec<i64> = migrated.iter().map(|m| m.version).collect();
+                print_json(&json!({ "migrations": versions }))?;
+            } else if dry_run {
+                for migration in &migrated {
+                    println!("-- up {}: {}", migration.version, migration.name);
+                    for sql in &migration.statements {
+                        println!("{};", sql);
+                    }
+                }
+            } else {
+                println!("Applied {} migrations to {}", migrated.len(), db.display());
+            }
+        }
+        Command::Seed { dir } => {
+            let mut imported = Vec::new();
+            for (table, sample) in SAMPLES {
+                let count = match &dir {
+                    Some(dir) => {
+                        let file = File::open(dir.join(format!("{}.csv", table)))?;
+                        csv_io::import_table(conn, table, file)?
+                    }
+                    None => csv_io::import_table(conn, table, sample.as_bytes())?,
+                };
+                imported.push(json!({ "table": table, "imported": count }));
+                if !json {
+                    println!("Imported {} rows into {}", count, table);
+                }
+            }
+            if json {
+                print_json(&imported)?;
+            }
+        }
+        Command::Customers(CustomersCommand::List {
+            name,
+            email,
+            page,
+            per_page,
+        }) => {
+            let filter = CustomerFilter {
+                name_contains: name,
+                email,
+            };
+            let page = Page::new(page.saturating_sub(1), per_page);
+            let customers = Repository::<Customer, _>::new(conn).list(&filter, page)?;
+            if json {
+                print_json(&customers)?;
+            } else {
+                print_customers(&customers);
+            }
+        }
+        Command::Rent {
+            customer_id,
+            film_id,
+            date,
+            days,
+        } => {
+            let rental = store::checkout(conn, customer_id, film_id, &date, days)?;
+            if json {
+                print_json(&rental)?;
+            } else {
+                print_rentals(&[rental]);
+            }
+        }
+        Command::Return { rental_id, date } => {
+            let receipt = store::return_film(conn, rental_id, &date)?;
+            if json {
+                print_json(&receipt)?;
+            } else {
+                print_receipt(&receipt);
+            }
+        }
+        Command::Overdue { as_of } => {
+            let report = store::overdue_report(conn, &as_of)?;
+            if json {
+                #[derive(Serialize)]
+                struct Overdue<'a> {
+                    #[serde(flatten)]
+                    overdue: &'a OverdueRental,
+                    late_fee: f64,
+                }
+                let report: Vec<_> = report
+                    .iter()
+                    .map(|overdue| Overdue {
+                        overdue,
+                        late_fee: overdue.late_fee(),
+                    })
+                    .collect();
+                print_json(&report)?;
+            } else {
+                print_overdue(&report);
+            }
+        }
+        Command::Import { table, file } => {
+            let count = csv_io::import_table(conn, &table, File::open(&file)?)?;
+            if json {
+                print_json(&json!({ "table": table, "imported": count }))?;
+            } else {
+                println!("Imported {} rows into {}", count, table);
+            }
+        }
+        Command::Export { table, file } => match file {
+            Some(file) => {
+                let count = csv_io::export(conn, &table, File::create(&file)?)?;
+                eprintln!("Exported {} rows to {}", count, file.display());
+            }
+            None => {
+                csv_io::export(conn, &table, io::stdout())?;
+            }
+        },
     }
 
     Ok(())
 }
 
-fn create_customer_table() -> TableCreateStatement {
-    Table::create()
-        .table(Customer::Table)
-        .if_not_exists()
-        .col(
-            ColumnDef::new(Customer::Id)
-                .integer()
-                .not_null()
-                .auto_increment()
-                .primary_key(),
-        )
-        .col(ColumnDef::new(Customer::FirstName).string().not_null())
-        .col(ColumnDef::new(Customer::LastName).string().not_null())
-        .col(ColumnDef::new(Customer::Email).string().not_null())
-        .to_owned()
+fn print_json<T: Serialize>(value: &T) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
 }
 
-fn create_film_table() -> TableCreateStatement {
-    Table::create()
-        .table(Film::Table)
-        .if_not_exists()
-        .col(
-            ColumnDef::new(Film::Id)
-                .integer()
-                .not_null()
-                .auto_increment()
-                .primary_key(),
-        )
-        .col(ColumnDef::new(Film::Title).string().not_null())
-        .col(ColumnDef::new(Film::Description).string().not_null())
-        .col(ColumnDef::new(Film::ReleaseYear).string().not_null())
-        .col(ColumnDef::new(Film::RentalRate).double().not_null())
-        .to_owned()
+fn print_customers(customers: &[Customer]) {
+    let rows = customers
+        .iter()
+        .map(|customer| {
+            vec![
+                customer.id.to_string(),
+                customer.first_name.clone(),
+                customer.last_name.clone(),
+                customer.email.clone(),
+            ]
+        })
+        .collect();
+    print_table(&["ID", "FIRST NAME", "LAST NAME", "EMAIL"], rows);
 }
 
-fn create_rental_table() -> TableCreateStatement {
-    Table::create()
-        .table(Rental::Table)
-        .if_not_exists()
-        .col(
-            ColumnDef::new(Rental::Id)
-                .integer()
-                .not_null()
-                .auto_increment()
-                .primary_key(),
-        )
-        .col(ColumnDef::new(Rental::RentalDate).date().not_null())
-        .col(ColumnDef::new(Rental::CustomerId).integer().not_null())
-        .col(ColumnDef::new(Rental::FilmId).integer().not_null())
-        .col(ColumnDef::new(Rental::DueDate).date().not_null())
-        .col(ColumnDef::new(Rental::ReturnDate).date())
-        .foreign_key(
-            ForeignKey::create()
-                .name("fk_rental_customer")
-                .from(Rental::Table, Rental::CustomerId)
-                .to(Customer::Table, Customer::Id),
-        )
-        .foreign_key(
-            ForeignKey::create()
-                .name("fk_rental_film")
-                .from(Rental::Table, Rental::FilmId)
-                .to(Film::Table, Film::Id),
-        )
-        .to_owned()
+fn print_rentals(rentals: &[Rental]) {
+    let rows = rentals
+        .iter()
+        .map(|rental| {
+            vec![
+                rental.id.to_string(),
+                rental.customer_id.to_string(),
+                rental.film_id.to_string(),
+                rental.rental_date.clone(),
+                rental.due_date.clone(),
+                rental.return_date.clone().unwrap_or_default(),
+                rental.late_fee.map(fee).unwrap_or_default(),
+            ]
+        })
+        .collect();
+    print_table(
+        &[
+            "ID", "CUSTOMER", "FILM", "RENTED", "DUE", "RETURNED", "LATE FEE",
+        ],
+        rows,
+    );
 }
 
-fn build_schema_stmt<T>(stmt: T) -> String
-where
-    T: SchemaStatementBuilder,
-{
-    fire::dbg!("return", stmt.build(SqliteQueryBuilder))
+fn print_receipt(receipt: &Receipt) {
+    print_rentals(std::slice::from_ref(&receipt.rental));
+    println!();
+    println!(
+        "{} days late, {} in late fees",
+        receipt.days_late,
+        fee(receipt.late_fee),
+    );
 }
 
-fn insert_customers() -> InsertStatement {
-    Query::insert()
-        .into_table(Customer::Table)
-        .columns([Customer::FirstName, Customer::LastName, Customer::Email])
-        .values_panic(["Cory".into(), "Hernandez".into(), "ab@zunu.hk".into()])
-        .values_panic(["Aaron".into(), "Fleming".into(), "fabas@nukem.my".into()])
-        .values_panic(["Clifford".into(), "Santiago".into(), "vobibe@ho.gq".into()])
-        .to_owned()
+fn print_overdue(report: &[OverdueRental]) {
+    let rows = report
+        .iter()
+        .map(|overdue| {
+            vec![
+                overdue.rental.id.to_string(),
+                format!(
+                    "{} {}",
+                    overdue.customer.first_name, overdue.customer.last_name
+                ),
+                overdue.film.title.clone(),
+                overdue.rental.due_date.clone(),
+                overdue.days_overdue.to_string(),
+                fee(overdue.late_fee()),
+            ]
+        })
+        .collect();
+    print_table(
+        &[
+            "RENTAL",
+            "CUSTOMER",
+            "FILM",
+            "DUE",
+            "DAYS OVERDUE",
+            "LATE FEE",
+        ],
+        rows,
+    );
 }
 
-fn insert_films() -> InsertStatement {
-    Query::insert()
-        .into_table(Film::Table)
-        .columns([
-            Film::Title,
-            Film::Description,
-            Film::ReleaseYear,
-            Film::RentalRate,
-        ])
-        .values_panic([
-            "Philosopher's Stone".into(),
-            "The philosopher's stone, a magical object that can turn metal into gold and produce an immortality elixir.".into(),
-            "2001".into(),
-            10.05.into(),
-        ])
-        .values_panic([
-            "Chamber of Secrets".into(),
-            "The Chamber of Secrets has been opened, enemies of the heir... beware.".into(),
-            "2002".into(),
-            8.99.into(),
-        ]).to_owned()
+fn fee(fee: f64) -> String {
+    format!("{:.2}", fee)
 }
 
-fn insert_rentals() -> InsertStatement {
-    Query::insert()
-        .into_table(Rental::Table)
-        .columns([
-            Rental::RentalDate,
-            Rental::CustomerId,
-            Rental::FilmId,
-            Rental::DueDate,
-            Rental::ReturnDate,
-        ])
-        .values_panic([
-            "2023-10-10".into(),
-            1.into(),
-            1.into(),
-            "2023-10-17".into(),
-            "2023-10-15".into(),
-        ])
-        .values_panic([
-            "2023-10-11".into(),
-            1.into(),
-            2.into(),
-            "2023-10-18".into(),
-            Option::<String>::None.into(),
-        ])
-        .values_panic([
-            "2023-10-12".into(),
-            2.into(),
-            1.into(),
-            "2023-10-20".into(),
-            Option::<String>::None.into(),
-        ])
-        .values_panic([
-            "2023-10-13".into(),
-            2.into(),
-            2.into(),
-            "2023-10-21".into(),
-            Option::<String>::None.into(),
-        ])
-        .to_owned()
+fn print_table(headers: &[&str], rows: Vec<Vec<String>>) {
+    print!("{}", table(headers, rows));
 }
 
-fn select_overdue_rentals() -> SelectStatement {
-    Query::select()
-        .columns([Customer::FirstName, Customer::LastName])
-        .columns([Film::Title])
-        .columns([Rental::RentalDate, Rental::DueDate, Rental::ReturnDate])
-        .from(Rental::Table)
-        .inner_join(
-            Customer::Table,
-            Expr::col((Customer::Table, Customer::Id)).equals((Rental::Table, Rental::CustomerId)),
-        )
-        .inner_join(
-            Film::Table,
-            Expr::col((Film::Table, Film::Id)).equals((Rental::Table, Rental::FilmId)),
-        )
-        .and_where(Expr::col((Rental::Table, Rental::ReturnDate)).is_null())
-        .and_where(Expr::col((Rental::Table, Rental::DueDate)).gt("2023-10-18"))
-        .to_owned()
-}
+// Every column is as wide as its widest cell.
+fn table(headers: &[&str], rows: Vec<Vec<String>>) -> String {
+    let mut widths: Vec<usize> = headers.iter().map(|h| h.chars().count()).collect();
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
 
-fn build_query_stmt<T>(stmt: T) -> (String, RusqliteValues)
-where
-    T: RusqliteBinder,
-{
-    fire::dbg!("return", stmt.build_rusqlite(SqliteQueryBuilder))
+    let headers: Vec<String> = headers.iter().map(|h| h.to_string()).collect();
+    let mut table = String::new();
+    for row in std::iter::once(headers).chain(rows) {
+        let cells: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, &width)| format!("{:width$}", cell, width = width))
+            .collect();
+        table.push_str(cells.join("  ").trim_end());
+        table.push('\n');
+    }
+    table
 }
 
-#[derive(Iden)]
-enum Customer {
-    Table,
-    Id,
-    FirstName,
-    LastName,
-    Email,
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
 
-#[derive(Iden)]
-enum Film {
-    Table,
-    Id,
-    Title,
-    Description,
-    ReleaseYear,
-    RentalRate,
-}
+    fn parse(args: &[&str]) -> Result<Opt, structopt::clap::Error> {
+        Opt::from_iter_safe(std::iter::once("rental").chain(args.iter().copied()))
+    }
 
-#[derive(Iden)]
-enum Rental {
-    Table,
-    Id,
-    RentalDate,
-    CustomerId,
-    FilmId,
-    DueDate,
-    ReturnDate,
-}
+    #[test]
+    fn parses_options_and_defaults() {
+        let opt = parse(&["--log", "--slow-ms", "50", "rent", "1", "2"]).unwrap();
+        assert_eq!(opt.db, PathBuf::from("rental.db"));
+        assert!(opt.log && !opt.json && !opt.explain);
+        assert_eq!(opt.slow_ms, Some(50));
+        assert!(matches!(
+            opt.command,
+            Command::Rent {
+                customer_id: 1,
+                film_id: 2,
+                ref date,
+                days: 7,
+            } if date == "now"
+        ));
 
-#[derive(Debug)]
-struct OverdueRental {
-    first_name: String,
-    last_name: String,
-    title: String,
-    rental_date: String,
-    due_date: String,
-    return_date: Option<String>,
-}
+        let opt = parse(&["--db", "other.db", "--json", "init", "--dry-run"]).unwrap();
+        assert_eq!(opt.db, PathBuf::from("other.db"));
+        assert!(opt.json);
+        assert!(matches!(opt.command, Command::Init { dry_run: true }));
 
-impl From<&Row<'_>> for OverdueRental {
-    fn from(row: &Row) -> Self {
-        fire::dbg!(
-            "return",
-            Self {
-                first_name: row.get_unwrap("first_name"),
-                last_name: row.get_unwrap("last_name"),
-                title: row.get_unwrap("title"),
-                rental_date: row.get_unwrap("rental_date"),
-                due_date: row.get_unwrap("due_date"),
-                return_date: row.get_unwrap("return_date"),
-            }
-        )
+        let opt = parse(&["customers", "list", "--name", "an", "--page", "2"]).unwrap();
+        assert!(matches!(
+            opt.command,
+            Command::Customers(CustomersCommand::List {
+                name: Some(ref name),
+                email: None,
+                page: 2,
+                per_page: 20,
+            }) if name == "an"
+        ));
+
+        let opt = parse(&["export", "film"]).unwrap();
+        assert!(matches!(opt.command, Command::Export { file: None, .. }));
+    }
+
+    #[test]
+    fn rejects_invalid_arguments() {
+        assert!(parse(&[]).is_err());
+        assert!(parse(&["rent", "1"]).is_err());
+        assert!(parse(&["rent", "one", "2"]).is_err());
+        assert!(parse(&["return", "1", "--days", "3"]).is_err());
+        assert!(parse(&["--slow-ms", "soon", "overdue"]).is_err());
+        assert!(parse(&["rentals"]).is_err());
+    }
+
+    #[test]
+    fn explain_implies_log() {
+        assert!(log_options(false, None, false).is_none());
+        let options = log_options(false, None, true).unwrap();
+        assert!(options.all && options.explain);
+        assert_eq!(options.slow_threshold, None);
+
+        let options = log_options(false, Some(50), true).unwrap();
+        assert!(!options.all && options.explain);
+        assert_eq!(options.slow_threshold, Some(Duration::from_millis(50)));
+        let options = log_options(true, Some(50), false).unwrap();
+        assert!(options.all && !options.explain);
+    }
+
+    #[test]
+    fn only_init_creates_the_database() {
+        let db = std::env::temp_dir().join(format!("rental-{}.db", std::process::id()));
+        let _ = fs::remove_file(&db);
+
+        let opt = parse(&["overdue"]).unwrap();
+        assert!(schema::open(&db, open_flags(&opt.command)).is_err());
+        assert!(!db.exists());
+
+        let opt = parse(&["init", "--dry-run"]).unwrap();
+        assert!(schema::open(&db, open_flags(&opt.command)).is_err());
+        open_db(&db, &opt.command).unwrap();
+        assert!(!db.exists());
+
+        let opt = parse(&["init"]).unwrap();
+        schema::open(&db, open_flags(&opt.command)).unwrap();
+        assert!(db.exists());
+        let opt = parse(&["overdue"]).unwrap();
+        schema::open(&db, open_flags(&opt.command)).unwrap();
+        fs::remove_file(&db).unwrap();
+    }
+
+    #[test]
+    fn table_columns() {
+        let rows = vec![
+            vec!["1".to_owned(), "Cory".to_owned(), "".to_owned()],
+            vec!["10".to_owned(), "Clifford".to_owned(), "2.50".to_owned()],
+        ];
+        assert_eq!(
+            table(&["ID", "NAME", "LATE FEE"], rows),
+            "\
+ID  NAME      LATE FEE
+1   Cory
+10  Clifford  2.50
+",
+        );
+        assert_eq!(table(&["ID", "NAME"], Vec::new()), "ID  NAME\n");
     }
 }
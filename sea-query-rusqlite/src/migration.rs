@@ -0,0 +1,390 @@
+use crate::executor::{Commit, Executor, Transact};
+use crate::schema::{create_customer_table, create_film_table, create_rental_table};
+use crate::schema::{table_exists, Customer, Film, Rental};
+use crate::{build_query_stmt, build_schema_stmt};
+use anyhow::{bail, Result};
+use firedbg_lib::fire;
+use sea_query::{
+    ColumnDef, Expr, Iden, Index, IndexCreateStatement, IndexDropStatement, Order, Query, Table,
+    TableAlterStatement, TableCreateStatement, TableDropStatement,
+};
+use sea_query_rusqlite::RusqliteValues;
+
+// Ordered by version. A version, once released, must never change, as it is
+// what an existing database records as applied.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "create customer, film and rental",
+        up: create_tables,
+        down: drop_tables,
+    },
+    Migration {
+        version: 2,
+        name: "index rental by customer and film",
+        up: create_rental_indexes,
+        down: drop_rental_indexes,
+    },
+    Migration {
+        version: 3,
+        name: "add rental late fee",
+        up: add_late_fee,
+        down: drop_late_fee,
+    },
+];
+
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    pub up: fn() -> Vec<Step>,
+    pub down: fn() -> Vec<Step>,
+}
+
+pub enum Step {
+    CreateTable(TableCreateStatement),
+    AlterTable(TableAlterStatement),
+    DropTable(TableDropStatement),
+    CreateIndex(IndexCreateStatement),
+    DropIndex(IndexDropStatement),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Apply,
+    // Only builds the SQL of every pending step, without running it.
+    DryRun,
+}
+
+// A migration applied or reverted, or that would be in a dry run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Migrated {
+    pub version: i64,
+    pub name: &'static str,
+    // The SQL of its steps, in order.
+    pub statements: Vec<String>,
+}
+
+#[derive(Iden, Clone, Copy)]
+pub enum SchemaMigration {
+    Table,
+    Version,
+    Name,
+    AppliedAt,
+}
+
+// Applies the migrations not applied yet, up to and including `target`, or
+// all of them. Returns the migrations applied.
+pub fn up(conn: &mut impl Transact, target: Option<i64>, mode: Mode) -> Result<Vec<Migrated>> {
+    let applied = applied_versions(conn)?;
+    let pending = MIGRATIONS.iter().filter(|migration| {
+        !applied.contains(&migration.version) && target.is_none_or(|v| migration.version <= v)
+    });
+
+    let mut migrated = Vec::new();
+    for migration in pending {
+        let statements = (migration.up)().into_iter().map(Step::build).collect();
+        migrated.push(run(conn, migration, "up", statements, mode)?);
+    }
+    Ok(migrated)
+}
+
+// Reverts the applied migrations above `target`, latest first. Returns the
+// migrations reverted.
+pub fn down(conn: &mut impl Transact, target: i64, mode: Mode) -> Result<Vec<Migrated>> {
+    let applied = applied_versions(conn)?;
+    let mut migrated = Vec::new();
+    for &version in applied.iter().rev().filter(|&&v| v > target) {
+        let Some(migration) = MIGRATIONS.iter().find(|m| m.version == version) else {
+            bail!("applied migration {} is unknown to this build", version);
+        };
+        let statements = (migration.down)().into_iter().map(Step::build).collect();
+        migrated.push(run(conn, migration, "down", statements, mode)?);
+    }
+    Ok(migrated)
+}
+
+// Ascending. A database without the version table has nothing applied.
+pub fn applied_versions(conn: &impl Executor) -> Result<Vec<i64>> {
+    if !table_exists(conn, &SchemaMigration::Table.to_string())? {
+        return Ok(Vec::new());
+    }
+    let (sql, values) = build_query_stmt(
+        Query::select()
+            .column(SchemaMigration::Version)
+            .from(SchemaMigration::Table)
+            .order_by(SchemaMigration::Version, Order::Asc)
+            .to_owned(),
+    );
+    conn.query_map(&sql, &values, |row| Ok(row.get(0)?))
+}
+
+// Each migration runs in its own transaction together with the bookkeeping of
+// its version, so a failing step leaves the database as it was. A dry run only
+// returns the statements.
+fn run(
+    conn: &mut impl Transact,
+    migration: &Migration,
+    direction: &str,
+    statements: Vec<String>,
+    mode: Mode,
+) -> Result<Migrated> {
+    let migrated = Migrated {
+        version: migration.version,
+        name: migration.name,
+        statements,
+    };
+    if mode == Mode::DryRun {
+        return Ok(migrated);
+    }
+
+    let tx = conn.transaction()?;
+    let no_values = RusqliteValues(Vec::new());
+    tx.execute(&build_schema_stmt(create_version_table()), &no_values)?;
+    for sql in &migrated.statements {
+        tx.execute(sql, &no_values)?;
+    }
+    let (sql, values) = if direction == "up" {
+        build_query_stmt(
+            Query::insert()
+                .into_table(SchemaMigration::Table)
+                .columns([SchemaMigration::Version, SchemaMigration::Name])
+                .values_panic([migration.version.into(), migration.name.into()])
+                .to_owned(),
+        )
+    } else {
+        build_query_stmt(
+            Query::delete()
+                .from_table(SchemaMigration::Table)
+                .and_where(Expr::col(SchemaMigration::Version).eq(migration.version))
+                .to_owned(),
+        )
+    };
+    tx.execute(&sql, &values)?;
+    tx.commit()?;
+
+    fire::dbg!("migrated", (direction, migration.version));
+    Ok(migrated)
+}
+
+fn create_version_table() -> TableCreateStatement {
+    Table::create()
+        .table(SchemaMigration::Table)
+        .if_not_exists()
+        .col(
+            ColumnDef::new(SchemaMigration::Version)
+                .integer()
+                .not_null()
+                .primary_key(),
+        )
+        .col(ColumnDef::new(SchemaMigration::Name).string().not_null())
+        .col(
+            ColumnDef::new(SchemaMigration::AppliedAt)
+                .timestamp()
+                .not_null()
+                .default(Expr::current_timestamp()),
+        )
+        .to_owned()
+}
+
+impl Step {
+    fn build(self) -> String {
+        match self {
+            Step::CreateTable(stmt) => build_schema_stmt(stmt),
+            Step::AlterTable(stmt) => build_schema_stmt(stmt),
+            Step::DropTable(stmt) => build_schema_stmt(stmt),
+            Step::CreateIndex(stmt) => build_schema_stmt(stmt),
+            Step::DropIndex(stmt) => build_schema_stmt(stmt),
+        }
+    }
+}
+
+impl From<TableCreateStatement> for Step {
+    fn from(stmt: TableCreateStatement) -> Self {
+        Step::CreateTable(stmt)
+    }
+}
+
+impl From<TableAlterStatement> for Step {
+    fn from(stmt: TableAlterStatement) -> Self {
+        Step::AlterTable(stmt)
+    }
+}
+
+impl From<TableDropStatement> for Step {
+    fn from(stmt: TableDropStatement) -> Self {
+        Step::DropTable(stmt)
+    }
+}
+
+impl From<IndexCreateStatement> for Step {
+    fn from(stmt: IndexCreateStatement) -> Self {
+        Step::CreateIndex(stmt)
+    }
+}
+
+impl From<IndexDropStatement> for Step {
+    fn from(stmt: IndexDropStatement) -> Self {
+        Step::DropIndex(stmt)
+    }
+}
+
+// The create statements keep `if_not_exists`, so that databases set up before
+// there were migrations get adopted as version 1.
+fn create_tables() -> Vec<Step> {
+    vec![
+        create_customer_table().into(),
+        create_film_table().into(),
+        create_rental_table().into(),
+    ]
+}
+
+fn drop_tables() -> Vec<Step> {
+    vec![
+        Table::drop().table(Rental::Table).to_owned().into(),
+        Table::drop().table(Film::Table).to_owned().into(),
+        Table::drop().table(Customer::Table).to_owned().into(),
+    ]
+}
+
+fn create_rental_indexes() -> Vec<Step> {
+    vec![
+        Index::create()
+            .name("idx_rental_customer")
+            .table(Rental::Table)
+            .col(Rental::CustomerId)
+            .to_owned()
+            .into(),
+        Index::create()
+            .name("idx_rental_film")
+            .table(Rental::Table)
+            .col(Rental::FilmId)
+            .to_owned()
+            .into(),
+    ]
+}
+
+fn drop_rental_indexes() -> Vec<Step> {
+    vec![
+        Index::drop().name("idx_rental_customer").to_owned().into(),
+        Index::drop().name("idx_rental_film").to_owned().into(),
+    ]
+}
+
+fn add_late_fee() -> Vec<Step> {
+    vec![Table::alter()
+        .table(Rental::Table)
+        .add_column(ColumnDef::new(Rental::LateFee).double())
+        .to_owned()
+        .into()]
+}
+
+fn drop_late_fee() -> Vec<Step> {
+    vec![Table::alter()
+        .table(Rental::Table)
+        .drop_column(Rental::LateFee)
+        .to_owned()
+        .into()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::Connection;
+
+    fn table_names(conn: &Connection) -> Vec<String> {
+        let mut stmt = conn
+            .prepare("SELECT name FROM sqlite_master WHERE type IN ('table', 'index') AND name NOT LIKE 'sqlite_%' ORDER BY name")
+            .unwrap();
+        let rows = stmt.query_map([], |row| row.get(0)).unwrap();
+        rows.collect::<rusqlite::Result<_>>().unwrap()
+    }
+
+    fn versions(migrated: Result<Vec<Migrated>>) -> Vec<i64> {
+        migrated.unwrap().iter().map(|m| m.version).collect()
+    }
+
+    #[test]
+    fn versions_are_ordered() {
+        for pair in MIGRATIONS.windows(2) {
+            assert!(pair[0].version < pair[1].version);
+        }
+    }
+
+    #[test]
+    fn up_and_down() {
+        let mut conn = Connection::open_in_memory().unwrap();
+
+        assert_eq!(versions(up(&mut conn, None, Mode::Apply)), [1, 2, 3]);
+        assert_eq!(applied_versions(&conn).unwrap(), [1, 2, 3]);
+        assert_eq!(
+            table_names(&conn),
+            [
+                "customer",
+                "film",
+                "idx_rental_customer",
+                "idx_rental_film",
+                "rental",
+                "schema_migration",
+            ],
+        );
+        assert!(up(&mut conn, None, Mode::Apply).unwrap().is_empty());
+
+        assert_eq!(versions(down(&mut conn, 1, Mode::Apply)), [3, 2]);
+        assert_eq!(applied_versions(&conn).unwrap(), [1]);
+        assert_eq!(versions(down(&mut conn, 0, Mode::Apply)), [1]);
+        assert_eq!(table_names(&conn), ["schema_migration"]);
+    }
+
+    #[test]
+    fn up_to_target() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        assert_eq!(versions(up(&mut conn, Some(1), Mode::Apply)), [1]);
+        assert_eq!(versions(up(&mut conn, None, Mode::Apply)), [2, 3]);
+    }
+
+    #[test]
+    fn dry_run_changes_nothing() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        assert_eq!(versions(up(&mut conn, None, Mode::DryRun)), [1, 2, 3]);
+        assert!(table_names(&conn).is_empty());
+
+        up(&mut conn, None, Mode::Apply).unwrap();
+        assert_eq!(versions(down(&mut conn, 0, Mode::DryRun)), [3, 2, 1]);
+        assert_eq!(applied_versions(&conn).unwrap(), [1, 2, 3]);
+    }
+
+    #[test]
+    fn dry_run_returns_sql() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let migrated = up(&mut conn, Some(2), Mode::DryRun).unwrap();
+        assert_eq!(migrated.len(), 2);
+        assert_eq!(migrated[0].statements.len(), 3);
+        assert!(migrated[0].statements[0].starts_with(r#"CREATE TABLE IF NOT EXISTS "customer" ("#));
+        assert_eq!(
+            migrated[1],
+            Migrated {
+                version: 2,
+                name: "index rental by customer and film",
+                statements: vec![
+                    r#"CREATE INDEX "idx_rental_customer" ON "rental" ("customer_id")"#.to_owned(),
+                    r#"CREATE INDEX "idx_rental_film" ON "rental" ("film_id")"#.to_owned(),
+                ],
+            },
+        );
+
+        up(&mut conn, None, Mode::Apply).unwrap();
+        let migrated = down(&mut conn, 2, Mode::DryRun).unwrap();
+        assert_eq!(
+            migrated[0].statements,
+            [r#"ALTER TABLE "rental" DROP COLUMN "late_fee""#],
+        );
+    }
+
+    #[test]
+    fn adopts_database_without_versions() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute(&build_schema_stmt(create_customer_table()), [])
+            .unwrap();
+        assert_eq!(versions(up(&mut conn, None, Mode::Apply)), [1, 2, 3]);
+    }
+}
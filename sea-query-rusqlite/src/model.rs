@@ -0,0 +1,184 @@
+use crate::repository::Entity;
+use crate::schema;
+use crate::FromRow;
+use sea_query::{Cond, Condition, Expr, Value};
+use serde::Serialize;
+
+#[derive(Debug, Clone, PartialEq, FromRow, Serialize)]
+pub struct Customer {
+    pub id: i64,
+    pub first_name: String,
+    pub last_name: String,
+    pub email: String,
+}
+
+#[derive(Debug, Clone, PartialEq, FromRow, Serialize)]
+pub struct Film {
+    pub id: i64,
+    pub title: String,
+    pub description: String,
+    pub release_year: String,
+    pub rental_rate: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, FromRow, Serialize)]
+pub struct Rental {
+    pub id: i64,
+    pub rental_date: String,
+    pub customer_id: i64,
+    pub film_id: i64,
+    pub due_date: String,
+    pub return_date: Option<String>,
+    // Charged when a film is returned after its due date.
+    pub late_fee: Option<f64>,
+}
+
+#[derive(Debug, Default)]
+pub struct CustomerFilter {
+    // Part of the first or last name.
+    pub name_contains: Option<String>,
+    pub email: Option<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct FilmFilter {
+    pub title_contains: Option<String>,
+    pub release_year: Option<String>,
+    pub max_rental_rate: Option<f64>,
+}
+
+#[derive(Debug, Default)]
+pub struct RentalFilter {
+    pub customer_id: Option<i64>,
+    pub film_id: Option<i64>,
+    pub returned: Option<bool>,
+}
+
+impl Entity for Customer {
+    type Column = schema::Customer;
+    type Filter = CustomerFilter;
+
+    const TABLE: schema::Customer = schema::Customer::Table;
+    const ID: schema::Customer = schema::Customer::Id;
+    const COLUMNS: &'static [schema::Customer] = &[
+        schema::Customer::FirstName,
+        schema::Customer::LastName,
+        schema::Customer::Email,
+    ];
+
+    fn id(&self) -> i64 {
+        self.id
+    }
+
+    fn values(&self) -> Vec<Value> {
+        vec![
+            self.first_name.clone().into(),
+            self.last_name.clone().into(),
+            self.email.clone().into(),
+        ]
+    }
+
+    fn condition(filter: &CustomerFilter) -> Condition {
+        let mut cond = Cond::all();
+        if let Some(name) = &filter.name_contains {
+            let pattern = format!("%{}%", name);
+            cond = cond.add(
+                Cond::any()
+                    .add(Expr::col(schema::Customer::FirstName).like(pattern.as_str()))
+                    .add(Expr::col(schema::Customer::LastName).like(pattern.as_str())),
+            );
+        }
+        if let Some(email) = &filter.email {
+            cond = cond.add(Expr::col(schema::Customer::Email).eq(email.as_str()));
+        }
+        cond
+    }
+}
+
+impl Entity for Film {
+    type Column = schema::Film;
+    type Filter = FilmFilter;
+
+    const TABLE: schema::Film = schema::Film::Table;
+    const ID: schema::Film = schema::Film::Id;
+    const COLUMNS: &'static [schema::Film] = &[
+        schema::Film::Title,
+        schema::Film::Description,
+        schema::Film::ReleaseYear,
+        schema::Film::RentalRate,
+    ];
+
+    fn id(&self) -> i64 {
+        self.id
+    }
+
+    fn values(&self) -> Vec<Value> {
+        vec![
+            self.title.clone().into(),
+            self.description.clone().into(),
+            self.release_year.clone().into(),
+            self.rental_rate.into(),
+        ]
+    }
+
+    fn condition(filter: &FilmFilter) -> Condition {
+        let mut cond = Cond::all();
+        if let Some(title) = &filter.title_contains {
+            cond = cond.add(Expr::col(schema::Film::Title).like(format!("%{}%", title).as_str()));
+        }
+        if let Some(year) = &filter.release_year {
+            cond = cond.add(Expr::col(schema::Film::ReleaseYear).eq(year.as_str()));
+        }
+        if let Some(rate) = filter.max_rental_rate {
+            cond = cond.add(Expr::col(schema::Film::RentalRate).lte(rate));
+        }
+        cond
+    }
+}
+
+impl Entity for Rental {
+    type Column = schema::Rental;
+    type Filter = RentalFilter;
+
+    const TABLE: schema::Rental = schema::Rental::Table;
+    const ID: schema::Rental = schema::Rental::Id;
+    const COLUMNS: &'static [schema::Rental] = &[
+        schema::Rental::RentalDate,
+        schema::Rental::CustomerId,
+        schema::Rental::FilmId,
+        schema::Rental::DueDate,
+        schema::Rental::ReturnDate,
+        schema::Rental::LateFee,
+    ];
+
+    fn id(&self) -> i64 {
+        self.id
+    }
+
+    fn values(&self) -> Vec<Value> {
+        vec![
+            self.rental_date.clone().into(),
+            self.customer_id.into(),
+            self.film_id.into(),
+            self.due_date.clone().into(),
+            self.return_date.clone().into(),
+            self.late_fee.into(),
+        ]
+    }
+
+    fn condition(filter: &RentalFilter) -> Condition {
+        let mut cond = Cond::all();
+        if let Some(customer_id) = filter.customer_id {
+            cond = cond.add(Expr::col(schema::Rental::CustomerId).eq(customer_id));
+        }
+        if let Some(film_id) = filter.film_id {
+            cond = cond.add(Expr::col(schema::Rental::FilmId).eq(film_id));
+        }
+        match filter.returned {
+            Some(true) => cond = cond.add(Expr::col(schema::Rental::ReturnDate).is_not_null()),
+            Some(false) => cond = cond.add(Expr::col(schema::Rental::ReturnDate).is_null()),
+            None => {}
+        }
+        cond
+    }
+}
@@ -0,0 +1,345 @@
+use crate::executor::Executor;
+use crate::{build_query_stmt, FromRow};
+use anyhow::{anyhow, Result};
+use sea_query::{
+    Alias, Condition, Expr, Func, Iden, Order, Query, SelectStatement, SimpleExpr, Value,
+};
+use std::marker::PhantomData;
+
+// A row type stored in the table of its `Iden` enum, with an integer primary
+// key assigned by the database.
+pub trait Entity: FromRow {
+    type Column: Iden + Copy + 'static;
+    type Filter;
+
+    const TABLE: Self::Column;
+    const ID: Self::Column;
+    // Every column but the id, in the order of `values`.
+    const COLUMNS: &'static [Self::Column];
+
+    fn id(&self) -> i64;
+    fn values(&self) -> Vec<Value>;
+    fn condition(filter: &Self::Filter) -> Condition;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Page {
+    // Counted from 0.
+    pub number: u64,
+    pub size: u64,
+}
+
+impl Page {
+    pub fn new(number: u64, size: u64) -> Self {
+        Self { number, size }
+    }
+
+    fn offset(&self) -> u64 {
+        self.number * self.size
+    }
+}
+
+impl Default for Page {
+    fn default() -> Self {
+        Self::new(0, 20)
+    }
+}
+
+pub struct Repository<'c, E, C> {
+    conn: &'c C,
+    entity: PhantomData<E>,
+}
+
+impl<'c, E: Entity, C: Executor> Repository<'c, E, C> {
+    pub fn new(conn: &'c C) -> Self {
+        Self {
+            conn,
+            entity: PhantomData,
+        }
+    }
+
+    // Inserts everything but the id, and returns the row as stored.
+    pub fn create(&self, entity: &E) -> Result<E> {
+        let (sql, values) = build_query_stmt(
+            Query::insert()
+                .into_table(E::TABLE)
+                .columns(E::COLUMNS.iter().copied())
+                .values(entity.values().into_iter().map(SimpleExpr::from))?
+                .to_owned(),
+        );
+        self.conn.execute(&sql, &values)?;
+
+        let id = self.conn.connection().last_insert_rowid();
+        self.get(id)?
+            .ok_or_else(|| anyhow!("{} {} not found after insert", E::TABLE.to_string(), id))
+    }
+
+    pub fn get(&self, id: i64) -> Result<Option<E>> {
+        let (sql, values) =
+            build_query_stmt(self.select().and_where(Expr::col(E::ID).eq(id)).to_owned());
+        let rows = self
+            .conn
+            .query_map(&sql, &values, |row| Ok(E::from_row(row)?))?;
+        Ok(rows.into_iter().next())
+    }
+
+    // Returns whether a row with the id of `entity` was found.
+    pub fn update(&self, entity: &E) -> Result<bool> {
+        let columns = E::COLUMNS.iter().copied();
+        let values = entity.values().into_iter().map(SimpleExpr::from);
+        let (sql, values) = build_query_stmt(
+            Query::update()
+                .table(E::TABLE)
+                .values(columns.zip(values))
+                .and_where(Expr::col(E::ID).eq(entity.id()))
+                .to_owned(),
+        );
+        let updated = self.conn.execute(&sql, &values)?;
+        Ok(updated > 0)
+    }
+
+    // Returns whether a row with the id was found.
+    pub fn delete(&self, id: i64) -> Result<bool> {
+        let (sql, values) = build_query_stmt(
+            Query::delete()
+                .from_table(E::TABLE)
+                .and_where(Expr::col(E::ID).eq(id))
+                .to_owned(),
+        );
+        let deleted = self.conn.execute(&sql, &values)?;
+        Ok(deleted > 0)
+    }
+
+    // The rows matching the filter, in order of id.
+    pub fn list(&self, filter: &E::Filter, page: Page) -> Result<Vec<E>> {
+        let (sql, values) = build_query_stmt(
+            self.select()
+                .cond_where(E::condition(filter))
+                .order_by(E::ID, Order::Asc)
+                .limit(page.size)
+                .offset(page.offset())
+                .to_owned(),
+        );
+        self.conn
+            .query_map(&sql, &values, |row| Ok(E::from_row(row)?))
+    }
+
+    pub fn count(&self, filter: &E::Filter) -> Result<u64> {
+        let (sql, values) = build_query_stmt(
+            Query::select()
+                .expr(Func::count(Expr::col(E::ID)))
+                .from(E::TABLE)
+                .cond_where(E::condition(filter))
+                .to_owned(),
+        );
+        let count: i64 = self.conn.query_row(&sql, &values, |row| Ok(row.get(0)?))?;
+        Ok(count as u64)
+    }
+
+    fn select(&self) -> SelectStatement {
+        Query::select()
+            .column(E::ID)
+            .columns(E::COLUMNS.iter().copied())
+            .from(E::TABLE)
+            .to_owned()
+    }
+}
+
+// Selects every column of `E` as `prefix` followed by the column name, for
+// reading `E` with `#[from_row(flatten, prefix = ...)]` out of a join.
+pub fn select_prefixed<E: Entity>(query: &mut SelectStatement, prefix: &str) {
+    for &column in std::iter::once(&E::ID).chain(E::COLUMNS) {
+        query.expr_as(
+            Expr::col((E::TABLE, column)),
+            Alias::new(format!("{}{}", prefix, column.to_string())),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{Customer, CustomerFilter, Film, FilmFilter, Rental, RentalFilter};
+    use crate::schema::{enable_foreign_keys, init_schema};
+    use rusqlite::Connection;
+
+    fn connection() -> Connection {
+        let mut conn = Connection::open_in_memory().unwrap();
+        enable_foreign_keys(&conn).unwrap();
+        init_schema(&mut conn).unwrap();
+        conn
+    }
+
+    fn customer(first_name: &str, last_name: &str) -> Customer {
+        Customer {
+            id: 0,
+            first_name: first_name.to_owned(),
+            last_name: last_name.to_owned(),
+            email: format!("{}@example.com", first_name.to_lowercase()),
+        }
+    }
+
+    #[test]
+    fn create_and_get() {
+        let conn = connection();
+        let customers = Repository::<Customer, _>::new(&conn);
+
+        let created = customers.create(&customer("Cory", "Hernandez")).unwrap();
+        assert_eq!(created.id, 1);
+        assert_eq!(created.first_name, "Cory");
+        assert_eq!(customers.get(created.id).unwrap(), Some(created));
+        assert_eq!(customers.get(2).unwrap(), None);
+    }
+
+    #[test]
+    fn update_and_delete() {
+        let conn = connection();
+        let customers = Repository::<Customer, _>::new(&conn);
+
+        let mut created = customers.create(&customer("Aaron", "Fleming")).unwrap();
+        created.email = "fabas@nukem.my".to_owned();
+        assert!(customers.update(&created).unwrap());
+        assert_eq!(customers.get(created.id).unwrap(), Some(created.clone()));
+
+        assert!(customers.delete(created.id).unwrap());
+        assert!(!customers.delete(created.id).unwrap());
+        assert!(!customers.update(&created).unwrap());
+        assert_eq!(customers.get(created.id).unwrap(), None);
+    }
+
+    #[test]
+    fn pagination() {
+        let conn = connection();
+        let customers = Repository::<Customer, _>::new(&conn);
+        for i in 0..5 {
+            customers
+                .create(&customer(&format!("C{}", i), "Doe"))
+                .unwrap();
+        }
+
+        let filter = CustomerFilter::default();
+        let names = |page| -> Vec<String> {
+            let list = customers.list(&filter, page).unwrap();
+            list.into_iter().map(|c| c.first_name).collect()
+        };
+        assert_eq!(names(Page::new(0, 2)), ["C0", "C1"]);
+        assert_eq!(names(Page::new(1, 2)), ["C2", "C3"]);
+        assert_eq!(names(Page::new(2, 2)), ["C4"]);
+        assert!(names(Page::new(3, 2)).is_empty());
+        assert_eq!(customers.count(&filter).unwrap(), 5);
+    }
+
+    #[test]
+    fn filtering() {
+        let conn = connection();
+        let customers = Repository::<Customer, _>::new(&conn);
+        customers.create(&customer("Cory", "Hernandez")).unwrap();
+        customers.create(&customer("Aaron", "Fleming")).unwrap();
+        customers.create(&customer("Clifford", "Santiago")).unwrap();
+
+        let filter = CustomerFilter {
+            name_contains: Some("an".to_owned()),
+            ..Default::default()
+        };
+        let found = customers.list(&filter, Page::default()).unwrap();
+        let names: Vec<_> = found.iter().map(|c| c.last_name.as_str()).collect();
+        assert_eq!(names, ["Hernandez", "Santiago"]);
+        assert_eq!(customers.count(&filter).unwrap(), 2);
+
+        let filter = CustomerFilter {
+            email: Some("aaron@example.com".to_owned()),
+            ..Default::default()
+        };
+        let found = customers.list(&filter, Page::default()).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].first_name, "Aaron");
+    }
+
+    #[test]
+    fn films_and_rentals() {
+        let conn = connection();
+        let customer = Repository::<Customer, _>::new(&conn)
+            .create(&customer("Cory", "Hernandez"))
+            .unwrap();
+        let films = Repository::<Film, _>::new(&conn);
+        let film = films
+            .create(&Film {
+                id: 0,
+                title: "Chamber of Secrets".to_owned(),
+                description: "The Chamber of Secrets has been opened.".to_owned(),
+                release_year: "2002".to_owned(),
+                rental_rate: 8.99,
+            })
+            .unwrap();
+        assert_eq!(film.rental_rate, 8.99);
+
+        let filter = FilmFilter {
+            max_rental_rate: Some(5.0),
+            ..Default::default()
+        };
+        assert_eq!(films.count(&filter).unwrap(), 0);
+
+        let rentals = Repository::<Rental, _>::new(&conn);
+        let mut rental = rentals
+            .create(&Rental {
+                id: 0,
+                rental_date: "2023-10-11".to_owned(),
+                customer_id: customer.id,
+                film_id: film.id,
+                due_date: "2023-10-18".to_owned(),
+                return_date: None,
+                late_fee: None,
+            })
+            .unwrap();
+        assert_eq!(rental.return_date, None);
+
+        let open = RentalFilter {
+            customer_id: Some(customer.id),
+            returned: Some(false),
+            ..Default::default()
+        };
+        assert_eq!(rentals.count(&open).unwrap(), 1);
+
+        rental.return_date = Some("2023-10-15".to_owned());
+        assert!(rentals.update(&rental).unwrap());
+        assert_eq!(rentals.count(&open).unwrap(), 0);
+        assert_eq!(rentals.get(rental.id).unwrap(), Some(rental));
+    }
+
+    #[test]
+    fn foreign_keys_are_enforced() {
+        let conn = connection();
+        let customers = Repository::<Customer, _>::new(&conn);
+        let customer = customers.create(&customer("Cory", "Hernandez")).unwrap();
+        let film = Repository::<Film, _>::new(&conn)
+            .create(&Film {
+                id: 0,
+                title: "Chamber of Secrets".to_owned(),
+                description: "The Chamber of Secrets has been opened.".to_owned(),
+                release_year: "2002".to_owned(),
+                rental_rate: 8.99,
+            })
+            .unwrap();
+        let rentals = Repository::<Rental, _>::new(&conn);
+        let rental = Rental {
+            id: 0,
+            rental_date: "2023-10-11".to_owned(),
+            customer_id: customer.id,
+            film_id: film.id,
+            due_date: "2023-10-18".to_owned(),
+            return_date: None,
+            late_fee: None,
+        };
+        rentals.create(&rental).unwrap();
+
+        let err = customers.delete(customer.id).unwrap_err();
+        assert!(err.to_string().contains("FOREIGN KEY constraint failed"));
+        assert_eq!(customers.get(customer.id).unwrap(), Some(customer));
+
+        let unknown = Rental {
+            customer_id: 2,
+            ..rental
+        };
+        assert!(rentals.create(&unknown).is_err());
+    }
+}
@@ -0,0 +1,141 @@
+use crate::build_query_stmt;
+use crate::executor::{Executor, Transact};
+use crate::migration::{self, Mode};
+use anyhow::Result;
+use firedbg_lib::fire;
+use rusqlite::{Connection, OpenFlags};
+use sea_query::{
+    Alias, ColumnDef, Expr, ForeignKey, Func, Iden, Query, Table, TableCreateStatement,
+};
+use std::path::Path;
+
+// Opens the database with foreign keys enforced.
+pub fn open<P: AsRef<Path>>(path: P, flags: OpenFlags) -> Result<Connection> {
+    let conn = Connection::open_with_flags(path, flags)?;
+    enable_foreign_keys(&conn)?;
+    Ok(conn)
+}
+
+// SQLite only enforces foreign keys on connections that turn them on.
+pub fn enable_foreign_keys(conn: &Connection) -> Result<()> {
+    conn.pragma_update(None, "foreign_keys", true)?;
+    Ok(())
+}
+
+// Brings the database up to the latest version of the schema.
+pub fn init_schema(conn: &mut impl Transact) -> Result<()> {
+    let migrated = migration::up(conn, None, Mode::Apply)?;
+    fire::dbg!(migrated);
+
+    Ok(())
+}
+
+pub fn table_exists(conn: &impl Executor, name: &str) -> Result<bool> {
+    let (sql, values) = build_query_stmt(
+        Query::select()
+            .expr(Func::count(Expr::col(Alias::new("name"))))
+            .from(Alias::new("sqlite_master"))
+            .and_where(Expr::col(Alias::new("type")).eq("table"))
+            .and_where(Expr::col(Alias::new("name")).eq(name))
+            .to_owned(),
+    );
+    let count: i64 = conn.query_row(&sql, &values, |row| Ok(row.get(0)?))?;
+    Ok(count > 0)
+}
+
+pub fn create_customer_table() -> TableCreateStatement {
+    Table::create()
+        .table(Customer::Table)
+        .if_not_exists()
+        .col(
+            ColumnDef::new(Customer::Id)
+                .integer()
+                .not_null()
+                .auto_increment()
+                .primary_key(),
+        )
+        .col(ColumnDef::new(Customer::FirstName).string().not_null())
+        .col(ColumnDef::new(Customer::LastName).string().not_null())
+        .col(ColumnDef::new(Customer::Email).string().not_null())
+        .to_owned()
+}
+
+pub fn create_film_table() -> TableCreateStatement {
+    Table::create()
+        .table(Film::Table)
+        .if_not_exists()
+        .col(
+            ColumnDef::new(Film::Id)
+                .integer()
+                .not_null()
+                .auto_increment()
+                .primary_key(),
+        )
+        .col(ColumnDef::new(Film::Title).string().not_null())
+        .col(ColumnDef::new(Film::Description).string().not_null())
+        .col(ColumnDef::new(Film::ReleaseYear).string().not_null())
+        .col(ColumnDef::new(Film::RentalRate).double().not_null())
+        .to_owned()
+}
+
+pub fn create_rental_table() -> TableCreateStatement {
+    Table::create()
+        .table(Rental::Table)
+        .if_not_exists()
+        .col(
+            ColumnDef::new(Rental::Id)
+                .integer()
+                .not_null()
+                .auto_increment()
+                .primary_key(),
+        )
+        .col(ColumnDef::new(Rental::RentalDate).date().not_null())
+        .col(ColumnDef::new(Rental::CustomerId).integer().not_null())
+        .col(ColumnDef::new(Rental::FilmId).integer().not_null())
+        .col(ColumnDef::new(Rental::DueDate).date().not_null())
+        .col(ColumnDef::new(Rental::ReturnDate).date())
+        .foreign_key(
+            ForeignKey::create()
+                .name("fk_rental_customer")
+                .from(Rental::Table, Rental::CustomerId)
+                .to(Customer::Table, Customer::Id),
+        )
+        .foreign_key(
+            ForeignKey::create()
+                .name("fk_rental_film")
+                .from(Rental::Table, Rental::FilmId)
+                .to(Film::Table, Film::Id),
+        )
+        .to_owned()
+}
+
+#[derive(Iden, Clone, Copy)]
+pub enum Customer {
+    Table,
+    Id,
+    FirstName,
+    LastName,
+    Email,
+}
+
+#[derive(Iden, Clone, Copy)]
+pub enum Film {
+    Table,
+    Id,
+    Title,
+    Description,
+    ReleaseYear,
+    RentalRate,
+}
+
+#[derive(Iden, Clone, Copy)]
+pub enum Rental {
+    Table,
+    Id,
+    RentalDate,
+    CustomerId,
+    FilmId,
+    DueDate,
+    ReturnDate,
+    LateFee,
+}
@@ -0,0 +1,385 @@
+use crate::executor::{Commit, Executor, Transact};
+use crate::model::{Customer, Film, Rental};
+use crate::repository::{select_prefixed, Repository};
+use crate::{build_query_stmt, schema, FromRow};
+use anyhow::{anyhow, bail, Result};
+use firedbg_lib::fire;
+use sea_query::{Alias, Expr, Func, Order, Query, SimpleExpr};
+use serde::Serialize;
+
+// Due dates, return dates and report dates are `YYYY-MM-DD`, as in SQLite's
+// date functions, which do all date arithmetic here. Dates given by the user
+// may also be `now`, for today in UTC.
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Receipt {
+    pub rental: Rental,
+    pub days_late: i64,
+    pub late_fee: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, FromRow, Serialize)]
+pub struct OverdueRental {
+    #[from_row(flatten, prefix = "rental_")]
+    pub rental: Rental,
+    #[from_row(flatten, prefix = "customer_")]
+    pub customer: Customer,
+    #[from_row(flatten, prefix = "film_")]
+    pub film: Film,
+    pub days_overdue: i64,
+}
+
+impl OverdueRental {
+    // What returning the film on the report date would cost.
+    pub fn late_fee(&self) -> f64 {
+        late_fee(self.film.rental_rate, self.days_overdue)
+    }
+}
+
+// Every day past the due date is charged the rental rate of the film.
+pub fn late_fee(rental_rate: f64, days_late: i64) -> f64 {
+    let fee = rental_rate * days_late.max(0) as f64;
+    (fee * 100.0).round() / 100.0
+}
+
+pub fn checkout(
+    conn: &mut impl Transact,
+    customer_id: i64,
+    film_id: i64,
+    rental_date: &str,
+    rental_days: u32,
+) -> Result<Rental> {
+    let tx = conn.transaction()?;
+
+    if Repository::<Customer, _>::new(&tx)
+        .get(customer_id)?
+        .is_none()
+    {
+        bail!("no customer {}", customer_id);
+    }
+    if Repository::<Film, _>::new(&tx).get(film_id)?.is_none() {
+        bail!("no film {}", film_id);
+    }
+    let rental_date = add_days(&tx, rental_date, 0)?;
+    let due_date = add_days(&tx, &rental_date, rental_days.into())?;
+
+    let rental = Repository::<Rental, _>::new(&tx).create(&Rental {
+        id: 0,
+        rental_date,
+        customer_id,
+        film_id,
+        due_date,
+        return_date: None,
+        late_fee: None,
+    })?;
+    tx.commit()?;
+
+    Ok(fire::dbg!("return", rental))
+}
+
+pub fn return_film(conn: &mut impl Transact, rental_id: i64, return_date: &str) -> Result<Receipt> {
+    let tx = conn.transaction()?;
+
+    let rentals = Repository::<Rental, _>::new(&tx);
+    let mut rental = rentals
+        .get(rental_id)?
+        .ok_or_else(|| anyhow!("no rental {}", rental_id))?;
+    if let Some(returned) = &rental.return_date {
+        bail!("rental {} was already returned on {}", rental_id, returned);
+    }
+    let return_date = add_days(&tx, return_date, 0)?;
+    if days_between(&tx, &rental.rental_date, &return_date)? < 0 {
+        bail!(
+            "rental {} cannot be returned on {}, before it was rented on {}",
+            rental_id,
+            return_date,
+            rental.rental_date,
+        );
+    }
+
+    let film = Repository::<Film, _>::new(&tx)
+        .get(rental.film_id)?
+        .ok_or_else(|| anyhow!("no film {}", rental.film_id))?;
+    let days_late = days_between(&tx, &rental.due_date, &return_date)?.max(0);
+    let late_fee = late_fee(film.rental_rate, days_late);
+
+    rental.return_date = Some(return_date);
+    rental.late_fee = Some(late_fee);
+    rentals.update(&rental)?;
+    tx.commit()?;
+
+    Ok(fire::dbg!(
+        "return",
+        Receipt {
+            rental,
+            days_late,
+            late_fee,
+        }
+    ))
+}
+
+// The rentals not returned by `as_of` that were due before it, the longest
+// overdue first.
+pub fn overdue_report(conn: &mut impl Transact, as_of: &str) -> Result<Vec<OverdueRental>> {
+    let tx = conn.transaction()?;
+    let as_of = add_days(&tx, as_of, 0)?;
+
+    let mut query = Query::select();
+    select_prefixed::<Rental>(&mut query, "rental_");
+    select_prefixed::<Customer>(&mut query, "customer_");
+    select_prefixed::<Film>(&mut query, "film_");
+    query
+        .expr_as(
+            days_between_expr(
+                Expr::col((schema::Rental::Table, schema::Rental::DueDate)).into(),
+                as_of.as_str().into(),
+            ),
+            Alias::new("days_overdue"),
+        )
+        .from(schema::Rental::Table)
+        .inner_join(
+            schema::Customer::Table,
+            Expr::col((schema::Customer::Table, schema::Customer::Id))
+                .equals((schema::Rental::Table, schema::Rental::CustomerId)),
+        )
+        .inner_join(
+            schema::Film::Table,
+            Expr::col((schema::Film::Table, schema::Film::Id))
+                .equals((schema::Rental::Table, schema::Rental::FilmId)),
+        )
+        .and_where(
+            Expr::col((schema::Rental::Table, schema::Rental::ReturnDate))
+                .is_null()
+                .or(
+                    Expr::col((schema::Rental::Table, schema::Rental::ReturnDate))
+                        .gt(as_of.as_str()),
+                ),
+        )
+        .and_where(Expr::col((schema::Rental::Table, schema::Rental::DueDate)).lt(as_of.as_str()))
+        .order_by((schema::Rental::Table, schema::Rental::DueDate), Order::Asc)
+        .order_by((schema::Rental::Table, schema::Rental::Id), Order::Asc);
+
+    let (sql, values) = build_query_stmt(query);
+    let report = tx.query_map(&sql, &values, |row| Ok(OverdueRental::from_row(row)?))?;
+    tx.commit()?;
+
+    Ok(report)
+}
+
+// `date` moved by a number of days, which also checks that `date` is one.
+fn add_days(conn: &impl Executor, date: &str, days: i64) -> Result<String> {
+    let invalid = || anyhow!("invalid date {:?}, expected YYYY-MM-DD", date);
+    if date != "now" && !is_date(date) {
+        return Err(invalid());
+    }
+    // Moving a date, even by 0 days, rolls days past the end of the month over
+    // into the next one, so a valid date is one that comes back unchanged.
+    let (sql, values) = build_query_stmt(
+        Query::select()
+            .expr(Expr::cust_with_values("date(?, '+0 days')", [date]))
+            .expr(Expr::cust_with_values(
+                "date(?, ?)",
+                [date.to_owned(), format!("{:+} days", days)],
+            ))
+            .to_owned(),
+    );
+    let (same, moved): (Option<String>, Option<String>) =
+        conn.query_row(&sql, &values, |row| Ok((row.get(0)?, row.get(1)?)))?;
+    match (same, moved) {
+        (Some(same), Some(moved)) if date == "now" || same == date => Ok(moved),
+        _ => Err(invalid()),
+    }
+}
+
+// Negative if `to` comes before `from`.
+fn days_between(conn: &impl Executor, from: &str, to: &str) -> Result<i64> {
+    let (sql, values) = build_query_stmt(
+        Query::select()
+            .expr(days_between_expr(from.into(), to.into()))
+            .to_owned(),
+    );
+    let days = conn.query_row(&sql, &values, |row| Ok(row.get(0)?))?;
+    Ok(days)
+}
+
+// Whole days from `from` to `to`, both dates.
+fn days_between_expr(from: SimpleExpr, to: SimpleExpr) -> SimpleExpr {
+    let julianday = |date| Func::cust(Alias::new("julianday")).arg(date);
+    Func::cast_as(
+        Expr::expr(julianday(to)).sub(julianday(from)),
+        Alias::new("INTEGER"),
+    )
+    .into()
+}
+
+// `YYYY-MM-DD`, of a day that exists.
+pub fn is_date(field: &str) -> bool {
+    let parts: Vec<&str> = field.split('-').collect();
+    let [year, month, day] = parts[..] else {
+        return false;
+    };
+    let number = |part: &str, len: usize| {
+        let digits = part.len() == len && part.bytes().all(|b| b.is_ascii_digit());
+        digits.then(|| part.parse::<u32>().unwrap())
+    };
+    let (Some(year), Some(month), Some(day)) = (number(year, 4), number(month, 2), number(day, 2))
+    else {
+        return false;
+    };
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days = match month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 if leap => 29,
+        2 => 28,
+        _ => return false,
+    };
+    (1..=days).contains(&day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::{enable_foreign_keys, init_schema};
+    use rusqlite::Connection;
+
+    fn connection() -> Connection {
+        let mut conn = Connection::open_in_memory().unwrap();
+        enable_foreign_keys(&conn).unwrap();
+        init_schema(&mut conn).unwrap();
+        Repository::<Customer, _>::new(&conn)
+            .create(&Customer {
+                id: 0,
+                first_name: "Cory".to_owned(),
+                last_name: "Hernandez".to_owned(),
+                email: "ab@zunu.hk".to_owned(),
+            })
+            .unwrap();
+        Repository::<Film, _>::new(&conn)
+            .create(&Film {
+                id: 0,
+                title: "Philosopher's Stone".to_owned(),
+                description: "The philosopher's stone.".to_owned(),
+                release_year: "2001".to_owned(),
+                rental_rate: 1.5,
+            })
+            .unwrap();
+        conn
+    }
+
+    #[test]
+    fn checkout_computes_due_date() {
+        let mut conn = connection();
+        let rental = checkout(&mut conn, 1, 1, "2023-10-28", 7).unwrap();
+        assert_eq!(rental.rental_date, "2023-10-28");
+        assert_eq!(rental.due_date, "2023-11-04");
+        assert_eq!(rental.return_date, None);
+    }
+
+    #[test]
+    fn checkout_rejects_unknown_ids_and_dates() {
+        let mut conn = connection();
+        assert!(checkout(&mut conn, 2, 1, "2023-10-28", 7).is_err());
+        assert!(checkout(&mut conn, 1, 2, "2023-10-28", 7).is_err());
+        assert!(checkout(&mut conn, 1, 1, "next tuesday", 7).is_err());
+        let rentals = Repository::<Rental, _>::new(&conn);
+        assert_eq!(rentals.count(&Default::default()).unwrap(), 0);
+    }
+
+    #[test]
+    fn dates_are_strict() {
+        let conn = connection();
+        assert_eq!(add_days(&conn, "2024-02-28", 1).unwrap(), "2024-02-29");
+        assert_eq!(add_days(&conn, "2024-02-29", 0).unwrap(), "2024-02-29");
+        for date in [
+            "2023-02-29",
+            "2023-02-30",
+            "2023-04-31",
+            "2023-2-3",
+            "2023-10-28 12:00",
+            "2023-10-28T00:00",
+            "+2023-10-28",
+            "2460246",
+        ] {
+            let err = add_days(&conn, date, 7).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!("invalid date {:?}, expected YYYY-MM-DD", date),
+            );
+        }
+        assert!(is_date(&add_days(&conn, "now", 0).unwrap()));
+    }
+
+    #[test]
+    fn days_between_dates() {
+        let (sql, _) = build_query_stmt(
+            Query::select()
+                .expr(days_between_expr(
+                    Expr::col((schema::Rental::Table, schema::Rental::DueDate)).into(),
+                    "2023-10-20".into(),
+                ))
+                .to_owned(),
+        );
+        assert_eq!(
+            sql,
+            r#"SELECT CAST((julianday(?) - julianday("rental"."due_date")) AS INTEGER)"#,
+        );
+
+        let conn = connection();
+        assert_eq!(days_between(&conn, "2023-10-20", "2023-10-17").unwrap(), -3);
+        assert_eq!(days_between(&conn, "2023-02-28", "2023-03-01").unwrap(), 1);
+    }
+
+    #[test]
+    fn return_on_time_and_late() {
+        let mut conn = connection();
+        let on_time = checkout(&mut conn, 1, 1, "2023-10-10", 7).unwrap();
+        let late = checkout(&mut conn, 1, 1, "2023-10-10", 7).unwrap();
+
+        let receipt = return_film(&mut conn, on_time.id, "2023-10-17").unwrap();
+        assert_eq!((receipt.days_late, receipt.late_fee), (0, 0.0));
+
+        let receipt = return_film(&mut conn, late.id, "2023-10-20").unwrap();
+        assert_eq!((receipt.days_late, receipt.late_fee), (3, 4.5));
+        assert_eq!(receipt.rental.return_date.as_deref(), Some("2023-10-20"));
+        let stored = Repository::<Rental, _>::new(&conn).get(late.id).unwrap();
+        assert_eq!(stored, Some(receipt.rental));
+
+        assert!(return_film(&mut conn, late.id, "2023-10-21").is_err());
+        assert!(return_film(&mut conn, 3, "2023-10-21").is_err());
+    }
+
+    #[test]
+    fn return_before_rental_date() {
+        let mut conn = connection();
+        let rental = checkout(&mut conn, 1, 1, "2023-10-10", 7).unwrap();
+        assert!(return_film(&mut conn, rental.id, "2023-10-09").is_err());
+    }
+
+    #[test]
+    fn overdue_as_of() {
+        let mut conn = connection();
+        let first = checkout(&mut conn, 1, 1, "2023-10-10", 7).unwrap();
+        let second = checkout(&mut conn, 1, 1, "2023-10-12", 7).unwrap();
+        let returned = checkout(&mut conn, 1, 1, "2023-10-01", 7).unwrap();
+        return_film(&mut conn, returned.id, "2023-10-20").unwrap();
+
+        assert!(overdue_report(&mut conn, "2023-10-08").unwrap().is_empty());
+
+        // Not returned yet as of the report date.
+        let report = overdue_report(&mut conn, "2023-10-17").unwrap();
+        assert_eq!(report.len(), 1);
+        assert_eq!(report[0].rental.id, returned.id);
+        assert_eq!(report[0].days_overdue, 9);
+
+        let report = overdue_report(&mut conn, "2023-10-20").unwrap();
+        assert_eq!(report.len(), 2);
+        assert_eq!(report[0].rental, first);
+        assert_eq!(report[0].customer.first_name, "Cory");
+        assert_eq!(report[0].film.title, "Philosopher's Stone");
+        assert_eq!(report[0].days_overdue, 3);
+        assert_eq!(report[0].late_fee(), 4.5);
+        assert_eq!(report[1].rental, second);
+        assert_eq!(report[1].days_overdue, 1);
+    }
+}